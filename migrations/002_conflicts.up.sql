@@ -0,0 +1,11 @@
+-- UP conflicts table
+-- pairs of bookings for the same resource which overlap in time
+CREATE TABLE conflicts (
+	first_booking_id INTEGER NOT NULL,
+	second_booking_id INTEGER NOT NULL,
+	resource_id INTEGER NOT NULL,
+	overlap_start DATETIME NOT NULL,
+	overlap_end DATETIME NOT NULL,
+	detected_at DATETIME NOT NULL,
+	PRIMARY KEY (first_booking_id, second_booking_id)
+);
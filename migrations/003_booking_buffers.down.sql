@@ -0,0 +1,2 @@
+ALTER TABLE bookings DROP COLUMN setup_minutes;
+ALTER TABLE bookings DROP COLUMN teardown_minutes;
@@ -0,0 +1,3 @@
+-- UP setup and teardown times recorded in CT bookings
+ALTER TABLE bookings ADD COLUMN setup_minutes INTEGER;
+ALTER TABLE bookings ADD COLUMN teardown_minutes INTEGER;
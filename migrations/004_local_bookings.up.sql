@@ -0,0 +1,13 @@
+-- UP local bookings table
+-- bookings created in room-overview itself. The CT sync never touches this table.
+CREATE TABLE local_bookings (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	title TEXT NOT NULL,
+	resource_id INTEGER NOT NULL,
+	start_time DATETIME NOT NULL,
+	end_time DATETIME NOT NULL,
+	-- 'booking' or 'block'
+	kind TEXT NOT NULL,
+	note TEXT,
+	created_at DATETIME NOT NULL
+);
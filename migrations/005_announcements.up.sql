@@ -0,0 +1,14 @@
+-- UP announcements table
+-- messages shown on the screens in addition to the bookings
+CREATE TABLE announcements (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	message TEXT NOT NULL,
+	start_time DATETIME NOT NULL,
+	end_time DATETIME NOT NULL,
+	-- 'info', 'important' or 'emergency'
+	priority TEXT NOT NULL,
+	-- comma separated resource IDs of the door signs to show this on. Empty means all door signs.
+	rooms TEXT NOT NULL,
+	show_on_landing INTEGER NOT NULL,
+	created_at DATETIME NOT NULL
+);
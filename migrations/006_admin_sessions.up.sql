@@ -0,0 +1,11 @@
+-- UP admin sessions table
+-- logged in users of the admin area
+CREATE TABLE admin_sessions (
+	-- the random value of the session cookie
+	id TEXT PRIMARY KEY NOT NULL,
+	user_name TEXT NOT NULL,
+	-- the value all mutating forms have to send
+	csrf_token TEXT NOT NULL,
+	created_at DATETIME NOT NULL,
+	expires_at DATETIME NOT NULL
+);
@@ -0,0 +1,14 @@
+-- UP api tokens table
+-- bearer tokens for machine clients of the API
+CREATE TABLE api_tokens (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	name TEXT NOT NULL UNIQUE,
+	-- hex encoded sha256 of the token. The token itself is never stored.
+	token_hash TEXT NOT NULL UNIQUE,
+	-- space separated, e.g. 'read:bookings read:rooms'
+	scopes TEXT NOT NULL,
+	created_at DATETIME NOT NULL,
+	-- NULL for tokens that do not expire
+	expires_at DATETIME,
+	last_used_at DATETIME
+);
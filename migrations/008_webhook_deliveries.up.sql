@@ -0,0 +1,15 @@
+-- UP webhook deliveries table
+-- queue of webhook payloads that still have to be delivered
+CREATE TABLE webhook_deliveries (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	-- name of the target in webhooks.targets
+	target TEXT NOT NULL,
+	event TEXT NOT NULL,
+	-- the JSON body, exactly as it is signed and sent
+	payload TEXT NOT NULL,
+	created_at DATETIME NOT NULL,
+	attempts INTEGER NOT NULL DEFAULT 0,
+	next_attempt_at DATETIME NOT NULL,
+	last_error TEXT
+);
+CREATE INDEX webhook_deliveries_next_attempt_at ON webhook_deliveries (next_attempt_at);
@@ -0,0 +1,15 @@
+-- UP booking changes table
+-- changes the sync made to bookings, for the daily email digest
+CREATE TABLE booking_changes (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	booking_id INTEGER NOT NULL,
+	resource_id INTEGER NOT NULL,
+	-- 'inserted', 'updated' or 'deleted'
+	kind TEXT NOT NULL,
+	-- the booking after the change, or before it was deleted
+	title TEXT NOT NULL,
+	start_time DATETIME NOT NULL,
+	end_time DATETIME NOT NULL,
+	changed_at DATETIME NOT NULL
+);
+CREATE INDEX booking_changes_changed_at ON booking_changes (changed_at);
@@ -0,0 +1,15 @@
+CREATE TABLE booking_changes (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	booking_id INTEGER NOT NULL,
+	resource_id INTEGER NOT NULL,
+	kind TEXT NOT NULL,
+	title TEXT NOT NULL,
+	start_time DATETIME NOT NULL,
+	end_time DATETIME NOT NULL,
+	changed_at DATETIME NOT NULL
+);
+CREATE INDEX booking_changes_changed_at ON booking_changes (changed_at);
+INSERT INTO booking_changes (booking_id, resource_id, kind, title, start_time, end_time, changed_at)
+	SELECT booking_id, resource_id, kind, title, start_time, end_time, occurred_at
+	FROM booking_events WHERE kind != 'pruned' ORDER BY id;
+DROP TABLE booking_events;
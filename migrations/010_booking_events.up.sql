@@ -0,0 +1,29 @@
+-- UP booking events table
+-- append-only history of everything the sync did to bookings; replaces booking_changes
+CREATE TABLE booking_events (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	-- the sync run that caused this event, NULL for pruning from the admin area
+	sync_run TEXT,
+	occurred_at DATETIME NOT NULL,
+	-- 'inserted', 'updated', 'deleted' or 'pruned'
+	kind TEXT NOT NULL,
+	booking_id INTEGER NOT NULL,
+	-- the booking after the change, or before it was deleted
+	resource_id INTEGER NOT NULL,
+	title TEXT NOT NULL,
+	start_time DATETIME NOT NULL,
+	end_time DATETIME NOT NULL,
+	-- JSON list of {"field", "old", "new"}, empty except for updates
+	diff TEXT NOT NULL DEFAULT '[]'
+);
+CREATE INDEX booking_events_occurred_at ON booking_events (occurred_at);
+CREATE INDEX booking_events_booking_id ON booking_events (booking_id);
+CREATE TRIGGER booking_events_append_only BEFORE UPDATE ON booking_events
+BEGIN
+	SELECT RAISE(ABORT, 'booking_events is append-only');
+END;
+
+INSERT INTO booking_events (occurred_at, kind, booking_id, resource_id, title, start_time, end_time)
+	SELECT changed_at, kind, booking_id, resource_id, title, start_time, end_time
+	FROM booking_changes ORDER BY id;
+DROP TABLE booking_changes;
@@ -0,0 +1,15 @@
+-- UP booking archive table
+-- bookings that ended and were pruned, kept for utilization reports
+CREATE TABLE booking_archive (
+	-- 'churchtools' or 'local'; the IDs of the two origins overlap
+	origin TEXT NOT NULL,
+	booking_id INTEGER NOT NULL,
+	title TEXT NOT NULL,
+	resource_id INTEGER NOT NULL,
+	start_time DATETIME NOT NULL,
+	end_time DATETIME NOT NULL,
+	setup_minutes INTEGER,
+	teardown_minutes INTEGER,
+	PRIMARY KEY (origin, booking_id)
+);
+CREATE INDEX booking_archive_start_time ON booking_archive (start_time);
@@ -0,0 +1,6 @@
+-- DOWN canonical timestamps
+-- the rewritten timestamps stay canonical; only the indexes are removed
+DROP INDEX local_bookings_start_time_end_time;
+DROP INDEX bookings_resource_id_start_time;
+DROP INDEX bookings_end_time;
+DROP INDEX bookings_start_time_end_time;
@@ -0,0 +1,47 @@
+-- UP canonical timestamps
+-- all times are stored as RFC 3339 UTC in the form 2021-03-26T15:30:00Z, so that comparing them as
+-- strings is correct. Older rows may carry no or another offset, fractional seconds, a space
+-- instead of the 'T' or unix epoch seconds; rewrite them. Values sqlite cannot parse are left
+-- untouched.
+DROP TRIGGER booking_events_append_only;
+UPDATE bookings SET
+	start_time = COALESCE(CASE typeof(start_time) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', start_time, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', start_time) END, start_time),
+	end_time = COALESCE(CASE typeof(end_time) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', end_time, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', end_time) END, end_time);
+UPDATE conflicts SET
+	overlap_start = COALESCE(CASE typeof(overlap_start) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', overlap_start, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', overlap_start) END, overlap_start),
+	overlap_end = COALESCE(CASE typeof(overlap_end) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', overlap_end, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', overlap_end) END, overlap_end),
+	detected_at = COALESCE(CASE typeof(detected_at) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', detected_at, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', detected_at) END, detected_at);
+UPDATE local_bookings SET
+	start_time = COALESCE(CASE typeof(start_time) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', start_time, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', start_time) END, start_time),
+	end_time = COALESCE(CASE typeof(end_time) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', end_time, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', end_time) END, end_time),
+	created_at = COALESCE(CASE typeof(created_at) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', created_at, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', created_at) END, created_at);
+UPDATE announcements SET
+	start_time = COALESCE(CASE typeof(start_time) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', start_time, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', start_time) END, start_time),
+	end_time = COALESCE(CASE typeof(end_time) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', end_time, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', end_time) END, end_time),
+	created_at = COALESCE(CASE typeof(created_at) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', created_at, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', created_at) END, created_at);
+UPDATE admin_sessions SET
+	created_at = COALESCE(CASE typeof(created_at) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', created_at, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', created_at) END, created_at),
+	expires_at = COALESCE(CASE typeof(expires_at) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', expires_at, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', expires_at) END, expires_at);
+UPDATE api_tokens SET
+	created_at = COALESCE(CASE typeof(created_at) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', created_at, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', created_at) END, created_at),
+	expires_at = COALESCE(CASE typeof(expires_at) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', expires_at, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', expires_at) END, expires_at),
+	last_used_at = COALESCE(CASE typeof(last_used_at) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', last_used_at, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', last_used_at) END, last_used_at);
+UPDATE webhook_deliveries SET
+	created_at = COALESCE(CASE typeof(created_at) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', created_at, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', created_at) END, created_at),
+	next_attempt_at = COALESCE(CASE typeof(next_attempt_at) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', next_attempt_at, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', next_attempt_at) END, next_attempt_at);
+UPDATE booking_events SET
+	occurred_at = COALESCE(CASE typeof(occurred_at) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', occurred_at, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', occurred_at) END, occurred_at),
+	start_time = COALESCE(CASE typeof(start_time) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', start_time, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', start_time) END, start_time),
+	end_time = COALESCE(CASE typeof(end_time) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', end_time, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', end_time) END, end_time);
+UPDATE booking_archive SET
+	start_time = COALESCE(CASE typeof(start_time) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', start_time, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', start_time) END, start_time),
+	end_time = COALESCE(CASE typeof(end_time) WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%SZ', end_time, 'unixepoch') ELSE strftime('%Y-%m-%dT%H:%M:%SZ', end_time) END, end_time);
+CREATE TRIGGER booking_events_append_only BEFORE UPDATE ON booking_events
+BEGIN
+	SELECT RAISE(ABORT, 'booking_events is append-only');
+END;
+
+CREATE INDEX bookings_start_time_end_time ON bookings (start_time, end_time);
+CREATE INDEX bookings_end_time ON bookings (end_time);
+CREATE INDEX bookings_resource_id_start_time ON bookings (resource_id, start_time, end_time);
+CREATE INDEX local_bookings_start_time_end_time ON local_bookings (start_time, end_time);
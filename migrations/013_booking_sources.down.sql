@@ -0,0 +1,51 @@
+-- DOWN booking sources
+-- bookings of all sources but 'churchtools' are dropped, their IDs may collide
+CREATE TABLE bookings_old (
+	booking_id INTEGER PRIMARY KEY,
+	title TEXT NOT NULL,
+	resource_id INTEGER NOT NULL,
+	start_time DATETIME NOT NULL,
+	end_time DATETIME NOT NULL,
+	setup_minutes INTEGER,
+	teardown_minutes INTEGER
+);
+INSERT INTO bookings_old
+	SELECT booking_id, title, resource_id, start_time, end_time, setup_minutes, teardown_minutes
+	FROM bookings WHERE source = 'churchtools';
+DROP TABLE bookings;
+ALTER TABLE bookings_old RENAME TO bookings;
+CREATE INDEX bookings_start_time_end_time ON bookings (start_time, end_time);
+CREATE INDEX bookings_end_time ON bookings (end_time);
+CREATE INDEX bookings_resource_id_start_time ON bookings (resource_id, start_time, end_time);
+
+-- conflicts are found again by the next sync
+DROP TABLE conflicts;
+CREATE TABLE conflicts (
+	first_booking_id INTEGER NOT NULL,
+	second_booking_id INTEGER NOT NULL,
+	resource_id INTEGER NOT NULL,
+	overlap_start DATETIME NOT NULL,
+	overlap_end DATETIME NOT NULL,
+	detected_at DATETIME NOT NULL,
+	PRIMARY KEY (first_booking_id, second_booking_id)
+);
+
+CREATE TABLE booking_archive_old (
+	origin TEXT NOT NULL,
+	booking_id INTEGER NOT NULL,
+	title TEXT NOT NULL,
+	resource_id INTEGER NOT NULL,
+	start_time DATETIME NOT NULL,
+	end_time DATETIME NOT NULL,
+	setup_minutes INTEGER,
+	teardown_minutes INTEGER,
+	PRIMARY KEY (origin, booking_id)
+);
+INSERT INTO booking_archive_old
+	SELECT origin, booking_id, title, resource_id, start_time, end_time, setup_minutes, teardown_minutes
+	FROM booking_archive WHERE source IN ('churchtools', '');
+DROP TABLE booking_archive;
+ALTER TABLE booking_archive_old RENAME TO booking_archive;
+CREATE INDEX booking_archive_start_time ON booking_archive (start_time);
+
+ALTER TABLE booking_events DROP COLUMN source;
@@ -0,0 +1,62 @@
+-- UP booking sources
+-- bookings can come from several ChurchTools instances whose IDs overlap, so they are namespaced by
+-- the name of their [[ct]] source. All existing bookings came from the single source 'churchtools'.
+CREATE TABLE bookings_new (
+	source TEXT NOT NULL,
+	booking_id INTEGER NOT NULL,
+	title TEXT NOT NULL,
+	resource_id INTEGER NOT NULL,
+	start_time DATETIME NOT NULL,
+	end_time DATETIME NOT NULL,
+	setup_minutes INTEGER,
+	teardown_minutes INTEGER,
+	PRIMARY KEY (source, booking_id)
+);
+INSERT INTO bookings_new
+	SELECT 'churchtools', booking_id, title, resource_id, start_time, end_time, setup_minutes, teardown_minutes
+	FROM bookings;
+DROP TABLE bookings;
+ALTER TABLE bookings_new RENAME TO bookings;
+CREATE INDEX bookings_start_time_end_time ON bookings (start_time, end_time);
+CREATE INDEX bookings_end_time ON bookings (end_time);
+CREATE INDEX bookings_resource_id_start_time ON bookings (resource_id, start_time, end_time);
+
+-- a pair of booking IDs is only unique within a source, and every room belongs to one source
+CREATE TABLE conflicts_new (
+	resource_id INTEGER NOT NULL,
+	first_booking_id INTEGER NOT NULL,
+	second_booking_id INTEGER NOT NULL,
+	overlap_start DATETIME NOT NULL,
+	overlap_end DATETIME NOT NULL,
+	detected_at DATETIME NOT NULL,
+	PRIMARY KEY (resource_id, first_booking_id, second_booking_id)
+);
+INSERT INTO conflicts_new
+	SELECT resource_id, first_booking_id, second_booking_id, overlap_start, overlap_end, detected_at
+	FROM conflicts;
+DROP TABLE conflicts;
+ALTER TABLE conflicts_new RENAME TO conflicts;
+
+CREATE TABLE booking_archive_new (
+	-- 'churchtools' or 'local'; the IDs of the two origins overlap
+	origin TEXT NOT NULL,
+	-- empty for local bookings
+	source TEXT NOT NULL,
+	booking_id INTEGER NOT NULL,
+	title TEXT NOT NULL,
+	resource_id INTEGER NOT NULL,
+	start_time DATETIME NOT NULL,
+	end_time DATETIME NOT NULL,
+	setup_minutes INTEGER,
+	teardown_minutes INTEGER,
+	PRIMARY KEY (origin, source, booking_id)
+);
+INSERT INTO booking_archive_new
+	SELECT origin, CASE origin WHEN 'churchtools' THEN 'churchtools' ELSE '' END, booking_id, title,
+		resource_id, start_time, end_time, setup_minutes, teardown_minutes
+	FROM booking_archive;
+DROP TABLE booking_archive;
+ALTER TABLE booking_archive_new RENAME TO booking_archive;
+CREATE INDEX booking_archive_start_time ON booking_archive (start_time);
+
+ALTER TABLE booking_events ADD COLUMN source TEXT NOT NULL DEFAULT 'churchtools';
@@ -0,0 +1,4 @@
+-- UP local source
+-- local bookings are namespaced by the source 'local', so their IDs cannot be mistaken for those
+-- of a sync source
+UPDATE booking_archive SET source = 'local' WHERE origin = 'local';
@@ -0,0 +1,3 @@
+-- DOWN shared instances
+DROP TABLE job_runs;
+ALTER TABLE webhook_deliveries DROP COLUMN claimed_until;
@@ -0,0 +1,9 @@
+-- UP shared instances
+-- several instances may share one db, so a delivery is claimed by the instance attempting it until
+-- claimed_until, and scheduled jobs record their last run so only one instance runs them
+ALTER TABLE webhook_deliveries ADD COLUMN claimed_until DATETIME;
+
+CREATE TABLE job_runs (
+	job TEXT PRIMARY KEY,
+	last_run DATETIME NOT NULL
+);
@@ -0,0 +1,12 @@
+-- DOWN conflict sources
+-- conflicts are found again by the next sync
+DROP TABLE conflicts;
+CREATE TABLE conflicts (
+	resource_id INTEGER NOT NULL,
+	first_booking_id INTEGER NOT NULL,
+	second_booking_id INTEGER NOT NULL,
+	overlap_start DATETIME NOT NULL,
+	overlap_end DATETIME NOT NULL,
+	detected_at DATETIME NOT NULL,
+	PRIMARY KEY (resource_id, first_booking_id, second_booking_id)
+);
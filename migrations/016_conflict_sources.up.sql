@@ -0,0 +1,29 @@
+-- UP conflict sources
+-- calendars can book the same rooms as CT, so the two bookings of a conflict may come from
+-- different sources whose IDs overlap. Each booking is given by its source and ID.
+CREATE TABLE conflicts_new (
+	resource_id INTEGER NOT NULL,
+	first_source TEXT NOT NULL,
+	first_booking_id INTEGER NOT NULL,
+	second_source TEXT NOT NULL,
+	second_booking_id INTEGER NOT NULL,
+	overlap_start DATETIME NOT NULL,
+	overlap_end DATETIME NOT NULL,
+	detected_at DATETIME NOT NULL,
+	PRIMARY KEY (resource_id, first_source, first_booking_id, second_source, second_booking_id)
+);
+-- the sources are taken from the bookings; conflicts without them are found again by the next sync
+INSERT INTO conflicts_new
+	SELECT * FROM (
+		SELECT c.resource_id,
+			(SELECT MIN(b.source) FROM bookings b
+				WHERE b.resource_id = c.resource_id AND b.booking_id = c.first_booking_id) AS first_source,
+			c.first_booking_id,
+			(SELECT MIN(b.source) FROM bookings b
+				WHERE b.resource_id = c.resource_id AND b.booking_id = c.second_booking_id) AS second_source,
+			c.second_booking_id, c.overlap_start, c.overlap_end, c.detected_at
+		FROM conflicts c
+	)
+	WHERE first_source IS NOT NULL AND second_source IS NOT NULL;
+DROP TABLE conflicts;
+ALTER TABLE conflicts_new RENAME TO conflicts;
@@ -0,0 +1,12 @@
+-- DOWN initial PostgreSQL schema
+DROP TABLE booking_archive;
+DROP TRIGGER booking_events_append_only ON booking_events;
+DROP FUNCTION booking_events_append_only;
+DROP TABLE booking_events;
+DROP TABLE webhook_deliveries;
+DROP TABLE api_tokens;
+DROP TABLE admin_sessions;
+DROP TABLE announcements;
+DROP TABLE local_bookings;
+DROP TABLE conflicts;
+DROP TABLE bookings;
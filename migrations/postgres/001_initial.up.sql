@@ -0,0 +1,131 @@
+-- UP initial PostgreSQL schema
+-- the same tables as the SQLite migrations up to 011, with native types
+CREATE TABLE bookings (
+	booking_id BIGINT PRIMARY KEY,
+	title TEXT NOT NULL,
+	resource_id BIGINT NOT NULL,
+	start_time TIMESTAMPTZ NOT NULL,
+	end_time TIMESTAMPTZ NOT NULL,
+	setup_minutes BIGINT,
+	teardown_minutes BIGINT
+);
+
+-- pairs of bookings for the same resource which overlap in time
+CREATE TABLE conflicts (
+	first_booking_id BIGINT NOT NULL,
+	second_booking_id BIGINT NOT NULL,
+	resource_id BIGINT NOT NULL,
+	overlap_start TIMESTAMPTZ NOT NULL,
+	overlap_end TIMESTAMPTZ NOT NULL,
+	detected_at TIMESTAMPTZ NOT NULL,
+	PRIMARY KEY (first_booking_id, second_booking_id)
+);
+
+-- bookings created in room-overview itself. The CT sync never touches this table.
+CREATE TABLE local_bookings (
+	id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+	title TEXT NOT NULL,
+	resource_id BIGINT NOT NULL,
+	start_time TIMESTAMPTZ NOT NULL,
+	end_time TIMESTAMPTZ NOT NULL,
+	-- 'booking' or 'block'
+	kind TEXT NOT NULL,
+	note TEXT,
+	created_at TIMESTAMPTZ NOT NULL
+);
+
+-- messages shown on the screens in addition to the bookings
+CREATE TABLE announcements (
+	id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+	message TEXT NOT NULL,
+	start_time TIMESTAMPTZ NOT NULL,
+	end_time TIMESTAMPTZ NOT NULL,
+	-- 'info', 'important' or 'emergency'
+	priority TEXT NOT NULL,
+	-- comma separated resource IDs of the door signs to show this on. Empty means all door signs.
+	rooms TEXT NOT NULL,
+	show_on_landing BOOLEAN NOT NULL,
+	created_at TIMESTAMPTZ NOT NULL
+);
+
+-- logged in users of the admin area
+CREATE TABLE admin_sessions (
+	-- the random value of the session cookie
+	id TEXT PRIMARY KEY,
+	user_name TEXT NOT NULL,
+	-- the value all mutating forms have to send
+	csrf_token TEXT NOT NULL,
+	created_at TIMESTAMPTZ NOT NULL,
+	expires_at TIMESTAMPTZ NOT NULL
+);
+
+-- bearer tokens for machine clients of the API
+CREATE TABLE api_tokens (
+	id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+	name TEXT NOT NULL UNIQUE,
+	-- hex encoded sha256 of the token. The token itself is never stored.
+	token_hash TEXT NOT NULL UNIQUE,
+	-- space separated, e.g. 'read:bookings read:rooms'
+	scopes TEXT NOT NULL,
+	created_at TIMESTAMPTZ NOT NULL,
+	-- NULL for tokens that do not expire
+	expires_at TIMESTAMPTZ,
+	last_used_at TIMESTAMPTZ
+);
+
+-- queue of webhook payloads that still have to be delivered
+CREATE TABLE webhook_deliveries (
+	id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+	-- name of the target in webhooks.targets
+	target TEXT NOT NULL,
+	event TEXT NOT NULL,
+	-- the JSON body, exactly as it is signed and sent
+	payload TEXT NOT NULL,
+	created_at TIMESTAMPTZ NOT NULL,
+	attempts BIGINT NOT NULL DEFAULT 0,
+	next_attempt_at TIMESTAMPTZ NOT NULL,
+	last_error TEXT
+);
+CREATE INDEX webhook_deliveries_next_attempt_at ON webhook_deliveries (next_attempt_at);
+
+-- append-only history of everything the sync did to bookings
+CREATE TABLE booking_events (
+	id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+	-- the sync or prune run that caused this event, NULL for pruning from the admin area
+	sync_run TEXT,
+	occurred_at TIMESTAMPTZ NOT NULL,
+	-- 'inserted', 'updated', 'deleted' or 'pruned'
+	kind TEXT NOT NULL,
+	booking_id BIGINT NOT NULL,
+	-- the booking after the change, or before it was deleted
+	resource_id BIGINT NOT NULL,
+	title TEXT NOT NULL,
+	start_time TIMESTAMPTZ NOT NULL,
+	end_time TIMESTAMPTZ NOT NULL,
+	-- JSON list of {"field", "old", "new"}, empty except for updates
+	diff TEXT NOT NULL DEFAULT '[]'
+);
+CREATE INDEX booking_events_occurred_at ON booking_events (occurred_at);
+CREATE INDEX booking_events_booking_id ON booking_events (booking_id);
+CREATE FUNCTION booking_events_append_only() RETURNS trigger AS $$
+BEGIN
+	RAISE EXCEPTION 'booking_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER booking_events_append_only BEFORE UPDATE ON booking_events
+	FOR EACH ROW EXECUTE FUNCTION booking_events_append_only();
+
+-- bookings that ended and were pruned, kept for utilization reports
+CREATE TABLE booking_archive (
+	-- 'churchtools' or 'local'; the IDs of the two origins overlap
+	origin TEXT NOT NULL,
+	booking_id BIGINT NOT NULL,
+	title TEXT NOT NULL,
+	resource_id BIGINT NOT NULL,
+	start_time TIMESTAMPTZ NOT NULL,
+	end_time TIMESTAMPTZ NOT NULL,
+	setup_minutes BIGINT,
+	teardown_minutes BIGINT,
+	PRIMARY KEY (origin, booking_id)
+);
+CREATE INDEX booking_archive_start_time ON booking_archive (start_time);
@@ -0,0 +1,5 @@
+-- DOWN booking indexes
+DROP INDEX local_bookings_start_time_end_time;
+DROP INDEX bookings_resource_id_start_time;
+DROP INDEX bookings_end_time;
+DROP INDEX bookings_start_time_end_time;
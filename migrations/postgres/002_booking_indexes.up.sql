@@ -0,0 +1,6 @@
+-- UP booking indexes
+-- timestamps are TIMESTAMPTZ here, so only the indexes of the sqlite migration 012 are needed
+CREATE INDEX bookings_start_time_end_time ON bookings (start_time, end_time);
+CREATE INDEX bookings_end_time ON bookings (end_time);
+CREATE INDEX bookings_resource_id_start_time ON bookings (resource_id, start_time, end_time);
+CREATE INDEX local_bookings_start_time_end_time ON local_bookings (start_time, end_time);
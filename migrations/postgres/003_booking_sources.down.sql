@@ -0,0 +1,18 @@
+-- DOWN booking sources
+-- bookings of all sources but 'churchtools' are dropped, their IDs may collide
+ALTER TABLE booking_events DROP COLUMN source;
+
+DELETE FROM booking_archive WHERE source NOT IN ('churchtools', '');
+ALTER TABLE booking_archive DROP CONSTRAINT booking_archive_pkey;
+ALTER TABLE booking_archive ADD PRIMARY KEY (origin, booking_id);
+ALTER TABLE booking_archive DROP COLUMN source;
+
+-- conflicts are found again by the next sync
+DELETE FROM conflicts;
+ALTER TABLE conflicts DROP CONSTRAINT conflicts_pkey;
+ALTER TABLE conflicts ADD PRIMARY KEY (first_booking_id, second_booking_id);
+
+DELETE FROM bookings WHERE source <> 'churchtools';
+ALTER TABLE bookings DROP CONSTRAINT bookings_pkey;
+ALTER TABLE bookings ADD PRIMARY KEY (booking_id);
+ALTER TABLE bookings DROP COLUMN source;
@@ -0,0 +1,18 @@
+-- UP booking sources
+-- like the sqlite migration 013: bookings are namespaced by the name of their [[ct]] source
+ALTER TABLE bookings ADD COLUMN source TEXT NOT NULL DEFAULT 'churchtools';
+ALTER TABLE bookings ALTER COLUMN source DROP DEFAULT;
+ALTER TABLE bookings DROP CONSTRAINT bookings_pkey;
+ALTER TABLE bookings ADD PRIMARY KEY (source, booking_id);
+
+ALTER TABLE conflicts DROP CONSTRAINT conflicts_pkey;
+ALTER TABLE conflicts ADD PRIMARY KEY (resource_id, first_booking_id, second_booking_id);
+
+-- the source is empty for local bookings
+ALTER TABLE booking_archive ADD COLUMN source TEXT NOT NULL DEFAULT '';
+UPDATE booking_archive SET source = 'churchtools' WHERE origin = 'churchtools';
+ALTER TABLE booking_archive ALTER COLUMN source DROP DEFAULT;
+ALTER TABLE booking_archive DROP CONSTRAINT booking_archive_pkey;
+ALTER TABLE booking_archive ADD PRIMARY KEY (origin, source, booking_id);
+
+ALTER TABLE booking_events ADD COLUMN source TEXT NOT NULL DEFAULT 'churchtools';
@@ -0,0 +1,2 @@
+-- DOWN local source
+UPDATE booking_archive SET source = '' WHERE origin = 'local';
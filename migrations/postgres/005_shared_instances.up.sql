@@ -0,0 +1,8 @@
+-- UP shared instances
+-- like the sqlite migration 015: deliveries are claimed and scheduled jobs record their last run
+ALTER TABLE webhook_deliveries ADD COLUMN claimed_until TIMESTAMPTZ;
+
+CREATE TABLE job_runs (
+	job TEXT PRIMARY KEY,
+	last_run TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,7 @@
+-- DOWN conflict sources
+-- conflicts are found again by the next sync
+DELETE FROM conflicts;
+ALTER TABLE conflicts DROP CONSTRAINT conflicts_pkey;
+ALTER TABLE conflicts ADD PRIMARY KEY (resource_id, first_booking_id, second_booking_id);
+ALTER TABLE conflicts DROP COLUMN second_source;
+ALTER TABLE conflicts DROP COLUMN first_source;
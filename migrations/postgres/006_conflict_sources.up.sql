@@ -0,0 +1,16 @@
+-- UP conflict sources
+-- like the sqlite migration 016: each booking of a conflict is given by its source and ID
+ALTER TABLE conflicts ADD COLUMN first_source TEXT;
+ALTER TABLE conflicts ADD COLUMN second_source TEXT;
+UPDATE conflicts c SET
+	first_source = (SELECT MIN(b.source) FROM bookings b
+		WHERE b.resource_id = c.resource_id AND b.booking_id = c.first_booking_id),
+	second_source = (SELECT MIN(b.source) FROM bookings b
+		WHERE b.resource_id = c.resource_id AND b.booking_id = c.second_booking_id);
+-- found again by the next sync
+DELETE FROM conflicts WHERE first_source IS NULL OR second_source IS NULL;
+ALTER TABLE conflicts ALTER COLUMN first_source SET NOT NULL;
+ALTER TABLE conflicts ALTER COLUMN second_source SET NOT NULL;
+ALTER TABLE conflicts DROP CONSTRAINT conflicts_pkey;
+ALTER TABLE conflicts ADD PRIMARY KEY
+	(resource_id, first_source, first_booking_id, second_source, second_booking_id);
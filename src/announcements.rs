@@ -0,0 +1,155 @@
+//! Announcements shown on the screens next to the bookings.
+//!
+//! An emergency announcement takes over the whole screen.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    #[default]
+    Info,
+    Important,
+    /// Shown instead of everything else, e.g. evacuation notices
+    Emergency,
+}
+impl Priority {
+    pub fn as_db_str(self) -> &'static str {
+        match self {
+            Self::Info => "info",
+            Self::Important => "important",
+            Self::Emergency => "emergency",
+        }
+    }
+
+    pub fn from_db_str(value: &str) -> Self {
+        match value {
+            "important" => Self::Important,
+            "emergency" => Self::Emergency,
+            _ => Self::Info,
+        }
+    }
+}
+
+/// A screen showing announcements
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Screen {
+    /// The overview of all rooms
+    Landing,
+    /// The door sign of the room with this `id`
+    Room(i64),
+}
+
+/// An announcement as stored in the db
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Announcement {
+    pub id: i64,
+    pub message: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub priority: Priority,
+    /// Door signs to show this on. Empty means all door signs.
+    pub rooms: Vec<i64>,
+    pub show_on_landing: bool,
+    pub created_at: DateTime<Utc>,
+}
+impl Announcement {
+    /// Should this be shown on `screen`?
+    pub fn targets(&self, screen: Screen) -> bool {
+        match screen {
+            Screen::Landing => self.show_on_landing,
+            Screen::Room(id) => self.rooms.is_empty() || self.rooms.contains(&id),
+        }
+    }
+
+    pub fn is_emergency(&self) -> bool {
+        self.priority == Priority::Emergency
+    }
+
+    pub fn is_important(&self) -> bool {
+        self.priority == Priority::Important
+    }
+}
+
+/// Store room IDs as in the `rooms` column
+pub fn rooms_to_db_str(rooms: &[i64]) -> String {
+    rooms
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Read room IDs from the `rooms` column, ignoring anything that is not an ID
+pub fn rooms_from_db_str(value: &str) -> Vec<i64> {
+    value
+        .split(',')
+        .filter_map(|x| x.trim().parse().ok())
+        .collect()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// An announcement as sent to the API to create it
+#[derive(Debug, Deserialize)]
+pub struct NewAnnouncement {
+    pub message: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub rooms: Vec<i64>,
+    #[serde(default = "default_true")]
+    pub show_on_landing: bool,
+}
+impl NewAnnouncement {
+    /// Check that this announcement can be stored, returning a human readable reason if not
+    pub fn validate(&self, config: &Config) -> Result<(), String> {
+        if self.message.trim().is_empty() {
+            return Err("message must not be empty".to_owned());
+        }
+        if self.end_time <= self.start_time {
+            return Err("end_time must be after start_time".to_owned());
+        }
+        if let Some(id) = self
+            .rooms
+            .iter()
+            .find(|id| !config.rooms.iter().any(|r| r.id() == **id))
+        {
+            return Err(format!("there is no room with resource_id {id}"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announcement_targets() {
+        let mut announcement = Announcement {
+            id: 1,
+            message: "Gottesdienst heute im Gemeindehaus".to_owned(),
+            start_time: Utc::now(),
+            end_time: Utc::now(),
+            priority: Priority::Info,
+            rooms: vec![],
+            show_on_landing: false,
+            created_at: Utc::now(),
+        };
+        assert!(!announcement.targets(Screen::Landing));
+        assert!(announcement.targets(Screen::Room(12)));
+        announcement.rooms = rooms_from_db_str("12, 13");
+        assert_eq!(announcement.rooms, vec![12, 13]);
+        assert!(announcement.targets(Screen::Room(13)));
+        assert!(!announcement.targets(Screen::Room(14)));
+        assert_eq!(rooms_to_db_str(&announcement.rooms), "12,13");
+    }
+}
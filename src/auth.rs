@@ -0,0 +1,372 @@
+//! Authentication: password hashes, sessions and CSRF tokens for the admin area, and API tokens
+//! with scopes for machine clients.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use argon2::{
+    password_hash::{PasswordHasher, SaltString},
+    Argon2, PasswordHash, PasswordVerifier,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::config::AdminConfig;
+
+/// Failed logins from one address before it has to wait
+const MAX_FAILED_LOGINS: u32 = 5;
+/// Failed logins are counted for this long after the first one
+const FAILED_LOGIN_WINDOW: Duration = Duration::from_secs(15 * 60);
+/// Basic auth credentials are not checked again for this long after they were verified
+const VERIFIED_LOGIN_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// A logged in user of the admin area
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdminSession {
+    /// the random value of the session cookie
+    pub id: String,
+    pub user_name: String,
+    /// the value all mutating forms have to send
+    pub csrf_token: String,
+    pub expires_at: DateTime<Utc>,
+}
+impl AdminSession {
+    /// Create a new session for `user_name`, valid for the configured time
+    pub fn new(user_name: &str, config: &AdminConfig) -> Self {
+        Self {
+            id: random_token(),
+            user_name: user_name.to_owned(),
+            csrf_token: random_token(),
+            expires_at: Utc::now() + chrono::TimeDelta::minutes(config.session_minutes),
+        }
+    }
+
+    /// Is `token` the CSRF token of this session?
+    pub fn csrf_token_matches(&self, token: &str) -> bool {
+        constant_time_eq(self.csrf_token.as_bytes(), token.as_bytes())
+    }
+}
+
+/// A random token of 244 bits, suitable for session IDs
+pub fn random_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Compare without returning early, so secrets cannot be guessed byte by byte from timings
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Hash a password for the `admin.users` config
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes())?;
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// A hash of a random password, checked for unknown users so they take as long as known ones
+fn dummy_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| hash_password(&random_token()).expect("a random password can be hashed"))
+}
+
+/// Check `password` against the configured users.
+///
+/// Returns false for unknown users and unparsable hashes. Unknown users are checked against a
+/// dummy hash, so the answer does not tell which users exist. The hash is checked on a blocking
+/// thread, as it takes long enough to stall the workers serving all other requests.
+pub async fn verify_login(config: &AdminConfig, user_name: &str, password: &str) -> bool {
+    let stored = config
+        .users
+        .iter()
+        .find(|u| u.name == user_name)
+        .map(|user| user.password_hash.clone());
+    let known = stored.is_some();
+    let (user_name, password) = (user_name.to_owned(), password.to_owned());
+    let matches = tokio::task::spawn_blocking(move || {
+        let stored = stored.unwrap_or_else(|| dummy_hash().to_owned());
+        let Ok(hash) = PasswordHash::new(&stored) else {
+            tracing::warn!("The password hash for admin user {user_name} cannot be parsed.");
+            return false;
+        };
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+    .await
+    .unwrap_or(false);
+    matches && known
+}
+
+/// Counts failed logins per client address, for the login form and basic auth alike.
+///
+/// After [`MAX_FAILED_LOGINS`] failures, the address has to wait until [`FAILED_LOGIN_WINDOW`]
+/// has passed since its first failure.
+#[derive(Debug, Default)]
+pub struct LoginThrottle {
+    /// Number of failures and the time of the first one
+    failures: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+}
+impl LoginThrottle {
+    /// How long `ip` has to wait before it may try to log in again, if at all
+    pub fn retry_after(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let failures = self
+            .failures
+            .lock()
+            .expect("login throttle lock is never poisoned");
+        let (count, since) = failures.get(&ip)?;
+        let passed = now.duration_since(*since);
+        (*count >= MAX_FAILED_LOGINS && passed < FAILED_LOGIN_WINDOW)
+            .then(|| FAILED_LOGIN_WINDOW - passed)
+    }
+
+    pub fn record_failure(&self, ip: IpAddr, now: Instant) {
+        let mut failures = self
+            .failures
+            .lock()
+            .expect("login throttle lock is never poisoned");
+        // forget old failures, so the map does not grow without bound
+        failures.retain(|_, (_, since)| now.duration_since(*since) < FAILED_LOGIN_WINDOW);
+        failures.entry(ip).or_insert((0, now)).0 += 1;
+    }
+
+    pub fn record_success(&self, ip: IpAddr) {
+        self.failures
+            .lock()
+            .expect("login throttle lock is never poisoned")
+            .remove(&ip);
+    }
+}
+
+/// Recently verified basic auth credentials, so argon2 does not run on every request.
+///
+/// Only a SHA-256 of user name and password is kept.
+#[derive(Debug, Default)]
+pub struct VerifiedLogins {
+    verified: Mutex<HashMap<[u8; 32], Instant>>,
+}
+impl VerifiedLogins {
+    /// [`verify_login`], unless the same credentials were verified within [`VERIFIED_LOGIN_TTL`]
+    pub async fn verify(
+        &self,
+        config: &AdminConfig,
+        user_name: &str,
+        password: &str,
+        now: Instant,
+    ) -> bool {
+        let mut hasher = Sha256::new();
+        hasher.update(user_name.as_bytes());
+        hasher.update([0]);
+        hasher.update(password.as_bytes());
+        let key: [u8; 32] = hasher.finalize().into();
+        let cached = self
+            .verified
+            .lock()
+            .expect("verified logins lock is never poisoned")
+            .get(&key)
+            .is_some_and(|at| now.duration_since(*at) < VERIFIED_LOGIN_TTL);
+        if cached {
+            return true;
+        }
+        if !verify_login(config, user_name, password).await {
+            return false;
+        }
+        let mut verified = self
+            .verified
+            .lock()
+            .expect("verified logins lock is never poisoned");
+        verified.retain(|_, at| now.duration_since(*at) < VERIFIED_LOGIN_TTL);
+        verified.insert(key, now);
+        true
+    }
+}
+
+/// What an API token may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Scope {
+    #[serde(rename = "read:bookings")]
+    ReadBookings,
+    #[serde(rename = "read:rooms")]
+    ReadRooms,
+    /// Create and delete local bookings and announcements
+    #[serde(rename = "write:overrides")]
+    WriteOverrides,
+    #[serde(rename = "admin:sync")]
+    AdminSync,
+}
+impl Scope {
+    pub const ALL: [Self; 4] = [
+        Self::ReadBookings,
+        Self::ReadRooms,
+        Self::WriteOverrides,
+        Self::AdminSync,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ReadBookings => "read:bookings",
+            Self::ReadRooms => "read:rooms",
+            Self::WriteOverrides => "write:overrides",
+            Self::AdminSync => "admin:sync",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.as_str() == value)
+    }
+}
+impl core::fmt::Display for Scope {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Store scopes as in the `scopes` column
+pub fn scopes_to_db_str(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Read scopes from the `scopes` column, ignoring unknown ones
+pub fn scopes_from_db_str(value: &str) -> Vec<Scope> {
+    value.split_whitespace().filter_map(Scope::parse).collect()
+}
+
+/// An API token as stored in the db
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+impl ApiToken {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|e| e <= now)
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+/// A new random API token. Only its hash is stored, the token is shown once.
+pub fn new_api_token() -> String {
+    format!("ro_{}", random_token())
+}
+
+/// Hash an API token for storage and lookup.
+///
+/// The tokens are long random values, so a fast hash is enough.
+pub fn hash_api_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AdminUser;
+
+    #[test]
+    fn constant_time_eq_compares_contents() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    #[tokio::test]
+    async fn login_with_hashed_password() {
+        let config = AdminConfig {
+            users: vec![AdminUser {
+                name: "kuester".to_owned(),
+                password_hash: hash_password("hunter2").unwrap(),
+            }],
+            ..Default::default()
+        };
+        assert!(verify_login(&config, "kuester", "hunter2").await);
+        assert!(!verify_login(&config, "kuester", "hunter3").await);
+        assert!(!verify_login(&config, "pfarrer", "hunter2").await);
+    }
+
+    #[tokio::test]
+    async fn verified_logins_are_cached() {
+        let mut config = AdminConfig {
+            users: vec![AdminUser {
+                name: "kuester".to_owned(),
+                password_hash: hash_password("hunter2").unwrap(),
+            }],
+            ..Default::default()
+        };
+        let verified = VerifiedLogins::default();
+        let now = Instant::now();
+        assert!(verified.verify(&config, "kuester", "hunter2", now).await);
+        assert!(!verified.verify(&config, "kuester", "hunter3", now).await);
+        // without the user, only the cache can let the login through
+        config.users.clear();
+        assert!(verified.verify(&config, "kuester", "hunter2", now).await);
+        assert!(
+            !verified
+                .verify(&config, "kuester", "hunter2", now + VERIFIED_LOGIN_TTL)
+                .await
+        );
+    }
+
+    #[test]
+    fn failed_logins_are_throttled() {
+        let throttle = LoginThrottle::default();
+        let ip = IpAddr::from([192, 168, 0, 7]);
+        let other = IpAddr::from([192, 168, 0, 8]);
+        let start = Instant::now();
+        for _ in 0..MAX_FAILED_LOGINS - 1 {
+            throttle.record_failure(ip, start);
+        }
+        assert_eq!(throttle.retry_after(ip, start), None);
+        throttle.record_failure(ip, start);
+        let later = start + Duration::from_secs(60);
+        assert_eq!(
+            throttle.retry_after(ip, later),
+            Some(FAILED_LOGIN_WINDOW - Duration::from_secs(60))
+        );
+        assert_eq!(throttle.retry_after(other, later), None);
+        assert_eq!(throttle.retry_after(ip, start + FAILED_LOGIN_WINDOW), None);
+
+        throttle.record_success(ip);
+        assert_eq!(throttle.retry_after(ip, later), None);
+    }
+
+    #[test]
+    fn api_token_hash_and_scopes() {
+        let token = new_api_token();
+        assert!(token.starts_with("ro_"));
+        assert_eq!(hash_api_token(&token), hash_api_token(&token));
+        assert_ne!(hash_api_token(&token), hash_api_token(&new_api_token()));
+        assert_eq!(
+            hash_api_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let scopes = scopes_from_db_str("read:rooms admin:sync unknown");
+        assert_eq!(scopes, vec![Scope::ReadRooms, Scope::AdminSync]);
+        assert_eq!(scopes_to_db_str(&scopes), "read:rooms admin:sync");
+    }
+}
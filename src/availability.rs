@@ -0,0 +1,332 @@
+//! Find rooms that are free in a given interval.
+
+use chrono::{DateTime, NaiveTime, TimeDelta, Utc};
+use serde::Serialize;
+
+use crate::{
+    config::{Config, RoomConfig, MAX_BUFFER},
+    db::DBError,
+    Booking,
+};
+
+/// How far after the requested start we look for a free slot when nothing is free
+pub const SUGGESTION_HORIZON: TimeDelta = TimeDelta::days(7);
+
+/// Restrict which rooms are considered at all
+#[derive(Debug, Default)]
+pub struct RoomFilter {
+    pub building: Option<String>,
+    pub min_seats: Option<u32>,
+    /// The room must have all of these, see [`RoomAttributes::has`](crate::config::RoomAttributes::has)
+    pub equipment: Vec<String>,
+}
+impl RoomFilter {
+    pub fn matches(&self, room: &RoomConfig) -> bool {
+        self.building
+            .as_ref()
+            .is_none_or(|b| room.building.as_ref() == Some(b))
+            && self
+                .min_seats
+                .is_none_or(|min| room.attributes.seats.is_some_and(|seats| seats >= min))
+            && self
+                .equipment
+                .iter()
+                .all(|wanted| room.attributes.has(wanted))
+    }
+}
+
+/// Does this booking block its room at some point in [start, end)?
+pub fn overlaps(booking: &Booking, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+    booking.start_time < end && start < booking.end_time
+}
+
+/// All rooms matching `filter` that have no booking overlapping [start, end)
+pub fn free_rooms<'a>(
+    rooms: &'a [RoomConfig],
+    filter: &RoomFilter,
+    bookings: &[Booking],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Vec<&'a RoomConfig> {
+    rooms
+        .iter()
+        .filter(|room| filter.matches(room))
+        .filter(|room| {
+            !bookings
+                .iter()
+                .any(|b| b.resource_id == room.id() && overlaps(b, start, end))
+        })
+        .collect()
+}
+
+/// The earliest time at or after `start` at which `room` is free for `duration`, if that time
+/// lies before `horizon`.
+pub fn next_free_slot(
+    room: &RoomConfig,
+    bookings: &[Booking],
+    start: DateTime<Utc>,
+    duration: TimeDelta,
+    horizon: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let mut room_bookings = bookings
+        .iter()
+        .filter(|b| b.resource_id == room.id())
+        .collect::<Vec<_>>();
+    room_bookings.sort_by_key(|b| b.start_time);
+
+    let mut candidate = start;
+    for booking in room_bookings {
+        if booking.end_time <= candidate {
+            continue;
+        }
+        if candidate + duration <= booking.start_time {
+            break;
+        }
+        candidate = booking.end_time;
+    }
+    (candidate + duration <= horizon).then_some(candidate)
+}
+
+/// Out of all rooms matching `filter`, find the one that gets free for `duration` first.
+///
+/// Slots of a room must end before `known_until` of that room.
+pub fn suggest_slot<'a>(
+    rooms: &'a [RoomConfig],
+    filter: &RoomFilter,
+    bookings: &[Booking],
+    start: DateTime<Utc>,
+    duration: TimeDelta,
+    known_until: impl Fn(&RoomConfig) -> DateTime<Utc>,
+) -> Option<(&'a RoomConfig, DateTime<Utc>)> {
+    rooms
+        .iter()
+        .filter(|room| filter.matches(room))
+        .filter_map(|room| {
+            let horizon = (start + SUGGESTION_HORIZON).min(known_until(room));
+            next_free_slot(room, bookings, start, duration, horizon).map(|slot| (room, slot))
+        })
+        .min_by_key(|(_, slot)| *slot)
+}
+
+/// The bookings of `room` are only known until the end of the sync window of its sources
+pub fn known_until(config: &Config, room: &RoomConfig, now: DateTime<Utc>) -> DateTime<Utc> {
+    let pull_days = config
+        .ct
+        .iter()
+        .filter(|ct| ct.name == room.source)
+        .map(|ct| ct.ct_pull_days)
+        .chain(
+            config
+                .calendars
+                .iter()
+                .filter(|c| c.room == room.id())
+                .map(|c| c.pull_days),
+        )
+        .min()
+        .unwrap_or(0);
+    (now.date_naive() + TimeDelta::days(pull_days + 1))
+        .and_time(NaiveTime::MIN)
+        .and_utc()
+}
+
+/// A room that is free from `start` on
+#[derive(Debug, Serialize)]
+pub struct Suggestion {
+    pub room: RoomConfig,
+    pub start: DateTime<Utc>,
+}
+
+/// The result of a search for free rooms
+#[derive(Debug, Serialize)]
+pub struct Availability {
+    pub free_rooms: Vec<RoomConfig>,
+    /// Rooms matching the filter whose bookings are not known for the whole interval, because it
+    /// reaches beyond the sync window
+    pub unknown_rooms: Vec<RoomConfig>,
+    /// Only set if no room is free in the requested interval
+    pub suggestion: Option<Suggestion>,
+}
+
+/// Find the rooms matching `filter` that are free in [start, end).
+///
+/// Rooms are only reported as free while their bookings are known. When no room is free, suggest
+/// the earliest slot of the same length in any matching room.
+pub async fn find_free_rooms(
+    config: &Config,
+    filter: &RoomFilter,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Availability, DBError> {
+    // rooms are blocked during setup and teardown as well
+    let bookings = config
+        .db
+        .get_visible_bookings_in_timeframe(
+            (start - MAX_BUFFER).naive_utc(),
+            ((start + SUGGESTION_HORIZON).max(end) + MAX_BUFFER).naive_utc(),
+        )
+        .await?
+        .iter()
+        .map(|b| config.with_buffers(b))
+        .collect::<Vec<_>>();
+    let now = Utc::now();
+    let known_until = |room: &RoomConfig| known_until(config, room, now);
+    let (known_rooms, unknown_rooms): (Vec<_>, Vec<_>) = config
+        .rooms
+        .iter()
+        .cloned()
+        .partition(|room| end <= known_until(room));
+    let free_rooms = free_rooms(&known_rooms, filter, &bookings, start, end)
+        .into_iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    let suggestion = if free_rooms.is_empty() {
+        suggest_slot(
+            &config.rooms,
+            filter,
+            &bookings,
+            start,
+            end - start,
+            known_until,
+        )
+        .map(|(room, start)| Suggestion {
+            room: room.clone(),
+            start,
+        })
+    } else {
+        None
+    };
+    Ok(Availability {
+        free_rooms,
+        unknown_rooms: unknown_rooms
+            .into_iter()
+            .filter(|room| filter.matches(room))
+            .collect(),
+        suggestion,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RoomAttributes;
+
+    fn room(id: i64, seats: Option<u32>, equipment: &[&str]) -> RoomConfig {
+        RoomConfig {
+            churchtools_id: id,
+            id: None,
+            source: "churchtools".to_owned(),
+            name: format!("Room {id}"),
+            location_hint: String::new(),
+            building: Some("Gemeindehaus".to_owned()),
+            attributes: RoomAttributes {
+                seats,
+                equipment: equipment.iter().map(|e| (*e).to_owned()).collect(),
+                ..Default::default()
+            },
+            attributes_from_ct: false,
+            setup_minutes: None,
+            teardown_minutes: None,
+            preheat_minutes: None,
+        }
+    }
+
+    fn booking(resource_id: i64, start: &str, end: &str) -> Booking {
+        Booking {
+            title: "title".to_owned(),
+            booking_id: 1,
+            source: "churchtools".to_owned(),
+            resource_id,
+            start_time: DateTime::parse_from_rfc3339(start).unwrap().into(),
+            end_time: DateTime::parse_from_rfc3339(end).unwrap().into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: crate::BookingOrigin::ChurchTools,
+        }
+    }
+
+    fn time(x: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(x).unwrap().into()
+    }
+
+    #[test]
+    fn touching_bookings_do_not_block() {
+        let rooms = vec![room(1, None, &[]), room(2, None, &[])];
+        let bookings = vec![
+            booking(1, "2024-10-15T16:00:00Z", "2024-10-15T18:00:00Z"),
+            booking(2, "2024-10-15T17:00:00Z", "2024-10-15T19:00:00Z"),
+        ];
+        let free = free_rooms(
+            &rooms,
+            &RoomFilter::default(),
+            &bookings,
+            time("2024-10-15T18:00:00Z"),
+            time("2024-10-15T20:00:00Z"),
+        );
+        assert_eq!(free.len(), 1);
+        assert_eq!(free[0].id(), 1);
+    }
+
+    #[test]
+    fn filter_by_seats_and_equipment() {
+        let rooms = vec![
+            room(1, Some(20), &["Küche"]),
+            room(2, Some(80), &["Küche", "Bühne"]),
+            room(3, None, &["Bühne"]),
+        ];
+        let filter = RoomFilter {
+            building: None,
+            min_seats: Some(30),
+            equipment: vec!["bühne".to_owned()],
+        };
+        let free = free_rooms(
+            &rooms,
+            &filter,
+            &[],
+            time("2024-10-15T18:00:00Z"),
+            time("2024-10-15T20:00:00Z"),
+        );
+        assert_eq!(free.len(), 1);
+        assert_eq!(free[0].id(), 2);
+    }
+
+    #[test]
+    fn suggest_earliest_gap() {
+        let rooms = vec![room(1, None, &[]), room(2, None, &[])];
+        let bookings = vec![
+            booking(1, "2024-10-15T16:00:00Z", "2024-10-15T18:30:00Z"),
+            // a gap of one hour is too short for the two hours requested
+            booking(1, "2024-10-15T19:30:00Z", "2024-10-15T21:00:00Z"),
+            booking(2, "2024-10-15T17:00:00Z", "2024-10-15T20:00:00Z"),
+        ];
+        let (room, slot) = suggest_slot(
+            &rooms,
+            &RoomFilter::default(),
+            &bookings,
+            time("2024-10-15T18:00:00Z"),
+            TimeDelta::hours(2),
+            |_| time("2024-10-20T00:00:00Z"),
+        )
+        .unwrap();
+        assert_eq!(room.id(), 2);
+        assert_eq!(slot, time("2024-10-15T20:00:00Z"));
+
+        // nothing is known about room 2 after 21:00, so its slot is not suggested
+        let (room, slot) = suggest_slot(
+            &rooms,
+            &RoomFilter::default(),
+            &bookings,
+            time("2024-10-15T18:00:00Z"),
+            TimeDelta::hours(2),
+            |room| {
+                if room.id() == 2 {
+                    time("2024-10-15T21:00:00Z")
+                } else {
+                    time("2024-10-20T00:00:00Z")
+                }
+            },
+        )
+        .unwrap();
+        assert_eq!(room.id(), 1);
+        assert_eq!(slot, time("2024-10-15T21:00:00Z"));
+    }
+}
@@ -0,0 +1,446 @@
+//! Export, import and scheduled backups of the db.
+//!
+//! A JSON export holds the bookings, local bookings, the archive and the history and can be
+//! imported into either backend. SQLite can additionally write a snapshot of the whole db with
+//! `VACUUM INTO`, which can be imported like an export.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::{BackupConfig, Config},
+    db::{DBError, SqliteStorage, Storage},
+    history::BookingEvent,
+    local_bookings::LocalBooking,
+    Booking, BookingOrigin, InShutdown,
+};
+
+/// Bumped whenever [`DbExport`] changes incompatibly
+pub const EXPORT_VERSION: u32 = 2;
+
+/// Every SQLite file starts with this
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// Backups written by [`write_backup`] start with this, so rotation leaves other files alone
+const BACKUP_FILE_PREFIX: &str = "room-overview-";
+
+#[derive(Debug)]
+pub enum BackupError {
+    DB(DBError),
+    Read(PathBuf, std::io::Error),
+    Write(PathBuf, std::io::Error),
+    Json(serde_json::Error),
+    Invalid(String),
+}
+impl core::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::DB(e) => write!(f, "{e}"),
+            Self::Read(path, e) => {
+                write!(f, "Unable to read {}. Inner Error: {e}.", path.display())
+            }
+            Self::Write(path, e) => {
+                write!(f, "Unable to write {}. Inner Error: {e}.", path.display())
+            }
+            Self::Json(e) => write!(f, "The export is no valid JSON. Inner Error: {e}."),
+            Self::Invalid(x) => write!(f, "The export is invalid: {x}."),
+        }
+    }
+}
+impl core::error::Error for BackupError {}
+impl From<DBError> for BackupError {
+    fn from(value: DBError) -> Self {
+        Self::DB(value)
+    }
+}
+
+/// How a backup is written
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupFormat {
+    /// A [`DbExport`], works with every backend
+    #[default]
+    Json,
+    /// A copy of the whole SQLite db
+    Sqlite,
+}
+impl BackupFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "json" => Some(Self::Json),
+            "sqlite" => Some(Self::Sqlite),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Sqlite => "sqlite",
+        }
+    }
+}
+
+/// Everything that cannot be pulled from CT again, and the bookings from CT for good measure
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DbExport {
+    /// [`EXPORT_VERSION`] of the program that wrote this
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub bookings: Vec<Booking>,
+    pub local_bookings: Vec<LocalBooking>,
+    pub archived_bookings: Vec<Booking>,
+    pub booking_events: Vec<BookingEvent>,
+}
+impl DbExport {
+    /// Check everything the db would not, before anything is replaced
+    pub fn validate(&self) -> Result<(), BackupError> {
+        if self.version != EXPORT_VERSION {
+            return Err(BackupError::Invalid(format!(
+                "it has version {}, but only version {EXPORT_VERSION} can be imported",
+                self.version
+            )));
+        }
+        let mut ids = HashSet::new();
+        for b in &self.bookings {
+            check_interval("booking", b.booking_id, b.start_time, b.end_time)?;
+            if b.origin != BookingOrigin::ChurchTools {
+                return Err(BackupError::Invalid(format!(
+                    "booking {} is not from ChurchTools",
+                    b.booking_id
+                )));
+            }
+            if !ids.insert(b.booking_id) {
+                return Err(duplicate("booking", b.booking_id));
+            }
+        }
+        let mut ids = HashSet::new();
+        for b in &self.local_bookings {
+            check_interval("local booking", b.id, b.start_time, b.end_time)?;
+            if !ids.insert(b.id) {
+                return Err(duplicate("local booking", b.id));
+            }
+        }
+        let mut ids = HashSet::new();
+        for b in &self.archived_bookings {
+            check_interval("archived booking", b.booking_id, b.start_time, b.end_time)?;
+            if !ids.insert((b.origin.as_db_str(), b.booking_id)) {
+                return Err(duplicate("archived booking", b.booking_id));
+            }
+        }
+        let mut ids = HashSet::new();
+        for e in &self.booking_events {
+            check_interval("history event", e.id, e.start_time, e.end_time)?;
+            if !ids.insert(e.id) {
+                return Err(duplicate("history event", e.id));
+            }
+        }
+        Ok(())
+    }
+
+    /// What is in here, for log messages
+    pub fn summary(&self) -> String {
+        format!(
+            "{} bookings, {} local bookings, {} archived bookings and {} history events",
+            self.bookings.len(),
+            self.local_bookings.len(),
+            self.archived_bookings.len(),
+            self.booking_events.len()
+        )
+    }
+}
+
+fn check_interval(
+    what: &str,
+    id: i64,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<(), BackupError> {
+    if start > end {
+        Err(BackupError::Invalid(format!(
+            "{what} {id} ends before it starts"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+fn duplicate(what: &str, id: i64) -> BackupError {
+    BackupError::Invalid(format!("{what} {id} appears twice"))
+}
+
+/// Write the db to `path`
+pub async fn export(
+    db: &dyn Storage,
+    format: BackupFormat,
+    path: &Path,
+) -> Result<(), BackupError> {
+    match format {
+        BackupFormat::Json => {
+            let data = db.export().await?;
+            let json = serde_json::to_vec(&data).map_err(BackupError::Json)?;
+            std::fs::write(path, json).map_err(|e| BackupError::Write(path.to_owned(), e))?;
+            info!("Exported {} to {}", data.summary(), path.display());
+        }
+        BackupFormat::Sqlite => {
+            db.snapshot(path).await?;
+            info!("Wrote a snapshot of the db to {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Read an export or a SQLite snapshot, whichever `content` is
+pub async fn parse(content: &[u8]) -> Result<DbExport, BackupError> {
+    if !content.starts_with(SQLITE_HEADER) {
+        return serde_json::from_slice(content).map_err(BackupError::Json);
+    }
+    // sqlite can only open files
+    let path = std::env::temp_dir().join(format!("room-overview-import-{}.sqlite", Uuid::new_v4()));
+    std::fs::write(&path, content).map_err(|e| BackupError::Write(path.clone(), e))?;
+    let res = match SqliteStorage::open_snapshot(&path).await {
+        Ok(snapshot) => {
+            let res = snapshot.export().await;
+            snapshot.close().await;
+            res
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = std::fs::remove_file(&path) {
+        warn!("Unable to remove {}: {e}", path.display());
+    }
+    Ok(res?)
+}
+
+/// Validate `data` and replace the contents of the db with it
+pub async fn import(db: &dyn Storage, data: &DbExport) -> Result<(), BackupError> {
+    data.validate()?;
+    db.import(data).await?;
+    info!(
+        "Imported {} exported at {}",
+        data.summary(),
+        data.exported_at
+    );
+    Ok(())
+}
+
+/// Write a backup into the configured directory and remove the oldest ones beyond `keep`
+pub async fn write_backup(
+    config: &Config,
+    backup: &BackupConfig,
+    now: DateTime<Utc>,
+) -> Result<PathBuf, BackupError> {
+    std::fs::create_dir_all(&backup.directory)
+        .map_err(|e| BackupError::Write(backup.directory.clone(), e))?;
+    let path = backup.directory.join(format!(
+        "{BACKUP_FILE_PREFIX}{}.{}",
+        now.format("%Y%m%dT%H%M%SZ"),
+        backup.format.extension()
+    ));
+    export(&*config.db, backup.format, &path).await?;
+
+    let names = std::fs::read_dir(&backup.directory)
+        .map_err(|e| BackupError::Read(backup.directory.clone(), e))?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+    for name in backups_to_remove(names, backup.keep) {
+        let old = backup.directory.join(name);
+        match std::fs::remove_file(&old) {
+            Ok(()) => info!("Removed old backup {}", old.display()),
+            Err(e) => warn!("Unable to remove old backup {}: {e}", old.display()),
+        }
+    }
+    Ok(path)
+}
+
+/// Backups among the files `names` that are not among the newest `keep`
+fn backups_to_remove(mut names: Vec<String>, keep: usize) -> Vec<String> {
+    names.retain(|name| {
+        name.strip_prefix(BACKUP_FILE_PREFIX).is_some_and(|rest| {
+            [BackupFormat::Json, BackupFormat::Sqlite]
+                .iter()
+                .any(|f| rest.ends_with(&format!(".{}", f.extension())))
+        })
+    });
+    // the timestamp in the name sorts chronologically
+    names.sort_unstable();
+    let remove = names.len().saturating_sub(keep);
+    names.truncate(remove);
+    names
+}
+
+/// Write a backup every `[backup] interval` seconds, if backups are configured.
+///
+/// Of several instances sharing the db, only one writes a backup per interval.
+pub async fn keep_backing_up(
+    config: Arc<Config>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+) {
+    let Some(backup) = &config.backup else {
+        return;
+    };
+    info!("Starting backup task");
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_secs(backup.interval.max(1)));
+    let period = TimeDelta::from_std(interval.period()).unwrap_or(TimeDelta::MAX);
+    // the first tick is immediate; do not write a backup on every restart
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down backups now.");
+                return;
+            }
+            _ = interval.tick() => {}
+        }
+        // instances sharing the db would all back up the same data
+        match config
+            .db
+            .claim_periodic_job_run("backup", period, Utc::now())
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!("Not writing a backup, another instance did so recently.");
+                continue;
+            }
+            Err(e) => {
+                warn!("Failed to write a backup. Error encountered: {e}");
+                continue;
+            }
+        }
+        match write_backup(&config, backup, Utc::now()).await {
+            Ok(path) => info!("Wrote backup {}", path.display()),
+            Err(e) => warn!("Failed to write a backup. Error encountered: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{history::BookingEventKind, local_bookings::LocalBookingKind};
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().to_utc()
+    }
+
+    fn booking(booking_id: i64, origin: BookingOrigin) -> Booking {
+        Booking {
+            resource_id: 12,
+            booking_id,
+            source: "churchtools".to_owned(),
+            title: "Chorprobe".to_owned(),
+            start_time: at("2024-10-16T18:00:00Z"),
+            end_time: at("2024-10-16T20:00:00Z"),
+            setup_minutes: None,
+            teardown_minutes: Some(15),
+            origin,
+        }
+    }
+
+    fn example_export() -> DbExport {
+        DbExport {
+            version: EXPORT_VERSION,
+            exported_at: at("2024-10-17T08:00:00Z"),
+            bookings: vec![booking(1, BookingOrigin::ChurchTools)],
+            local_bookings: vec![LocalBooking {
+                id: 4,
+                title: "Renovierung".to_owned(),
+                resource_id: 13,
+                start_time: at("2024-10-18T00:00:00Z"),
+                end_time: at("2024-10-20T00:00:00Z"),
+                kind: LocalBookingKind::Block,
+                note: Some("Malerarbeiten".to_owned()),
+                created_at: at("2024-10-01T10:00:00Z"),
+            }],
+            archived_bookings: vec![
+                booking(2, BookingOrigin::ChurchTools),
+                booking(2, BookingOrigin::Local),
+            ],
+            booking_events: vec![BookingEvent {
+                id: 9,
+                sync_run: Some("run".to_owned()),
+                occurred_at: at("2024-10-15T12:00:00Z"),
+                kind: BookingEventKind::Inserted,
+                booking_id: 1,
+                source: "churchtools".to_owned(),
+                resource_id: 12,
+                title: "Chorprobe".to_owned(),
+                start_time: at("2024-10-16T18:00:00Z"),
+                end_time: at("2024-10-16T20:00:00Z"),
+                diff: vec![],
+            }],
+        }
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let data = example_export();
+        let json = serde_json::to_vec(&data).unwrap();
+        assert_eq!(serde_json::from_slice::<DbExport>(&json).unwrap(), data);
+        data.validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_exports() {
+        let mut data = example_export();
+        data.version = EXPORT_VERSION + 1;
+        assert!(matches!(data.validate(), Err(BackupError::Invalid(_))));
+
+        let mut data = example_export();
+        data.bookings.push(booking(1, BookingOrigin::ChurchTools));
+        assert!(matches!(data.validate(), Err(BackupError::Invalid(_))));
+
+        let mut data = example_export();
+        data.bookings[0].origin = BookingOrigin::Local;
+        assert!(matches!(data.validate(), Err(BackupError::Invalid(_))));
+
+        let mut data = example_export();
+        data.local_bookings[0].end_time = at("2024-10-17T00:00:00Z");
+        assert!(matches!(data.validate(), Err(BackupError::Invalid(_))));
+
+        let mut data = example_export();
+        data.archived_bookings
+            .push(booking(2, BookingOrigin::Local));
+        assert!(matches!(data.validate(), Err(BackupError::Invalid(_))));
+    }
+
+    #[tokio::test]
+    async fn parses_json() {
+        assert!(matches!(
+            parse(b"{\"version\": 1}").await,
+            Err(BackupError::Json(_))
+        ));
+        let json = serde_json::to_vec(&example_export()).unwrap();
+        assert_eq!(parse(&json).await.unwrap(), example_export());
+    }
+
+    #[test]
+    fn rotation() {
+        let names = [
+            "room-overview-20241016T020000Z.json",
+            "notes.txt",
+            "room-overview-20241014T020000Z.sqlite",
+            "room-overview-20241015T020000Z.json",
+            "room-overview-20241013T020000Z.json.tmp",
+        ]
+        .map(str::to_owned)
+        .to_vec();
+        assert_eq!(
+            backups_to_remove(names.clone(), 2),
+            ["room-overview-20241014T020000Z.sqlite"]
+        );
+        assert!(backups_to_remove(names, 3).is_empty());
+    }
+}
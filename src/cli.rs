@@ -0,0 +1,654 @@
+//! The command line interface.
+//!
+//! Without a subcommand, the server is started. Subcommands do a single job and exit.
+
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use chrono::{NaiveDate, Utc};
+use chrono_tz::Europe::Berlin;
+use tracing::{info, warn};
+
+use crate::{
+    auth::{hash_api_token, new_api_token, scopes_to_db_str, Scope},
+    backup::{self, BackupError, BackupFormat},
+    config::Config,
+    db::DBError,
+    email::{send_digests, EmailError, Mailer},
+    pdf::{day_interval_utc, render_week_schedule_from_db, week_start, PdfError, ScheduleSubject},
+    sync::sources,
+    webhooks::run_test_receiver,
+};
+
+const USAGE: &str = "\
+Usage:
+    room-overview
+        Run the server.
+    room-overview render-pdf [--week YYYY-MM-DD] [--room ID]... [--building NAME]... [--out-dir DIR]
+        Render weekly schedules as PDF. Without --room or --building, one PDF is rendered for
+        every configured room and building. --week selects the week containing that day and
+        defaults to the current week.
+    room-overview hash-password
+        Read a password from stdin and print its hash for the admin.users config.
+    room-overview create-token NAME --scope SCOPE... [--expires YYYY-MM-DD]
+        Create an API token and print it. It cannot be shown again. SCOPE is one of
+        read:bookings, read:rooms, write:overrides and admin:sync. The token is valid until the
+        end of the --expires day, or forever.
+    room-overview list-tokens
+        List all API tokens.
+    room-overview revoke-token NAME
+        Delete an API token.
+    room-overview receive-webhooks [ADDR]
+        Receive webhooks on ADDR (default 127.0.0.1:8099) and print them with the result of
+        checking their signature. Set webhooks.test_receiver to http://ADDR/ to send all
+        deliveries there.
+    room-overview send-digest
+        Send today's email digest to every group in email.groups now.
+    room-overview export FILE [--format json|sqlite]
+        Write the bookings, local bookings, archive and history to FILE as JSON (the default), or
+        a snapshot of the whole SQLite db. FILE must not exist for a snapshot.
+    room-overview import FILE
+        Replace the bookings, local bookings, archive and history with an export or snapshot.
+    room-overview backup
+        Write a backup into backup.directory now and remove the oldest ones beyond backup.keep.
+    room-overview check-sources
+        Check that every CT instance and calendar can be reached with the configured credentials.";
+
+/// Where `receive-webhooks` listens by default
+const DEFAULT_WEBHOOK_RECEIVER: &str = "127.0.0.1:8099";
+
+#[derive(Debug)]
+pub enum CliError {
+    UnknownCommand(String),
+    UnknownArgument(String),
+    MissingValue(String),
+    InvalidDate(String, chrono::ParseError),
+    InvalidRoomId(String),
+    UnknownRoom(i64),
+    UnknownBuilding(String),
+    Pdf(PdfError),
+    Write(PathBuf, std::io::Error),
+    ReadPassword(std::io::Error),
+    HashPassword(argon2::password_hash::Error),
+    MissingTokenName,
+    InvalidScope(String),
+    NoScopes,
+    UnknownToken(String),
+    InvalidAddress(String),
+    ReceiveWebhooks(std::io::Error),
+    NoEmailConfig,
+    Email(EmailError),
+    MissingFile,
+    InvalidFormat(String),
+    ReadBackup(PathBuf, std::io::Error),
+    NoBackupConfig,
+    Backup(BackupError),
+    UnhealthySources(usize),
+    DB(DBError),
+}
+impl core::fmt::Display for CliError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::UnknownCommand(x) => write!(f, "Unknown command: {x}.\n{USAGE}"),
+            Self::UnknownArgument(x) => write!(f, "Unknown argument: {x}.\n{USAGE}"),
+            Self::MissingValue(x) => write!(f, "Argument {x} needs a value.\n{USAGE}"),
+            Self::InvalidDate(x, e) => write!(f, "Cannot parse {x} as YYYY-MM-DD: {e}."),
+            Self::InvalidRoomId(x) => write!(f, "Cannot parse {x} as a room ID."),
+            Self::UnknownRoom(x) => write!(f, "There is no room with ID {x} in the config."),
+            Self::UnknownBuilding(x) => {
+                write!(f, "There is no room in building {x} in the config.")
+            }
+            Self::Pdf(e) => write!(f, "{e}"),
+            Self::Write(path, e) => {
+                write!(f, "Unable to write {}. Inner Error: {e}.", path.display())
+            }
+            Self::ReadPassword(e) => write!(f, "Unable to read the password. Inner Error: {e}."),
+            Self::HashPassword(e) => write!(f, "Unable to hash the password. Inner Error: {e}."),
+            Self::MissingTokenName => write!(f, "The token needs a name.\n{USAGE}"),
+            Self::InvalidScope(x) => write!(f, "Unknown scope: {x}.\n{USAGE}"),
+            Self::NoScopes => write!(f, "The token needs at least one --scope.\n{USAGE}"),
+            Self::UnknownToken(x) => write!(f, "There is no API token named {x}."),
+            Self::InvalidAddress(x) => write!(f, "Cannot parse {x} as IP:PORT."),
+            Self::ReceiveWebhooks(e) => {
+                write!(f, "Unable to receive webhooks. Inner Error: {e}.")
+            }
+            Self::NoEmailConfig => write!(f, "There is no [email] section in the config."),
+            Self::Email(e) => write!(f, "{e}"),
+            Self::MissingFile => write!(f, "The command needs a FILE.\n{USAGE}"),
+            Self::InvalidFormat(x) => write!(f, "Unknown format: {x}.\n{USAGE}"),
+            Self::ReadBackup(path, e) => {
+                write!(f, "Unable to read {}. Inner Error: {e}.", path.display())
+            }
+            Self::NoBackupConfig => write!(f, "There is no [backup] section in the config."),
+            Self::Backup(e) => write!(f, "{e}"),
+            Self::UnhealthySources(x) => write!(f, "{x} sources failed the check."),
+            Self::DB(e) => write!(f, "{e}"),
+        }
+    }
+}
+impl core::error::Error for CliError {}
+impl From<DBError> for CliError {
+    fn from(value: DBError) -> Self {
+        Self::DB(value)
+    }
+}
+impl From<EmailError> for CliError {
+    fn from(value: EmailError) -> Self {
+        Self::Email(value)
+    }
+}
+impl From<BackupError> for CliError {
+    fn from(value: BackupError) -> Self {
+        Self::Backup(value)
+    }
+}
+impl From<PdfError> for CliError {
+    fn from(value: PdfError) -> Self {
+        Self::Pdf(value)
+    }
+}
+
+/// What the binary was asked to do
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve,
+    RenderPdf(RenderPdfArgs),
+    HashPassword,
+    CreateToken(CreateTokenArgs),
+    ListTokens,
+    RevokeToken(String),
+    ReceiveWebhooks(SocketAddr),
+    SendDigest,
+    Export(ExportArgs),
+    Import(PathBuf),
+    Backup,
+    CheckSources,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExportArgs {
+    path: PathBuf,
+    format: BackupFormat,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CreateTokenArgs {
+    name: String,
+    scopes: Vec<Scope>,
+    /// The token is valid until the end of this day
+    expires: Option<NaiveDate>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RenderPdfArgs {
+    /// Any day in the week to render
+    week: Option<NaiveDate>,
+    /// `id`s of the rooms to render
+    rooms: Vec<i64>,
+    buildings: Vec<String>,
+    out_dir: PathBuf,
+}
+
+/// Parse the arguments, excluding the program name.
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Command, CliError> {
+    match args.next().as_deref() {
+        None => Ok(Command::Serve),
+        Some("render-pdf") => parse_render_pdf_args(args).map(Command::RenderPdf),
+        Some("hash-password") => match args.next() {
+            None => Ok(Command::HashPassword),
+            Some(x) => Err(CliError::UnknownArgument(x)),
+        },
+        Some("create-token") => parse_create_token_args(args).map(Command::CreateToken),
+        Some("list-tokens") => match args.next() {
+            None => Ok(Command::ListTokens),
+            Some(x) => Err(CliError::UnknownArgument(x)),
+        },
+        Some("revoke-token") => match (args.next(), args.next()) {
+            (Some(name), None) => Ok(Command::RevokeToken(name)),
+            (None, _) => Err(CliError::MissingTokenName),
+            (Some(_), Some(x)) => Err(CliError::UnknownArgument(x)),
+        },
+        Some("receive-webhooks") => {
+            let addr = args
+                .next()
+                .unwrap_or_else(|| DEFAULT_WEBHOOK_RECEIVER.to_owned());
+            if let Some(x) = args.next() {
+                return Err(CliError::UnknownArgument(x));
+            }
+            addr.parse()
+                .map(Command::ReceiveWebhooks)
+                .map_err(|_| CliError::InvalidAddress(addr))
+        }
+        Some("send-digest") => match args.next() {
+            None => Ok(Command::SendDigest),
+            Some(x) => Err(CliError::UnknownArgument(x)),
+        },
+        Some("export") => parse_export_args(args).map(Command::Export),
+        Some("import") => match (args.next(), args.next()) {
+            (Some(path), None) => Ok(Command::Import(PathBuf::from(path))),
+            (None, _) => Err(CliError::MissingFile),
+            (Some(_), Some(x)) => Err(CliError::UnknownArgument(x)),
+        },
+        Some("backup") => match args.next() {
+            None => Ok(Command::Backup),
+            Some(x) => Err(CliError::UnknownArgument(x)),
+        },
+        Some("check-sources") => match args.next() {
+            None => Ok(Command::CheckSources),
+            Some(x) => Err(CliError::UnknownArgument(x)),
+        },
+        Some(x) => Err(CliError::UnknownCommand(x.to_owned())),
+    }
+}
+
+fn parse_render_pdf_args<I: Iterator<Item = String>>(
+    mut args: I,
+) -> Result<RenderPdfArgs, CliError> {
+    let mut res = RenderPdfArgs {
+        week: None,
+        rooms: vec![],
+        buildings: vec![],
+        out_dir: PathBuf::from("."),
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| CliError::MissingValue(arg.clone()))
+        };
+        match arg.as_str() {
+            "--week" => {
+                let day = value()?;
+                res.week = Some(
+                    NaiveDate::parse_from_str(&day, "%Y-%m-%d")
+                        .map_err(|e| CliError::InvalidDate(day, e))?,
+                );
+            }
+            "--room" => {
+                let id = value()?;
+                res.rooms
+                    .push(id.parse().map_err(|_| CliError::InvalidRoomId(id))?);
+            }
+            "--building" => res.buildings.push(value()?),
+            "--out-dir" => res.out_dir = PathBuf::from(value()?),
+            _ => return Err(CliError::UnknownArgument(arg)),
+        }
+    }
+    Ok(res)
+}
+
+fn parse_create_token_args<I: Iterator<Item = String>>(
+    mut args: I,
+) -> Result<CreateTokenArgs, CliError> {
+    let name = args
+        .next()
+        .filter(|n| !n.starts_with("--"))
+        .ok_or(CliError::MissingTokenName)?;
+    let mut res = CreateTokenArgs {
+        name,
+        scopes: vec![],
+        expires: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| CliError::MissingValue(arg.clone()))
+        };
+        match arg.as_str() {
+            "--scope" => {
+                let scope = value()?;
+                res.scopes
+                    .push(Scope::parse(&scope).ok_or(CliError::InvalidScope(scope))?);
+            }
+            "--expires" => {
+                let day = value()?;
+                res.expires = Some(
+                    NaiveDate::parse_from_str(&day, "%Y-%m-%d")
+                        .map_err(|e| CliError::InvalidDate(day, e))?,
+                );
+            }
+            _ => return Err(CliError::UnknownArgument(arg)),
+        }
+    }
+    if res.scopes.is_empty() {
+        return Err(CliError::NoScopes);
+    }
+    Ok(res)
+}
+
+fn parse_export_args<I: Iterator<Item = String>>(mut args: I) -> Result<ExportArgs, CliError> {
+    let path = args
+        .next()
+        .filter(|p| !p.starts_with("--"))
+        .ok_or(CliError::MissingFile)?;
+    let mut res = ExportArgs {
+        path: PathBuf::from(path),
+        format: BackupFormat::Json,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                let format = args
+                    .next()
+                    .ok_or_else(|| CliError::MissingValue(arg.clone()))?;
+                res.format = BackupFormat::parse(&format).ok_or(CliError::InvalidFormat(format))?;
+            }
+            _ => return Err(CliError::UnknownArgument(arg)),
+        }
+    }
+    Ok(res)
+}
+
+/// Create an API token and print it
+pub async fn create_token(config: &Config, args: CreateTokenArgs) -> Result<(), CliError> {
+    let token = new_api_token();
+    let expires_at = args.expires.map(|day| day_interval_utc(day).1.and_utc());
+    config
+        .db
+        .insert_api_token(
+            &args.name,
+            &hash_api_token(&token),
+            &args.scopes,
+            expires_at,
+        )
+        .await?;
+    info!("Created API token {}", args.name);
+    println!("{token}");
+    Ok(())
+}
+
+/// Print all API tokens, without the tokens themselves
+pub async fn list_tokens(config: &Config) -> Result<(), CliError> {
+    let fmt = |t: Option<chrono::DateTime<Utc>>, default: &str| {
+        t.map_or_else(
+            || default.to_owned(),
+            |t| t.format("%Y-%m-%d %H:%M UTC").to_string(),
+        )
+    };
+    for token in config.db.get_api_tokens().await? {
+        println!(
+            "{}\tscopes: {}\texpires: {}\tlast used: {}",
+            token.name,
+            scopes_to_db_str(&token.scopes),
+            fmt(token.expires_at, "never"),
+            fmt(token.last_used_at, "never"),
+        );
+    }
+    Ok(())
+}
+
+pub async fn revoke_token(config: &Config, name: String) -> Result<(), CliError> {
+    if config.db.delete_api_token(&name).await? {
+        info!("Revoked API token {name}");
+        Ok(())
+    } else {
+        Err(CliError::UnknownToken(name))
+    }
+}
+
+/// Print the webhooks sent to `addr` until killed
+pub async fn receive_webhooks(config: &Config, addr: SocketAddr) -> Result<(), CliError> {
+    run_test_receiver(config, addr)
+        .await
+        .map_err(CliError::ReceiveWebhooks)
+}
+
+/// Send the digest of today right away
+pub async fn send_digest(config: &Config) -> Result<(), CliError> {
+    let email = config.email.as_ref().ok_or(CliError::NoEmailConfig)?;
+    let mailer = Mailer::new(email)?;
+    send_digests(config, email, &mailer, Utc::now()).await?;
+    info!("Sent the digest to {} groups.", email.groups.len());
+    Ok(())
+}
+
+pub async fn export(config: &Config, args: ExportArgs) -> Result<(), CliError> {
+    backup::export(&*config.db, args.format, &args.path).await?;
+    Ok(())
+}
+
+/// Validate the export or snapshot at `path` and replace the contents of the db with it
+pub async fn import(config: &Config, path: PathBuf) -> Result<(), CliError> {
+    let content = std::fs::read(&path).map_err(|e| CliError::ReadBackup(path, e))?;
+    let data = backup::parse(&content).await?;
+    backup::import(&*config.db, &data).await?;
+    Ok(())
+}
+
+/// Write a backup as the scheduled backups do
+pub async fn backup(config: &Config) -> Result<(), CliError> {
+    let backup = config.backup.as_ref().ok_or(CliError::NoBackupConfig)?;
+    let path = backup::write_backup(config, backup, Utc::now()).await?;
+    info!("Wrote backup {}", path.display());
+    Ok(())
+}
+
+/// Check the health of every source, logging the result of each
+pub async fn check_sources(config: &Arc<Config>) -> Result<(), CliError> {
+    let mut failed = 0;
+    for source in sources(config) {
+        match source.check_health().await {
+            Ok(()) => info!("Source {} is healthy.", source.name()),
+            Err(e) => {
+                warn!("Source {} failed the check: {e}", source.name());
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(CliError::UnhealthySources(failed));
+    }
+    Ok(())
+}
+
+/// Render the PDFs requested in `args` into files
+pub async fn render_pdf(config: &Config, args: RenderPdfArgs) -> Result<(), CliError> {
+    let monday = week_start(
+        args.week
+            .unwrap_or_else(|| Utc::now().with_timezone(&Berlin).date_naive()),
+    );
+    let (rooms, buildings) = if args.rooms.is_empty() && args.buildings.is_empty() {
+        (
+            config.rooms.iter().map(|r| r.id()).collect(),
+            config.buildings(),
+        )
+    } else {
+        (args.rooms, args.buildings)
+    };
+
+    for id in rooms {
+        let room = config
+            .rooms
+            .iter()
+            .find(|r| r.id() == id)
+            .ok_or(CliError::UnknownRoom(id))?;
+        let pdf =
+            render_week_schedule_from_db(config, &ScheduleSubject::Room(room), monday).await?;
+        write_pdf(&args.out_dir.join(format!("room-{id}-{monday}.pdf")), &pdf)?;
+    }
+    for building in buildings {
+        let rooms = config.rooms_in_building(&building);
+        if rooms.is_empty() {
+            return Err(CliError::UnknownBuilding(building));
+        }
+        let pdf = render_week_schedule_from_db(
+            config,
+            &ScheduleSubject::Building(&building, &rooms),
+            monday,
+        )
+        .await?;
+        write_pdf(
+            &args
+                .out_dir
+                .join(format!("building-{building}-{monday}.pdf")),
+            &pdf,
+        )?;
+    }
+    Ok(())
+}
+
+/// Read a password from stdin and print its hash
+pub fn hash_password() -> Result<(), CliError> {
+    let mut password = String::new();
+    std::io::stdin()
+        .read_line(&mut password)
+        .map_err(CliError::ReadPassword)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    println!(
+        "{}",
+        crate::auth::hash_password(password).map_err(CliError::HashPassword)?
+    );
+    Ok(())
+}
+
+fn write_pdf(path: &PathBuf, pdf: &[u8]) -> Result<(), CliError> {
+    std::fs::write(path, pdf).map_err(|e| CliError::Write(path.clone(), e))?;
+    info!("Wrote {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(x: &[&str]) -> impl Iterator<Item = String> {
+        x.iter()
+            .map(|s| (*s).to_owned())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn no_args_serves() {
+        assert_eq!(parse_args(args(&[])).unwrap(), Command::Serve);
+    }
+
+    #[test]
+    fn render_pdf_args() {
+        let cmd = parse_args(args(&[
+            "render-pdf",
+            "--week",
+            "2024-10-16",
+            "--room",
+            "12",
+            "--building",
+            "Gemeindehaus",
+            "--out-dir",
+            "/tmp",
+        ]))
+        .unwrap();
+        assert_eq!(
+            cmd,
+            Command::RenderPdf(RenderPdfArgs {
+                week: NaiveDate::from_ymd_opt(2024, 10, 16),
+                rooms: vec![12],
+                buildings: vec!["Gemeindehaus".to_owned()],
+                out_dir: PathBuf::from("/tmp"),
+            })
+        );
+    }
+
+    #[test]
+    fn render_pdf_missing_value() {
+        assert!(matches!(
+            parse_args(args(&["render-pdf", "--room"])),
+            Err(CliError::MissingValue(_))
+        ));
+    }
+
+    #[test]
+    fn create_token_args() {
+        let cmd = parse_args(args(&[
+            "create-token",
+            "chatbot",
+            "--scope",
+            "read:bookings",
+            "--scope",
+            "read:rooms",
+            "--expires",
+            "2025-12-31",
+        ]))
+        .unwrap();
+        assert_eq!(
+            cmd,
+            Command::CreateToken(CreateTokenArgs {
+                name: "chatbot".to_owned(),
+                scopes: vec![Scope::ReadBookings, Scope::ReadRooms],
+                expires: NaiveDate::from_ymd_opt(2025, 12, 31),
+            })
+        );
+        assert!(matches!(
+            parse_args(args(&["create-token", "chatbot", "--scope", "write:all"])),
+            Err(CliError::InvalidScope(_))
+        ));
+        assert!(matches!(
+            parse_args(args(&["create-token", "chatbot"])),
+            Err(CliError::NoScopes)
+        ));
+    }
+
+    #[test]
+    fn receive_webhooks_args() {
+        assert_eq!(
+            parse_args(args(&["receive-webhooks"])).unwrap(),
+            Command::ReceiveWebhooks(DEFAULT_WEBHOOK_RECEIVER.parse().unwrap())
+        );
+        assert_eq!(
+            parse_args(args(&["receive-webhooks", "0.0.0.0:9000"])).unwrap(),
+            Command::ReceiveWebhooks("0.0.0.0:9000".parse().unwrap())
+        );
+        assert!(matches!(
+            parse_args(args(&["receive-webhooks", "localhost"])),
+            Err(CliError::InvalidAddress(_))
+        ));
+    }
+
+    #[test]
+    fn send_digest_args() {
+        assert_eq!(
+            parse_args(args(&["send-digest"])).unwrap(),
+            Command::SendDigest
+        );
+        assert!(matches!(
+            parse_args(args(&["send-digest", "now"])),
+            Err(CliError::UnknownArgument(_))
+        ));
+    }
+
+    #[test]
+    fn backup_args() {
+        assert_eq!(
+            parse_args(args(&["export", "backup.sqlite", "--format", "sqlite"])).unwrap(),
+            Command::Export(ExportArgs {
+                path: PathBuf::from("backup.sqlite"),
+                format: BackupFormat::Sqlite,
+            })
+        );
+        assert_eq!(
+            parse_args(args(&["export", "backup.json"])).unwrap(),
+            Command::Export(ExportArgs {
+                path: PathBuf::from("backup.json"),
+                format: BackupFormat::Json,
+            })
+        );
+        assert!(matches!(
+            parse_args(args(&["export", "--format", "json"])),
+            Err(CliError::MissingFile)
+        ));
+        assert!(matches!(
+            parse_args(args(&["export", "backup.csv", "--format", "csv"])),
+            Err(CliError::InvalidFormat(_))
+        ));
+        assert_eq!(
+            parse_args(args(&["import", "backup.json"])).unwrap(),
+            Command::Import(PathBuf::from("backup.json"))
+        );
+        assert!(matches!(
+            parse_args(args(&["import"])),
+            Err(CliError::MissingFile)
+        ));
+        assert_eq!(parse_args(args(&["backup"])).unwrap(), Command::Backup);
+        assert_eq!(
+            parse_args(args(&["check-sources"])).unwrap(),
+            Command::CheckSources
+        );
+    }
+}
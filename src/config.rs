@@ -1,16 +1,47 @@
-use std::{fs::read_to_string, path::Path};
+use core::{net::IpAddr, str::FromStr};
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
 
 use axum_server::tls_rustls::RustlsConfig;
-use serde::Deserialize;
-use sqlx::{Pool, Sqlite, SqlitePool};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteConnectOptions, PgPool, SqlitePool};
 use tracing::{event, Level};
 
-use crate::BOOKING_DATABASE_NAME;
+use crate::{
+    auth::Scope,
+    backup::BackupFormat,
+    ct_auth::{decode_base32, CtSession},
+    db::{PostgresStorage, SqliteStorage, Storage},
+    heating::HeatingRules,
+    local_bookings::LOCAL_SOURCE,
+    webhooks::WebhookEventKind,
+    Booking, BOOKING_DATABASE_NAME,
+};
+
+/// Setup and teardown buffers are capped at this length.
+///
+/// Views look this far around their interval to find bookings whose buffers reach into it.
+pub(crate) const MAX_BUFFER: chrono::TimeDelta = chrono::TimeDelta::hours(4);
 
 #[derive(Debug)]
 pub(crate) enum ConfigError {
     Tls(std::io::Error),
     TomlParse(toml::de::Error),
+    DuplicateWebhookTarget(String),
+    UnknownAccessRoute(String),
+    NoCtSource,
+    DuplicateSource(String),
+    ReservedSourceName(String),
+    NoCtCredentials(String),
+    InvalidTotpSecret(String),
+    UnknownCtSource { room: String, source: String },
+    UnknownCalendarRoom { calendar: String, room: i64 },
+    AmbiguousRoomId(i64),
+    InvalidEmailAddress(String),
+    IncompleteMqttCredentials,
     ConfigFileRead(std::io::Error),
     PoolCreate(sqlx::Error),
 }
@@ -23,11 +54,65 @@ impl core::fmt::Display for ConfigError {
             Self::TomlParse(e) => {
                 write!(f, "Unable to parse config file as toml: {e}")
             }
+            Self::DuplicateWebhookTarget(name) => {
+                write!(f, "There is more than one webhook target named {name}.")
+            }
+            Self::UnknownAccessRoute(route) => {
+                write!(f, "The access policy for {route} matches no route.")
+            }
+            Self::NoCtSource => {
+                write!(f, "There is no [ct] section.")
+            }
+            Self::DuplicateSource(name) => {
+                write!(f, "There is more than one sync source named {name}.")
+            }
+            Self::ReservedSourceName(name) => {
+                write!(
+                    f,
+                    "The sync source name {name} is reserved for bookings made in room-overview."
+                )
+            }
+            Self::NoCtCredentials(name) => {
+                write!(
+                    f,
+                    "The ct source {name} needs either a login_token or a username and password."
+                )
+            }
+            Self::InvalidTotpSecret(name) => {
+                write!(f, "The totp_secret of the ct source {name} is not base32.")
+            }
+            Self::UnknownCtSource { room, source } => {
+                write!(
+                    f,
+                    "Room {room} uses the ct source {source}, which does not exist."
+                )
+            }
+            Self::UnknownCalendarRoom { calendar, room } => {
+                write!(
+                    f,
+                    "Calendar {calendar} books the room {room}, which does not exist."
+                )
+            }
+            Self::AmbiguousRoomId(id) => {
+                write!(
+                    f,
+                    "Room ID {id} does not belong to exactly one CT resource. Give the rooms distinct ids."
+                )
+            }
+            Self::InvalidEmailAddress(address) => {
+                write!(f, "{address} is no valid email address.")
+            }
+            Self::IncompleteMqttCredentials => {
+                write!(
+                    f,
+                    "mqtt.username and mqtt.password have to be set together."
+                )
+            }
             Self::ConfigFileRead(e) => {
                 write!(f, "Unable to read config file: {e}")
             }
             Self::PoolCreate(e) => {
-                write!(f, "Unable to create sqlite pool: {e}")
+                write!(f, "Unable to connect to the db: {e}")
             }
         }
     }
@@ -81,71 +166,1750 @@ impl WebConfig {
     }
 }
 
+/// Time rooms are needed before and after their bookings, unless CT records it in the booking
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct BufferConfig {
+    /// Default for all rooms
+    #[serde(default)]
+    pub setup_minutes: i64,
+    /// Default for all rooms
+    #[serde(default)]
+    pub teardown_minutes: i64,
+    /// Buffers for bookings by title. The first matching rule wins.
+    #[serde(default)]
+    pub rules: Vec<BufferRule>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct BufferRule {
+    /// The rule applies to bookings whose title contains this, ignoring case
+    pub title_contains: String,
+    pub setup_minutes: Option<i64>,
+    pub teardown_minutes: Option<i64>,
+}
+impl BufferConfig {
+    /// Setup and teardown time for this booking in `room`.
+    ///
+    /// Times recorded in CT take precedence over the first matching rule, which takes precedence
+    /// over the room config and then the global default. The result is clamped to
+    /// [0, [`MAX_BUFFER`]].
+    fn buffers_for(
+        &self,
+        booking: &Booking,
+        room: Option<&RoomConfig>,
+    ) -> (chrono::TimeDelta, chrono::TimeDelta) {
+        let rule = self.rules.iter().find(|r| r.matches(booking));
+        let setup = booking
+            .setup_minutes
+            .or_else(|| rule.and_then(|r| r.setup_minutes))
+            .or_else(|| room.and_then(|r| r.setup_minutes))
+            .unwrap_or(self.setup_minutes);
+        let teardown = booking
+            .teardown_minutes
+            .or_else(|| rule.and_then(|r| r.teardown_minutes))
+            .or_else(|| room.and_then(|r| r.teardown_minutes))
+            .unwrap_or(self.teardown_minutes);
+        let clamp = |minutes: i64| {
+            chrono::TimeDelta::minutes(minutes).clamp(chrono::TimeDelta::zero(), MAX_BUFFER)
+        };
+        (clamp(setup), clamp(teardown))
+    }
+}
+
+impl BufferRule {
+    fn matches(&self, booking: &Booking) -> bool {
+        booking
+            .title
+            .to_lowercase()
+            .contains(&self.title_contains.to_lowercase())
+    }
+}
+
+/// Where the db is, what is kept in it, and when it is pruned
+#[derive(Deserialize)]
+pub(crate) struct DbConfig {
+    /// `postgres://...` to use PostgreSQL, or `sqlite://...`. Defaults to the SQLite file
+    /// `.bookings.db` in the working directory.
+    pub url: Option<String>,
+    /// Bookings, conflicts and announcements are kept this many days after the day they ended
+    #[serde(default)]
+    pub retain_days: i64,
+    /// Days start at midnight in this timezone
+    #[serde(
+        default = "default_timezone",
+        deserialize_with = "deserialize_timezone"
+    )]
+    pub timezone: chrono_tz::Tz,
+    /// Prune this often, in seconds
+    #[serde(default = "default_prune_interval")]
+    pub prune_interval: u64,
+}
+impl core::fmt::Debug for DbConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DbConfig")
+            .field("url", &self.url.as_ref().map(|_| "<redacted>"))
+            .field("retain_days", &self.retain_days)
+            .field("timezone", &self.timezone)
+            .field("prune_interval", &self.prune_interval)
+            .finish()
+    }
+}
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            retain_days: 0,
+            timezone: default_timezone(),
+            prune_interval: default_prune_interval(),
+        }
+    }
+}
+impl DbConfig {
+    /// Connect to the db `url` points to
+    async fn connect(&self) -> Result<Box<dyn Storage>, ConfigError> {
+        match self.url.as_deref() {
+            Some(url) if url.starts_with("postgres:") || url.starts_with("postgresql:") => {
+                let pool = PgPool::connect(url)
+                    .await
+                    .map_err(ConfigError::PoolCreate)?;
+                Ok(Box::new(PostgresStorage::new(pool)))
+            }
+            url => {
+                let options = match url {
+                    Some(url) => {
+                        SqliteConnectOptions::from_str(url).map_err(ConfigError::PoolCreate)?
+                    }
+                    None => SqliteConnectOptions::new().filename(BOOKING_DATABASE_NAME),
+                };
+                let pool = SqlitePool::connect_with(options.create_if_missing(true))
+                    .await
+                    .map_err(ConfigError::PoolCreate)?;
+                Ok(Box::new(SqliteStorage::new(pool)))
+            }
+        }
+    }
+
+    /// Everything that ended before this (naive UTC) is pruned: the start of the day `retain_days`
+    /// before the day containing `now`, in the configured timezone.
+    pub(crate) fn prune_reference(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> chrono::NaiveDateTime {
+        let day = now.with_timezone(&self.timezone).date_naive()
+            - chrono::TimeDelta::days(self.retain_days);
+        let midnight = day.and_hms_opt(0, 0, 0).expect("midnight always exists");
+        // where DST starts at midnight, the day starts at the first time that exists
+        (0..=3)
+            .find_map(|hours| {
+                (midnight + chrono::TimeDelta::hours(hours))
+                    .and_local_timezone(self.timezone)
+                    .earliest()
+            })
+            .map_or(midnight, |t| t.naive_utc())
+    }
+}
+fn default_timezone() -> chrono_tz::Tz {
+    chrono_tz::Europe::Berlin
+}
+fn deserialize_timezone<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<chrono_tz::Tz, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    value
+        .parse()
+        .map_err(|_| serde::de::Error::custom(format!("{value} is no known timezone")))
+}
+fn default_prune_interval() -> u64 {
+    3600
+}
+
+/// How long the booking history is kept
+#[derive(Debug, Deserialize)]
+pub(crate) struct HistoryConfig {
+    /// Events older than this many days are removed when pruning
+    #[serde(default = "default_history_retain_days")]
+    pub retain_days: i64,
+}
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            retain_days: default_history_retain_days(),
+        }
+    }
+}
+fn default_history_retain_days() -> i64 {
+    365
+}
+
+/// Backups written regularly into a directory
+#[derive(Debug, Deserialize)]
+pub(crate) struct BackupConfig {
+    pub directory: PathBuf,
+    /// Seconds between two backups
+    #[serde(default = "default_backup_interval")]
+    pub interval: u64,
+    /// How many backups are kept; older ones are removed
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+    #[serde(default)]
+    pub format: BackupFormat,
+}
+fn default_backup_interval() -> u64 {
+    24 * 3600
+}
+fn default_backup_keep() -> usize {
+    7
+}
+
+/// When rooms are heated for their bookings
+#[derive(Debug, Deserialize)]
+pub(crate) struct HeatingConfig {
+    /// Default for all rooms
+    #[serde(default = "default_preheat_minutes")]
+    pub preheat_minutes: i64,
+    /// Bookings at most this far apart share one comfort period
+    #[serde(default = "default_merge_gap_minutes")]
+    pub merge_gap_minutes: i64,
+    /// Comfort periods are merged unless the heating can be off for at least this long
+    #[serde(default = "default_min_off_minutes")]
+    pub min_off_minutes: i64,
+    /// Publish `{mqtt.topic_prefix}/rooms/{id}/heating` when the heating switches
+    #[serde(default)]
+    pub mqtt: bool,
+    /// Send `heating_on` and `heating_off` to webhook targets when the heating switches
+    #[serde(default)]
+    pub webhooks: bool,
+}
+impl Default for HeatingConfig {
+    fn default() -> Self {
+        Self {
+            preheat_minutes: default_preheat_minutes(),
+            merge_gap_minutes: default_merge_gap_minutes(),
+            min_off_minutes: default_min_off_minutes(),
+            mqtt: false,
+            webhooks: false,
+        }
+    }
+}
+fn default_preheat_minutes() -> i64 {
+    60
+}
+fn default_merge_gap_minutes() -> i64 {
+    60
+}
+fn default_min_off_minutes() -> i64 {
+    30
+}
+
+/// Who may access which routes of the web server
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct AccessConfig {
+    /// The first matching entry applies. Routes without an entry are public.
+    #[serde(default)]
+    pub routes: Vec<RouteAccess>,
+}
+impl AccessConfig {
+    /// The policy for a route as written in `run_web_server`, e.g. `/rooms/:id`
+    pub(crate) fn policy_for(&self, route: &str) -> &AccessPolicy {
+        self.routes
+            .iter()
+            .find(|r| r.matches(route))
+            .map_or(&AccessPolicy::Public, |r| &r.policy)
+    }
+
+    /// Check that every entry matches at least one of `routes`, so a typo does not leave the route
+    /// it was meant for public
+    fn validate(&self, routes: &[&str]) -> Result<(), ConfigError> {
+        match self
+            .routes
+            .iter()
+            .find(|entry| !routes.iter().any(|route| entry.matches(route)))
+        {
+            Some(entry) => Err(ConfigError::UnknownAccessRoute(entry.route.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct RouteAccess {
+    /// A route as written in `run_web_server`, e.g. `/rooms/:id`.
+    /// A trailing `*` matches the rest and all routes below it, e.g. `/admin*` matches `/admin` and
+    /// `/admin/login`, but not `/administrator`.
+    pub route: String,
+    #[serde(flatten)]
+    pub policy: AccessPolicy,
+}
+impl RouteAccess {
+    fn matches(&self, route: &str) -> bool {
+        match self.route.strip_suffix('*') {
+            // only whole path segments match
+            Some(prefix) => {
+                let prefix = prefix.trim_end_matches('/');
+                route == prefix
+                    || route
+                        .strip_prefix(prefix)
+                        .is_some_and(|rest| rest.starts_with('/'))
+            }
+            None => route == self.route,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub(crate) enum AccessPolicy {
+    Public,
+    /// Only clients from these networks
+    Allowlist {
+        allow: Vec<IpNet>,
+    },
+    /// HTTP basic auth with the users from `admin.users`
+    BasicAuth,
+    /// An API token with this scope, as bearer token or in the `token` query parameter
+    Token {
+        #[serde(default = "default_access_scope")]
+        scope: Scope,
+    },
+}
+fn default_access_scope() -> Scope {
+    Scope::ReadBookings
+}
+
+/// An IP network like `10.0.20.0/24`. A plain address is a network of just that address.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+impl IpNet {
+    pub(crate) fn contains(&self, addr: IpAddr) -> bool {
+        // clients on dual stack sockets show up as ::ffff:a.b.c.d
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+impl TryFrom<String> for IpNet {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (addr, prefix_len) = match value.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (value.as_str(), None),
+        };
+        let addr = IpAddr::from_str(addr).map_err(|e| format!("{value} is no IP network: {e}"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("{value} has an invalid prefix length"))?,
+            None => max_len,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ConflictConfig {
+    /// Deprecated: becomes a webhook target for `conflict_detected` without a secret
+    pub webhook_url: Option<String>,
+}
+
+/// Emails about the bookings of the day and about failing syncs
+#[derive(Deserialize)]
+pub(crate) struct EmailConfig {
+    pub smtp_host: String,
+    /// Defaults to the usual port for `smtp_security`
+    pub smtp_port: Option<u16>,
+    #[serde(default)]
+    pub smtp_security: SmtpSecurity,
+    pub username: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_secret")]
+    pub password: Option<String>,
+    /// Sender of all emails, e.g. `Raumübersicht <raeume@example.com>`
+    pub from: String,
+    /// The digest is sent every day at this local time
+    #[serde(
+        default = "default_digest_time",
+        deserialize_with = "deserialize_time_of_day"
+    )]
+    pub digest_time: chrono::NaiveTime,
+    /// Every group gets a digest of its rooms
+    #[serde(default)]
+    pub groups: Vec<EmailGroup>,
+    /// Send an alert once the sync has been failing for this many minutes
+    pub sync_alert_minutes: Option<i64>,
+    #[serde(default)]
+    pub alert_recipients: Vec<String>,
+}
+impl core::fmt::Debug for EmailConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EmailConfig")
+            .field("smtp_host", &self.smtp_host)
+            .field("smtp_port", &self.smtp_port)
+            .field("smtp_security", &self.smtp_security)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("from", &self.from)
+            .field("digest_time", &self.digest_time)
+            .field("groups", &self.groups)
+            .field("sync_alert_minutes", &self.sync_alert_minutes)
+            .field("alert_recipients", &self.alert_recipients)
+            .finish()
+    }
+}
+impl EmailConfig {
+    /// All addresses, so they can be checked at startup
+    fn addresses(&self) -> impl Iterator<Item = &String> {
+        core::iter::once(&self.from)
+            .chain(&self.alert_recipients)
+            .chain(self.groups.iter().flat_map(|g| &g.recipients))
+    }
+}
+fn default_digest_time() -> chrono::NaiveTime {
+    chrono::NaiveTime::from_hms_opt(6, 0, 0).expect("statically good time")
+}
+fn deserialize_time_of_day<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<chrono::NaiveTime, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    chrono::NaiveTime::parse_from_str(&value, "%H:%M")
+        .map_err(|e| serde::de::Error::custom(format!("{value} is no time like 06:30: {e}")))
+}
+
+/// A secret written into the config, or read from a file or environment variable
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SecretData {
+    Value(String),
+    /// The whole file without trailing whitespace, e.g. a docker secret
+    File {
+        file: PathBuf,
+    },
+    Env {
+        env: String,
+    },
+}
+impl SecretData {
+    fn read(self) -> Result<String, String> {
+        self.read_with_env(|name| std::env::var(name))
+    }
+
+    /// Read the secret, looking up environment variables with `env`
+    fn read_with_env(
+        self,
+        env: impl FnOnce(&str) -> Result<String, std::env::VarError>,
+    ) -> Result<String, String> {
+        match self {
+            Self::Value(x) => Ok(x),
+            Self::File { file } => read_to_string(&file)
+                .map(|x| x.trim_end().to_owned())
+                .map_err(|e| format!("Unable to read the secret in {}: {e}", file.display())),
+            Self::Env { env: name } => {
+                env(&name).map_err(|e| format!("Unable to read the secret in ${name}: {e}"))
+            }
+        }
+    }
+}
+/// A secret given as `"value"`, `{ file = "/path" }` or `{ env = "VARIABLE" }`
+fn deserialize_optional_secret<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Option::<SecretData>::deserialize(deserializer)?
+        .map(SecretData::read)
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SmtpSecurity {
+    /// Plain connection upgraded with STARTTLS, port 587
+    #[default]
+    Starttls,
+    /// TLS from the start, port 465
+    Tls,
+    /// Unencrypted, port 25. Only for local relays and testing.
+    None,
+}
+
+/// Recipients of the digest for some rooms
+#[derive(Debug, Deserialize)]
+pub(crate) struct EmailGroup {
+    pub name: String,
+    pub recipients: Vec<String>,
+    /// `id`s of rooms in this group
+    #[serde(default)]
+    pub rooms: Vec<i64>,
+    /// All rooms in these buildings are in this group
+    #[serde(default)]
+    pub buildings: Vec<String>,
+}
+impl EmailGroup {
+    /// Groups without rooms and buildings contain all rooms
+    pub(crate) fn contains(&self, room: &RoomConfig) -> bool {
+        (self.rooms.is_empty() && self.buildings.is_empty())
+            || self.rooms.contains(&room.id())
+            || room
+                .building
+                .as_ref()
+                .is_some_and(|b| self.buildings.contains(b))
+    }
+}
+
+/// Publishing the state of every room to an MQTT broker
+#[derive(Deserialize)]
+pub(crate) struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_secret")]
+    pub password: Option<String>,
+    /// States go to `{topic_prefix}/rooms/{id}/state`
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+    /// Publish Home Assistant discovery configs under `discovery_prefix`
+    #[serde(default = "default_true")]
+    pub home_assistant_discovery: bool,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Check the db for changed bookings this often, in seconds.
+    /// Transitions of already known bookings are published on time regardless.
+    #[serde(default = "default_mqtt_poll_interval")]
+    pub poll_interval: u64,
+}
+impl MqttConfig {
+    /// The broker would silently get no credentials at all if only one of them was set
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.username.is_some() != self.password.is_some() {
+            return Err(ConfigError::IncompleteMqttCredentials);
+        }
+        Ok(())
+    }
+}
+impl core::fmt::Debug for MqttConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MqttConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("topic_prefix", &self.topic_prefix)
+            .field("home_assistant_discovery", &self.home_assistant_discovery)
+            .field("discovery_prefix", &self.discovery_prefix)
+            .field("poll_interval", &self.poll_interval)
+            .finish()
+    }
+}
+fn default_mqtt_port() -> u16 {
+    1883
+}
+fn default_mqtt_client_id() -> String {
+    "room-overview".to_owned()
+}
+fn default_mqtt_topic_prefix() -> String {
+    "room-overview".to_owned()
+}
+fn default_true() -> bool {
+    true
+}
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_owned()
+}
+fn default_mqtt_poll_interval() -> u64 {
+    60
+}
+
+/// Webhooks about changes made by the gatherer
+#[derive(Debug, Deserialize)]
+pub(crate) struct WebhookConfig {
+    #[serde(default)]
+    pub targets: Vec<WebhookTarget>,
+    /// A delivery is given up after this many failed attempts
+    #[serde(default = "default_webhook_max_attempts")]
+    pub max_attempts: i64,
+    /// Test mode: send all deliveries to this URL instead of their targets
+    pub test_receiver: Option<String>,
+}
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            targets: vec![],
+            max_attempts: default_webhook_max_attempts(),
+            test_receiver: None,
+        }
+    }
+}
+fn default_webhook_max_attempts() -> i64 {
+    10
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WebhookTarget {
+    /// Deliveries are queued by this name, so it has to be unique
+    pub name: String,
+    pub url: String,
+    /// Key for the HMAC-SHA256 signature of the payload. Payloads are not signed without it.
+    #[serde(default, deserialize_with = "deserialize_optional_secret")]
+    pub secret: Option<String>,
+    #[serde(default = "default_webhook_events")]
+    pub events: Vec<WebhookEventKind>,
+    /// Created from the deprecated `conflicts.webhook_url`, which keeps getting the old body:
+    /// all new conflicts of a sync as `{"new_conflicts": [...]}`
+    #[serde(skip)]
+    pub legacy_conflicts: bool,
+}
+impl core::fmt::Debug for WebhookTarget {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("WebhookTarget")
+            .field("name", &self.name)
+            .field("url", &self.url)
+            .field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+            .field("events", &self.events)
+            .field("legacy_conflicts", &self.legacy_conflicts)
+            .finish()
+    }
+}
+fn default_webhook_events() -> Vec<WebhookEventKind> {
+    WebhookEventKind::ALL.to_vec()
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AdminConfig {
+    /// Deprecated bearer token with all scopes, from before there were API tokens
+    #[serde(default, deserialize_with = "deserialize_optional_secret")]
+    pub api_token: Option<String>,
+    /// Users that can log into /admin
+    #[serde(default)]
+    pub users: Vec<AdminUser>,
+    /// Sessions expire this long after login
+    #[serde(default = "default_session_minutes")]
+    pub session_minutes: i64,
+    /// Only send the session cookie over HTTPS. Turn this off if the admin area is only reachable
+    /// over plain HTTP.
+    #[serde(default = "default_secure_cookie")]
+    pub secure_cookie: bool,
+}
+fn default_session_minutes() -> i64 {
+    8 * 60
+}
+fn default_secure_cookie() -> bool {
+    true
+}
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            api_token: None,
+            users: vec![],
+            session_minutes: default_session_minutes(),
+            secure_cookie: default_secure_cookie(),
+        }
+    }
+}
+impl core::fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("AdminConfig")
+            .field("api_token", &self.api_token.as_ref().map(|_| "[redacted]"))
+            .field("users", &self.users)
+            .field("session_minutes", &self.session_minutes)
+            .field("secure_cookie", &self.secure_cookie)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AdminUser {
+    pub name: String,
+    /// argon2 hash in PHC format, as printed by `room-overview hash-password`
+    pub password_hash: String,
+}
+impl core::fmt::Debug for AdminUser {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("AdminUser")
+            .field("name", &self.name)
+            .field("password_hash", &"[redacted]")
+            .finish()
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct ConfigData {
-    pub ct: ChurchToolsConfig,
+    #[serde(default)]
+    pub access: AccessConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    pub backup: Option<BackupConfig>,
+    #[serde(default)]
+    pub buffers: BufferConfig,
+    #[serde(default)]
+    pub calendars: Vec<CalendarConfig>,
+    #[serde(default)]
+    pub conflicts: ConflictConfig,
+    pub ct: ChurchToolsSources,
+    #[serde(default, rename = "db")]
+    pub database: DbConfig,
+    pub email: Option<EmailConfig>,
+    #[serde(default)]
+    pub heating: HeatingConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
     pub log_level: String,
+    pub mqtt: Option<MqttConfig>,
     pub rooms: Vec<RoomConfig>,
     pub web: WebConfigData,
+    #[serde(default)]
+    pub webhooks: WebhookConfig,
 }
 #[derive(Debug)]
 pub(crate) struct Config {
-    pub ct: ChurchToolsConfig,
-    pub db: Pool<Sqlite>,
+    pub access: AccessConfig,
+    pub admin: AdminConfig,
+    pub backup: Option<BackupConfig>,
+    pub buffers: BufferConfig,
+    /// ICS feeds and CalDAV collections synced alongside CT
+    pub calendars: Vec<CalendarConfig>,
+    /// All CT instances, from `[ct]` or `[[ct]]`
+    pub ct: Vec<ChurchToolsConfig>,
+    pub db: Box<dyn Storage>,
+    /// The `[db]` section
+    pub database: DbConfig,
+    pub email: Option<EmailConfig>,
+    pub heating: HeatingConfig,
+    pub history: HistoryConfig,
     pub log_level: String,
+    pub mqtt: Option<MqttConfig>,
     pub rooms: Vec<RoomConfig>,
     pub web: WebConfig,
+    pub webhooks: WebhookConfig,
 }
 impl Config {
     async fn try_from_config_data(value: ConfigData) -> Result<Self, ConfigError> {
-        let sqlite_connect_options = sqlx::sqlite::SqliteConnectOptions::new()
-            .filename(BOOKING_DATABASE_NAME)
-            .create_if_missing(true);
-        let db = SqlitePool::connect_with(sqlite_connect_options)
-            .await
-            .map_err(ConfigError::PoolCreate)?;
+        let db = value.database.connect().await?;
+
+        if let Some(address) = value
+            .email
+            .iter()
+            .flat_map(EmailConfig::addresses)
+            .find(|a| a.parse::<lettre::message::Mailbox>().is_err())
+        {
+            return Err(ConfigError::InvalidEmailAddress(address.clone()));
+        }
+
+        let mut webhooks = value.webhooks;
+        if let Some(url) = value.conflicts.webhook_url {
+            webhooks.targets.push(WebhookTarget {
+                name: "conflicts".to_owned(),
+                url,
+                secret: None,
+                events: vec![WebhookEventKind::ConflictDetected],
+                legacy_conflicts: true,
+            });
+        }
+        if let Some(name) = webhooks.targets.iter().map(|t| &t.name).duplicates().next() {
+            return Err(ConfigError::DuplicateWebhookTarget(name.clone()));
+        }
+        value.access.validate(&crate::web::ROUTES)?;
+        if let Some(mqtt) = &value.mqtt {
+            mqtt.validate()?;
+        }
+
+        let ct = value.ct.into_vec();
+        let mut rooms = value.rooms;
+        validate_sources(&ct, &value.calendars, &mut rooms)?;
 
         Ok(Self {
-            ct: value.ct,
+            access: value.access,
+            admin: value.admin,
+            backup: value.backup,
+            buffers: value.buffers,
+            calendars: value.calendars,
+            ct,
             db,
+            database: value.database,
+            email: value.email,
+            heating: value.heating,
+            history: value.history,
             log_level: value.log_level,
-            rooms: value.rooms,
+            mqtt: value.mqtt,
+            rooms,
             web: WebConfig::try_from_web_config_data(value.web).await?,
+            webhooks,
         })
     }
 
+    /// All buildings that have at least one room, in the order they first appear in the config
+    pub(crate) fn buildings(&self) -> Vec<String> {
+        self.rooms
+            .iter()
+            .filter_map(|r| r.building.clone())
+            .unique()
+            .collect()
+    }
+
+    /// All rooms in the given building
+    pub(crate) fn rooms_in_building(&self, building: &str) -> Vec<RoomConfig> {
+        self.rooms
+            .iter()
+            .filter(|r| r.building.as_deref() == Some(building))
+            .cloned()
+            .collect()
+    }
+
+    /// How far ahead bookings are known: the sync window of the source looking furthest ahead
+    pub(crate) fn pull_window(&self) -> chrono::TimeDelta {
+        let pull_days = self
+            .ct
+            .iter()
+            .map(|ct| ct.ct_pull_days)
+            .chain(self.calendars.iter().map(|c| c.pull_days))
+            .max()
+            .unwrap_or(0);
+        // sources sync until the end of the last day
+        chrono::TimeDelta::days(pull_days + 1)
+    }
+
+    /// Is there a room with this `id`?
+    pub(crate) fn has_room(&self, id: i64) -> bool {
+        self.rooms.iter().any(|r| r.id() == id)
+    }
+
+    /// Setup and teardown time for this booking, see [`BufferConfig::buffers_for`]
+    pub(crate) fn buffers_for(&self, booking: &Booking) -> (chrono::TimeDelta, chrono::TimeDelta) {
+        let room = self.rooms.iter().find(|r| r.id() == booking.resource_id);
+        self.buffers.buffers_for(booking, room)
+    }
+
+    /// How the comfort schedule of this room is computed
+    pub(crate) fn heating_rules_for(&self, resource_id: i64) -> HeatingRules {
+        let preheat = self
+            .rooms
+            .iter()
+            .find(|r| r.id() == resource_id)
+            .and_then(|r| r.preheat_minutes)
+            .unwrap_or(self.heating.preheat_minutes);
+        HeatingRules {
+            preheat: chrono::TimeDelta::minutes(preheat.max(0)),
+            merge_gap: chrono::TimeDelta::minutes(self.heating.merge_gap_minutes.max(0)),
+            min_off: chrono::TimeDelta::minutes(self.heating.min_off_minutes.max(0)),
+        }
+    }
+
+    /// The booking with its start and end moved to include setup and teardown
+    pub(crate) fn with_buffers(&self, booking: &Booking) -> Booking {
+        let (setup, teardown) = self.buffers_for(booking);
+        Booking {
+            start_time: booking.start_time - setup,
+            end_time: booking.end_time + teardown,
+            ..booking.clone()
+        }
+    }
+
+    /// The longest `ct_min_sync_request_interval` of all sources, as requested syncs run all of them
+    pub(crate) fn min_sync_request_interval(&self) -> u64 {
+        self.ct
+            .iter()
+            .map(|ct| ct.ct_min_sync_request_interval)
+            .max()
+            .unwrap_or_else(default_ct_min_sync_request_interval)
+    }
+
     pub async fn create() -> Result<Config, ConfigError> {
         let path = Path::new("/etc/room-overview/config.toml");
         let content = read_to_string(path).map_err(ConfigError::ConfigFileRead)?;
-        let config_data: ConfigData = toml::from_str(&content).map_err(ConfigError::TomlParse)?;
+        Self::from_toml(&content).await
+    }
+
+    /// Parse and check the content of a config file and connect to its db
+    pub(crate) async fn from_toml(content: &str) -> Result<Config, ConfigError> {
+        let config_data: ConfigData = toml::from_str(content).map_err(ConfigError::TomlParse)?;
         Self::try_from_config_data(config_data).await
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// Check the `[[ct]]` and `[[calendars]]` sources and fill in the ids of rooms that do not set one
+fn validate_sources(
+    sources: &[ChurchToolsConfig],
+    calendars: &[CalendarConfig],
+    rooms: &mut [RoomConfig],
+) -> Result<(), ConfigError> {
+    if sources.is_empty() {
+        return Err(ConfigError::NoCtSource);
+    }
+    if let Some(name) = sources
+        .iter()
+        .map(|s| &s.name)
+        .chain(calendars.iter().map(|c| &c.name))
+        .duplicates()
+        .next()
+    {
+        return Err(ConfigError::DuplicateSource(name.clone()));
+    }
+    if let Some(name) = sources
+        .iter()
+        .map(|s| &s.name)
+        .chain(calendars.iter().map(|c| &c.name))
+        .find(|name| *name == LOCAL_SOURCE)
+    {
+        return Err(ConfigError::ReservedSourceName(name.clone()));
+    }
+    for ct in sources {
+        if ct.login_token.is_none() && (ct.username.is_none() || ct.password.is_none()) {
+            return Err(ConfigError::NoCtCredentials(ct.name.clone()));
+        }
+        if ct
+            .totp_secret
+            .as_deref()
+            .is_some_and(|secret| decode_base32(secret).is_none())
+        {
+            return Err(ConfigError::InvalidTotpSecret(ct.name.clone()));
+        }
+    }
+    for room in rooms.iter_mut() {
+        if !sources.iter().any(|s| s.name == room.source) {
+            return Err(ConfigError::UnknownCtSource {
+                room: room.name.clone(),
+                source: room.source.clone(),
+            });
+        }
+        room.id.get_or_insert(room.churchtools_id);
+    }
+    // rooms may share an id only if they are the same CT resource
+    for (a, b) in rooms.iter().tuple_combinations() {
+        let same_resource = a.source == b.source && a.churchtools_id == b.churchtools_id;
+        if (a.id() == b.id()) != same_resource {
+            return Err(ConfigError::AmbiguousRoomId(if same_resource {
+                a.id()
+            } else {
+                b.id()
+            }));
+        }
+    }
+    if let Some(calendar) = calendars
+        .iter()
+        .find(|c| !rooms.iter().any(|r| r.id() == c.room))
+    {
+        return Err(ConfigError::UnknownCalendarRoom {
+            calendar: calendar.name.clone(),
+            room: calendar.room,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) struct RoomConfig {
+    /// The ID of this room in the db, the API and URLs. Defaults to `churchtools_id`; rooms from
+    /// different CT instances sharing a resource ID need distinct ones.
+    pub id: Option<i64>,
+    /// The resource ID of this room in its CT instance
     pub churchtools_id: i64,
+    /// Name of the `[[ct]]` source this room is booked in
+    #[serde(default = "default_ct_source")]
+    pub source: String,
     pub name: String,
     pub location_hint: String,
+    /// The building this room is in. Rooms sharing a building get a common printed schedule.
+    pub building: Option<String>,
+    /// What this room offers
+    #[serde(flatten)]
+    pub attributes: RoomAttributes,
+    /// Fill attributes not set in this config from the resource description in CT
+    #[serde(default, skip_serializing)]
+    pub attributes_from_ct: bool,
+    /// Minutes this room is needed before each booking. Overrides `buffers.setup_minutes`.
+    pub setup_minutes: Option<i64>,
+    /// Minutes this room is needed after each booking. Overrides `buffers.teardown_minutes`.
+    pub teardown_minutes: Option<i64>,
+    /// Minutes this room is heated before its bookings. Overrides `heating.preheat_minutes`.
+    pub preheat_minutes: Option<i64>,
 }
 impl RoomConfig {
+    /// Bookings of this room have this `resource_id`
+    pub(crate) fn id(&self) -> i64 {
+        self.id.unwrap_or(self.churchtools_id)
+    }
+
     pub(crate) fn ics_location(&self) -> String {
         format!("{} - {}", self.name, self.location_hint)
     }
+
+    /// Location and attributes, for the ICS description
+    pub(crate) fn ics_description(&self) -> String {
+        let attributes = self.attributes.summary();
+        if attributes.is_empty() {
+            self.ics_location()
+        } else {
+            format!("{}. {attributes}", self.ics_location())
+        }
+    }
+}
+
+/// The known boolean attributes. Each has a canonical name and aliases accepted in filters and
+/// CT resource descriptions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Feature {
+    Projector,
+    Piano,
+    WheelchairAccessible,
+}
+impl Feature {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "projector" | "beamer" => Some(Self::Projector),
+            "piano" | "klavier" | "flügel" => Some(Self::Piano),
+            "wheelchair_accessible" | "barrierefrei" | "rollstuhlgerecht" => {
+                Some(Self::WheelchairAccessible)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Capacity and equipment of a room
+///
+/// The boolean attributes are `None` if they are neither set in the config nor in CT, which counts
+/// as not having the feature.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+pub(crate) struct RoomAttributes {
+    /// Number of seats in this room
+    pub seats: Option<u32>,
+    #[serde(default, serialize_with = "serialize_flag")]
+    pub projector: Option<bool>,
+    #[serde(default, serialize_with = "serialize_flag")]
+    pub piano: Option<bool>,
+    #[serde(default, serialize_with = "serialize_flag")]
+    pub wheelchair_accessible: Option<bool>,
+    /// Free-form equipment not covered by the other attributes, e.g. "Küche"
+    #[serde(default)]
+    pub equipment: Vec<String>,
+}
+impl RoomAttributes {
+    fn feature(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::Projector => self.projector,
+            Feature::Piano => self.piano,
+            Feature::WheelchairAccessible => self.wheelchair_accessible,
+        }
+        .unwrap_or(false)
+    }
+
+    /// Does this room have the named feature or equipment?
+    ///
+    /// `name` is either one of the boolean attributes (or an alias of one) or compared to the
+    /// free-form equipment, ignoring case.
+    pub(crate) fn has(&self, name: &str) -> bool {
+        match Feature::from_name(name) {
+            Some(feature) => self.feature(feature),
+            None => self.equipment.iter().any(|e| e.eq_ignore_ascii_case(name)),
+        }
+    }
+
+    /// Human readable (german) list of all attributes
+    pub(crate) fn summary(&self) -> String {
+        let mut parts = vec![];
+        if let Some(seats) = self.seats {
+            parts.push(format!("{seats} Plätze"));
+        }
+        if self.feature(Feature::Projector) {
+            parts.push("Beamer".to_owned());
+        }
+        if self.feature(Feature::Piano) {
+            parts.push("Klavier".to_owned());
+        }
+        if self.feature(Feature::WheelchairAccessible) {
+            parts.push("barrierefrei".to_owned());
+        }
+        parts.extend(self.equipment.iter().cloned());
+        parts.join(", ")
+    }
+
+    /// Parse attributes from a CT resource description.
+    ///
+    /// Every line of the form `key: value` or `key = value` is considered, e.g. `Plätze: 80` or
+    /// `Beamer: ja`. Unknown keys are ignored.
+    pub(crate) fn from_description(description: &str) -> Self {
+        let mut res = Self::default();
+        for line in description.lines() {
+            let Some((key, value)) = line.split_once(':').or_else(|| line.split_once('=')) else {
+                continue;
+            };
+            let key = key.trim().to_lowercase();
+            let value = value.trim().to_lowercase();
+            if key == "seats" || key == "plätze" {
+                res.seats = value.parse().ok().or(res.seats);
+                continue;
+            }
+            let enabled = matches!(value.as_str(), "ja" | "yes" | "true" | "1" | "x");
+            match Feature::from_name(&key) {
+                Some(Feature::Projector) => res.projector = Some(enabled),
+                Some(Feature::Piano) => res.piano = Some(enabled),
+                Some(Feature::WheelchairAccessible) => res.wheelchair_accessible = Some(enabled),
+                None => {}
+            }
+        }
+        res
+    }
+
+    /// Fill everything not set in `self` from `other`
+    ///
+    /// A flag set in `self` wins, even if it is `false`.
+    pub(crate) fn merge(&mut self, other: Self) {
+        self.seats = self.seats.or(other.seats);
+        self.projector = self.projector.or(other.projector);
+        self.piano = self.piano.or(other.piano);
+        self.wheelchair_accessible = self.wheelchair_accessible.or(other.wheelchair_accessible);
+        for e in other.equipment {
+            if !self.equipment.contains(&e) {
+                self.equipment.push(e);
+            }
+        }
+    }
+}
+
+/// Unset flags are serialized as `false`
+fn serialize_flag<S: serde::Serializer>(
+    value: &Option<bool>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bool(value.unwrap_or(false))
+}
+
+/// `[ct]` for a single CT instance, or `[[ct]]` for several
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ChurchToolsSources {
+    One(Box<ChurchToolsConfig>),
+    Many(Vec<ChurchToolsConfig>),
+}
+impl ChurchToolsSources {
+    fn into_vec(self) -> Vec<ChurchToolsConfig> {
+        match self {
+            Self::One(x) => vec![*x],
+            Self::Many(x) => x,
+        }
+    }
 }
 
 #[derive(Deserialize)]
 pub(crate) struct ChurchToolsConfig {
+    /// Identifies this CT instance in `rooms.source` and in the db. After a rename, the bookings
+    /// stored under the old name are deleted at startup and pulled again under the new one.
+    #[serde(default = "default_ct_source")]
+    pub name: String,
     pub host: String,
-    pub login_token: String,
+    /// Sent as `Authorization: Login ...`. Without it, we log in with `username` and `password`.
+    #[serde(default, deserialize_with = "deserialize_optional_secret")]
+    pub login_token: Option<String>,
+    pub username: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_secret")]
+    pub password: Option<String>,
+    /// Base32 secret of the second factor of `username`, if CT asks for a TOTP code at login
+    #[serde(default, deserialize_with = "deserialize_optional_secret")]
+    pub totp_secret: Option<String>,
     pub ct_pull_frequency: u64,
+    /// How many days ahead of today are pulled from CT
+    #[serde(default = "default_ct_pull_days")]
+    pub ct_pull_days: i64,
+    /// Syncs requested via the API or admin area are refused for this many seconds after the last
+    /// one
+    #[serde(default = "default_ct_min_sync_request_interval")]
+    pub ct_min_sync_request_interval: u64,
+    /// The login shared by all requests to this instance
+    #[serde(skip)]
+    pub session: CtSession,
+    /// Always https, except in tests against a local server
+    #[serde(skip, default = "default_ct_scheme")]
+    pub scheme: &'static str,
+}
+impl ChurchToolsConfig {
+    /// The URL of `path` (starting with `/api/`) on this instance
+    pub(crate) fn api_url(&self, path: &str) -> String {
+        format!("{}://{}{path}", self.scheme, self.host)
+    }
+}
+fn default_ct_scheme() -> &'static str {
+    "https"
+}
+fn default_ct_source() -> String {
+    "churchtools".to_owned()
+}
+fn default_ct_pull_days() -> i64 {
+    1
+}
+fn default_ct_min_sync_request_interval() -> u64 {
+    30
 }
 impl core::fmt::Debug for ChurchToolsConfig {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         f.debug_struct("ChurchToolsConfig")
+            .field("name", &self.name)
             .field("host", &self.host)
-            .field("login_token", &"[redacated]")
+            .field(
+                "login_token",
+                &self.login_token.as_ref().map(|_| "[redacted]"),
+            )
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "[redacted]"))
+            .field(
+                "totp_secret",
+                &self.totp_secret.as_ref().map(|_| "[redacted]"),
+            )
             .field("ct_pull_frequency", &self.ct_pull_frequency)
+            .field("ct_pull_days", &self.ct_pull_days)
+            .field(
+                "ct_min_sync_request_interval",
+                &self.ct_min_sync_request_interval,
+            )
+            .finish()
+    }
+}
+
+/// How a `[[calendars]]` source is fetched
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CalendarKind {
+    /// Download an ICS file
+    #[default]
+    Ics,
+    /// Ask a CalDAV collection for the events in the sync window
+    CalDav,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CalendarConfig {
+    /// Identifies this calendar in the db, like the name of a `[[ct]]` source
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub kind: CalendarKind,
+    /// Basic auth for the calendar server, e.g. a Nextcloud app password
+    pub user: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_secret")]
+    pub password: Option<String>,
+    /// The `id` of the room all events of this calendar are booked in
+    pub room: i64,
+    pub pull_frequency: u64,
+    /// How many days ahead of today are pulled
+    #[serde(default = "default_ct_pull_days")]
+    pub pull_days: i64,
+}
+impl core::fmt::Debug for CalendarConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("CalendarConfig")
+            .field("name", &self.name)
+            .field("url", &self.url)
+            .field("kind", &self.kind)
+            .field("user", &self.user)
+            .field("password", &self.password.as_ref().map(|_| "[redacted]"))
+            .field("room", &self.room)
+            .field("pull_frequency", &self.pull_frequency)
+            .field("pull_days", &self.pull_days)
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_from_description() {
+        let attributes = RoomAttributes::from_description(
+            "Großer Saal im Erdgeschoss.\nPlätze: 80\nBeamer: ja\nbarrierefrei = yes\nKlavier: nein",
+        );
+        assert_eq!(
+            attributes,
+            RoomAttributes {
+                seats: Some(80),
+                projector: Some(true),
+                piano: Some(false),
+                wheelchair_accessible: Some(true),
+                equipment: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn configured_attributes_win_over_ct() {
+        let mut attributes = RoomAttributes {
+            seats: Some(40),
+            projector: Some(false),
+            piano: None,
+            wheelchair_accessible: Some(true),
+            equipment: vec!["Küche".to_owned()],
+        };
+        attributes.merge(RoomAttributes::from_description(
+            "Plätze: 80\nBeamer: ja\nKlavier: ja\nbarrierefrei: nein\nKüche: ja",
+        ));
+        assert_eq!(
+            attributes,
+            RoomAttributes {
+                seats: Some(40),
+                projector: Some(false),
+                piano: Some(true),
+                wheelchair_accessible: Some(true),
+                equipment: vec!["Küche".to_owned()],
+            }
+        );
+        assert!(!attributes.has("Beamer"));
+    }
+
+    fn buffered_booking(title: &str, setup: Option<i64>, teardown: Option<i64>) -> Booking {
+        Booking {
+            title: title.to_owned(),
+            booking_id: 1,
+            source: "churchtools".to_owned(),
+            resource_id: 12,
+            start_time: chrono::DateTime::parse_from_rfc3339("2024-10-16T18:00:00Z")
+                .unwrap()
+                .into(),
+            end_time: chrono::DateTime::parse_from_rfc3339("2024-10-16T20:00:00Z")
+                .unwrap()
+                .into(),
+            setup_minutes: setup,
+            teardown_minutes: teardown,
+            origin: crate::BookingOrigin::ChurchTools,
+        }
+    }
+
+    #[test]
+    fn buffer_precedence() {
+        let buffers: BufferConfig = toml::from_str(
+            r#"
+            setup_minutes = 5
+            teardown_minutes = 5
+            [[rules]]
+            title_contains = "hochzeit"
+            setup_minutes = 60
+            [[rules]]
+            title_contains = "Hochzeit"
+            setup_minutes = 1
+            teardown_minutes = 1
+            "#,
+        )
+        .unwrap();
+        let room: RoomConfig = toml::from_str(
+            r#"
+            churchtools_id = 12
+            name = "Saal"
+            location_hint = ""
+            setup_minutes = 15
+            teardown_minutes = 30
+            "#,
+        )
+        .unwrap();
+        let minutes = |(setup, teardown): (chrono::TimeDelta, chrono::TimeDelta)| {
+            (setup.num_minutes(), teardown.num_minutes())
+        };
+
+        // only the global default
+        assert_eq!(
+            minutes(buffers.buffers_for(&buffered_booking("Chorprobe", None, None), None)),
+            (5, 5)
+        );
+        // the room beats the default
+        assert_eq!(
+            minutes(buffers.buffers_for(&buffered_booking("Chorprobe", None, None), Some(&room))),
+            (15, 30)
+        );
+        // the first matching rule beats the room, but only for what it sets
+        assert_eq!(
+            minutes(buffers.buffers_for(&buffered_booking("HOCHZEIT", None, None), Some(&room))),
+            (60, 30)
+        );
+        // CT beats everything
+        assert_eq!(
+            minutes(buffers.buffers_for(
+                &buffered_booking("Hochzeit", Some(0), Some(10)),
+                Some(&room)
+            )),
+            (0, 10)
+        );
+    }
+
+    #[test]
+    fn buffers_are_clamped() {
+        let buffers = BufferConfig {
+            setup_minutes: -30,
+            teardown_minutes: 24 * 60,
+            rules: vec![],
+        };
+        assert_eq!(
+            buffers.buffers_for(&buffered_booking("Chorprobe", None, None), None),
+            (chrono::TimeDelta::zero(), MAX_BUFFER)
+        );
+        assert_eq!(
+            buffers.buffers_for(&buffered_booking("Chorprobe", Some(-1), Some(1000)), None),
+            (chrono::TimeDelta::zero(), MAX_BUFFER)
+        );
+    }
+
+    #[test]
+    fn deprecated_admin_api_token() {
+        let admin: AdminConfig = toml::from_str(r#"api_token = "change-me""#).unwrap();
+        assert_eq!(admin.api_token.as_deref(), Some("change-me"));
+        assert!(!format!("{admin:?}").contains("change-me"));
+        assert!(admin.secure_cookie);
+    }
+
+    #[test]
+    fn attributes_by_alias_or_equipment() {
+        let attributes = RoomAttributes {
+            seats: None,
+            projector: Some(true),
+            piano: None,
+            wheelchair_accessible: Some(false),
+            equipment: vec!["Küche".to_owned()],
+        };
+        assert!(attributes.has("Beamer"));
+        assert!(attributes.has("projector"));
+        assert!(!attributes.has("Klavier"));
+        assert!(attributes.has("küche"));
+    }
+
+    #[derive(Deserialize)]
+    struct Sources {
+        ct: ChurchToolsSources,
+        #[serde(default)]
+        calendars: Vec<CalendarConfig>,
+        rooms: Vec<RoomConfig>,
+    }
+
+    #[test]
+    fn ct_sources() {
+        let single: Sources = toml::from_str(
+            r#"
+            [ct]
+            host = "gemeinde.church.tools"
+            login_token = "token"
+            ct_pull_frequency = 60
+
+            [[rooms]]
+            churchtools_id = 10
+            name = "Saal"
+            location_hint = "EG"
+            "#,
+        )
+        .unwrap();
+        let ct = single.ct.into_vec();
+        let calendars = single.calendars;
+        let mut rooms = single.rooms;
+        validate_sources(&ct, &calendars, &mut rooms).unwrap();
+        assert_eq!(ct[0].name, "churchtools");
+        assert_eq!(rooms[0].id(), 10);
+
+        let several: Sources = toml::from_str(
+            r#"
+            [[ct]]
+            host = "gemeinde.church.tools"
+            login_token = "token"
+            ct_pull_frequency = 60
+
+            [[ct]]
+            name = "nachbar"
+            host = "nachbar.church.tools"
+            login_token = "token"
+            ct_pull_frequency = 300
+
+            [[calendars]]
+            name = "jugend"
+            url = "https://cloud.example.org/remote.php/dav/calendars/jugend/raum/"
+            kind = "caldav"
+            room = 1010
+            pull_frequency = 300
+
+            [[rooms]]
+            churchtools_id = 10
+            name = "Saal"
+            location_hint = "EG"
+
+            [[rooms]]
+            churchtools_id = 10
+            source = "nachbar"
+            name = "Gemeindehaus"
+            location_hint = "Nachbarort"
+            "#,
+        )
+        .unwrap();
+        let ct = several.ct.into_vec();
+        assert_eq!(ct.len(), 2);
+        let mut calendars = several.calendars;
+        assert_eq!(calendars[0].kind, CalendarKind::CalDav);
+        let mut rooms = several.rooms;
+        // both rooms would get the id 10
+        assert!(matches!(
+            validate_sources(&ct, &calendars, &mut rooms),
+            Err(ConfigError::AmbiguousRoomId(10))
+        ));
+        rooms[1].id = Some(1010);
+        validate_sources(&ct, &calendars, &mut rooms).unwrap();
+        assert_eq!(rooms[1].id(), 1010);
+
+        calendars[0].name = "nachbar".to_owned();
+        assert!(matches!(
+            validate_sources(&ct, &calendars, &mut rooms),
+            Err(ConfigError::DuplicateSource(_))
+        ));
+        calendars[0].name = LOCAL_SOURCE.to_owned();
+        assert!(matches!(
+            validate_sources(&ct, &calendars, &mut rooms),
+            Err(ConfigError::ReservedSourceName(_))
+        ));
+        calendars[0].name = "jugend".to_owned();
+        calendars[0].room = 11;
+        assert!(matches!(
+            validate_sources(&ct, &calendars, &mut rooms),
+            Err(ConfigError::UnknownCalendarRoom { room: 11, .. })
+        ));
+        calendars[0].room = 1010;
+
+        rooms[1].source = "unbekannt".to_owned();
+        assert!(matches!(
+            validate_sources(&ct, &calendars, &mut rooms),
+            Err(ConfigError::UnknownCtSource { .. })
+        ));
+    }
+
+    #[test]
+    fn ct_credentials_and_secrets() {
+        let password_file = std::env::temp_dir().join("room-overview-test-ct-password");
+        std::fs::write(&password_file, "geheim\n").unwrap();
+        let login: Sources = toml::from_str(&format!(
+            r#"
+            [ct]
+            host = "gemeinde.church.tools"
+            username = "raeume"
+            password = {{ file = "{}" }}
+            totp_secret = "JBSWY3DPEHPK3PXP"
+            ct_pull_frequency = 60
+
+            [[rooms]]
+            churchtools_id = 10
+            name = "Saal"
+            location_hint = "EG"
+            "#,
+            password_file.display()
+        ))
+        .unwrap();
+        let mut ct = login.ct.into_vec();
+        let mut rooms = login.rooms;
+        assert_eq!(ct[0].login_token, None);
+        assert_eq!(ct[0].password.as_deref(), Some("geheim"));
+        assert_eq!(ct[0].totp_secret.as_deref(), Some("JBSWY3DPEHPK3PXP"));
+        validate_sources(&ct, &[], &mut rooms).unwrap();
+
+        ct[0].totp_secret = Some("kein base32!".to_owned());
+        assert!(matches!(
+            validate_sources(&ct, &[], &mut rooms),
+            Err(ConfigError::InvalidTotpSecret(_))
+        ));
+        ct[0].totp_secret = None;
+        ct[0].password = None;
+        assert!(matches!(
+            validate_sources(&ct, &[], &mut rooms),
+            Err(ConfigError::NoCtCredentials(_))
+        ));
+
+        let missing = toml::from_str::<Sources>(
+            r#"
+            rooms = []
+
+            [ct]
+            host = "gemeinde.church.tools"
+            login_token = { env = "ROOM_OVERVIEW_TEST_UNSET" }
+            ct_pull_frequency = 60
+            "#,
+        );
+        assert!(missing.is_err());
+        std::fs::remove_file(password_file).unwrap();
+
+        // the process environment is shared by all tests, so it is not changed here
+        #[derive(Deserialize)]
+        struct Secrets {
+            totp_secret: SecretData,
+        }
+        let secrets: Secrets =
+            toml::from_str(r#"totp_secret = { env = "ROOM_OVERVIEW_TEST_TOTP" }"#).unwrap();
+        assert_eq!(
+            secrets.totp_secret.read_with_env(|name| {
+                assert_eq!(name, "ROOM_OVERVIEW_TEST_TOTP");
+                Ok("JBSWY3DPEHPK3PXP".to_owned())
+            }),
+            Ok("JBSWY3DPEHPK3PXP".to_owned())
+        );
+    }
+
+    #[test]
+    fn mqtt_credentials() {
+        let mut mqtt: MqttConfig = toml::from_str(
+            r#"
+            host = "127.0.0.1"
+            username = "room-overview"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(mqtt.topic_prefix, "room-overview");
+        assert!(matches!(
+            mqtt.validate(),
+            Err(ConfigError::IncompleteMqttCredentials)
+        ));
+        mqtt.password = Some("geheim".to_owned());
+        mqtt.validate().unwrap();
+        mqtt.username = None;
+        assert!(mqtt.validate().is_err());
+    }
+
+    #[test]
+    fn ip_networks() {
+        let net = IpNet::try_from("10.0.20.0/24".to_owned()).unwrap();
+        assert!(net.contains("10.0.20.17".parse().unwrap()));
+        assert!(net.contains("::ffff:10.0.20.17".parse().unwrap()));
+        assert!(!net.contains("10.0.21.17".parse().unwrap()));
+        let single = IpNet::try_from("fd00::1".to_owned()).unwrap();
+        assert!(single.contains("fd00::1".parse().unwrap()));
+        assert!(!single.contains("fd00::2".parse().unwrap()));
+        assert!(IpNet::try_from("0.0.0.0/0".to_owned())
+            .unwrap()
+            .contains("192.168.1.1".parse().unwrap()));
+        assert!(IpNet::try_from("10.0.20.0/33".to_owned()).is_err());
+        assert!(IpNet::try_from("screens".to_owned()).is_err());
+    }
+
+    #[test]
+    fn access_policy_by_route() {
+        let access: AccessConfig = toml::from_str(
+            r#"
+            [[routes]]
+            route = "/all_rooms.ics"
+            policy = "token"
+
+            [[routes]]
+            route = "/admin*"
+            policy = "allowlist"
+            allow = ["10.0.20.0/24"]
+
+            [[routes]]
+            route = "/free_rooms"
+            policy = "basic_auth"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            access.policy_for("/all_rooms.ics"),
+            &AccessPolicy::Token {
+                scope: Scope::ReadBookings
+            }
+        );
+        assert!(matches!(
+            access.policy_for("/admin/conflicts"),
+            AccessPolicy::Allowlist { .. }
+        ));
+        assert_eq!(access.policy_for("/free_rooms"), &AccessPolicy::BasicAuth);
+        assert_eq!(access.policy_for("/rooms/:id"), &AccessPolicy::Public);
+        assert!(matches!(
+            access.policy_for("/admin"),
+            AccessPolicy::Allowlist { .. }
+        ));
+        // only whole segments
+        assert_eq!(access.policy_for("/administrator"), &AccessPolicy::Public);
+        access
+            .validate(&["/all_rooms.ics", "/admin", "/free_rooms"])
+            .unwrap();
+        assert!(matches!(
+            access.validate(&["/all_rooms.ics", "/admin"]),
+            Err(ConfigError::UnknownAccessRoute(route)) if route == "/free_rooms"
+        ));
+        access.validate(&crate::web::ROUTES).unwrap();
+    }
+
+    fn utc(x: &str) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(x).unwrap().into()
+    }
+
+    fn naive(x: &str) -> chrono::NaiveDateTime {
+        chrono::NaiveDateTime::parse_from_str(x, "%Y-%m-%dT%H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn prune_reference_in_local_time() {
+        let db = DbConfig::default();
+        // 00:30 local time is already the next day
+        assert_eq!(
+            db.prune_reference(utc("2024-10-16T22:30:00+00:00")),
+            naive("2024-10-16T22:00:00")
+        );
+        assert_eq!(
+            db.prune_reference(utc("2024-10-16T21:30:00+00:00")),
+            naive("2024-10-15T22:00:00")
+        );
+        // summer time starts on 2024-03-31 at 02:00, after midnight
+        assert_eq!(
+            db.prune_reference(utc("2024-03-31T12:00:00+00:00")),
+            naive("2024-03-30T23:00:00")
+        );
+        assert_eq!(
+            db.prune_reference(utc("2024-04-01T12:00:00+00:00")),
+            naive("2024-03-31T22:00:00")
+        );
+        // winter time starts on 2024-10-27 at 03:00
+        assert_eq!(
+            db.prune_reference(utc("2024-10-27T23:30:00+00:00")),
+            naive("2024-10-27T23:00:00")
+        );
+        assert_eq!(
+            db.prune_reference(utc("2024-10-27T22:30:00+00:00")),
+            naive("2024-10-26T22:00:00")
+        );
+
+        // retained days are calendar days, not 24 hours
+        let db = DbConfig {
+            retain_days: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            db.prune_reference(utc("2024-03-31T12:00:00+00:00")),
+            naive("2024-03-29T23:00:00")
+        );
+        assert_eq!(
+            db.prune_reference(utc("2024-10-28T12:00:00+00:00")),
+            naive("2024-10-26T22:00:00")
+        );
+    }
+
+    #[test]
+    fn prune_reference_without_midnight() {
+        // in 2018, summer time in Sao Paulo started at midnight
+        let db = DbConfig {
+            timezone: chrono_tz::America::Sao_Paulo,
+            ..Default::default()
+        };
+        assert_eq!(
+            db.prune_reference(utc("2018-11-04T12:00:00+00:00")),
+            naive("2018-11-04T03:00:00")
+        );
+        assert_eq!(
+            db.prune_reference(utc("2018-11-05T12:00:00+00:00")),
+            naive("2018-11-05T02:00:00")
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Detect bookings that block the same resource at the same time.
+
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    config::Config,
+    db::DBError,
+    webhooks::{self, WebhookEvent},
+    Booking,
+};
+
+/// Two bookings of the same resource that overlap in time.
+///
+/// Booking IDs are only unique within a source, so each booking is given by its source and ID.
+/// `first_booking_id` is always the smaller of the two IDs; if they are equal, `first_source` is the
+/// smaller source.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Conflict {
+    pub resource_id: i64,
+    pub first_source: String,
+    pub first_booking_id: i64,
+    pub second_source: String,
+    pub second_booking_id: i64,
+    pub overlap_start: DateTime<Utc>,
+    pub overlap_end: DateTime<Utc>,
+}
+
+/// A conflict as stored in the db, with the titles of the bookings involved.
+#[derive(Debug, Serialize)]
+pub struct ConflictReport {
+    #[serde(flatten)]
+    pub conflict: Conflict,
+    pub first_title: String,
+    pub second_title: String,
+    pub detected_at: DateTime<Utc>,
+}
+
+/// Find all pairs of bookings for the same resource that overlap.
+///
+/// Bookings that only touch (one ends when the other starts) do not conflict.
+pub fn detect_conflicts(bookings: &[Booking]) -> Vec<Conflict> {
+    bookings
+        .iter()
+        .tuple_combinations()
+        .filter(|(a, b)| {
+            a.resource_id == b.resource_id
+                && (&a.source, a.booking_id) != (&b.source, b.booking_id)
+                && a.start_time < b.end_time
+                && b.start_time < a.end_time
+        })
+        .map(|(a, b)| {
+            let (first, second) = if (a.booking_id, &a.source) < (b.booking_id, &b.source) {
+                (a, b)
+            } else {
+                (b, a)
+            };
+            Conflict {
+                resource_id: a.resource_id,
+                first_source: first.source.clone(),
+                first_booking_id: first.booking_id,
+                second_source: second.source.clone(),
+                second_booking_id: second.booking_id,
+                overlap_start: a.start_time.max(b.start_time),
+                overlap_end: a.end_time.min(b.end_time),
+            }
+        })
+        .collect()
+}
+
+/// Log newly detected conflicts and queue webhooks about them.
+///
+/// Nothing is sent here: the deliveries are made by [`webhooks::keep_delivering_webhooks`], so a
+/// slow or unreachable target never holds up the sync.
+pub async fn notify_new_conflicts(config: &Config, conflicts: &[&Conflict]) -> Result<(), DBError> {
+    for conflict in conflicts {
+        info!("Detected new conflict: {conflict:?}");
+    }
+    let events = conflicts
+        .iter()
+        .map(|conflict| WebhookEvent::ConflictDetected { conflict })
+        .collect::<Vec<_>>();
+    webhooks::enqueue(&*config.db, &config.webhooks, &events).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn booking(booking_id: i64, resource_id: i64, start: &str, end: &str) -> Booking {
+        calendar_booking("churchtools", booking_id, resource_id, start, end)
+    }
+
+    fn calendar_booking(
+        source: &str,
+        booking_id: i64,
+        resource_id: i64,
+        start: &str,
+        end: &str,
+    ) -> Booking {
+        Booking {
+            title: "title".to_owned(),
+            booking_id,
+            source: source.to_owned(),
+            resource_id,
+            start_time: DateTime::parse_from_rfc3339(start).unwrap().into(),
+            end_time: DateTime::parse_from_rfc3339(end).unwrap().into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: crate::BookingOrigin::ChurchTools,
+        }
+    }
+
+    #[test]
+    fn overlapping_bookings_conflict() {
+        let bookings = vec![
+            booking(7, 10, "2024-10-15T16:00:00Z", "2024-10-15T18:00:00Z"),
+            booking(3, 10, "2024-10-15T17:00:00Z", "2024-10-15T19:00:00Z"),
+            // only touches booking 3
+            booking(4, 10, "2024-10-15T19:00:00Z", "2024-10-15T20:00:00Z"),
+            // different resource
+            booking(5, 11, "2024-10-15T16:00:00Z", "2024-10-15T18:00:00Z"),
+        ];
+        assert_eq!(
+            detect_conflicts(&bookings),
+            vec![Conflict {
+                resource_id: 10,
+                first_source: "churchtools".to_owned(),
+                first_booking_id: 3,
+                second_source: "churchtools".to_owned(),
+                second_booking_id: 7,
+                overlap_start: DateTime::parse_from_rfc3339("2024-10-15T17:00:00Z")
+                    .unwrap()
+                    .into(),
+                overlap_end: DateTime::parse_from_rfc3339("2024-10-15T18:00:00Z")
+                    .unwrap()
+                    .into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn bookings_of_different_sources_with_the_same_id_conflict() {
+        let bookings = vec![
+            booking(7, 10, "2024-10-15T16:00:00Z", "2024-10-15T18:00:00Z"),
+            calendar_booking(
+                "gemeindehaus",
+                7,
+                10,
+                "2024-10-15T17:00:00Z",
+                "2024-10-15T19:00:00Z",
+            ),
+        ];
+        assert_eq!(
+            detect_conflicts(&bookings),
+            vec![Conflict {
+                resource_id: 10,
+                first_source: "churchtools".to_owned(),
+                first_booking_id: 7,
+                second_source: "gemeindehaus".to_owned(),
+                second_booking_id: 7,
+                overlap_start: DateTime::parse_from_rfc3339("2024-10-15T17:00:00Z")
+                    .unwrap()
+                    .into(),
+                overlap_end: DateTime::parse_from_rfc3339("2024-10-15T18:00:00Z")
+                    .unwrap()
+                    .into(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,585 @@
+//! Authenticate requests to CT
+//!
+//! A `[[ct]]` instance either has a static `login_token`, or logs in via /api/login with
+//! `username` and `password`, answering a TOTP challenge with `totp_secret` if CT asks for one.
+//! The session cookie and CSRF token of a login are kept until CT answers 401, then we log in
+//! again.
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use tracing::{debug, info, warn};
+
+use crate::{config::ChurchToolsConfig, pull_from_ct::CTApiError};
+
+/// Length of a TOTP time step in seconds
+const TOTP_STEP: i64 = 30;
+
+#[derive(Debug)]
+pub enum LoginError {
+    Request(reqwest::Error),
+    Deserialize,
+    /// CT answered with a status other than success or totp
+    Rejected(String),
+    /// CT asks for a second factor, but there is no `totp_secret`
+    NeedsTotp,
+}
+impl core::fmt::Display for LoginError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Request(e) => write!(f, "Cannot log in. reqwest Error: {e}"),
+            Self::Deserialize => write!(f, "Cannot deserialize the login response."),
+            Self::Rejected(status) => write!(f, "CT rejected the login with status {status}."),
+            Self::NeedsTotp => write!(
+                f,
+                "CT asks for a TOTP code, but there is no totp_secret in the config."
+            ),
+        }
+    }
+}
+impl core::error::Error for LoginError {}
+
+#[derive(Debug, Serialize)]
+struct LoginRequest<'a> {
+    username: &'a str,
+    password: &'a str,
+    #[serde(rename = "rememberMe")]
+    remember_me: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct TotpRequest {
+    code: String,
+    #[serde(rename = "personId")]
+    person_id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginResponse {
+    data: LoginData,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginData {
+    /// "success", or "totp" if a second factor is needed
+    status: String,
+    #[serde(rename = "personId")]
+    person_id: Option<i64>,
+}
+
+/// The response of /api/csrftoken
+#[derive(Debug, Deserialize)]
+struct CsrfTokenResponse {
+    data: String,
+}
+
+/// A logged in session
+#[derive(Debug, Clone, PartialEq)]
+struct Session {
+    /// `name=value` of every cookie CT set during the login
+    cookies: BTreeMap<String, String>,
+    csrf_token: Option<String>,
+}
+impl Session {
+    /// The value of the Cookie header
+    fn cookie_header(&self) -> String {
+        self.cookies
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    /// Remember the cookies set in `response`
+    fn store_cookies(&mut self, response: &reqwest::Response) {
+        for header in response.headers().get_all(reqwest::header::SET_COOKIE) {
+            if let Some((name, value)) = header.to_str().ok().and_then(parse_set_cookie) {
+                self.cookies.insert(name, value);
+            }
+        }
+    }
+}
+
+/// Name and value of a Set-Cookie header, without its attributes
+fn parse_set_cookie(header: &str) -> Option<(String, String)> {
+    let (name, value) = header.split(';').next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((name.to_owned(), value.trim().to_owned()))
+}
+
+/// The session of one CT instance, shared by all its requests
+#[derive(Default)]
+pub struct CtSession {
+    session: tokio::sync::Mutex<Option<Session>>,
+}
+
+/// Send `request` to `ct` with its credentials.
+///
+/// With a login instead of a `login_token`, we log in first if there is no session yet, and once
+/// more if CT answers 401. `on_error` turns a failed request into the error of the caller.
+pub(crate) async fn send(
+    ct: &ChurchToolsConfig,
+    request: reqwest::RequestBuilder,
+    on_error: fn(reqwest::Error) -> CTApiError,
+) -> Result<reqwest::Response, CTApiError> {
+    if let Some(token) = &ct.login_token {
+        return request
+            .header("Authorization", format!("Login {token}"))
+            .send()
+            .await
+            .map_err(on_error);
+    }
+    let retry = request.try_clone();
+    let session = current_session(ct, None).await?;
+    let response = with_session(request, &session)
+        .send()
+        .await
+        .map_err(on_error)?;
+    match retry {
+        Some(retry) if response.status() == reqwest::StatusCode::UNAUTHORIZED => {
+            info!(
+                "The session for CT source {} has expired. Logging in again.",
+                ct.name
+            );
+            let session = current_session(ct, Some(&session)).await?;
+            with_session(retry, &session).send().await.map_err(on_error)
+        }
+        _ => Ok(response),
+    }
+}
+
+fn with_session(request: reqwest::RequestBuilder, session: &Session) -> reqwest::RequestBuilder {
+    let request = request.header(reqwest::header::COOKIE, session.cookie_header());
+    match &session.csrf_token {
+        Some(token) => request.header("CSRF-Token", token),
+        None => request,
+    }
+}
+
+/// The session of `ct`, logging in if there is none or it is still `expired`
+async fn current_session(
+    ct: &ChurchToolsConfig,
+    expired: Option<&Session>,
+) -> Result<Session, CTApiError> {
+    let mut current = ct.session.session.lock().await;
+    match current.as_ref() {
+        // another request has logged in again already
+        Some(session) if Some(session) != expired => Ok(session.clone()),
+        _ => {
+            *current = None;
+            let session = login(ct).await.map_err(CTApiError::Login)?;
+            *current = Some(session.clone());
+            Ok(session)
+        }
+    }
+}
+
+/// Log in with the username and password of `ct`
+async fn login(ct: &ChurchToolsConfig) -> Result<Session, LoginError> {
+    let username = ct
+        .username
+        .as_deref()
+        .expect("a username is required without a login_token");
+    let password = ct
+        .password
+        .as_deref()
+        .expect("a password is required without a login_token");
+    let client = reqwest::Client::new();
+    let mut session = Session {
+        cookies: BTreeMap::new(),
+        csrf_token: None,
+    };
+
+    let body = serde_json::to_string(&LoginRequest {
+        username,
+        password,
+        remember_me: false,
+    })
+    .expect("the login request is valid json");
+    let response = client
+        .post(ct.api_url("/api/login"))
+        .header("accept", "application/json")
+        .header("content-type", "application/json")
+        .body(body)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(LoginError::Request)?;
+    session.store_cookies(&response);
+    let mut data = login_data(response).await?;
+
+    if data.status == "totp" {
+        let secret = ct.totp_secret.as_deref().ok_or(LoginError::NeedsTotp)?;
+        let key = decode_base32(secret).expect("totp_secret is validated when reading the config");
+        let body = serde_json::to_string(&TotpRequest {
+            code: totp(&key, Utc::now().timestamp()),
+            person_id: data.person_id.ok_or(LoginError::Deserialize)?,
+        })
+        .expect("the totp request is valid json");
+        let response = client
+            .post(ct.api_url("/api/login/totp"))
+            .header("accept", "application/json")
+            .header("content-type", "application/json")
+            .header(reqwest::header::COOKIE, session.cookie_header())
+            .body(body)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(LoginError::Request)?;
+        session.store_cookies(&response);
+        data = login_data(response).await?;
+    }
+    if data.status != "success" {
+        return Err(LoginError::Rejected(data.status));
+    }
+
+    // the CSRF token is only needed for requests that change something, so a session without one
+    // is still usable
+    session.csrf_token = match csrf_token(ct, &client, &session).await {
+        Ok(token) => Some(token),
+        Err(e) => {
+            warn!("Unable to get a CSRF token from CT source {}: {e}", ct.name);
+            None
+        }
+    };
+    debug!("Logged in to CT source {} as {username}.", ct.name);
+    Ok(session)
+}
+
+async fn login_data(response: reqwest::Response) -> Result<LoginData, LoginError> {
+    let text = response.text().await.map_err(LoginError::Request)?;
+    serde_json::from_str::<LoginResponse>(&text)
+        .map(|r| r.data)
+        .map_err(|_| {
+            warn!("There was an error parsing the login response from CT.");
+            LoginError::Deserialize
+        })
+}
+
+async fn csrf_token(
+    ct: &ChurchToolsConfig,
+    client: &reqwest::Client,
+    session: &Session,
+) -> Result<String, LoginError> {
+    let text = client
+        .get(ct.api_url("/api/csrftoken"))
+        .header("accept", "application/json")
+        .header(reqwest::header::COOKIE, session.cookie_header())
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(LoginError::Request)?
+        .text()
+        .await
+        .map_err(LoginError::Request)?;
+    serde_json::from_str::<CsrfTokenResponse>(&text)
+        .map(|r| r.data)
+        .map_err(|_| LoginError::Deserialize)
+}
+
+/// Decode an RFC 4648 base32 string, ignoring case, spaces and padding
+pub(crate) fn decode_base32(value: &str) -> Option<Vec<u8>> {
+    let mut bits = 0_u32;
+    let mut len = 0;
+    let mut res = vec![];
+    for c in value.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let digit = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return None,
+        };
+        bits = (bits << 5) | digit;
+        len += 5;
+        if len >= 8 {
+            len -= 8;
+            res.push(u8::try_from((bits >> len) & 0xff).expect("masked to a byte"));
+        }
+    }
+    if res.is_empty() {
+        return None;
+    }
+    Some(res)
+}
+
+/// The 6 digit TOTP code (RFC 6238 with SHA-1 and 30 second steps) of `key` at `timestamp`
+fn totp(key: &[u8], timestamp: i64) -> String {
+    let counter = timestamp.div_euclid(TOTP_STEP).to_be_bytes();
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(&counter);
+    let digest = mac.finalize().into_bytes();
+    let offset = usize::from(digest[digest.len() - 1] & 0xf);
+    let code = u32::from_be_bytes(
+        digest[offset..offset + 4]
+            .try_into()
+            .expect("the digest has 20 bytes"),
+    ) & 0x7fff_ffff;
+    format!("{:06}", code % 1_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        http::{header, HeaderMap, StatusCode},
+        response::IntoResponse,
+        routing::{get, post},
+        Router,
+    };
+
+    use super::*;
+
+    #[test]
+    fn base32() {
+        assert_eq!(
+            decode_base32("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap(),
+            b"12345678901234567890"
+        );
+        assert_eq!(decode_base32("mzxw 6ytb oi======").unwrap(), b"foobar");
+        assert_eq!(decode_base32("not base32!"), None);
+        assert_eq!(decode_base32(""), None);
+    }
+
+    #[test]
+    fn totp_codes() {
+        // the SHA-1 test vectors of RFC 6238, truncated to 6 digits
+        let key = b"12345678901234567890";
+        assert_eq!(totp(key, 59), "287082");
+        assert_eq!(totp(key, 1_111_111_109), "081804");
+        assert_eq!(totp(key, 1_234_567_890), "005924");
+        assert_eq!(totp(key, 2_000_000_000), "279037");
+    }
+
+    #[test]
+    fn cookies() {
+        assert_eq!(
+            parse_set_cookie("ChurchTools_ct_example=abc123; path=/; secure; HttpOnly"),
+            Some(("ChurchTools_ct_example".to_owned(), "abc123".to_owned()))
+        );
+        assert_eq!(parse_set_cookie("=nameless; path=/"), None);
+        assert_eq!(parse_set_cookie("garbage"), None);
+        let session = Session {
+            cookies: [
+                ("a".to_owned(), "1".to_owned()),
+                ("b".to_owned(), "2".to_owned()),
+            ]
+            .into(),
+            csrf_token: None,
+        };
+        assert_eq!(session.cookie_header(), "a=1; b=2");
+    }
+
+    const TOTP_SECRET: &str = "JBSWY3DPEHPK3PXP";
+
+    /// What the local CT server has seen
+    #[derive(Debug, Default)]
+    struct CtState {
+        requires_totp: bool,
+        logins: usize,
+        totp_logins: usize,
+        /// requests to /api/whoami, including rejected ones
+        requests: usize,
+        /// the session cookie waiting for its second factor
+        pending: Option<String>,
+        /// the session cookie that is logged in
+        valid: Option<String>,
+    }
+    type State = Arc<Mutex<CtState>>;
+
+    fn session_cookie(headers: &HeaderMap) -> Option<String> {
+        headers
+            .get(header::COOKIE)?
+            .to_str()
+            .ok()?
+            .split("; ")
+            .find_map(|c| c.strip_prefix("ct_session="))
+            .map(ToOwned::to_owned)
+    }
+
+    /// A local CT server for the user `raeume` with the password `geheim`
+    async fn server(requires_totp: bool) -> (String, State) {
+        let state = State::new(Mutex::new(CtState {
+            requires_totp,
+            ..Default::default()
+        }));
+        let app = Router::new()
+            .route(
+                "/api/login",
+                post({
+                    let state = state.clone();
+                    move |body: String| async move {
+                        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+                        assert_eq!(body["username"], "raeume");
+                        assert_eq!(body["password"], "geheim");
+                        // concurrent requests overlap with the login
+                        tokio::time::sleep(core::time::Duration::from_millis(50)).await;
+                        let mut state = state.lock().unwrap();
+                        state.logins += 1;
+                        let session = format!("s{}", state.logins);
+                        let status = if state.requires_totp {
+                            state.pending = Some(session.clone());
+                            "totp"
+                        } else {
+                            state.valid = Some(session.clone());
+                            "success"
+                        };
+                        (
+                            [(header::SET_COOKIE, format!("ct_session={session}; path=/"))],
+                            format!(r#"{{"data":{{"status":"{status}","personId":7}}}}"#),
+                        )
+                    }
+                }),
+            )
+            .route(
+                "/api/login/totp",
+                post({
+                    let state = state.clone();
+                    move |headers: HeaderMap, body: String| async move {
+                        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+                        assert_eq!(body["personId"], 7);
+                        let key = decode_base32(TOTP_SECRET).unwrap();
+                        let now = Utc::now().timestamp();
+                        // the code may be from the step before
+                        assert!([now, now - TOTP_STEP]
+                            .iter()
+                            .any(|t| body["code"] == totp(&key, *t)));
+                        let mut state = state.lock().unwrap();
+                        assert_eq!(session_cookie(&headers), state.pending);
+                        state.valid = state.pending.take();
+                        state.totp_logins += 1;
+                        r#"{"data":{"status":"success","personId":7}}"#
+                    }
+                }),
+            )
+            .route(
+                "/api/csrftoken",
+                get({
+                    let state = state.clone();
+                    move |headers: HeaderMap| async move {
+                        let state = state.lock().unwrap();
+                        match session_cookie(&headers) {
+                            Some(session) if state.valid == Some(session.clone()) => {
+                                format!(r#"{{"data":"csrf-{session}"}}"#).into_response()
+                            }
+                            _ => StatusCode::UNAUTHORIZED.into_response(),
+                        }
+                    }
+                }),
+            )
+            .route(
+                "/api/whoami",
+                get({
+                    let state = state.clone();
+                    move |headers: HeaderMap| async move {
+                        let mut state = state.lock().unwrap();
+                        state.requests += 1;
+                        let csrf = headers.get("csrf-token").and_then(|v| v.to_str().ok());
+                        match (session_cookie(&headers), &state.valid) {
+                            (Some(session), Some(valid))
+                                if session == *valid
+                                    && csrf == Some(&format!("csrf-{session}")) =>
+                            {
+                                StatusCode::OK
+                            }
+                            _ => StatusCode::UNAUTHORIZED,
+                        }
+                    }
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (host, state)
+    }
+
+    fn ct(host: &str, totp_secret: Option<&str>) -> ChurchToolsConfig {
+        let mut ct: ChurchToolsConfig = toml::from_str(&format!(
+            r#"
+            host = "{host}"
+            username = "raeume"
+            password = "geheim"
+            ct_pull_frequency = 60
+            {}
+            "#,
+            totp_secret.map_or_else(String::new, |s| format!(r#"totp_secret = "{s}""#))
+        ))
+        .unwrap();
+        ct.scheme = "http";
+        ct
+    }
+
+    async fn whoami(ct: &ChurchToolsConfig) -> Result<StatusCode, CTApiError> {
+        let request = reqwest::Client::new().get(ct.api_url("/api/whoami"));
+        Ok(send(ct, request, CTApiError::CheckLogin).await?.status())
+    }
+
+    #[tokio::test]
+    async fn first_login() {
+        let (host, state) = server(false).await;
+        let ct = ct(&host, None);
+        assert_eq!(whoami(&ct).await.unwrap(), StatusCode::OK);
+        assert_eq!(whoami(&ct).await.unwrap(), StatusCode::OK);
+        let state = state.lock().unwrap();
+        // the session is kept
+        assert_eq!(state.logins, 1);
+        assert_eq!(state.totp_logins, 0);
+        assert_eq!(state.requests, 2);
+    }
+
+    #[tokio::test]
+    async fn first_login_with_totp() {
+        let (host, state) = server(true).await;
+        assert!(matches!(
+            whoami(&ct(&host, None)).await,
+            Err(CTApiError::Login(LoginError::NeedsTotp))
+        ));
+
+        let ct = ct(&host, Some(TOTP_SECRET));
+        assert_eq!(whoami(&ct).await.unwrap(), StatusCode::OK);
+        let state = state.lock().unwrap();
+        assert_eq!(state.logins, 2);
+        assert_eq!(state.totp_logins, 1);
+        assert_eq!(state.requests, 1);
+    }
+
+    #[tokio::test]
+    async fn expired_session_logs_in_again_once() {
+        let (host, state) = server(false).await;
+        let ct = ct(&host, None);
+        assert_eq!(whoami(&ct).await.unwrap(), StatusCode::OK);
+        state.lock().unwrap().valid = None;
+
+        assert_eq!(whoami(&ct).await.unwrap(), StatusCode::OK);
+        let state = state.lock().unwrap();
+        assert_eq!(state.logins, 2);
+        // the rejected request and its retry
+        assert_eq!(state.requests, 3);
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_session() {
+        let (host, state) = server(false).await;
+        let ct = ct(&host, None);
+        let statuses = futures::future::join_all((0..5).map(|_| whoami(&ct))).await;
+        assert!(statuses
+            .iter()
+            .all(|s| *s.as_ref().unwrap() == StatusCode::OK));
+        assert_eq!(state.lock().unwrap().logins, 1);
+
+        // all of them are rejected, but only one logs in again
+        state.lock().unwrap().valid = None;
+        let statuses = futures::future::join_all((0..5).map(|_| whoami(&ct))).await;
+        assert!(statuses
+            .iter()
+            .all(|s| *s.as_ref().unwrap() == StatusCode::OK));
+        let state = state.lock().unwrap();
+        assert_eq!(state.logins, 2);
+        assert_eq!(state.requests, 15);
+    }
+}
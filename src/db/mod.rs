@@ -0,0 +1,1651 @@
+//! All the db-related functions
+//!
+//! Everything is stored through [`Storage`], which is implemented for SQLite (the default) and
+//! PostgreSQL (to share one database between several instances).
+
+use std::path::Path;
+
+use chrono::NaiveDateTime;
+
+use crate::{
+    announcements::{Announcement, NewAnnouncement},
+    auth::{AdminSession, ApiToken, Scope},
+    backup::DbExport,
+    conflicts::{Conflict, ConflictReport},
+    history::{BookingEvent, BookingEventFilter, NewBookingEvent},
+    local_bookings::{LocalBooking, LocalBookingKind, NewLocalBooking},
+    webhooks::{NewWebhookDelivery, WebhookDelivery},
+    Booking,
+};
+
+mod postgres;
+mod sqlite;
+
+pub use postgres::PostgresStorage;
+pub use sqlite::SqliteStorage;
+
+/// Tables emptied by [`Storage::import`]. Conflicts are found again by the next sync.
+const IMPORTED_TABLES: [&str; 5] = [
+    "conflicts",
+    "bookings",
+    "local_bookings",
+    "booking_archive",
+    "booking_events",
+];
+
+/// What one sync changes in the bookings of `source`, written by
+/// [`Storage::apply_booking_changes`] in one transaction
+#[derive(Debug, Default)]
+pub struct BookingChanges<'a> {
+    pub source: &'a str,
+    pub inserted: Vec<&'a Booking>,
+    pub updated: Vec<&'a Booking>,
+    /// `booking_id`s in `source`
+    pub deleted: Vec<i64>,
+    /// Webhooks about these changes
+    pub deliveries: Vec<NewWebhookDelivery>,
+    /// The sync run these changes are recorded with in the booking history
+    pub sync_run: Option<&'a str>,
+    /// History events about these changes
+    pub history: Vec<NewBookingEvent<'a>>,
+}
+
+/// Size of the db, as shown in the admin area
+#[derive(Debug, PartialEq)]
+pub struct DbStatistics {
+    pub bookings: i64,
+    pub local_bookings: i64,
+    pub conflicts: i64,
+    pub announcements: i64,
+    pub admin_sessions: i64,
+    pub webhook_deliveries: i64,
+    pub booking_events: i64,
+    pub size_bytes: i64,
+}
+
+#[derive(Debug)]
+pub enum DBError {
+    SelectBookings(sqlx::Error),
+    InsertBooking(sqlx::Error),
+    DeleteBooking(sqlx::Error),
+    UpdateBooking(sqlx::Error),
+    SelectConflicts(sqlx::Error),
+    InsertConflict(sqlx::Error),
+    DeleteConflict(sqlx::Error),
+    SelectLocalBookings(sqlx::Error),
+    InsertLocalBooking(sqlx::Error),
+    DeleteLocalBooking(sqlx::Error),
+    SelectAnnouncements(sqlx::Error),
+    InsertAnnouncement(sqlx::Error),
+    DeleteAnnouncement(sqlx::Error),
+    SelectSession(sqlx::Error),
+    InsertSession(sqlx::Error),
+    DeleteSession(sqlx::Error),
+    Statistics(sqlx::Error),
+    SelectApiTokens(sqlx::Error),
+    InsertApiToken(sqlx::Error),
+    UpdateApiToken(sqlx::Error),
+    DeleteApiToken(sqlx::Error),
+    SelectWebhookDeliveries(sqlx::Error),
+    InsertWebhookDelivery(sqlx::Error),
+    UpdateWebhookDelivery(sqlx::Error),
+    DeleteWebhookDelivery(sqlx::Error),
+    SelectBookingEvents(sqlx::Error),
+    ArchiveBookings(sqlx::Error),
+    SelectArchive(sqlx::Error),
+    InsertBookingEvent(sqlx::Error),
+    DeleteBookingEvent(sqlx::Error),
+    ClaimJobRun(sqlx::Error),
+    Transaction(sqlx::Error),
+    Migrate(sqlx::migrate::MigrateError),
+    Export(sqlx::Error),
+    Import(sqlx::Error),
+    Snapshot(sqlx::Error),
+    SnapshotUnsupported,
+    OpenSnapshot(sqlx::Error),
+    /// The snapshot is at this migration, which is not the latest one
+    SnapshotVersion(Option<i64>),
+}
+impl core::fmt::Display for DBError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::SelectBookings(e) => {
+                write!(
+                    f,
+                    "Unable to select bookings from the DB. Inner Error: {e}."
+                )
+            }
+            Self::InsertBooking(e) => {
+                write!(f, "Unable to insert booking into the DB. Inner Error: {e}.")
+            }
+            Self::UpdateBooking(e) => {
+                write!(f, "Unable to update booking in the DB. Inner Error: {e}.")
+            }
+            Self::DeleteBooking(e) => {
+                write!(f, "Unable to delete booking from the DB. Inner Error: {e}.")
+            }
+            Self::SelectConflicts(e) => {
+                write!(
+                    f,
+                    "Unable to select conflicts from the DB. Inner Error: {e}."
+                )
+            }
+            Self::InsertConflict(e) => {
+                write!(
+                    f,
+                    "Unable to insert conflict into the DB. Inner Error: {e}."
+                )
+            }
+            Self::DeleteConflict(e) => {
+                write!(
+                    f,
+                    "Unable to delete conflict from the DB. Inner Error: {e}."
+                )
+            }
+            Self::SelectLocalBookings(e) => {
+                write!(
+                    f,
+                    "Unable to select local bookings from the DB. Inner Error: {e}."
+                )
+            }
+            Self::InsertLocalBooking(e) => {
+                write!(
+                    f,
+                    "Unable to insert local booking into the DB. Inner Error: {e}."
+                )
+            }
+            Self::DeleteLocalBooking(e) => {
+                write!(
+                    f,
+                    "Unable to delete local booking from the DB. Inner Error: {e}."
+                )
+            }
+            Self::SelectAnnouncements(e) => {
+                write!(
+                    f,
+                    "Unable to select announcements from the DB. Inner Error: {e}."
+                )
+            }
+            Self::InsertAnnouncement(e) => {
+                write!(
+                    f,
+                    "Unable to insert announcement into the DB. Inner Error: {e}."
+                )
+            }
+            Self::DeleteAnnouncement(e) => {
+                write!(
+                    f,
+                    "Unable to delete announcement from the DB. Inner Error: {e}."
+                )
+            }
+            Self::SelectSession(e) => {
+                write!(f, "Unable to select session from the DB. Inner Error: {e}.")
+            }
+            Self::InsertSession(e) => {
+                write!(f, "Unable to insert session into the DB. Inner Error: {e}.")
+            }
+            Self::DeleteSession(e) => {
+                write!(f, "Unable to delete session from the DB. Inner Error: {e}.")
+            }
+            Self::Statistics(e) => {
+                write!(f, "Unable to get statistics of the DB. Inner Error: {e}.")
+            }
+            Self::SelectApiTokens(e) => {
+                write!(
+                    f,
+                    "Unable to select API tokens from the DB. Inner Error: {e}."
+                )
+            }
+            Self::InsertApiToken(e) => {
+                write!(
+                    f,
+                    "Unable to insert API token into the DB. Inner Error: {e}."
+                )
+            }
+            Self::UpdateApiToken(e) => {
+                write!(f, "Unable to update API token in the DB. Inner Error: {e}.")
+            }
+            Self::DeleteApiToken(e) => {
+                write!(
+                    f,
+                    "Unable to delete API token from the DB. Inner Error: {e}."
+                )
+            }
+            Self::SelectWebhookDeliveries(e) => {
+                write!(
+                    f,
+                    "Unable to select webhook deliveries from the DB. Inner Error: {e}."
+                )
+            }
+            Self::InsertWebhookDelivery(e) => {
+                write!(
+                    f,
+                    "Unable to insert webhook delivery into the DB. Inner Error: {e}."
+                )
+            }
+            Self::UpdateWebhookDelivery(e) => {
+                write!(
+                    f,
+                    "Unable to update webhook delivery in the DB. Inner Error: {e}."
+                )
+            }
+            Self::DeleteWebhookDelivery(e) => {
+                write!(
+                    f,
+                    "Unable to delete webhook delivery from the DB. Inner Error: {e}."
+                )
+            }
+            Self::ArchiveBookings(e) => {
+                write!(f, "Unable to archive bookings in the DB. Inner Error: {e}.")
+            }
+            Self::SelectArchive(e) => {
+                write!(
+                    f,
+                    "Unable to select archived bookings from the DB. Inner Error: {e}."
+                )
+            }
+            Self::SelectBookingEvents(e) => {
+                write!(
+                    f,
+                    "Unable to select booking events from the DB. Inner Error: {e}."
+                )
+            }
+            Self::InsertBookingEvent(e) => {
+                write!(
+                    f,
+                    "Unable to insert booking event into the DB. Inner Error: {e}."
+                )
+            }
+            Self::DeleteBookingEvent(e) => {
+                write!(
+                    f,
+                    "Unable to delete booking events from the DB. Inner Error: {e}."
+                )
+            }
+            Self::ClaimJobRun(e) => {
+                write!(f, "Unable to record a job run in the DB. Inner Error: {e}.")
+            }
+            Self::Migrate(e) => {
+                write!(f, "Unable to migrate the DB. Inner Error: {e}.")
+            }
+            Self::Transaction(e) => {
+                write!(
+                    f,
+                    "Unable to run a transaction in the DB. Inner Error: {e}."
+                )
+            }
+            Self::Export(e) => {
+                write!(f, "Unable to export the DB. Inner Error: {e}.")
+            }
+            Self::Import(e) => {
+                write!(f, "Unable to import into the DB. Inner Error: {e}.")
+            }
+            Self::Snapshot(e) => {
+                write!(f, "Unable to write a snapshot of the DB. Inner Error: {e}.")
+            }
+            Self::SnapshotUnsupported => {
+                write!(f, "Snapshots are only supported for SQLite.")
+            }
+            Self::OpenSnapshot(e) => {
+                write!(f, "Unable to open the snapshot. Inner Error: {e}.")
+            }
+            Self::SnapshotVersion(x) => {
+                write!(
+                    f,
+                    "The snapshot is at migration {}, but this version needs the latest migration.",
+                    x.map_or_else(|| "none".to_owned(), |x| x.to_string())
+                )
+            }
+        }
+    }
+}
+impl core::error::Error for DBError {}
+
+/// A database holding bookings and everything else room-overview keeps.
+///
+/// All datetimes are UTC. Intervals are inclusive at both ends.
+#[async_trait::async_trait]
+pub trait Storage: core::fmt::Debug + Send + Sync {
+    /// Create or update the schema
+    async fn migrate(&self) -> Result<(), DBError>;
+
+    /// Get all bookings in the db which intersect the interval [start, end]
+    ///
+    /// Order by start-date
+    async fn get_bookings_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Booking>, DBError>;
+
+    /// Insert, update and delete bookings and queue their webhooks, all or nothing
+    async fn apply_booking_changes(&self, changes: &BookingChanges<'_>) -> Result<(), DBError>;
+
+    /// Delete old bookings from the DB
+    ///
+    /// This removes all bookings which have ended anytime before `reference` (naive UTC), usually the
+    /// start of a day (see [`DbConfig::prune_reference`](crate::config::DbConfig::prune_reference)).
+    /// Bookings that have ended today must be kept, because the CT Rest-API only allows granularity
+    /// down to the day. If we removed bookings from earlier today, the same entries would constantly
+    /// get rewritten and repruned.
+    ///
+    /// Pruned bookings are archived and recorded in the history with `sync_run`.
+    async fn prune_old_bookings(
+        &self,
+        sync_run: Option<&str>,
+        reference: NaiveDateTime,
+    ) -> Result<u64, DBError>;
+
+    /// Get all conflicts whose overlap intersects the interval [start, end]
+    async fn get_conflicts_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Conflict>, DBError>;
+
+    /// Get all known conflicts together with the titles of the bookings involved
+    ///
+    /// Order by start of the overlap
+    async fn get_conflict_reports(&self) -> Result<Vec<ConflictReport>, DBError>;
+
+    async fn insert_conflict(&self, conflict: &Conflict) -> Result<(), DBError>;
+
+    async fn delete_conflict(&self, conflict: &Conflict) -> Result<(), DBError>;
+
+    /// Delete conflicts whose overlap ended before `reference`, like [`Self::prune_old_bookings`].
+    async fn prune_old_conflicts(&self, reference: NaiveDateTime) -> Result<u64, DBError>;
+
+    /// Get all local bookings which intersect the interval [start, end]
+    ///
+    /// Order by start-date
+    async fn get_local_bookings_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<LocalBooking>, DBError>;
+
+    /// Insert a local booking into the DB, returning its new ID
+    async fn insert_local_booking(&self, booking: &NewLocalBooking) -> Result<i64, DBError>;
+
+    /// Delete a local booking. Returns whether it existed.
+    async fn delete_local_booking(&self, id: i64) -> Result<bool, DBError>;
+
+    /// Delete local bookings that ended before `reference`, like [`Self::prune_old_bookings`].
+    /// Bookings (but not blocks) are archived.
+    async fn prune_old_local_bookings(&self, reference: NaiveDateTime) -> Result<u64, DBError>;
+
+    /// Get all archived bookings which intersect the interval [start, end], in no particular order
+    async fn get_archived_bookings_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Booking>, DBError>;
+
+    /// Get all announcements which intersect the interval [start, end]
+    ///
+    /// Order by priority (highest first), then by start-date
+    async fn get_announcements_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Announcement>, DBError>;
+
+    /// Insert an announcement into the DB, returning its new ID
+    async fn insert_announcement(&self, announcement: &NewAnnouncement) -> Result<i64, DBError>;
+
+    /// Delete an announcement. Returns whether it existed.
+    async fn delete_announcement(&self, id: i64) -> Result<bool, DBError>;
+
+    /// Delete announcements that ended before `reference`, like [`Self::prune_old_bookings`].
+    async fn prune_old_announcements(&self, reference: NaiveDateTime) -> Result<u64, DBError>;
+
+    /// Get the session with this ID, unless it has expired
+    async fn get_admin_session(&self, id: &str) -> Result<Option<AdminSession>, DBError>;
+
+    async fn insert_admin_session(&self, session: &AdminSession) -> Result<(), DBError>;
+
+    async fn delete_admin_session(&self, id: &str) -> Result<(), DBError>;
+
+    /// Delete all expired sessions
+    async fn prune_expired_admin_sessions(&self) -> Result<u64, DBError>;
+
+    /// Count the rows in all tables and get the size of the db
+    async fn get_statistics(&self) -> Result<DbStatistics, DBError>;
+
+    /// Get the API token with this hash, if it exists
+    async fn get_api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>, DBError>;
+
+    /// Get all API tokens, ordered by name
+    async fn get_api_tokens(&self) -> Result<Vec<ApiToken>, DBError>;
+
+    /// Store a new API token, returning its ID. Names are unique.
+    async fn insert_api_token(
+        &self,
+        name: &str,
+        token_hash: &str,
+        scopes: &[Scope],
+        expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<i64, DBError>;
+
+    /// Remember that the token was used at `at`
+    async fn touch_api_token(
+        &self,
+        id: i64,
+        at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), DBError>;
+
+    /// Delete the API token with this name. Returns whether it existed.
+    async fn delete_api_token(&self, name: &str) -> Result<bool, DBError>;
+
+    /// Queue a webhook delivery, to be attempted right away
+    async fn insert_webhook_delivery(
+        &self,
+        target: &str,
+        event: &str,
+        payload: &str,
+    ) -> Result<i64, DBError>;
+
+    /// Claim at most `limit` webhook deliveries that are due and not claimed at `now`, oldest first.
+    ///
+    /// They are claimed until `claimed_until`, so no other instance sharing the db attempts them
+    /// before that.
+    async fn claim_due_webhook_deliveries(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        claimed_until: chrono::DateTime<chrono::Utc>,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>, DBError>;
+
+    /// Remember a failed attempt and when to try again, releasing the claim
+    async fn record_failed_webhook_delivery(
+        &self,
+        id: i64,
+        error: &str,
+        next_attempt_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), DBError>;
+
+    /// Remove a delivery from the queue, after it succeeded or was given up
+    async fn delete_webhook_delivery(&self, id: i64) -> Result<(), DBError>;
+
+    /// Get at most `limit` events matching `filter` from the booking history, newest first
+    async fn get_booking_events(
+        &self,
+        filter: &BookingEventFilter,
+        limit: i64,
+    ) -> Result<Vec<BookingEvent>, DBError>;
+
+    /// Remove events that occurred before `reference` (naive UTC) from the booking history
+    async fn prune_old_booking_events(&self, reference: NaiveDateTime) -> Result<u64, DBError>;
+
+    /// Record a run of the scheduled `job` at `now`, unless any instance ran it at or after `since`.
+    ///
+    /// Returns whether the run was recorded. Of several instances sharing the db, only one gets
+    /// `true` and should run the job.
+    async fn claim_job_run(
+        &self,
+        job: &str,
+        since: chrono::DateTime<chrono::Utc>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DBError>;
+
+    /// Read everything [`DbExport`] holds in one transaction
+    async fn export(&self) -> Result<DbExport, DBError>;
+
+    /// Replace bookings, local bookings, the archive and the history with `data`, keeping all IDs.
+    ///
+    /// Conflicts are removed; the next sync finds them again.
+    async fn import(&self, data: &DbExport) -> Result<(), DBError>;
+
+    /// Write a consistent copy of the whole db to the new file `path`
+    async fn snapshot(&self, path: &Path) -> Result<(), DBError>;
+
+    /// Make the conflicts stored for [start, end] in the rooms `resource_ids` match `detected`.
+    ///
+    /// Conflicts that are no longer detected are removed, conflicts that have changed are replaced.
+    /// Conflicts in other rooms are left alone. Returns the conflicts that were not known before.
+    async fn sync_conflicts<'a>(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+        resource_ids: &[i64],
+        detected: &'a [Conflict],
+    ) -> Result<Vec<&'a Conflict>, DBError> {
+        let known = self
+            .get_conflicts_in_timeframe(start, end)
+            .await?
+            .into_iter()
+            .filter(|c| resource_ids.contains(&c.resource_id))
+            .collect::<Vec<_>>();
+        for conflict in known.iter().filter(|c| !detected.contains(c)) {
+            self.delete_conflict(conflict).await?;
+        }
+        let mut new_conflicts = vec![];
+        for conflict in detected.iter().filter(|c| !known.contains(c)) {
+            // the overlap may have moved, in which case an entry for this pair already exists
+            self.delete_conflict(conflict).await?;
+            self.insert_conflict(conflict).await?;
+            if !known.iter().any(|k| {
+                k.resource_id == conflict.resource_id
+                    && k.first_source == conflict.first_source
+                    && k.first_booking_id == conflict.first_booking_id
+                    && k.second_source == conflict.second_source
+                    && k.second_booking_id == conflict.second_booking_id
+            }) {
+                new_conflicts.push(conflict);
+            }
+        }
+        Ok(new_conflicts)
+    }
+
+    /// Record a run of `job`, which runs every `interval`, at `now` unless another instance ran it
+    /// less than `interval` ago. Returns whether this instance should run it.
+    async fn claim_periodic_job_run(
+        &self,
+        job: &str,
+        interval: chrono::TimeDelta,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DBError> {
+        // instances wake up at slightly different times, so the run that is almost one interval
+        // ago does not count
+        let since = now
+            .checked_sub_signed(interval - interval / 10)
+            .unwrap_or(chrono::DateTime::<chrono::Utc>::MIN_UTC);
+        self.claim_job_run(job, since, now).await
+    }
+
+    /// Get all bookings from CT and all local bookings which intersect the interval [start, end]
+    ///
+    /// This is what all views should show. Order by start-date.
+    async fn get_visible_bookings_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Booking>, DBError> {
+        let mut bookings = self.get_bookings_in_timeframe(start, end).await?;
+        bookings.extend(
+            self.get_local_bookings_in_timeframe(start, end)
+                .await?
+                .into_iter()
+                .map(LocalBooking::into_booking),
+        );
+        bookings.sort_by_key(|b| b.start_time);
+        Ok(bookings)
+    }
+
+    /// Get all bookings that used a room in the interval [start, end]: archived bookings, bookings
+    /// from CT and local bookings, but no blocks. Order by start-date.
+    async fn get_used_bookings_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Booking>, DBError> {
+        let mut bookings = self.get_archived_bookings_in_timeframe(start, end).await?;
+        let current = self
+            .get_bookings_in_timeframe(start, end)
+            .await?
+            .into_iter()
+            .chain(
+                self.get_local_bookings_in_timeframe(start, end)
+                    .await?
+                    .into_iter()
+                    .filter(|b| b.kind == LocalBookingKind::Booking)
+                    .map(LocalBooking::into_booking),
+            );
+        for booking in current.collect::<Vec<_>>() {
+            let archived = bookings
+                .iter()
+                .any(|b| b.source == booking.source && b.booking_id == booking.booking_id);
+            if !archived {
+                bookings.push(booking);
+            }
+        }
+        bookings.sort_by_key(|b| b.start_time);
+        Ok(bookings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::{DateTime, NaiveDate, TimeDelta, Timelike};
+
+    use crate::{announcements::Priority, auth::Scope, history::BookingEventKind, BookingOrigin};
+
+    /// Run each check against SQLite, and against PostgreSQL with
+    /// `DATABASE_URL=postgres://... cargo test -- --include-ignored`
+    macro_rules! storage_tests {
+        ($($name:ident: $fixture:literal,)*) => {
+            mod sqlite {
+                use sqlx::SqlitePool;
+
+                use crate::db::SqliteStorage;
+
+                $(
+                    #[sqlx::test(fixtures(path = "../fixtures", scripts($fixture)))]
+                    async fn $name(pool: SqlitePool) {
+                        super::$name(&SqliteStorage::new(pool)).await;
+                    }
+                )*
+
+                #[sqlx::test(fixtures(path = "../fixtures", scripts("002_empty")))]
+                async fn history_is_append_only(pool: SqlitePool) {
+                    super::insert_event(&SqliteStorage::new(pool.clone())).await;
+                    assert!(sqlx::query("UPDATE booking_events SET title = 'x';")
+                        .execute(&pool)
+                        .await
+                        .is_err());
+                }
+            }
+
+            mod postgres {
+                use sqlx::PgPool;
+
+                use crate::db::PostgresStorage;
+
+                $(
+                    #[sqlx::test(
+                        migrations = "migrations/postgres",
+                        fixtures(path = "../fixtures", scripts("000_postgres_utc", $fixture))
+                    )]
+                    #[ignore = "needs DATABASE_URL pointing to PostgreSQL"]
+                    async fn $name(pool: PgPool) {
+                        super::$name(&PostgresStorage::new(pool)).await;
+                    }
+                )*
+
+                #[sqlx::test(
+                    migrations = "migrations/postgres",
+                    fixtures(path = "../fixtures", scripts("000_postgres_utc", "002_empty"))
+                )]
+                #[ignore = "needs DATABASE_URL pointing to PostgreSQL"]
+                async fn history_is_append_only(pool: PgPool) {
+                    super::insert_event(&PostgresStorage::new(pool.clone())).await;
+                    assert!(sqlx::query("UPDATE booking_events SET title = 'x';")
+                        .execute(&pool)
+                        .await
+                        .is_err());
+                }
+            }
+        };
+    }
+
+    storage_tests! {
+        select_all_bookings: "001_good_data",
+        select_bookings_in_timeframe: "001_good_data",
+        delete_single_booking: "001_good_data",
+        delete_multiple_bookings: "001_good_data",
+        test_update_booking: "001_good_data",
+        test_insert_booking: "001_good_data",
+        test_booking_changes: "001_good_data",
+        test_sources: "001_good_data",
+        test_pruning: "002_empty",
+        test_sync_conflicts: "002_empty",
+        test_local_bookings: "001_good_data",
+        test_announcements: "002_empty",
+        test_admin_sessions: "002_empty",
+        test_statistics: "001_good_data",
+        test_api_tokens: "002_empty",
+        test_booking_events: "002_empty",
+        test_export_import: "001_good_data",
+        test_webhook_claims: "002_empty",
+        test_job_runs: "002_empty",
+    }
+
+    /// Rows written by older versions or by hand are rewritten to [`sqlite::TIME_FORMAT`]
+    #[sqlx::test(fixtures(path = "../fixtures", scripts("002_empty")))]
+    async fn legacy_timestamps_are_normalized(pool: sqlx::SqlitePool) {
+        let migrator = sqlx::migrate!();
+        migrator.undo(&pool, 11).await.unwrap();
+        sqlx::query(
+            "INSERT INTO bookings (booking_id, title, resource_id, start_time, end_time) VALUES \
+            (1, 'title', 10, '2021-03-26T15:30:00+00:00', '2021-03-26T19:00:00+02:00'), \
+            (2, 'title', 10, '2021-03-26 16:00:00', '2021-03-26T17:00:00.250Z'), \
+            (3, 'title', 11, 1616774400, '2021-03-26T18:00:00');",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO booking_events \
+            (occurred_at, kind, booking_id, resource_id, title, start_time, end_time) VALUES \
+            ('2021-03-25 12:00:00.5', 'inserted', 1, 10, 'title', \
+            '2021-03-26T16:30:00+01:00', '2021-03-26T17:00:00Z');",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        migrator.run(&pool).await.unwrap();
+
+        let times: Vec<(String, String)> =
+            sqlx::query_as("SELECT start_time, end_time FROM bookings ORDER BY booking_id;")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            times,
+            [
+                ("2021-03-26T15:30:00Z", "2021-03-26T17:00:00Z"),
+                ("2021-03-26T16:00:00Z", "2021-03-26T17:00:00Z"),
+                ("2021-03-26T16:00:00Z", "2021-03-26T18:00:00Z"),
+            ]
+            .map(|(s, e)| (s.to_owned(), e.to_owned()))
+        );
+        let event: (String, String, String) =
+            sqlx::query_as("SELECT occurred_at, start_time, end_time FROM booking_events;")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            event,
+            (
+                "2021-03-25T12:00:00Z".to_owned(),
+                "2021-03-26T15:30:00Z".to_owned(),
+                "2021-03-26T17:00:00Z".to_owned()
+            )
+        );
+        // the trigger is back in place
+        assert!(sqlx::query("UPDATE booking_events SET title = 'x';")
+            .execute(&pool)
+            .await
+            .is_err());
+
+        // these windows were answered wrongly by string comparison before
+        let db = SqliteStorage::new(pool);
+        let at = |time: &str| NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S").unwrap();
+        let ids = |bookings: Vec<Booking>| {
+            bookings
+                .into_iter()
+                .map(|b| b.booking_id)
+                .collect::<Vec<_>>()
+        };
+        let late = db
+            .get_bookings_in_timeframe(at("2021-03-26T17:30:00"), at("2021-03-26T17:45:00"))
+            .await
+            .unwrap();
+        assert_eq!(ids(late), [3]);
+        let early = db
+            .get_bookings_in_timeframe(at("2021-03-26T15:00:00"), at("2021-03-26T15:45:00"))
+            .await
+            .unwrap();
+        assert_eq!(ids(early), [1]);
+    }
+
+    async fn test_export_import(db: &dyn Storage) {
+        let new_booking = NewLocalBooking {
+            title: "Wasserschaden".to_owned(),
+            resource_id: 10,
+            start_time: DateTime::parse_from_rfc3339("2021-03-29T08:00:00+00:00")
+                .unwrap()
+                .into(),
+            end_time: DateTime::parse_from_rfc3339("2021-03-29T16:00:00+00:00")
+                .unwrap()
+                .into(),
+            kind: LocalBookingKind::Block,
+            note: Some("Rohrbruch".to_owned()),
+        };
+        let local_id = db.insert_local_booking(&new_booking).await.unwrap();
+        insert_event(db).await;
+        // archives booking 123
+        let reference = NaiveDate::from_ymd_opt(2021, 3, 27)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        db.prune_old_bookings(Some("run"), reference).await.unwrap();
+
+        let export = db.export().await.unwrap();
+        let ids = |bookings: &[Booking]| bookings.iter().map(|b| b.booking_id).collect::<Vec<_>>();
+        assert_eq!(ids(&export.bookings), [125]);
+        assert_eq!(ids(&export.archived_bookings), [123]);
+        assert_eq!(export.local_bookings.len(), 1);
+        assert_eq!(export.local_bookings[0].note.as_deref(), Some("Rohrbruch"));
+        assert_eq!(export.booking_events.len(), 2);
+        export.validate().unwrap();
+
+        delete_bookings(db, "churchtools", &[125]).await.unwrap();
+        db.delete_local_booking(local_id).await.unwrap();
+        db.prune_old_booking_events(reference + TimeDelta::days(365 * 100))
+            .await
+            .unwrap();
+        db.import(&export).await.unwrap();
+        let again = db.export().await.unwrap();
+        assert_eq!(again.bookings, export.bookings);
+        assert_eq!(again.local_bookings, export.local_bookings);
+        assert_eq!(again.archived_bookings, export.archived_bookings);
+        assert_eq!(again.booking_events, export.booking_events);
+
+        // new rows do not collide with the imported IDs
+        assert!(db.insert_local_booking(&new_booking).await.unwrap() > local_id);
+    }
+
+    /// A snapshot can be read back like an export
+    #[sqlx::test(fixtures(path = "../fixtures", scripts("001_good_data")))]
+    async fn sqlite_snapshot(pool: sqlx::SqlitePool) {
+        let db = SqliteStorage::new(pool);
+        insert_event(&db).await;
+        let path = std::env::temp_dir().join(format!("snapshot-{}.sqlite", uuid::Uuid::new_v4()));
+        db.snapshot(&path).await.unwrap();
+        // VACUUM INTO does not overwrite
+        assert!(matches!(
+            db.snapshot(&path).await,
+            Err(DBError::Snapshot(_))
+        ));
+        let content = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let snapshot = crate::backup::parse(&content).await.unwrap();
+        let export = db.export().await.unwrap();
+        assert_eq!(snapshot.bookings, export.bookings);
+        assert_eq!(snapshot.booking_events, export.booking_events);
+    }
+
+    async fn insert_bookings(db: &dyn Storage, bookings: &[&Booking]) -> Result<(), DBError> {
+        db.apply_booking_changes(&BookingChanges {
+            inserted: bookings.to_vec(),
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn update_bookings(db: &dyn Storage, bookings: &[&Booking]) -> Result<(), DBError> {
+        db.apply_booking_changes(&BookingChanges {
+            updated: bookings.to_vec(),
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn delete_bookings(
+        db: &dyn Storage,
+        source: &str,
+        booking_ids: &[i64],
+    ) -> Result<(), DBError> {
+        db.apply_booking_changes(&BookingChanges {
+            source,
+            deleted: booking_ids.to_vec(),
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn all_bookings(db: &dyn Storage) -> Result<Vec<Booking>, DBError> {
+        let at = |year| {
+            NaiveDate::from_ymd_opt(year, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        db.get_bookings_in_timeframe(at(1970), at(2100)).await
+    }
+
+    async fn insert_event(db: &dyn Storage) {
+        let booking = Booking {
+            title: "Chorprobe".to_owned(),
+            booking_id: 7,
+            source: "churchtools".to_owned(),
+            resource_id: 12,
+            start_time: DateTime::parse_from_rfc3339("2024-10-16T18:00:00+00:00")
+                .unwrap()
+                .into(),
+            end_time: DateTime::parse_from_rfc3339("2024-10-16T20:00:00+00:00")
+                .unwrap()
+                .into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::ChurchTools,
+        };
+        db.apply_booking_changes(&BookingChanges {
+            history: vec![NewBookingEvent::inserted(&booking)],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    }
+
+    async fn select_all_bookings(db: &dyn Storage) {
+        let bookings = all_bookings(db).await.unwrap();
+        assert_eq!(bookings.len(), 2);
+        assert_eq!(
+            bookings[0],
+            Booking {
+                title: "title".to_owned(),
+                booking_id: 123,
+                source: "churchtools".to_owned(),
+                resource_id: 10,
+                start_time: DateTime::parse_from_rfc3339("2021-03-26T15:30:00+00:00")
+                    .unwrap()
+                    .into(),
+                end_time: DateTime::parse_from_rfc3339("2021-03-26T17:00:00+00:00")
+                    .unwrap()
+                    .into(),
+                setup_minutes: None,
+                teardown_minutes: None,
+                origin: BookingOrigin::ChurchTools,
+            }
+        );
+        assert_eq!(
+            bookings[1],
+            Booking {
+                title: "title".to_owned(),
+                booking_id: 125,
+                source: "churchtools".to_owned(),
+                resource_id: 11,
+                start_time: DateTime::parse_from_rfc3339("2021-03-28T15:30:00+00:00")
+                    .unwrap()
+                    .into(),
+                end_time: DateTime::parse_from_rfc3339("2021-03-28T17:00:00+00:00")
+                    .unwrap()
+                    .into(),
+                setup_minutes: None,
+                teardown_minutes: None,
+                origin: BookingOrigin::ChurchTools,
+            }
+        );
+    }
+
+    async fn select_bookings_in_timeframe(db: &dyn Storage) {
+        let start = NaiveDate::from_ymd_opt(2021, 3, 26)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let end = NaiveDate::from_ymd_opt(2021, 3, 26)
+            .unwrap()
+            .and_hms_opt(23, 59, 59)
+            .unwrap();
+        let bookings = db.get_bookings_in_timeframe(start, end).await.unwrap();
+        assert_eq!(bookings.len(), 1);
+        assert_eq!(
+            bookings[0],
+            Booking {
+                title: "title".to_owned(),
+                booking_id: 123,
+                source: "churchtools".to_owned(),
+                resource_id: 10,
+                start_time: DateTime::parse_from_rfc3339("2021-03-26T15:30:00+00:00")
+                    .unwrap()
+                    .into(),
+                end_time: DateTime::parse_from_rfc3339("2021-03-26T17:00:00+00:00")
+                    .unwrap()
+                    .into(),
+                setup_minutes: None,
+                teardown_minutes: None,
+                origin: BookingOrigin::ChurchTools,
+            }
+        );
+    }
+
+    async fn delete_single_booking(db: &dyn Storage) {
+        delete_bookings(db, "churchtools", &[123]).await.unwrap();
+
+        let start = NaiveDate::from_ymd_opt(2021, 3, 26)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let end = NaiveDate::from_ymd_opt(2021, 3, 26)
+            .unwrap()
+            .and_hms_opt(23, 59, 59)
+            .unwrap();
+        let bookings = db.get_bookings_in_timeframe(start, end).await.unwrap();
+        assert_eq!(bookings.len(), 0);
+    }
+
+    async fn delete_multiple_bookings(db: &dyn Storage) {
+        delete_bookings(db, "churchtools", &[123, 125])
+            .await
+            .unwrap();
+
+        let bookings = all_bookings(db).await.unwrap();
+        assert_eq!(bookings.len(), 0);
+    }
+
+    async fn test_update_booking(db: &dyn Storage) {
+        let new_booking = Booking {
+            title: "title".to_owned(),
+            booking_id: 123,
+            source: "churchtools".to_owned(),
+            resource_id: 10,
+            start_time: DateTime::parse_from_rfc3339("2021-04-26T15:30:00+00:00")
+                .unwrap()
+                .into(),
+            end_time: DateTime::parse_from_rfc3339("2021-04-26T17:00:00+00:00")
+                .unwrap()
+                .into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::ChurchTools,
+        };
+        update_bookings(db, &[&new_booking]).await.unwrap();
+        let start = NaiveDate::from_ymd_opt(2021, 4, 20)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let end = NaiveDate::from_ymd_opt(2021, 5, 30)
+            .unwrap()
+            .and_hms_opt(23, 59, 59)
+            .unwrap();
+        let bookings = db.get_bookings_in_timeframe(start, end).await.unwrap();
+        assert_eq!(bookings.len(), 1);
+        assert_eq!(bookings[0], new_booking);
+    }
+
+    async fn test_insert_booking(db: &dyn Storage) {
+        let new_booking = Booking {
+            title: "title".to_owned(),
+            booking_id: 12341234,
+            source: "churchtools".to_owned(),
+            resource_id: 21,
+            start_time: DateTime::parse_from_rfc3339("2019-04-26T14:28:00+00:00")
+                .unwrap()
+                .into(),
+            end_time: DateTime::parse_from_rfc3339("2019-04-26T18:00:00+00:00")
+                .unwrap()
+                .into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::ChurchTools,
+        };
+        insert_bookings(db, &[&new_booking]).await.unwrap();
+        let start = NaiveDate::from_ymd_opt(2019, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let end = NaiveDate::from_ymd_opt(2019, 12, 31)
+            .unwrap()
+            .and_hms_opt(23, 59, 59)
+            .unwrap();
+        let bookings = db.get_bookings_in_timeframe(start, end).await.unwrap();
+        assert_eq!(bookings.len(), 1);
+        assert_eq!(bookings[0], new_booking);
+    }
+
+    async fn test_booking_changes(db: &dyn Storage) {
+        let new_booking = Booking {
+            title: "title".to_owned(),
+            booking_id: 12341234,
+            source: "churchtools".to_owned(),
+            resource_id: 21,
+            start_time: DateTime::parse_from_rfc3339("2019-04-26T14:28:00+00:00")
+                .unwrap()
+                .into(),
+            end_time: DateTime::parse_from_rfc3339("2019-04-26T18:00:00+00:00")
+                .unwrap()
+                .into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::ChurchTools,
+        };
+        let delivery = || NewWebhookDelivery {
+            target: "heating".to_owned(),
+            event: "booking_inserted",
+            payload: "{}".to_owned(),
+        };
+        let before = all_bookings(db).await.unwrap();
+        let due = || due_webhook_deliveries(db, chrono::Utc::now() + TimeDelta::days(1));
+        let filter = BookingEventFilter {
+            booking_id: Some(new_booking.booking_id),
+            ..Default::default()
+        };
+
+        // the second insert fails, so nothing is written
+        assert!(db
+            .apply_booking_changes(&BookingChanges {
+                source: "churchtools",
+                inserted: vec![&new_booking, &new_booking],
+                deleted: vec![123],
+                deliveries: vec![delivery()],
+                sync_run: Some("failed"),
+                history: vec![NewBookingEvent::inserted(&new_booking)],
+                ..Default::default()
+            })
+            .await
+            .is_err());
+        assert_eq!(all_bookings(db).await.unwrap(), before);
+        assert!(due().await.unwrap().is_empty());
+        assert!(db.get_booking_events(&filter, 10).await.unwrap().is_empty());
+
+        db.apply_booking_changes(&BookingChanges {
+            source: "churchtools",
+            inserted: vec![&new_booking],
+            deleted: vec![123],
+            deliveries: vec![delivery()],
+            sync_run: Some("run"),
+            history: vec![NewBookingEvent::inserted(&new_booking)],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let events = db.get_booking_events(&filter, 10).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, BookingEventKind::Inserted);
+        assert_eq!(events[0].sync_run.as_deref(), Some("run"));
+        let bookings = all_bookings(db).await.unwrap();
+        assert_eq!(bookings.len(), before.len());
+        assert!(bookings.contains(&new_booking));
+        assert!(!bookings.iter().any(|b| b.booking_id == 123));
+        let due = due().await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].target, "heating");
+    }
+
+    async fn test_pruning(db: &dyn Storage) {
+        // insert booking for today and tomorrow
+        let now = chrono::Utc::now().with_nanosecond(0).unwrap();
+        let in_an_hour = now + TimeDelta::hours(1);
+        let booking_today = Booking {
+            title: "title".to_owned(),
+            resource_id: 31,
+            booking_id: 9999,
+            source: "churchtools".to_owned(),
+            start_time: now,
+            end_time: in_an_hour,
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::ChurchTools,
+        };
+        let yesterday = now - TimeDelta::days(1);
+        let yesterday_plus_one_hour = yesterday + TimeDelta::hours(1);
+        let booking_yesterday = Booking {
+            title: "title".to_owned(),
+            resource_id: 31,
+            booking_id: 8888,
+            source: "churchtools".to_owned(),
+            start_time: yesterday,
+            end_time: yesterday_plus_one_hour,
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::ChurchTools,
+        };
+        insert_bookings(db, &[&booking_yesterday, &booking_today])
+            .await
+            .unwrap();
+        // prune
+        let rows_changed = db
+            .prune_old_bookings(Some("run"), now.naive_utc())
+            .await
+            .unwrap();
+        assert_eq!(rows_changed, 1);
+        // the pruned booking is in the history
+        let events = db
+            .get_booking_events(&BookingEventFilter::default(), 10)
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, BookingEventKind::Pruned);
+        assert_eq!(events[0].booking_id, booking_yesterday.booking_id);
+        assert_eq!(events[0].sync_run.as_deref(), Some("run"));
+        // and still counts as a use of the room
+        let used = db
+            .get_used_bookings_in_timeframe(
+                (yesterday - TimeDelta::hours(1)).naive_utc(),
+                (now + TimeDelta::days(2)).naive_utc(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(used, vec![booking_yesterday, booking_today.clone()]);
+        // check that only the one from tomorrow survives
+        let bookings = all_bookings(db).await.unwrap();
+        assert_eq!(bookings.len(), 1);
+        assert_eq!(bookings[0], booking_today);
+    }
+
+    async fn test_sources(db: &dyn Storage) {
+        // the same booking ID in another CT instance
+        let mut other = all_bookings(db).await.unwrap()[0].clone();
+        other.source = "nachbargemeinde".to_owned();
+        other.resource_id = 1010;
+        insert_bookings(db, &[&other]).await.unwrap();
+        other.title = "changed".to_owned();
+        update_bookings(db, &[&other]).await.unwrap();
+        let bookings = all_bookings(db).await.unwrap();
+        assert_eq!(bookings.len(), 3);
+        assert_eq!(bookings.iter().filter(|b| b.title == "title").count(), 2);
+
+        delete_bookings(db, "churchtools", &[other.booking_id])
+            .await
+            .unwrap();
+        let bookings = all_bookings(db).await.unwrap();
+        assert_eq!(bookings.len(), 2);
+        assert!(bookings.contains(&other));
+
+        db.prune_old_bookings(None, NaiveDate::from_ymd_opt(2022, 1, 1).unwrap().into())
+            .await
+            .unwrap();
+        let start = NaiveDate::from_ymd_opt(2021, 1, 1).unwrap().into();
+        let end = NaiveDate::from_ymd_opt(2022, 1, 1).unwrap().into();
+        let archived = db
+            .get_archived_bookings_in_timeframe(start, end)
+            .await
+            .unwrap();
+        assert_eq!(archived.len(), 2);
+        assert!(archived.contains(&other));
+    }
+
+    async fn test_sync_conflicts(db: &dyn Storage) {
+        let first = Booking {
+            title: "first".to_owned(),
+            resource_id: 31,
+            booking_id: 1,
+            source: "churchtools".to_owned(),
+            start_time: DateTime::parse_from_rfc3339("2021-03-26T15:00:00+00:00")
+                .unwrap()
+                .into(),
+            end_time: DateTime::parse_from_rfc3339("2021-03-26T17:00:00+00:00")
+                .unwrap()
+                .into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::ChurchTools,
+        };
+        let second = Booking {
+            title: "second".to_owned(),
+            resource_id: 31,
+            booking_id: 2,
+            source: "churchtools".to_owned(),
+            start_time: DateTime::parse_from_rfc3339("2021-03-26T16:00:00+00:00")
+                .unwrap()
+                .into(),
+            end_time: DateTime::parse_from_rfc3339("2021-03-26T18:00:00+00:00")
+                .unwrap()
+                .into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::ChurchTools,
+        };
+        insert_bookings(db, &[&first, &second]).await.unwrap();
+        let start = NaiveDate::from_ymd_opt(2021, 3, 26)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let end = NaiveDate::from_ymd_opt(2021, 3, 26)
+            .unwrap()
+            .and_hms_opt(23, 59, 59)
+            .unwrap();
+
+        let detected = crate::conflicts::detect_conflicts(&[first.clone(), second.clone()]);
+        let new_conflicts = db
+            .sync_conflicts(start, end, &[31], &detected)
+            .await
+            .unwrap();
+        assert_eq!(new_conflicts.len(), 1);
+        // a second sync does not report the same conflict again
+        let new_conflicts = db
+            .sync_conflicts(start, end, &[31], &detected)
+            .await
+            .unwrap();
+        assert!(new_conflicts.is_empty());
+        // nor does the sync of a source without this room remove it
+        db.sync_conflicts(start, end, &[32], &[]).await.unwrap();
+
+        let reports = db.get_conflict_reports().await.unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].first_title, "first");
+        assert_eq!(reports[0].second_title, "second");
+        assert_eq!(reports[0].conflict, detected[0]);
+
+        // resolved conflicts are removed
+        db.sync_conflicts(start, end, &[31], &[]).await.unwrap();
+        assert!(db.get_conflict_reports().await.unwrap().is_empty());
+
+        // a calendar booking with the same ID as a CT booking in the same room
+        let calendar = Booking {
+            title: "calendar".to_owned(),
+            source: "gemeindehaus".to_owned(),
+            ..first.clone()
+        };
+        insert_bookings(db, &[&calendar]).await.unwrap();
+        let detected =
+            crate::conflicts::detect_conflicts(&[first.clone(), second.clone(), calendar]);
+        assert_eq!(detected.len(), 3);
+        let new_conflicts = db
+            .sync_conflicts(start, end, &[31], &detected)
+            .await
+            .unwrap();
+        assert_eq!(new_conflicts.len(), 3);
+        let reports = db.get_conflict_reports().await.unwrap();
+        assert_eq!(reports.len(), 3);
+        let same_id = reports
+            .iter()
+            .find(|r| r.conflict.first_booking_id == r.conflict.second_booking_id)
+            .unwrap();
+        assert_eq!(same_id.conflict.first_source, "churchtools");
+        assert_eq!(same_id.first_title, "first");
+        assert_eq!(same_id.conflict.second_source, "gemeindehaus");
+        assert_eq!(same_id.second_title, "calendar");
+    }
+
+    async fn test_local_bookings(db: &dyn Storage) {
+        let new_booking = NewLocalBooking {
+            title: "Wasserschaden".to_owned(),
+            resource_id: 10,
+            start_time: DateTime::parse_from_rfc3339("2021-03-26T08:00:00+00:00")
+                .unwrap()
+                .into(),
+            end_time: DateTime::parse_from_rfc3339("2021-03-26T16:00:00+00:00")
+                .unwrap()
+                .into(),
+            kind: LocalBookingKind::Block,
+            note: None,
+        };
+        let id = db.insert_local_booking(&new_booking).await.unwrap();
+
+        let start = NaiveDate::from_ymd_opt(2021, 3, 26)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let end = NaiveDate::from_ymd_opt(2021, 3, 26)
+            .unwrap()
+            .and_hms_opt(23, 59, 59)
+            .unwrap();
+        // local bookings are not part of the CT bookings
+        assert_eq!(
+            db.get_bookings_in_timeframe(start, end)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        let visible = db
+            .get_visible_bookings_in_timeframe(start, end)
+            .await
+            .unwrap();
+        assert_eq!(visible.len(), 2);
+        assert_eq!(visible[0].booking_id, id);
+        assert_eq!(visible[0].title, "Gesperrt: Wasserschaden");
+        assert_eq!(visible[0].origin, BookingOrigin::Local);
+        // its ID may be that of a synced booking as well
+        assert_eq!(visible[0].source, crate::local_bookings::LOCAL_SOURCE);
+        assert_eq!(visible[1].booking_id, 123);
+
+        assert!(db.delete_local_booking(id).await.unwrap());
+        assert!(!db.delete_local_booking(id).await.unwrap());
+    }
+
+    async fn test_announcements(db: &dyn Storage) {
+        let announcement = |message: &str, start: &str, end: &str, priority| NewAnnouncement {
+            message: message.to_owned(),
+            start_time: DateTime::parse_from_rfc3339(start).unwrap().into(),
+            end_time: DateTime::parse_from_rfc3339(end).unwrap().into(),
+            priority,
+            rooms: vec![12],
+            show_on_landing: true,
+        };
+        let info = db
+            .insert_announcement(&announcement(
+                "Gottesdienst heute im Gemeindehaus",
+                "2024-10-20T06:00:00+00:00",
+                "2024-10-20T12:00:00+00:00",
+                Priority::Info,
+            ))
+            .await
+            .unwrap();
+        let emergency = db
+            .insert_announcement(&announcement(
+                "Bitte das Gebäude verlassen",
+                "2024-10-20T09:00:00+00:00",
+                "2024-10-20T10:00:00+00:00",
+                Priority::Emergency,
+            ))
+            .await
+            .unwrap();
+
+        let at = |time: &str| NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S").unwrap();
+        let active = db
+            .get_announcements_in_timeframe(at("2024-10-20T09:30:00"), at("2024-10-20T09:30:00"))
+            .await
+            .unwrap();
+        assert_eq!(
+            active.iter().map(|a| a.id).collect::<Vec<_>>(),
+            vec![emergency, info]
+        );
+        assert_eq!(active[0].rooms, vec![12]);
+        let active = db
+            .get_announcements_in_timeframe(at("2024-10-20T11:00:00"), at("2024-10-20T11:00:00"))
+            .await
+            .unwrap();
+        assert_eq!(active.iter().map(|a| a.id).collect::<Vec<_>>(), vec![info]);
+
+        assert!(db.delete_announcement(info).await.unwrap());
+        assert!(!db.delete_announcement(info).await.unwrap());
+    }
+
+    async fn test_admin_sessions(db: &dyn Storage) {
+        let session = AdminSession {
+            id: "abc".to_owned(),
+            user_name: "kuester".to_owned(),
+            csrf_token: "def".to_owned(),
+            expires_at: chrono::Utc::now() + chrono::TimeDelta::hours(1),
+        };
+        db.insert_admin_session(&session).await.unwrap();
+        let expired = AdminSession {
+            id: "old".to_owned(),
+            expires_at: chrono::Utc::now() - chrono::TimeDelta::hours(1),
+            ..session.clone()
+        };
+        db.insert_admin_session(&expired).await.unwrap();
+
+        let found = db.get_admin_session("abc").await.unwrap().unwrap();
+        assert_eq!(found.user_name, "kuester");
+        assert_eq!(found.csrf_token, "def");
+        assert_eq!(db.get_admin_session("old").await.unwrap(), None);
+        assert_eq!(db.get_statistics().await.unwrap().admin_sessions, 2);
+        assert_eq!(db.prune_expired_admin_sessions().await.unwrap(), 1);
+
+        db.delete_admin_session("abc").await.unwrap();
+        assert_eq!(db.get_admin_session("abc").await.unwrap(), None);
+    }
+
+    async fn test_statistics(db: &dyn Storage) {
+        let stats = db.get_statistics().await.unwrap();
+        assert_eq!(stats.bookings, 2);
+        assert_eq!(stats.local_bookings, 0);
+        assert!(stats.size_bytes > 0);
+    }
+
+    async fn test_api_tokens(db: &dyn Storage) {
+        let id = db
+            .insert_api_token(
+                "chatbot",
+                "abc",
+                &[Scope::ReadBookings, Scope::ReadRooms],
+                None,
+            )
+            .await
+            .unwrap();
+        // names are unique
+        assert!(db
+            .insert_api_token("chatbot", "def", &[], None)
+            .await
+            .is_err());
+
+        let token = db.get_api_token_by_hash("abc").await.unwrap().unwrap();
+        assert_eq!(token.id, id);
+        assert_eq!(token.scopes, vec![Scope::ReadBookings, Scope::ReadRooms]);
+        assert_eq!(token.last_used_at, None);
+        assert_eq!(db.get_api_token_by_hash("def").await.unwrap(), None);
+
+        let used = DateTime::parse_from_rfc3339("2024-10-20T09:00:00+00:00")
+            .unwrap()
+            .into();
+        db.touch_api_token(id, used).await.unwrap();
+        let tokens = db.get_api_tokens().await.unwrap();
+        assert_eq!(tokens[0].last_used_at, Some(used));
+
+        assert!(db.delete_api_token("chatbot").await.unwrap());
+        assert!(db.get_api_tokens().await.unwrap().is_empty());
+    }
+
+    async fn test_booking_events(db: &dyn Storage) {
+        let previous = Booking {
+            title: "Chorprobe".to_owned(),
+            booking_id: 7,
+            source: "churchtools".to_owned(),
+            resource_id: 12,
+            start_time: DateTime::parse_from_rfc3339("2024-10-16T18:00:00+00:00")
+                .unwrap()
+                .into(),
+            end_time: DateTime::parse_from_rfc3339("2024-10-16T20:00:00+00:00")
+                .unwrap()
+                .into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::ChurchTools,
+        };
+        let booking = Booking {
+            resource_id: 13,
+            ..previous.clone()
+        };
+        let other = Booking {
+            booking_id: 8,
+            ..previous.clone()
+        };
+        let before = chrono::Utc::now() - TimeDelta::seconds(1);
+        db.apply_booking_changes(&BookingChanges {
+            sync_run: Some("first"),
+            history: vec![
+                NewBookingEvent::inserted(&previous),
+                NewBookingEvent::inserted(&other),
+            ],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        db.apply_booking_changes(&BookingChanges {
+            sync_run: Some("second"),
+            history: vec![
+                NewBookingEvent::updated(&previous, &booking),
+                NewBookingEvent::deleted(&booking),
+            ],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let filter = BookingEventFilter {
+            booking_id: Some(7),
+            ..Default::default()
+        };
+        let events = db.get_booking_events(&filter, 10).await.unwrap();
+        assert_eq!(
+            events.iter().map(|e| e.kind).collect::<Vec<_>>(),
+            vec![
+                BookingEventKind::Deleted,
+                BookingEventKind::Updated,
+                BookingEventKind::Inserted
+            ]
+        );
+        assert_eq!(events[1].diff.len(), 1);
+        assert_eq!(events[1].diff[0].field, "resource_id");
+        assert_eq!(events[1].resource_id, 13);
+        assert_eq!(events[1].start_time, booking.start_time);
+        assert_eq!(events[1].sync_run.as_deref(), Some("second"));
+
+        let filter = BookingEventFilter {
+            room: Some(12),
+            sync_run: Some("first".to_owned()),
+            since: Some(before),
+            ..Default::default()
+        };
+        assert_eq!(db.get_booking_events(&filter, 10).await.unwrap().len(), 2);
+        assert_eq!(db.get_booking_events(&filter, 1).await.unwrap().len(), 1);
+        let filter = BookingEventFilter {
+            until: Some(before),
+            ..Default::default()
+        };
+        assert!(db.get_booking_events(&filter, 10).await.unwrap().is_empty());
+
+        // events from today are kept
+        assert_eq!(
+            db.prune_old_booking_events(before.naive_utc())
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    /// The deliveries due at `now`, without claiming them
+    async fn due_webhook_deliveries(
+        db: &dyn Storage,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<WebhookDelivery>, DBError> {
+        db.claim_due_webhook_deliveries(now, now, i64::MAX).await
+    }
+
+    async fn test_webhook_claims(db: &dyn Storage) {
+        for event in ["first", "second", "third"] {
+            db.insert_webhook_delivery("heating", event, "{}")
+                .await
+                .unwrap();
+        }
+        // deliveries are due from their insertion on, which PostgreSQL stores in microseconds
+        let now = chrono::Utc::now();
+        let claimed_until = now + TimeDelta::minutes(10);
+
+        let claimed = db
+            .claim_due_webhook_deliveries(now, claimed_until, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            claimed.iter().map(|d| d.event.as_str()).collect::<Vec<_>>(),
+            vec!["first", "second"]
+        );
+        // another instance only gets what is left
+        let rest = db
+            .claim_due_webhook_deliveries(now, claimed_until, 10)
+            .await
+            .unwrap();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].event, "third");
+        assert!(db
+            .claim_due_webhook_deliveries(now, claimed_until, 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // a failed attempt releases the claim, the others are claimable once it runs out
+        db.record_failed_webhook_delivery(claimed[0].id, "timeout", now)
+            .await
+            .unwrap();
+        let retried = db
+            .claim_due_webhook_deliveries(now, claimed_until, 10)
+            .await
+            .unwrap();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].attempts, 1);
+        assert_eq!(
+            due_webhook_deliveries(db, claimed_until)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    async fn test_job_runs(db: &dyn Storage) {
+        let at = |minute| {
+            DateTime::parse_from_rfc3339(&format!("2024-10-16T18:{minute:02}:00+00:00"))
+                .unwrap()
+                .to_utc()
+        };
+        assert!(db.claim_job_run("digest", at(0), at(1)).await.unwrap());
+        // another instance ran it after 00 already
+        assert!(!db.claim_job_run("digest", at(0), at(2)).await.unwrap());
+        assert!(db.claim_job_run("backup", at(0), at(2)).await.unwrap());
+        // times are stored in whole seconds, so a run at `since` counts
+        assert!(!db.claim_job_run("digest", at(1), at(3)).await.unwrap());
+        assert!(db.claim_job_run("digest", at(2), at(4)).await.unwrap());
+
+        let interval = TimeDelta::minutes(10);
+        assert!(db
+            .claim_periodic_job_run("prune", interval, at(0))
+            .await
+            .unwrap());
+        assert!(!db
+            .claim_periodic_job_run("prune", interval, at(5))
+            .await
+            .unwrap());
+        // the next tick of the same instance may be a little early
+        assert!(db
+            .claim_periodic_job_run("prune", interval, at(9) + TimeDelta::seconds(59))
+            .await
+            .unwrap());
+    }
+}
@@ -0,0 +1,1127 @@
+//! The PostgreSQL backend, for several instances sharing one database
+//!
+//! Datetimes are stored as `TIMESTAMPTZ`. The queries are checked by the tests only, because the
+//! offline query data is generated from SQLite.
+
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sqlx::{FromRow, PgConnection, PgPool};
+
+use super::{BookingChanges, DBError, DbStatistics, Storage};
+use crate::{
+    announcements::{rooms_from_db_str, rooms_to_db_str, Announcement, NewAnnouncement, Priority},
+    auth::{scopes_from_db_str, scopes_to_db_str, AdminSession, ApiToken, Scope},
+    backup::{DbExport, EXPORT_VERSION},
+    conflicts::{Conflict, ConflictReport},
+    history::{BookingEvent, BookingEventFilter, BookingEventKind, FieldChange, NewBookingEvent},
+    local_bookings::{LocalBooking, LocalBookingKind, NewLocalBooking},
+    webhooks::WebhookDelivery,
+    Booking, BookingOrigin,
+};
+
+#[derive(FromRow)]
+struct BookingRow {
+    source: String,
+    booking_id: i64,
+    title: String,
+    resource_id: i64,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    setup_minutes: Option<i64>,
+    teardown_minutes: Option<i64>,
+}
+impl From<BookingRow> for Booking {
+    fn from(row: BookingRow) -> Self {
+        Self {
+            booking_id: row.booking_id,
+            source: row.source,
+            title: row.title,
+            resource_id: row.resource_id,
+            start_time: row.start_time,
+            end_time: row.end_time,
+            setup_minutes: row.setup_minutes,
+            teardown_minutes: row.teardown_minutes,
+            origin: BookingOrigin::ChurchTools,
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct ConflictRow {
+    resource_id: i64,
+    first_source: String,
+    first_booking_id: i64,
+    second_source: String,
+    second_booking_id: i64,
+    overlap_start: DateTime<Utc>,
+    overlap_end: DateTime<Utc>,
+}
+impl From<ConflictRow> for Conflict {
+    fn from(row: ConflictRow) -> Self {
+        Self {
+            resource_id: row.resource_id,
+            first_source: row.first_source,
+            first_booking_id: row.first_booking_id,
+            second_source: row.second_source,
+            second_booking_id: row.second_booking_id,
+            overlap_start: row.overlap_start,
+            overlap_end: row.overlap_end,
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct ConflictReportRow {
+    #[sqlx(flatten)]
+    conflict: ConflictRow,
+    detected_at: DateTime<Utc>,
+    first_title: String,
+    second_title: String,
+}
+impl From<ConflictReportRow> for ConflictReport {
+    fn from(row: ConflictReportRow) -> Self {
+        Self {
+            conflict: row.conflict.into(),
+            first_title: row.first_title,
+            second_title: row.second_title,
+            detected_at: row.detected_at,
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct ArchivedBookingRow {
+    origin: String,
+    #[sqlx(flatten)]
+    booking: BookingRow,
+}
+impl From<ArchivedBookingRow> for Booking {
+    fn from(row: ArchivedBookingRow) -> Self {
+        Self {
+            origin: BookingOrigin::from_db_str(&row.origin),
+            ..row.booking.into()
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct LocalBookingRow {
+    id: i64,
+    title: String,
+    resource_id: i64,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    kind: String,
+    note: Option<String>,
+    created_at: DateTime<Utc>,
+}
+impl From<LocalBookingRow> for LocalBooking {
+    fn from(row: LocalBookingRow) -> Self {
+        Self {
+            id: row.id,
+            title: row.title,
+            resource_id: row.resource_id,
+            start_time: row.start_time,
+            end_time: row.end_time,
+            kind: LocalBookingKind::from_db_str(&row.kind),
+            note: row.note,
+            created_at: row.created_at,
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct AnnouncementRow {
+    id: i64,
+    message: String,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    priority: String,
+    rooms: String,
+    show_on_landing: bool,
+    created_at: DateTime<Utc>,
+}
+impl From<AnnouncementRow> for Announcement {
+    fn from(row: AnnouncementRow) -> Self {
+        Self {
+            id: row.id,
+            message: row.message,
+            start_time: row.start_time,
+            end_time: row.end_time,
+            priority: Priority::from_db_str(&row.priority),
+            rooms: rooms_from_db_str(&row.rooms),
+            show_on_landing: row.show_on_landing,
+            created_at: row.created_at,
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct AdminSessionRow {
+    id: String,
+    user_name: String,
+    csrf_token: String,
+    expires_at: DateTime<Utc>,
+}
+impl From<AdminSessionRow> for AdminSession {
+    fn from(row: AdminSessionRow) -> Self {
+        Self {
+            id: row.id,
+            user_name: row.user_name,
+            csrf_token: row.csrf_token,
+            expires_at: row.expires_at,
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct ApiTokenRow {
+    id: i64,
+    name: String,
+    token_hash: String,
+    scopes: String,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+impl From<ApiTokenRow> for ApiToken {
+    fn from(row: ApiTokenRow) -> Self {
+        Self {
+            id: row.id,
+            name: row.name,
+            token_hash: row.token_hash,
+            scopes: scopes_from_db_str(&row.scopes),
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            last_used_at: row.last_used_at,
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct WebhookDeliveryRow {
+    id: i64,
+    target: String,
+    event: String,
+    payload: String,
+    attempts: i64,
+    next_attempt_at: DateTime<Utc>,
+}
+impl From<WebhookDeliveryRow> for WebhookDelivery {
+    fn from(row: WebhookDeliveryRow) -> Self {
+        Self {
+            id: row.id,
+            target: row.target,
+            event: row.event,
+            payload: row.payload,
+            attempts: row.attempts,
+            next_attempt_at: row.next_attempt_at,
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct BookingEventRow {
+    id: i64,
+    sync_run: Option<String>,
+    occurred_at: DateTime<Utc>,
+    kind: String,
+    booking_id: i64,
+    source: String,
+    resource_id: i64,
+    title: String,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    diff: String,
+}
+impl From<BookingEventRow> for BookingEvent {
+    fn from(row: BookingEventRow) -> Self {
+        Self {
+            id: row.id,
+            sync_run: row.sync_run,
+            occurred_at: row.occurred_at,
+            kind: BookingEventKind::from_db_str(&row.kind),
+            booking_id: row.booking_id,
+            source: row.source,
+            resource_id: row.resource_id,
+            title: row.title,
+            start_time: row.start_time,
+            end_time: row.end_time,
+            diff: serde_json::from_str::<Vec<FieldChange>>(&row.diff).unwrap_or_default(),
+        }
+    }
+}
+
+async fn insert_booking(conn: &mut PgConnection, booking: &Booking) -> Result<(), DBError> {
+    sqlx::query(
+        "INSERT INTO bookings (source, booking_id, title, resource_id, start_time, end_time, \
+         setup_minutes, teardown_minutes) VALUES ($1, $2, $3, $4, $5, $6, $7, $8);",
+    )
+    .bind(&booking.source)
+    .bind(booking.booking_id)
+    .bind(&booking.title)
+    .bind(booking.resource_id)
+    .bind(booking.start_time)
+    .bind(booking.end_time)
+    .bind(booking.setup_minutes)
+    .bind(booking.teardown_minutes)
+    .execute(&mut *conn)
+    .await
+    .map(|_| ())
+    .map_err(DBError::InsertBooking)
+}
+
+async fn delete_booking(
+    conn: &mut PgConnection,
+    source: &str,
+    booking_id: i64,
+) -> Result<(), DBError> {
+    sqlx::query("DELETE FROM bookings WHERE source = $1 AND booking_id = $2;")
+        .bind(source)
+        .bind(booking_id)
+        .execute(&mut *conn)
+        .await
+        .map(|_| ())
+        .map_err(DBError::DeleteBooking)
+}
+
+async fn update_booking(conn: &mut PgConnection, booking: &Booking) -> Result<(), DBError> {
+    sqlx::query(
+        "UPDATE bookings SET title = $1, resource_id = $2, start_time = $3, end_time = $4, \
+         setup_minutes = $5, teardown_minutes = $6 WHERE source = $7 AND booking_id = $8;",
+    )
+    .bind(&booking.title)
+    .bind(booking.resource_id)
+    .bind(booking.start_time)
+    .bind(booking.end_time)
+    .bind(booking.setup_minutes)
+    .bind(booking.teardown_minutes)
+    .bind(&booking.source)
+    .bind(booking.booking_id)
+    .execute(&mut *conn)
+    .await
+    .map(|_| ())
+    .map_err(DBError::UpdateBooking)
+}
+
+async fn insert_webhook_delivery(
+    conn: &mut PgConnection,
+    target: &str,
+    event: &str,
+    payload: &str,
+) -> Result<i64, DBError> {
+    let now = Utc::now();
+    sqlx::query_scalar(
+        "INSERT INTO webhook_deliveries (target, event, payload, created_at, next_attempt_at) \
+         VALUES ($1, $2, $3, $4, $5) RETURNING id;",
+    )
+    .bind(target)
+    .bind(event)
+    .bind(payload)
+    .bind(now)
+    .bind(now)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(DBError::InsertWebhookDelivery)
+}
+
+async fn insert_booking_events(
+    conn: &mut PgConnection,
+    sync_run: Option<&str>,
+    events: &[NewBookingEvent<'_>],
+) -> Result<(), DBError> {
+    let occurred_at = Utc::now();
+    for event in events {
+        let diff = serde_json::to_string(&event.diff).expect("field changes are always valid json");
+        sqlx::query(
+            "INSERT INTO booking_events (sync_run, occurred_at, kind, booking_id, source, \
+             resource_id, title, start_time, end_time, diff) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10);",
+        )
+        .bind(sync_run)
+        .bind(occurred_at)
+        .bind(event.kind.as_db_str())
+        .bind(event.booking.booking_id)
+        .bind(&event.booking.source)
+        .bind(event.booking.resource_id)
+        .bind(&event.booking.title)
+        .bind(event.booking.start_time)
+        .bind(event.booking.end_time)
+        .bind(diff)
+        .execute(&mut *conn)
+        .await
+        .map_err(DBError::InsertBookingEvent)?;
+    }
+    Ok(())
+}
+
+/// Everything in one PostgreSQL database, which may be shared by several instances
+#[derive(Debug)]
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+impl PostgresStorage {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for PostgresStorage {
+    async fn migrate(&self) -> Result<(), DBError> {
+        sqlx::migrate!("migrations/postgres")
+            .run(&self.pool)
+            .await
+            .map_err(DBError::Migrate)
+    }
+
+    async fn get_bookings_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Booking>, DBError> {
+        Ok(sqlx::query_as::<_, BookingRow>(
+            "SELECT source, booking_id, title, resource_id, start_time, end_time, \
+             setup_minutes, teardown_minutes FROM bookings \
+             WHERE start_time <= $1 AND $2 <= end_time \
+             ORDER BY start_time;",
+        )
+        .bind(end.and_utc())
+        .bind(start.and_utc())
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectBookings)?
+        .into_iter()
+        .map(Booking::from)
+        .collect())
+    }
+
+    async fn apply_booking_changes(&self, changes: &BookingChanges<'_>) -> Result<(), DBError> {
+        let mut tx = self.pool.begin().await.map_err(DBError::Transaction)?;
+        for booking in &changes.inserted {
+            insert_booking(&mut tx, booking).await?;
+        }
+        for booking_id in &changes.deleted {
+            delete_booking(&mut tx, changes.source, *booking_id).await?;
+        }
+        for booking in &changes.updated {
+            update_booking(&mut tx, booking).await?;
+        }
+        for delivery in &changes.deliveries {
+            insert_webhook_delivery(&mut tx, &delivery.target, delivery.event, &delivery.payload)
+                .await?;
+        }
+        insert_booking_events(&mut tx, changes.sync_run, &changes.history).await?;
+        tx.commit().await.map_err(DBError::Transaction)
+    }
+
+    async fn prune_old_bookings(
+        &self,
+        sync_run: Option<&str>,
+        reference: NaiveDateTime,
+    ) -> Result<u64, DBError> {
+        let reference = reference.and_utc();
+        let mut tx = self.pool.begin().await.map_err(DBError::DeleteBooking)?;
+        sqlx::query(
+            "INSERT INTO booking_events \
+             (sync_run, occurred_at, kind, booking_id, source, resource_id, title, start_time, \
+             end_time) \
+             SELECT $1, $2, $3, booking_id, source, resource_id, title, start_time, end_time \
+             FROM bookings WHERE end_time < $4 ORDER BY start_time;",
+        )
+        .bind(sync_run)
+        .bind(Utc::now())
+        .bind(BookingEventKind::Pruned.as_db_str())
+        .bind(reference)
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::InsertBookingEvent)?;
+        sqlx::query(
+            "INSERT INTO booking_archive \
+             (origin, source, booking_id, title, resource_id, start_time, end_time, \
+             setup_minutes, teardown_minutes) \
+             SELECT 'churchtools', source, booking_id, title, resource_id, start_time, end_time, \
+             setup_minutes, teardown_minutes FROM bookings WHERE end_time < $1 \
+             ON CONFLICT (origin, source, booking_id) DO UPDATE SET title = EXCLUDED.title, \
+             resource_id = EXCLUDED.resource_id, start_time = EXCLUDED.start_time, \
+             end_time = EXCLUDED.end_time, setup_minutes = EXCLUDED.setup_minutes, \
+             teardown_minutes = EXCLUDED.teardown_minutes;",
+        )
+        .bind(reference)
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::ArchiveBookings)?;
+        let removed = sqlx::query("DELETE FROM bookings WHERE end_time < $1;")
+            .bind(reference)
+            .execute(&mut *tx)
+            .await
+            .map(|x| x.rows_affected())
+            .map_err(DBError::DeleteBooking)?;
+        tx.commit().await.map_err(DBError::DeleteBooking)?;
+        Ok(removed)
+    }
+
+    async fn get_conflicts_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Conflict>, DBError> {
+        Ok(sqlx::query_as::<_, ConflictRow>(
+            "SELECT resource_id, first_source, first_booking_id, second_source, \
+             second_booking_id, overlap_start, overlap_end FROM conflicts \
+             WHERE overlap_start <= $1 AND $2 <= overlap_end;",
+        )
+        .bind(end.and_utc())
+        .bind(start.and_utc())
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectConflicts)?
+        .into_iter()
+        .map(Conflict::from)
+        .collect())
+    }
+
+    async fn get_conflict_reports(&self) -> Result<Vec<ConflictReport>, DBError> {
+        Ok(sqlx::query_as::<_, ConflictReportRow>(
+            "SELECT c.resource_id, c.first_source, c.first_booking_id, c.second_source, \
+             c.second_booking_id, c.overlap_start, c.overlap_end, c.detected_at, \
+             f.title AS first_title, s.title AS second_title \
+             FROM conflicts c \
+             JOIN bookings f ON f.resource_id = c.resource_id AND f.source = c.first_source \
+             AND f.booking_id = c.first_booking_id \
+             JOIN bookings s ON s.resource_id = c.resource_id AND s.source = c.second_source \
+             AND s.booking_id = c.second_booking_id \
+             ORDER BY c.overlap_start;",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectConflicts)?
+        .into_iter()
+        .map(ConflictReport::from)
+        .collect())
+    }
+
+    async fn insert_conflict(&self, conflict: &Conflict) -> Result<(), DBError> {
+        sqlx::query(
+            "INSERT INTO conflicts (first_source, first_booking_id, second_source, \
+             second_booking_id, resource_id, overlap_start, overlap_end, detected_at) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8);",
+        )
+        .bind(&conflict.first_source)
+        .bind(conflict.first_booking_id)
+        .bind(&conflict.second_source)
+        .bind(conflict.second_booking_id)
+        .bind(conflict.resource_id)
+        .bind(conflict.overlap_start)
+        .bind(conflict.overlap_end)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(DBError::InsertConflict)
+    }
+
+    async fn delete_conflict(&self, conflict: &Conflict) -> Result<(), DBError> {
+        sqlx::query(
+            "DELETE FROM conflicts \
+             WHERE resource_id = $1 AND first_source = $2 AND first_booking_id = $3 \
+             AND second_source = $4 AND second_booking_id = $5;",
+        )
+        .bind(conflict.resource_id)
+        .bind(&conflict.first_source)
+        .bind(conflict.first_booking_id)
+        .bind(&conflict.second_source)
+        .bind(conflict.second_booking_id)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(DBError::DeleteConflict)
+    }
+
+    async fn prune_old_conflicts(&self, reference: NaiveDateTime) -> Result<u64, DBError> {
+        sqlx::query("DELETE FROM conflicts WHERE overlap_end < $1;")
+            .bind(reference.and_utc())
+            .execute(&self.pool)
+            .await
+            .map(|x| x.rows_affected())
+            .map_err(DBError::DeleteConflict)
+    }
+
+    async fn get_local_bookings_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<LocalBooking>, DBError> {
+        Ok(sqlx::query_as::<_, LocalBookingRow>(
+            "SELECT id, title, resource_id, start_time, end_time, kind, note, created_at \
+             FROM local_bookings \
+             WHERE start_time <= $1 AND $2 <= end_time \
+             ORDER BY start_time;",
+        )
+        .bind(end.and_utc())
+        .bind(start.and_utc())
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectLocalBookings)?
+        .into_iter()
+        .map(LocalBooking::from)
+        .collect())
+    }
+
+    async fn insert_local_booking(&self, booking: &NewLocalBooking) -> Result<i64, DBError> {
+        sqlx::query_scalar(
+            "INSERT INTO local_bookings \
+             (title, resource_id, start_time, end_time, kind, note, created_at) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id;",
+        )
+        .bind(&booking.title)
+        .bind(booking.resource_id)
+        .bind(booking.start_time)
+        .bind(booking.end_time)
+        .bind(booking.kind.as_db_str())
+        .bind(&booking.note)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await
+        .map_err(DBError::InsertLocalBooking)
+    }
+
+    async fn delete_local_booking(&self, id: i64) -> Result<bool, DBError> {
+        sqlx::query("DELETE FROM local_bookings WHERE id = $1;")
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map(|x| x.rows_affected() > 0)
+            .map_err(DBError::DeleteLocalBooking)
+    }
+
+    async fn prune_old_local_bookings(&self, reference: NaiveDateTime) -> Result<u64, DBError> {
+        let reference = reference.and_utc();
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(DBError::DeleteLocalBooking)?;
+        // blocks are not archived, they are no use of the room
+        sqlx::query(
+            "INSERT INTO booking_archive \
+             (origin, source, booking_id, title, resource_id, start_time, end_time) \
+             SELECT 'local', 'local', id, title, resource_id, start_time, end_time \
+             FROM local_bookings WHERE end_time < $1 AND kind = $2 \
+             ON CONFLICT (origin, source, booking_id) DO UPDATE SET title = EXCLUDED.title, \
+             resource_id = EXCLUDED.resource_id, start_time = EXCLUDED.start_time, \
+             end_time = EXCLUDED.end_time;",
+        )
+        .bind(reference)
+        .bind(LocalBookingKind::Booking.as_db_str())
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::ArchiveBookings)?;
+        let removed = sqlx::query("DELETE FROM local_bookings WHERE end_time < $1;")
+            .bind(reference)
+            .execute(&mut *tx)
+            .await
+            .map(|x| x.rows_affected())
+            .map_err(DBError::DeleteLocalBooking)?;
+        tx.commit().await.map_err(DBError::DeleteLocalBooking)?;
+        Ok(removed)
+    }
+
+    async fn get_archived_bookings_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Booking>, DBError> {
+        Ok(sqlx::query_as::<_, ArchivedBookingRow>(
+            "SELECT origin, source, booking_id, title, resource_id, start_time, end_time, \
+             setup_minutes, teardown_minutes FROM booking_archive \
+             WHERE start_time <= $1 AND $2 <= end_time;",
+        )
+        .bind(end.and_utc())
+        .bind(start.and_utc())
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectArchive)?
+        .into_iter()
+        .map(Booking::from)
+        .collect())
+    }
+
+    async fn get_announcements_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Announcement>, DBError> {
+        let mut announcements = sqlx::query_as::<_, AnnouncementRow>(
+            "SELECT id, message, start_time, end_time, priority, rooms, show_on_landing, \
+             created_at FROM announcements \
+             WHERE start_time <= $1 AND $2 <= end_time \
+             ORDER BY start_time;",
+        )
+        .bind(end.and_utc())
+        .bind(start.and_utc())
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectAnnouncements)?
+        .into_iter()
+        .map(Announcement::from)
+        .collect::<Vec<_>>();
+        // stable, so announcements of equal priority stay ordered by start
+        announcements.sort_by_key(|a| core::cmp::Reverse(a.priority));
+        Ok(announcements)
+    }
+
+    async fn insert_announcement(&self, announcement: &NewAnnouncement) -> Result<i64, DBError> {
+        sqlx::query_scalar(
+            "INSERT INTO announcements \
+             (message, start_time, end_time, priority, rooms, show_on_landing, created_at) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id;",
+        )
+        .bind(&announcement.message)
+        .bind(announcement.start_time)
+        .bind(announcement.end_time)
+        .bind(announcement.priority.as_db_str())
+        .bind(rooms_to_db_str(&announcement.rooms))
+        .bind(announcement.show_on_landing)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await
+        .map_err(DBError::InsertAnnouncement)
+    }
+
+    async fn delete_announcement(&self, id: i64) -> Result<bool, DBError> {
+        sqlx::query("DELETE FROM announcements WHERE id = $1;")
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map(|x| x.rows_affected() > 0)
+            .map_err(DBError::DeleteAnnouncement)
+    }
+
+    async fn prune_old_announcements(&self, reference: NaiveDateTime) -> Result<u64, DBError> {
+        sqlx::query("DELETE FROM announcements WHERE end_time < $1;")
+            .bind(reference.and_utc())
+            .execute(&self.pool)
+            .await
+            .map(|x| x.rows_affected())
+            .map_err(DBError::DeleteAnnouncement)
+    }
+
+    async fn get_admin_session(&self, id: &str) -> Result<Option<AdminSession>, DBError> {
+        Ok(sqlx::query_as::<_, AdminSessionRow>(
+            "SELECT id, user_name, csrf_token, expires_at FROM admin_sessions \
+             WHERE id = $1 AND $2 < expires_at;",
+        )
+        .bind(id)
+        .bind(Utc::now())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(DBError::SelectSession)?
+        .map(AdminSession::from))
+    }
+
+    async fn insert_admin_session(&self, session: &AdminSession) -> Result<(), DBError> {
+        sqlx::query(
+            "INSERT INTO admin_sessions (id, user_name, csrf_token, created_at, expires_at) \
+             VALUES ($1, $2, $3, $4, $5);",
+        )
+        .bind(&session.id)
+        .bind(&session.user_name)
+        .bind(&session.csrf_token)
+        .bind(Utc::now())
+        .bind(session.expires_at)
+        .execute(&self.pool)
+        .await
+        .map_err(DBError::InsertSession)?;
+        Ok(())
+    }
+
+    async fn delete_admin_session(&self, id: &str) -> Result<(), DBError> {
+        sqlx::query("DELETE FROM admin_sessions WHERE id = $1;")
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(DBError::DeleteSession)?;
+        Ok(())
+    }
+
+    async fn prune_expired_admin_sessions(&self) -> Result<u64, DBError> {
+        sqlx::query("DELETE FROM admin_sessions WHERE expires_at <= $1;")
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await
+            .map(|x| x.rows_affected())
+            .map_err(DBError::DeleteSession)
+    }
+
+    async fn get_statistics(&self) -> Result<DbStatistics, DBError> {
+        let row: (i64, i64, i64, i64, i64, i64, i64, i64) = sqlx::query_as(
+            "SELECT \
+             (SELECT COUNT(*) FROM bookings), \
+             (SELECT COUNT(*) FROM local_bookings), \
+             (SELECT COUNT(*) FROM conflicts), \
+             (SELECT COUNT(*) FROM announcements), \
+             (SELECT COUNT(*) FROM admin_sessions), \
+             (SELECT COUNT(*) FROM webhook_deliveries), \
+             (SELECT COUNT(*) FROM booking_events), \
+             pg_database_size(current_database());",
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(DBError::Statistics)?;
+        Ok(DbStatistics {
+            bookings: row.0,
+            local_bookings: row.1,
+            conflicts: row.2,
+            announcements: row.3,
+            admin_sessions: row.4,
+            webhook_deliveries: row.5,
+            booking_events: row.6,
+            size_bytes: row.7,
+        })
+    }
+
+    async fn get_api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>, DBError> {
+        Ok(sqlx::query_as::<_, ApiTokenRow>(
+            "SELECT id, name, token_hash, scopes, created_at, expires_at, last_used_at \
+             FROM api_tokens WHERE token_hash = $1;",
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(DBError::SelectApiTokens)?
+        .map(ApiToken::from))
+    }
+
+    async fn get_api_tokens(&self) -> Result<Vec<ApiToken>, DBError> {
+        Ok(sqlx::query_as::<_, ApiTokenRow>(
+            "SELECT id, name, token_hash, scopes, created_at, expires_at, last_used_at \
+             FROM api_tokens ORDER BY name;",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectApiTokens)?
+        .into_iter()
+        .map(ApiToken::from)
+        .collect())
+    }
+
+    async fn insert_api_token(
+        &self,
+        name: &str,
+        token_hash: &str,
+        scopes: &[Scope],
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<i64, DBError> {
+        sqlx::query_scalar(
+            "INSERT INTO api_tokens (name, token_hash, scopes, created_at, expires_at) \
+             VALUES ($1, $2, $3, $4, $5) RETURNING id;",
+        )
+        .bind(name)
+        .bind(token_hash)
+        .bind(scopes_to_db_str(scopes))
+        .bind(Utc::now())
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(DBError::InsertApiToken)
+    }
+
+    async fn touch_api_token(&self, id: i64, at: DateTime<Utc>) -> Result<(), DBError> {
+        sqlx::query("UPDATE api_tokens SET last_used_at = $1 WHERE id = $2;")
+            .bind(at)
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(DBError::UpdateApiToken)?;
+        Ok(())
+    }
+
+    async fn delete_api_token(&self, name: &str) -> Result<bool, DBError> {
+        sqlx::query("DELETE FROM api_tokens WHERE name = $1;")
+            .bind(name)
+            .execute(&self.pool)
+            .await
+            .map(|x| x.rows_affected() > 0)
+            .map_err(DBError::DeleteApiToken)
+    }
+
+    async fn insert_webhook_delivery(
+        &self,
+        target: &str,
+        event: &str,
+        payload: &str,
+    ) -> Result<i64, DBError> {
+        let mut conn = self
+            .pool
+            .acquire()
+            .await
+            .map_err(DBError::InsertWebhookDelivery)?;
+        insert_webhook_delivery(&mut conn, target, event, payload).await
+    }
+
+    async fn claim_due_webhook_deliveries(
+        &self,
+        now: DateTime<Utc>,
+        claimed_until: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>, DBError> {
+        // rows another instance is claiming right now are skipped instead of claimed twice
+        let mut deliveries = sqlx::query_as::<_, WebhookDeliveryRow>(
+            "UPDATE webhook_deliveries SET claimed_until = $1 \
+             WHERE id IN (SELECT id FROM webhook_deliveries \
+             WHERE next_attempt_at <= $2 AND (claimed_until IS NULL OR claimed_until <= $2) \
+             ORDER BY id LIMIT $3 FOR UPDATE SKIP LOCKED) \
+             RETURNING id, target, event, payload, attempts, next_attempt_at;",
+        )
+        .bind(claimed_until)
+        .bind(now)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectWebhookDeliveries)?
+        .into_iter()
+        .map(WebhookDelivery::from)
+        .collect::<Vec<_>>();
+        // RETURNING does not keep the order of the subquery
+        deliveries.sort_by_key(|d| d.id);
+        Ok(deliveries)
+    }
+
+    async fn record_failed_webhook_delivery(
+        &self,
+        id: i64,
+        error: &str,
+        next_attempt_at: DateTime<Utc>,
+    ) -> Result<(), DBError> {
+        sqlx::query(
+            "UPDATE webhook_deliveries SET attempts = attempts + 1, last_error = $1, \
+             next_attempt_at = $2, claimed_until = NULL WHERE id = $3;",
+        )
+        .bind(error)
+        .bind(next_attempt_at)
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map_err(DBError::UpdateWebhookDelivery)?;
+        Ok(())
+    }
+
+    async fn delete_webhook_delivery(&self, id: i64) -> Result<(), DBError> {
+        sqlx::query("DELETE FROM webhook_deliveries WHERE id = $1;")
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(DBError::DeleteWebhookDelivery)?;
+        Ok(())
+    }
+
+    async fn get_booking_events(
+        &self,
+        filter: &BookingEventFilter,
+        limit: i64,
+    ) -> Result<Vec<BookingEvent>, DBError> {
+        Ok(sqlx::query_as::<_, BookingEventRow>(
+            "SELECT id, sync_run, occurred_at, kind, booking_id, source, resource_id, title, \
+             start_time, end_time, diff FROM booking_events \
+             WHERE ($1::BIGINT IS NULL OR booking_id = $1) \
+             AND ($2::BIGINT IS NULL OR resource_id = $2) \
+             AND ($3::TEXT IS NULL OR sync_run = $3) \
+             AND ($4::TIMESTAMPTZ IS NULL OR occurred_at >= $4) \
+             AND ($5::TIMESTAMPTZ IS NULL OR occurred_at < $5) \
+             ORDER BY id DESC LIMIT $6;",
+        )
+        .bind(filter.booking_id)
+        .bind(filter.room)
+        .bind(&filter.sync_run)
+        .bind(filter.since)
+        .bind(filter.until)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectBookingEvents)?
+        .into_iter()
+        .map(BookingEvent::from)
+        .collect())
+    }
+
+    async fn prune_old_booking_events(&self, reference: NaiveDateTime) -> Result<u64, DBError> {
+        sqlx::query("DELETE FROM booking_events WHERE occurred_at < $1;")
+            .bind(reference.and_utc())
+            .execute(&self.pool)
+            .await
+            .map(|x| x.rows_affected())
+            .map_err(DBError::DeleteBookingEvent)
+    }
+
+    async fn claim_job_run(
+        &self,
+        job: &str,
+        since: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, DBError> {
+        // a concurrent claim waits for the row lock and then sees the new last_run
+        sqlx::query(
+            "INSERT INTO job_runs (job, last_run) VALUES ($1, $2) \
+             ON CONFLICT (job) DO UPDATE SET last_run = EXCLUDED.last_run \
+             WHERE job_runs.last_run < $3;",
+        )
+        .bind(job)
+        .bind(now)
+        .bind(since)
+        .execute(&self.pool)
+        .await
+        .map(|x| x.rows_affected() == 1)
+        .map_err(DBError::ClaimJobRun)
+    }
+
+    async fn export(&self) -> Result<DbExport, DBError> {
+        let mut tx = self.pool.begin().await.map_err(DBError::Export)?;
+        // all selects see the same state
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY;")
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::Export)?;
+        let bookings = sqlx::query_as::<_, BookingRow>(
+            "SELECT source, booking_id, title, resource_id, start_time, end_time, \
+             setup_minutes, teardown_minutes FROM bookings ORDER BY source, booking_id;",
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(DBError::Export)?;
+        let local_bookings = sqlx::query_as::<_, LocalBookingRow>(
+            "SELECT id, title, resource_id, start_time, end_time, kind, note, created_at \
+             FROM local_bookings ORDER BY id;",
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(DBError::Export)?;
+        let archived_bookings = sqlx::query_as::<_, ArchivedBookingRow>(
+            "SELECT origin, source, booking_id, title, resource_id, start_time, end_time, \
+             setup_minutes, teardown_minutes FROM booking_archive \
+             ORDER BY origin, source, booking_id;",
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(DBError::Export)?;
+        let booking_events = sqlx::query_as::<_, BookingEventRow>(
+            "SELECT id, sync_run, occurred_at, kind, booking_id, source, resource_id, title, \
+             start_time, end_time, diff FROM booking_events ORDER BY id;",
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(DBError::Export)?;
+        tx.commit().await.map_err(DBError::Export)?;
+        Ok(DbExport {
+            version: EXPORT_VERSION,
+            exported_at: Utc::now(),
+            bookings: bookings.into_iter().map(Booking::from).collect(),
+            local_bookings: local_bookings.into_iter().map(LocalBooking::from).collect(),
+            archived_bookings: archived_bookings.into_iter().map(Booking::from).collect(),
+            booking_events: booking_events.into_iter().map(BookingEvent::from).collect(),
+        })
+    }
+
+    async fn import(&self, data: &DbExport) -> Result<(), DBError> {
+        let mut tx = self.pool.begin().await.map_err(DBError::Import)?;
+        for table in super::IMPORTED_TABLES {
+            sqlx::query(&format!("DELETE FROM {table};"))
+                .execute(&mut *tx)
+                .await
+                .map_err(DBError::Import)?;
+        }
+        for b in &data.bookings {
+            sqlx::query(
+                "INSERT INTO bookings (source, booking_id, title, resource_id, start_time, \
+                 end_time, setup_minutes, teardown_minutes) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8);",
+            )
+            .bind(&b.source)
+            .bind(b.booking_id)
+            .bind(&b.title)
+            .bind(b.resource_id)
+            .bind(b.start_time)
+            .bind(b.end_time)
+            .bind(b.setup_minutes)
+            .bind(b.teardown_minutes)
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::Import)?;
+        }
+        for b in &data.local_bookings {
+            sqlx::query(
+                "INSERT INTO local_bookings \
+                 (id, title, resource_id, start_time, end_time, kind, note, created_at) \
+                 OVERRIDING SYSTEM VALUE VALUES ($1, $2, $3, $4, $5, $6, $7, $8);",
+            )
+            .bind(b.id)
+            .bind(&b.title)
+            .bind(b.resource_id)
+            .bind(b.start_time)
+            .bind(b.end_time)
+            .bind(b.kind.as_db_str())
+            .bind(&b.note)
+            .bind(b.created_at)
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::Import)?;
+        }
+        for b in &data.archived_bookings {
+            sqlx::query(
+                "INSERT INTO booking_archive (origin, source, booking_id, title, resource_id, \
+                 start_time, end_time, setup_minutes, teardown_minutes) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9);",
+            )
+            .bind(b.origin.as_db_str())
+            .bind(&b.source)
+            .bind(b.booking_id)
+            .bind(&b.title)
+            .bind(b.resource_id)
+            .bind(b.start_time)
+            .bind(b.end_time)
+            .bind(b.setup_minutes)
+            .bind(b.teardown_minutes)
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::Import)?;
+        }
+        for e in &data.booking_events {
+            let diff = serde_json::to_string(&e.diff).expect("field changes are always valid json");
+            sqlx::query(
+                "INSERT INTO booking_events (id, sync_run, occurred_at, kind, booking_id, \
+                 source, resource_id, title, start_time, end_time, diff) \
+                 OVERRIDING SYSTEM VALUE VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11);",
+            )
+            .bind(e.id)
+            .bind(&e.sync_run)
+            .bind(e.occurred_at)
+            .bind(e.kind.as_db_str())
+            .bind(e.booking_id)
+            .bind(&e.source)
+            .bind(e.resource_id)
+            .bind(&e.title)
+            .bind(e.start_time)
+            .bind(e.end_time)
+            .bind(diff)
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::Import)?;
+        }
+        // new rows have to get IDs after the imported ones
+        for table in ["local_bookings", "booking_events"] {
+            sqlx::query(&format!(
+                "SELECT setval(pg_get_serial_sequence('{table}', 'id'), \
+                 (SELECT COALESCE(MAX(id), 0) + 1 FROM {table}), false);"
+            ))
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::Import)?;
+        }
+        tx.commit().await.map_err(DBError::Import)
+    }
+
+    async fn snapshot(&self, _path: &Path) -> Result<(), DBError> {
+        Err(DBError::SnapshotUnsupported)
+    }
+}
@@ -0,0 +1,1308 @@
+//! The SQLite backend, used unless `db.url` points to PostgreSQL
+
+use std::path::Path;
+
+use chrono::{format::StrftimeItems, DateTime, NaiveDateTime, Utc};
+use sqlx::{sqlite::SqliteConnectOptions, Pool, Sqlite, SqliteConnection, SqlitePool};
+
+use super::{BookingChanges, DBError, DbStatistics, Storage};
+use crate::{
+    announcements::{rooms_from_db_str, rooms_to_db_str, Announcement, NewAnnouncement, Priority},
+    auth::{scopes_from_db_str, scopes_to_db_str, AdminSession, ApiToken, Scope},
+    backup::{DbExport, EXPORT_VERSION},
+    conflicts::{Conflict, ConflictReport},
+    history::{BookingEvent, BookingEventFilter, BookingEventKind, FieldChange, NewBookingEvent},
+    local_bookings::{LocalBooking, LocalBookingKind, NewLocalBooking},
+    webhooks::WebhookDelivery,
+    Booking, BookingOrigin,
+};
+
+/// The only form in which times are written to sqlite: RFC 3339 UTC, with `Z` and without fraction.
+/// Queries compare times as strings, so this has to match what migration 012 normalizes to.
+pub(super) const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+/// sqlite does not have tz-aware types, so we can only get [`NaiveDateTime`] from it.
+/// We ALWAYS STORE UTC DATETIMES IN SQLITE.
+struct NaiveBooking {
+    source: String,
+    booking_id: i64,
+    title: String,
+    resource_id: i64,
+    start_time: chrono::NaiveDateTime,
+    end_time: chrono::NaiveDateTime,
+    setup_minutes: Option<i64>,
+    teardown_minutes: Option<i64>,
+}
+impl NaiveBooking {
+    /// Taking a naive booking, interpret all datetimes as UTC datetimes
+    fn interpret_as_utc(self) -> crate::Booking {
+        Booking {
+            booking_id: self.booking_id,
+            source: self.source,
+            title: self.title,
+            resource_id: self.resource_id,
+            start_time: self.start_time.and_utc(),
+            end_time: self.end_time.and_utc(),
+            setup_minutes: self.setup_minutes,
+            teardown_minutes: self.teardown_minutes,
+            origin: BookingOrigin::ChurchTools,
+        }
+    }
+}
+
+struct NaiveConflict {
+    resource_id: i64,
+    first_source: String,
+    first_booking_id: i64,
+    second_source: String,
+    second_booking_id: i64,
+    overlap_start: chrono::NaiveDateTime,
+    overlap_end: chrono::NaiveDateTime,
+}
+impl NaiveConflict {
+    fn interpret_as_utc(self) -> Conflict {
+        Conflict {
+            resource_id: self.resource_id,
+            first_source: self.first_source,
+            first_booking_id: self.first_booking_id,
+            second_source: self.second_source,
+            second_booking_id: self.second_booking_id,
+            overlap_start: self.overlap_start.and_utc(),
+            overlap_end: self.overlap_end.and_utc(),
+        }
+    }
+}
+
+struct NaiveConflictReport {
+    resource_id: i64,
+    first_source: String,
+    first_booking_id: i64,
+    second_source: String,
+    second_booking_id: i64,
+    overlap_start: chrono::NaiveDateTime,
+    overlap_end: chrono::NaiveDateTime,
+    detected_at: chrono::NaiveDateTime,
+    first_title: String,
+    second_title: String,
+}
+impl NaiveConflictReport {
+    fn interpret_as_utc(self) -> ConflictReport {
+        ConflictReport {
+            conflict: Conflict {
+                resource_id: self.resource_id,
+                first_source: self.first_source,
+                first_booking_id: self.first_booking_id,
+                second_source: self.second_source,
+                second_booking_id: self.second_booking_id,
+                overlap_start: self.overlap_start.and_utc(),
+                overlap_end: self.overlap_end.and_utc(),
+            },
+            first_title: self.first_title,
+            second_title: self.second_title,
+            detected_at: self.detected_at.and_utc(),
+        }
+    }
+}
+
+struct NaiveArchivedBooking {
+    origin: String,
+    source: String,
+    booking_id: i64,
+    title: String,
+    resource_id: i64,
+    start_time: chrono::NaiveDateTime,
+    end_time: chrono::NaiveDateTime,
+    setup_minutes: Option<i64>,
+    teardown_minutes: Option<i64>,
+}
+impl NaiveArchivedBooking {
+    fn interpret_as_utc(self) -> Booking {
+        Booking {
+            booking_id: self.booking_id,
+            source: self.source,
+            title: self.title,
+            resource_id: self.resource_id,
+            start_time: self.start_time.and_utc(),
+            end_time: self.end_time.and_utc(),
+            setup_minutes: self.setup_minutes,
+            teardown_minutes: self.teardown_minutes,
+            origin: BookingOrigin::from_db_str(&self.origin),
+        }
+    }
+}
+
+struct NaiveLocalBooking {
+    id: i64,
+    title: String,
+    resource_id: i64,
+    start_time: chrono::NaiveDateTime,
+    end_time: chrono::NaiveDateTime,
+    kind: String,
+    note: Option<String>,
+    created_at: chrono::NaiveDateTime,
+}
+impl NaiveLocalBooking {
+    fn interpret_as_utc(self) -> LocalBooking {
+        LocalBooking {
+            id: self.id,
+            title: self.title,
+            resource_id: self.resource_id,
+            start_time: self.start_time.and_utc(),
+            end_time: self.end_time.and_utc(),
+            kind: LocalBookingKind::from_db_str(&self.kind),
+            note: self.note,
+            created_at: self.created_at.and_utc(),
+        }
+    }
+}
+
+struct NaiveAnnouncement {
+    id: i64,
+    message: String,
+    start_time: chrono::NaiveDateTime,
+    end_time: chrono::NaiveDateTime,
+    priority: String,
+    rooms: String,
+    show_on_landing: bool,
+    created_at: chrono::NaiveDateTime,
+}
+impl NaiveAnnouncement {
+    fn interpret_as_utc(self) -> Announcement {
+        Announcement {
+            id: self.id,
+            message: self.message,
+            start_time: self.start_time.and_utc(),
+            end_time: self.end_time.and_utc(),
+            priority: Priority::from_db_str(&self.priority),
+            rooms: rooms_from_db_str(&self.rooms),
+            show_on_landing: self.show_on_landing,
+            created_at: self.created_at.and_utc(),
+        }
+    }
+}
+
+struct NaiveAdminSession {
+    id: String,
+    user_name: String,
+    csrf_token: String,
+    expires_at: chrono::NaiveDateTime,
+}
+impl NaiveAdminSession {
+    fn interpret_as_utc(self) -> AdminSession {
+        AdminSession {
+            id: self.id,
+            user_name: self.user_name,
+            csrf_token: self.csrf_token,
+            expires_at: self.expires_at.and_utc(),
+        }
+    }
+}
+
+struct NaiveApiToken {
+    id: i64,
+    name: String,
+    token_hash: String,
+    scopes: String,
+    created_at: chrono::NaiveDateTime,
+    expires_at: Option<chrono::NaiveDateTime>,
+    last_used_at: Option<chrono::NaiveDateTime>,
+}
+impl NaiveApiToken {
+    fn interpret_as_utc(self) -> ApiToken {
+        ApiToken {
+            id: self.id,
+            name: self.name,
+            token_hash: self.token_hash,
+            scopes: scopes_from_db_str(&self.scopes),
+            created_at: self.created_at.and_utc(),
+            expires_at: self.expires_at.map(|t| t.and_utc()),
+            last_used_at: self.last_used_at.map(|t| t.and_utc()),
+        }
+    }
+}
+
+struct NaiveWebhookDelivery {
+    id: i64,
+    target: String,
+    event: String,
+    payload: String,
+    attempts: i64,
+    next_attempt_at: chrono::NaiveDateTime,
+}
+impl NaiveWebhookDelivery {
+    fn interpret_as_utc(self) -> WebhookDelivery {
+        WebhookDelivery {
+            id: self.id,
+            target: self.target,
+            event: self.event,
+            payload: self.payload,
+            attempts: self.attempts,
+            next_attempt_at: self.next_attempt_at.and_utc(),
+        }
+    }
+}
+
+struct NaiveBookingEvent {
+    id: i64,
+    sync_run: Option<String>,
+    occurred_at: chrono::NaiveDateTime,
+    kind: String,
+    booking_id: i64,
+    source: String,
+    resource_id: i64,
+    title: String,
+    start_time: chrono::NaiveDateTime,
+    end_time: chrono::NaiveDateTime,
+    diff: String,
+}
+impl NaiveBookingEvent {
+    fn interpret_as_utc(self) -> BookingEvent {
+        BookingEvent {
+            id: self.id,
+            sync_run: self.sync_run,
+            occurred_at: self.occurred_at.and_utc(),
+            kind: BookingEventKind::from_db_str(&self.kind),
+            booking_id: self.booking_id,
+            source: self.source,
+            resource_id: self.resource_id,
+            title: self.title,
+            start_time: self.start_time.and_utc(),
+            end_time: self.end_time.and_utc(),
+            diff: serde_json::from_str::<Vec<FieldChange>>(&self.diff).unwrap_or_default(),
+        }
+    }
+}
+
+async fn insert_booking(conn: &mut SqliteConnection, booking: &Booking) -> Result<(), DBError> {
+    let fmt = StrftimeItems::new(TIME_FORMAT);
+    let start_str = booking
+        .start_time
+        .format_with_items(fmt.clone())
+        .to_string();
+    let end_str = booking.end_time.format_with_items(fmt.clone()).to_string();
+    sqlx::query!(
+        "INSERT INTO bookings (source, booking_id, title, resource_id, start_time, end_time, \
+    setup_minutes, teardown_minutes) VALUES \
+    (?, ?, ?, ?, ?, ?, ?, ?);
+    ",
+        booking.source,
+        booking.booking_id,
+        booking.title,
+        booking.resource_id,
+        start_str,
+        end_str,
+        booking.setup_minutes,
+        booking.teardown_minutes,
+    )
+    .execute(&mut *conn)
+    .await
+    .map(|_| ())
+    .map_err(DBError::InsertBooking)
+}
+
+async fn delete_booking(
+    conn: &mut SqliteConnection,
+    source: &str,
+    booking_id: i64,
+) -> Result<(), DBError> {
+    sqlx::query!(
+        "DELETE FROM bookings \
+    WHERE source = ? AND booking_id = ?;
+    ",
+        source,
+        booking_id,
+    )
+    .execute(&mut *conn)
+    .await
+    .map(|_| ())
+    .map_err(DBError::DeleteBooking)
+}
+
+async fn update_booking(conn: &mut SqliteConnection, booking: &Booking) -> Result<(), DBError> {
+    let fmt = StrftimeItems::new(TIME_FORMAT);
+    let start_time = booking
+        .start_time
+        .format_with_items(fmt.clone())
+        .to_string();
+    let end_time = booking.end_time.format_with_items(fmt).to_string();
+    sqlx::query!(
+        "UPDATE bookings SET title = ?, resource_id = ?, start_time = ?, end_time = ?, \
+    setup_minutes = ?, teardown_minutes = ? \
+    WHERE source = ? AND booking_id = ?;
+    ",
+        booking.title,
+        booking.resource_id,
+        start_time,
+        end_time,
+        booking.setup_minutes,
+        booking.teardown_minutes,
+        booking.source,
+        booking.booking_id,
+    )
+    .execute(&mut *conn)
+    .await
+    .map(|_| ())
+    .map_err(DBError::UpdateBooking)
+}
+
+async fn insert_webhook_delivery(
+    conn: &mut SqliteConnection,
+    target: &str,
+    event: &str,
+    payload: &str,
+) -> Result<i64, DBError> {
+    let fmt = StrftimeItems::new(TIME_FORMAT);
+    let now = chrono::Utc::now().format_with_items(fmt).to_string();
+    sqlx::query!(
+        "INSERT INTO webhook_deliveries (target, event, payload, created_at, next_attempt_at) \
+    VALUES (?, ?, ?, ?, ?);",
+        target,
+        event,
+        payload,
+        now,
+        now,
+    )
+    .execute(&mut *conn)
+    .await
+    .map(|x| x.last_insert_rowid())
+    .map_err(DBError::InsertWebhookDelivery)
+}
+
+async fn insert_booking_events(
+    conn: &mut SqliteConnection,
+    sync_run: Option<&str>,
+    events: &[NewBookingEvent<'_>],
+) -> Result<(), DBError> {
+    let fmt = StrftimeItems::new(TIME_FORMAT);
+    let occurred_at = chrono::Utc::now()
+        .format_with_items(fmt.clone())
+        .to_string();
+    for event in events {
+        let kind = event.kind.as_db_str();
+        let start_time = event
+            .booking
+            .start_time
+            .format_with_items(fmt.clone())
+            .to_string();
+        let end_time = event
+            .booking
+            .end_time
+            .format_with_items(fmt.clone())
+            .to_string();
+        let diff = serde_json::to_string(&event.diff).expect("field changes are always valid json");
+        sqlx::query!(
+            "INSERT INTO booking_events \
+        (sync_run, occurred_at, kind, booking_id, source, resource_id, title, start_time, \
+        end_time, diff) \
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+            sync_run,
+            occurred_at,
+            kind,
+            event.booking.booking_id,
+            event.booking.source,
+            event.booking.resource_id,
+            event.booking.title,
+            start_time,
+            end_time,
+            diff,
+        )
+        .execute(&mut *conn)
+        .await
+        .map_err(DBError::InsertBookingEvent)?;
+    }
+    Ok(())
+}
+
+/// Everything in one SQLite file
+#[derive(Debug)]
+pub struct SqliteStorage {
+    pool: Pool<Sqlite>,
+}
+impl SqliteStorage {
+    pub fn new(pool: Pool<Sqlite>) -> Self {
+        Self { pool }
+    }
+
+    /// Open a snapshot written by [`Storage::snapshot`] read-only, to import it elsewhere.
+    ///
+    /// Snapshots are not migrated, so they have to be from the same version.
+    pub async fn open_snapshot(path: &Path) -> Result<Self, DBError> {
+        let options = SqliteConnectOptions::new().filename(path).read_only(true);
+        let pool = SqlitePool::connect_with(options)
+            .await
+            .map_err(DBError::OpenSnapshot)?;
+        // the migrations table is created at runtime, so this query cannot be checked offline
+        let version: Option<i64> =
+            sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success;")
+                .fetch_one(&pool)
+                .await
+                .map_err(DBError::OpenSnapshot)?;
+        if version != sqlx::migrate!().iter().map(|m| m.version).max() {
+            pool.close().await;
+            return Err(DBError::SnapshotVersion(version));
+        }
+        Ok(Self::new(pool))
+    }
+
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for SqliteStorage {
+    async fn migrate(&self) -> Result<(), DBError> {
+        sqlx::migrate!()
+            .run(&self.pool)
+            .await
+            .map_err(DBError::Migrate)
+    }
+
+    async fn get_bookings_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Booking>, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let start_str = start.format_with_items(fmt.clone()).to_string();
+        let end_str = end.format_with_items(fmt.clone()).to_string();
+        Ok(sqlx::query_as!(
+            NaiveBooking,
+            "SELECT source, booking_id, title, resource_id, start_time, end_time, \
+         setup_minutes, teardown_minutes FROM bookings \
+         WHERE start_time <= ? AND ? <= end_time
+         ORDER BY start_time;",
+            end_str,
+            start_str,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectBookings)?
+        .into_iter()
+        .map(NaiveBooking::interpret_as_utc)
+        .collect::<Vec<_>>())
+    }
+
+    async fn apply_booking_changes(&self, changes: &BookingChanges<'_>) -> Result<(), DBError> {
+        let mut tx = self.pool.begin().await.map_err(DBError::Transaction)?;
+        for booking in &changes.inserted {
+            insert_booking(&mut tx, booking).await?;
+        }
+        for booking_id in &changes.deleted {
+            delete_booking(&mut tx, changes.source, *booking_id).await?;
+        }
+        for booking in &changes.updated {
+            update_booking(&mut tx, booking).await?;
+        }
+        for delivery in &changes.deliveries {
+            insert_webhook_delivery(&mut tx, &delivery.target, delivery.event, &delivery.payload)
+                .await?;
+        }
+        insert_booking_events(&mut tx, changes.sync_run, &changes.history).await?;
+        tx.commit().await.map_err(DBError::Transaction)
+    }
+
+    async fn prune_old_bookings(
+        &self,
+        sync_run: Option<&str>,
+        reference: NaiveDateTime,
+    ) -> Result<u64, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let time_str = reference.format_with_items(fmt.clone()).to_string();
+        let now = chrono::Utc::now().format_with_items(fmt).to_string();
+        let pruned = BookingEventKind::Pruned.as_db_str();
+        let mut tx = self.pool.begin().await.map_err(DBError::DeleteBooking)?;
+        sqlx::query!(
+            "INSERT INTO booking_events \
+        (sync_run, occurred_at, kind, booking_id, source, resource_id, title, start_time, \
+        end_time) \
+        SELECT ?, ?, ?, booking_id, source, resource_id, title, start_time, end_time \
+        FROM bookings WHERE end_time < ? ORDER BY start_time;",
+            sync_run,
+            now,
+            pruned,
+            time_str,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::InsertBookingEvent)?;
+        sqlx::query!(
+            "INSERT OR REPLACE INTO booking_archive \
+        (origin, source, booking_id, title, resource_id, start_time, end_time, setup_minutes, \
+        teardown_minutes) \
+        SELECT 'churchtools', source, booking_id, title, resource_id, start_time, end_time, \
+        setup_minutes, teardown_minutes FROM bookings WHERE end_time < ?;",
+            time_str,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::ArchiveBookings)?;
+        let removed = sqlx::query!("DELETE FROM bookings where end_time < ?;", time_str,)
+            .execute(&mut *tx)
+            .await
+            .map(|x| x.rows_affected())
+            .map_err(DBError::DeleteBooking)?;
+        tx.commit().await.map_err(DBError::DeleteBooking)?;
+        Ok(removed)
+    }
+
+    async fn get_conflicts_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Conflict>, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let start_str = start.format_with_items(fmt.clone()).to_string();
+        let end_str = end.format_with_items(fmt).to_string();
+        Ok(sqlx::query_as!(
+            NaiveConflict,
+            "SELECT resource_id, first_source, first_booking_id, second_source, second_booking_id, \
+         overlap_start, overlap_end FROM conflicts \
+         WHERE overlap_start <= ? AND ? <= overlap_end;",
+            end_str,
+            start_str,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectConflicts)?
+        .into_iter()
+        .map(NaiveConflict::interpret_as_utc)
+        .collect::<Vec<_>>())
+    }
+
+    async fn get_conflict_reports(&self) -> Result<Vec<ConflictReport>, DBError> {
+        Ok(sqlx::query_as!(
+            NaiveConflictReport,
+            "SELECT c.resource_id, c.first_source, c.first_booking_id, c.second_source, \
+         c.second_booking_id, c.overlap_start, c.overlap_end, c.detected_at, \
+         f.title AS first_title, s.title AS second_title \
+         FROM conflicts c \
+         JOIN bookings f ON f.resource_id = c.resource_id AND f.source = c.first_source \
+         AND f.booking_id = c.first_booking_id \
+         JOIN bookings s ON s.resource_id = c.resource_id AND s.source = c.second_source \
+         AND s.booking_id = c.second_booking_id \
+         ORDER BY c.overlap_start;",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectConflicts)?
+        .into_iter()
+        .map(NaiveConflictReport::interpret_as_utc)
+        .collect::<Vec<_>>())
+    }
+
+    async fn insert_conflict(&self, conflict: &Conflict) -> Result<(), DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let overlap_start = conflict
+            .overlap_start
+            .format_with_items(fmt.clone())
+            .to_string();
+        let overlap_end = conflict
+            .overlap_end
+            .format_with_items(fmt.clone())
+            .to_string();
+        let detected_at = chrono::Utc::now().format_with_items(fmt).to_string();
+        sqlx::query!(
+            "INSERT INTO conflicts \
+        (first_source, first_booking_id, second_source, second_booking_id, resource_id, \
+        overlap_start, overlap_end, detected_at) \
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?);",
+            conflict.first_source,
+            conflict.first_booking_id,
+            conflict.second_source,
+            conflict.second_booking_id,
+            conflict.resource_id,
+            overlap_start,
+            overlap_end,
+            detected_at,
+        )
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(DBError::InsertConflict)
+    }
+
+    async fn delete_conflict(&self, conflict: &Conflict) -> Result<(), DBError> {
+        sqlx::query!(
+            "DELETE FROM conflicts \
+        WHERE resource_id = ? AND first_source = ? AND first_booking_id = ? \
+        AND second_source = ? AND second_booking_id = ?;",
+            conflict.resource_id,
+            conflict.first_source,
+            conflict.first_booking_id,
+            conflict.second_source,
+            conflict.second_booking_id,
+        )
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(DBError::DeleteConflict)
+    }
+
+    async fn prune_old_conflicts(&self, reference: NaiveDateTime) -> Result<u64, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let time_str = reference.format_with_items(fmt).to_string();
+        sqlx::query!("DELETE FROM conflicts where overlap_end < ?;", time_str,)
+            .execute(&self.pool)
+            .await
+            .map(|x| x.rows_affected())
+            .map_err(DBError::DeleteConflict)
+    }
+
+    async fn get_local_bookings_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<LocalBooking>, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let start_str = start.format_with_items(fmt.clone()).to_string();
+        let end_str = end.format_with_items(fmt).to_string();
+        Ok(sqlx::query_as!(
+            NaiveLocalBooking,
+            "SELECT id AS \"id!\", title, resource_id, start_time, end_time, kind, note, \
+         created_at FROM local_bookings \
+         WHERE start_time <= ? AND ? <= end_time \
+         ORDER BY start_time;",
+            end_str,
+            start_str,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectLocalBookings)?
+        .into_iter()
+        .map(NaiveLocalBooking::interpret_as_utc)
+        .collect::<Vec<_>>())
+    }
+
+    async fn insert_local_booking(&self, booking: &NewLocalBooking) -> Result<i64, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let start_str = booking
+            .start_time
+            .format_with_items(fmt.clone())
+            .to_string();
+        let end_str = booking.end_time.format_with_items(fmt.clone()).to_string();
+        let created_at = chrono::Utc::now().format_with_items(fmt).to_string();
+        let kind = booking.kind.as_db_str();
+        sqlx::query!(
+            "INSERT INTO local_bookings \
+        (title, resource_id, start_time, end_time, kind, note, created_at) \
+        VALUES (?, ?, ?, ?, ?, ?, ?);",
+            booking.title,
+            booking.resource_id,
+            start_str,
+            end_str,
+            kind,
+            booking.note,
+            created_at,
+        )
+        .execute(&self.pool)
+        .await
+        .map(|x| x.last_insert_rowid())
+        .map_err(DBError::InsertLocalBooking)
+    }
+
+    async fn delete_local_booking(&self, id: i64) -> Result<bool, DBError> {
+        sqlx::query!("DELETE FROM local_bookings WHERE id = ?;", id)
+            .execute(&self.pool)
+            .await
+            .map(|x| x.rows_affected() > 0)
+            .map_err(DBError::DeleteLocalBooking)
+    }
+
+    async fn prune_old_local_bookings(&self, reference: NaiveDateTime) -> Result<u64, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let time_str = reference.format_with_items(fmt).to_string();
+        let booking = LocalBookingKind::Booking.as_db_str();
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(DBError::DeleteLocalBooking)?;
+        // blocks are not archived, they are no use of the room
+        sqlx::query!(
+            "INSERT OR REPLACE INTO booking_archive \
+        (origin, source, booking_id, title, resource_id, start_time, end_time) \
+        SELECT 'local', 'local', id, title, resource_id, start_time, end_time \
+        FROM local_bookings WHERE end_time < ? AND kind = ?;",
+            time_str,
+            booking,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::ArchiveBookings)?;
+        let removed = sqlx::query!("DELETE FROM local_bookings where end_time < ?;", time_str,)
+            .execute(&mut *tx)
+            .await
+            .map(|x| x.rows_affected())
+            .map_err(DBError::DeleteLocalBooking)?;
+        tx.commit().await.map_err(DBError::DeleteLocalBooking)?;
+        Ok(removed)
+    }
+
+    async fn get_archived_bookings_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Booking>, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let start_str = start.format_with_items(fmt.clone()).to_string();
+        let end_str = end.format_with_items(fmt).to_string();
+        Ok(sqlx::query_as!(
+            NaiveArchivedBooking,
+            "SELECT origin, source, booking_id, title, resource_id, start_time, end_time, \
+         setup_minutes, teardown_minutes FROM booking_archive \
+         WHERE start_time <= ? AND ? <= end_time;",
+            end_str,
+            start_str,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectArchive)?
+        .into_iter()
+        .map(NaiveArchivedBooking::interpret_as_utc)
+        .collect::<Vec<_>>())
+    }
+
+    async fn get_announcements_in_timeframe(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    ) -> Result<Vec<Announcement>, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let start_str = start.format_with_items(fmt.clone()).to_string();
+        let end_str = end.format_with_items(fmt).to_string();
+        let mut announcements = sqlx::query_as!(
+            NaiveAnnouncement,
+            "SELECT id, message, start_time, end_time, priority, rooms, \
+         show_on_landing as \"show_on_landing: bool\", created_at \
+         FROM announcements \
+         WHERE start_time <= ? AND ? <= end_time \
+         ORDER BY start_time;",
+            end_str,
+            start_str,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectAnnouncements)?
+        .into_iter()
+        .map(NaiveAnnouncement::interpret_as_utc)
+        .collect::<Vec<_>>();
+        // stable, so announcements of equal priority stay ordered by start
+        announcements.sort_by_key(|a| core::cmp::Reverse(a.priority));
+        Ok(announcements)
+    }
+
+    async fn insert_announcement(&self, announcement: &NewAnnouncement) -> Result<i64, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let start_str = announcement
+            .start_time
+            .format_with_items(fmt.clone())
+            .to_string();
+        let end_str = announcement
+            .end_time
+            .format_with_items(fmt.clone())
+            .to_string();
+        let created_at = chrono::Utc::now().format_with_items(fmt).to_string();
+        let priority = announcement.priority.as_db_str();
+        let rooms = rooms_to_db_str(&announcement.rooms);
+        sqlx::query!(
+            "INSERT INTO announcements \
+        (message, start_time, end_time, priority, rooms, show_on_landing, created_at) \
+        VALUES (?, ?, ?, ?, ?, ?, ?);",
+            announcement.message,
+            start_str,
+            end_str,
+            priority,
+            rooms,
+            announcement.show_on_landing,
+            created_at,
+        )
+        .execute(&self.pool)
+        .await
+        .map(|x| x.last_insert_rowid())
+        .map_err(DBError::InsertAnnouncement)
+    }
+
+    async fn delete_announcement(&self, id: i64) -> Result<bool, DBError> {
+        sqlx::query!("DELETE FROM announcements WHERE id = ?;", id)
+            .execute(&self.pool)
+            .await
+            .map(|x| x.rows_affected() > 0)
+            .map_err(DBError::DeleteAnnouncement)
+    }
+
+    async fn prune_old_announcements(&self, reference: NaiveDateTime) -> Result<u64, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let time_str = reference.format_with_items(fmt).to_string();
+        sqlx::query!("DELETE FROM announcements where end_time < ?;", time_str,)
+            .execute(&self.pool)
+            .await
+            .map(|x| x.rows_affected())
+            .map_err(DBError::DeleteAnnouncement)
+    }
+
+    async fn get_admin_session(&self, id: &str) -> Result<Option<AdminSession>, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let now_str = chrono::Utc::now().format_with_items(fmt).to_string();
+        Ok(sqlx::query_as!(
+            NaiveAdminSession,
+            "SELECT id, user_name, csrf_token, expires_at FROM admin_sessions \
+         WHERE id = ? AND ? < expires_at;",
+            id,
+            now_str,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(DBError::SelectSession)?
+        .map(NaiveAdminSession::interpret_as_utc))
+    }
+
+    async fn insert_admin_session(&self, session: &AdminSession) -> Result<(), DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let created_at = chrono::Utc::now()
+            .format_with_items(fmt.clone())
+            .to_string();
+        let expires_at = session.expires_at.format_with_items(fmt).to_string();
+        sqlx::query!(
+            "INSERT INTO admin_sessions (id, user_name, csrf_token, created_at, expires_at) \
+        VALUES (?, ?, ?, ?, ?);",
+            session.id,
+            session.user_name,
+            session.csrf_token,
+            created_at,
+            expires_at,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(DBError::InsertSession)?;
+        Ok(())
+    }
+
+    async fn delete_admin_session(&self, id: &str) -> Result<(), DBError> {
+        sqlx::query!("DELETE FROM admin_sessions WHERE id = ?;", id)
+            .execute(&self.pool)
+            .await
+            .map_err(DBError::DeleteSession)?;
+        Ok(())
+    }
+
+    async fn prune_expired_admin_sessions(&self) -> Result<u64, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let now_str = chrono::Utc::now().format_with_items(fmt).to_string();
+        sqlx::query!("DELETE FROM admin_sessions WHERE expires_at <= ?;", now_str)
+            .execute(&self.pool)
+            .await
+            .map(|x| x.rows_affected())
+            .map_err(DBError::DeleteSession)
+    }
+
+    async fn get_statistics(&self) -> Result<DbStatistics, DBError> {
+        sqlx::query_as!(
+            DbStatistics,
+            "SELECT \
+         (SELECT COUNT(*) FROM bookings) AS \"bookings!: i64\", \
+         (SELECT COUNT(*) FROM local_bookings) AS \"local_bookings!: i64\", \
+         (SELECT COUNT(*) FROM conflicts) AS \"conflicts!: i64\", \
+         (SELECT COUNT(*) FROM announcements) AS \"announcements!: i64\", \
+         (SELECT COUNT(*) FROM admin_sessions) AS \"admin_sessions!: i64\", \
+         (SELECT COUNT(*) FROM webhook_deliveries) AS \"webhook_deliveries!: i64\", \
+         (SELECT COUNT(*) FROM booking_events) AS \"booking_events!: i64\", \
+         (SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()) \
+         AS \"size_bytes!: i64\";"
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(DBError::Statistics)
+    }
+
+    async fn get_api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>, DBError> {
+        Ok(sqlx::query_as!(
+            NaiveApiToken,
+            "SELECT id AS \"id!\", name, token_hash, scopes, created_at, expires_at, last_used_at \
+         FROM api_tokens WHERE token_hash = ?;",
+            token_hash,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(DBError::SelectApiTokens)?
+        .map(NaiveApiToken::interpret_as_utc))
+    }
+
+    async fn get_api_tokens(&self) -> Result<Vec<ApiToken>, DBError> {
+        Ok(sqlx::query_as!(
+            NaiveApiToken,
+            "SELECT id AS \"id!\", name, token_hash, scopes, created_at, expires_at, last_used_at \
+         FROM api_tokens ORDER BY name;",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectApiTokens)?
+        .into_iter()
+        .map(NaiveApiToken::interpret_as_utc)
+        .collect())
+    }
+
+    async fn insert_api_token(
+        &self,
+        name: &str,
+        token_hash: &str,
+        scopes: &[Scope],
+        expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<i64, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let created_at = chrono::Utc::now()
+            .format_with_items(fmt.clone())
+            .to_string();
+        let expires_at = expires_at.map(|t| t.format_with_items(fmt).to_string());
+        let scopes = scopes_to_db_str(scopes);
+        sqlx::query!(
+            "INSERT INTO api_tokens (name, token_hash, scopes, created_at, expires_at) \
+        VALUES (?, ?, ?, ?, ?);",
+            name,
+            token_hash,
+            scopes,
+            created_at,
+            expires_at,
+        )
+        .execute(&self.pool)
+        .await
+        .map(|x| x.last_insert_rowid())
+        .map_err(DBError::InsertApiToken)
+    }
+
+    async fn touch_api_token(
+        &self,
+        id: i64,
+        at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let at = at.format_with_items(fmt).to_string();
+        sqlx::query!(
+            "UPDATE api_tokens SET last_used_at = ? WHERE id = ?;",
+            at,
+            id
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(DBError::UpdateApiToken)?;
+        Ok(())
+    }
+
+    async fn delete_api_token(&self, name: &str) -> Result<bool, DBError> {
+        sqlx::query!("DELETE FROM api_tokens WHERE name = ?;", name)
+            .execute(&self.pool)
+            .await
+            .map(|x| x.rows_affected() > 0)
+            .map_err(DBError::DeleteApiToken)
+    }
+
+    async fn insert_webhook_delivery(
+        &self,
+        target: &str,
+        event: &str,
+        payload: &str,
+    ) -> Result<i64, DBError> {
+        let mut conn = self
+            .pool
+            .acquire()
+            .await
+            .map_err(DBError::InsertWebhookDelivery)?;
+        insert_webhook_delivery(&mut conn, target, event, payload).await
+    }
+
+    async fn claim_due_webhook_deliveries(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        claimed_until: chrono::DateTime<chrono::Utc>,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let now = now.format_with_items(fmt.clone()).to_string();
+        let claimed_until = claimed_until.format_with_items(fmt).to_string();
+        // SQLite has a single writer, so no two instances claim the same row
+        let mut deliveries = sqlx::query_as!(
+            NaiveWebhookDelivery,
+            "UPDATE webhook_deliveries SET claimed_until = ? \
+         WHERE id IN (SELECT id FROM webhook_deliveries \
+         WHERE next_attempt_at <= ? AND (claimed_until IS NULL OR claimed_until <= ?) \
+         ORDER BY id LIMIT ?) \
+         RETURNING id AS \"id!\", target, event, payload, attempts, next_attempt_at;",
+            claimed_until,
+            now,
+            now,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectWebhookDeliveries)?
+        .into_iter()
+        .map(NaiveWebhookDelivery::interpret_as_utc)
+        .collect::<Vec<_>>();
+        // RETURNING does not keep the order of the subquery
+        deliveries.sort_by_key(|d| d.id);
+        Ok(deliveries)
+    }
+
+    async fn record_failed_webhook_delivery(
+        &self,
+        id: i64,
+        error: &str,
+        next_attempt_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let next_attempt_at = next_attempt_at.format_with_items(fmt).to_string();
+        sqlx::query!(
+            "UPDATE webhook_deliveries SET attempts = attempts + 1, last_error = ?, \
+         next_attempt_at = ?, claimed_until = NULL WHERE id = ?;",
+            error,
+            next_attempt_at,
+            id,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(DBError::UpdateWebhookDelivery)?;
+        Ok(())
+    }
+
+    async fn delete_webhook_delivery(&self, id: i64) -> Result<(), DBError> {
+        sqlx::query!("DELETE FROM webhook_deliveries WHERE id = ?;", id)
+            .execute(&self.pool)
+            .await
+            .map_err(DBError::DeleteWebhookDelivery)?;
+        Ok(())
+    }
+
+    async fn get_booking_events(
+        &self,
+        filter: &BookingEventFilter,
+        limit: i64,
+    ) -> Result<Vec<BookingEvent>, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let since = filter
+            .since
+            .map(|t| t.format_with_items(fmt.clone()).to_string());
+        let until = filter.until.map(|t| t.format_with_items(fmt).to_string());
+        Ok(sqlx::query_as!(
+            NaiveBookingEvent,
+            "SELECT id, sync_run, occurred_at, kind, booking_id, source, resource_id, title, \
+         start_time, end_time, diff FROM booking_events \
+         WHERE (?1 IS NULL OR booking_id = ?1) AND (?2 IS NULL OR resource_id = ?2) \
+         AND (?3 IS NULL OR sync_run = ?3) AND (?4 IS NULL OR occurred_at >= ?4) \
+         AND (?5 IS NULL OR occurred_at < ?5) \
+         ORDER BY id DESC LIMIT ?6;",
+            filter.booking_id,
+            filter.room,
+            filter.sync_run,
+            since,
+            until,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(DBError::SelectBookingEvents)?
+        .into_iter()
+        .map(NaiveBookingEvent::interpret_as_utc)
+        .collect())
+    }
+
+    async fn prune_old_booking_events(&self, reference: NaiveDateTime) -> Result<u64, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let time_str = reference.format_with_items(fmt).to_string();
+        sqlx::query!(
+            "DELETE FROM booking_events WHERE occurred_at < ?;",
+            time_str
+        )
+        .execute(&self.pool)
+        .await
+        .map(|x| x.rows_affected())
+        .map_err(DBError::DeleteBookingEvent)
+    }
+
+    async fn claim_job_run(
+        &self,
+        job: &str,
+        since: chrono::DateTime<chrono::Utc>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<bool, DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let since = since.format_with_items(fmt.clone()).to_string();
+        let now = now.format_with_items(fmt).to_string();
+        sqlx::query!(
+            "INSERT INTO job_runs (job, last_run) VALUES (?, ?) \
+         ON CONFLICT (job) DO UPDATE SET last_run = excluded.last_run \
+         WHERE job_runs.last_run < ?;",
+            job,
+            now,
+            since,
+        )
+        .execute(&self.pool)
+        .await
+        .map(|x| x.rows_affected() == 1)
+        .map_err(DBError::ClaimJobRun)
+    }
+
+    async fn export(&self) -> Result<DbExport, DBError> {
+        let mut tx = self.pool.begin().await.map_err(DBError::Export)?;
+        let bookings = sqlx::query_as!(
+            NaiveBooking,
+            "SELECT source, booking_id, title, resource_id, start_time, end_time, setup_minutes, \
+         teardown_minutes FROM bookings ORDER BY source, booking_id;"
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(DBError::Export)?;
+        let local_bookings = sqlx::query_as!(
+            NaiveLocalBooking,
+            "SELECT id, title, resource_id, start_time, end_time, kind, note, created_at \
+         FROM local_bookings ORDER BY id;"
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(DBError::Export)?;
+        let archived_bookings = sqlx::query_as!(
+            NaiveArchivedBooking,
+            "SELECT origin, source, booking_id, title, resource_id, start_time, end_time, \
+         setup_minutes, teardown_minutes FROM booking_archive \
+         ORDER BY origin, source, booking_id;"
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(DBError::Export)?;
+        let booking_events = sqlx::query_as!(
+            NaiveBookingEvent,
+            "SELECT id, sync_run, occurred_at, kind, booking_id, source, resource_id, title, \
+         start_time, end_time, diff FROM booking_events ORDER BY id;"
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(DBError::Export)?;
+        tx.commit().await.map_err(DBError::Export)?;
+        Ok(DbExport {
+            version: EXPORT_VERSION,
+            exported_at: Utc::now(),
+            bookings: bookings
+                .into_iter()
+                .map(NaiveBooking::interpret_as_utc)
+                .collect(),
+            local_bookings: local_bookings
+                .into_iter()
+                .map(NaiveLocalBooking::interpret_as_utc)
+                .collect(),
+            archived_bookings: archived_bookings
+                .into_iter()
+                .map(NaiveArchivedBooking::interpret_as_utc)
+                .collect(),
+            booking_events: booking_events
+                .into_iter()
+                .map(NaiveBookingEvent::interpret_as_utc)
+                .collect(),
+        })
+    }
+
+    async fn import(&self, data: &DbExport) -> Result<(), DBError> {
+        let fmt = StrftimeItems::new(TIME_FORMAT);
+        let time = |t: DateTime<Utc>| t.format_with_items(fmt.clone()).to_string();
+        let mut tx = self.pool.begin().await.map_err(DBError::Import)?;
+        for table in super::IMPORTED_TABLES {
+            sqlx::query(&format!("DELETE FROM {table};"))
+                .execute(&mut *tx)
+                .await
+                .map_err(DBError::Import)?;
+        }
+        for b in &data.bookings {
+            let (start_time, end_time) = (time(b.start_time), time(b.end_time));
+            sqlx::query!(
+                "INSERT INTO bookings (source, booking_id, title, resource_id, start_time, \
+            end_time, setup_minutes, teardown_minutes) VALUES (?, ?, ?, ?, ?, ?, ?, ?);",
+                b.source,
+                b.booking_id,
+                b.title,
+                b.resource_id,
+                start_time,
+                end_time,
+                b.setup_minutes,
+                b.teardown_minutes,
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::Import)?;
+        }
+        for b in &data.local_bookings {
+            let (start_time, end_time) = (time(b.start_time), time(b.end_time));
+            let created_at = time(b.created_at);
+            let kind = b.kind.as_db_str();
+            sqlx::query!(
+                "INSERT INTO local_bookings \
+            (id, title, resource_id, start_time, end_time, kind, note, created_at) \
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?);",
+                b.id,
+                b.title,
+                b.resource_id,
+                start_time,
+                end_time,
+                kind,
+                b.note,
+                created_at,
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::Import)?;
+        }
+        for b in &data.archived_bookings {
+            let (start_time, end_time) = (time(b.start_time), time(b.end_time));
+            let origin = b.origin.as_db_str();
+            sqlx::query!(
+                "INSERT INTO booking_archive (origin, source, booking_id, title, resource_id, \
+            start_time, end_time, setup_minutes, teardown_minutes) \
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);",
+                origin,
+                b.source,
+                b.booking_id,
+                b.title,
+                b.resource_id,
+                start_time,
+                end_time,
+                b.setup_minutes,
+                b.teardown_minutes,
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::Import)?;
+        }
+        for e in &data.booking_events {
+            let (start_time, end_time) = (time(e.start_time), time(e.end_time));
+            let occurred_at = time(e.occurred_at);
+            let kind = e.kind.as_db_str();
+            let diff = serde_json::to_string(&e.diff).expect("field changes are always valid json");
+            sqlx::query!(
+                "INSERT INTO booking_events (id, sync_run, occurred_at, kind, booking_id, \
+            source, resource_id, title, start_time, end_time, diff) \
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+                e.id,
+                e.sync_run,
+                occurred_at,
+                kind,
+                e.booking_id,
+                e.source,
+                e.resource_id,
+                e.title,
+                start_time,
+                end_time,
+                diff,
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::Import)?;
+        }
+        tx.commit().await.map_err(DBError::Import)
+    }
+
+    async fn snapshot(&self, path: &Path) -> Result<(), DBError> {
+        let path = path.to_string_lossy();
+        sqlx::query!("VACUUM INTO ?;", path)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(DBError::Snapshot)
+    }
+}
@@ -0,0 +1,768 @@
+//! Emails: a daily digest of the bookings per room group, and alerts when the sync keeps failing.
+//!
+//! The digest lists the bookings of the day by building and the changes the sync made since the
+//! last digest.
+
+use std::{sync::Arc, time::Duration};
+
+use askama::Template;
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeDelta, Utc};
+use chrono_tz::Europe::Berlin;
+use itertools::Itertools;
+use lettre::{
+    message::{header::ContentType, Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{Config, EmailConfig, EmailGroup, RoomConfig, SmtpSecurity},
+    db::DBError,
+    history::{BookingEvent, BookingEventFilter, BookingEventKind},
+    pdf::{day_interval_utc, WEEKDAYS},
+    sync::{SyncState, SyncStatus},
+    Booking, BookingOrigin, InShutdown,
+};
+
+/// Check the sync status this often
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// Rooms without a building are listed under this heading
+const NO_BUILDING: &str = "Weitere Räume";
+/// At most this many changes are listed in a digest
+const MAX_DIGEST_CHANGES: i64 = 1000;
+
+#[derive(Debug)]
+pub enum EmailError {
+    DB(DBError),
+    Address(lettre::address::AddressError),
+    Build(lettre::error::Error),
+    Render(askama::Error),
+    Smtp(lettre::transport::smtp::Error),
+}
+impl core::fmt::Display for EmailError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::DB(e) => write!(f, "{e}"),
+            Self::Address(e) => write!(f, "Invalid email address. Inner Error: {e}."),
+            Self::Build(e) => write!(f, "Unable to build the email. Inner Error: {e}."),
+            Self::Render(e) => write!(f, "Unable to render the email. Inner Error: {e}."),
+            Self::Smtp(e) => write!(f, "Unable to send the email. Inner Error: {e}."),
+        }
+    }
+}
+impl core::error::Error for EmailError {}
+impl From<DBError> for EmailError {
+    fn from(value: DBError) -> Self {
+        Self::DB(value)
+    }
+}
+
+/// A booking as listed in the digest
+#[derive(Debug, PartialEq)]
+struct DigestBooking {
+    time: String,
+    room: String,
+    title: String,
+    local: bool,
+}
+
+#[derive(Debug, PartialEq)]
+struct DigestBuilding {
+    name: String,
+    bookings: Vec<DigestBooking>,
+}
+
+#[derive(Debug, PartialEq)]
+struct DigestChange {
+    kind: &'static str,
+    time: String,
+    room: String,
+    title: String,
+}
+
+/// Everything one group is told about one day
+#[derive(Debug, PartialEq)]
+struct Digest {
+    group: String,
+    day: String,
+    buildings: Vec<DigestBuilding>,
+    changes: Vec<DigestChange>,
+}
+impl Digest {
+    fn subject(&self) -> String {
+        format!("Raumbelegung {}: {}", self.group, self.day)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "email_digest.html")]
+struct DigestHtmlTemplate<'a> {
+    digest: &'a Digest,
+}
+
+#[derive(Template)]
+#[template(path = "email_digest.txt")]
+struct DigestTextTemplate<'a> {
+    digest: &'a Digest,
+}
+
+#[derive(Template)]
+#[template(path = "email_sync_alert.txt")]
+struct SyncAlertTemplate<'a> {
+    since: String,
+    /// None if the sync works again
+    error: Option<&'a str>,
+}
+
+/// "18:00-20:00" for bookings on `day`, with dates for the parts on other days
+fn time_range(day: NaiveDate, start: DateTime<Utc>, end: DateTime<Utc>) -> String {
+    let fmt = |t: DateTime<Utc>| {
+        let t = t.with_timezone(&Berlin);
+        if t.date_naive() == day {
+            t.format("%H:%M").to_string()
+        } else {
+            t.format("%d.%m. %H:%M").to_string()
+        }
+    };
+    format!("{}-{}", fmt(start), fmt(end))
+}
+
+fn format_day(day: NaiveDate) -> String {
+    use chrono::Datelike;
+    format!(
+        "{}, {}",
+        WEEKDAYS[day.weekday().num_days_from_monday() as usize],
+        day.format("%d.%m.%Y")
+    )
+}
+
+/// The digest of `group` for `day`, from the visible bookings of that day and recent changes
+fn build_digest(
+    rooms: &[RoomConfig],
+    group: &EmailGroup,
+    day: NaiveDate,
+    bookings: &[Booking],
+    changes: &[BookingEvent],
+) -> Digest {
+    let rooms = rooms
+        .iter()
+        .filter(|r| group.contains(r))
+        .collect::<Vec<_>>();
+    let room_of = |id: i64| rooms.iter().find(|r| r.id() == id);
+
+    let mut buildings: Vec<DigestBuilding> = vec![];
+    for booking in bookings {
+        let Some(room) = room_of(booking.resource_id) else {
+            continue;
+        };
+        let building = room.building.as_deref().unwrap_or(NO_BUILDING);
+        let entry = DigestBooking {
+            time: time_range(day, booking.start_time, booking.end_time),
+            room: room.name.clone(),
+            title: booking.title.clone(),
+            local: booking.origin == BookingOrigin::Local,
+        };
+        match buildings.iter_mut().find(|b| b.name == building) {
+            Some(b) => b.bookings.push(entry),
+            None => buildings.push(DigestBuilding {
+                name: building.to_owned(),
+                bookings: vec![entry],
+            }),
+        }
+    }
+    // the catch-all heading goes last
+    buildings.sort_by_key(|b| b.name == NO_BUILDING);
+
+    let changes = changes
+        .iter()
+        .filter(|c| c.kind != BookingEventKind::Pruned)
+        .filter_map(|c| {
+            let room = room_of(c.resource_id)?;
+            let start_day = c.start_time.with_timezone(&Berlin).date_naive();
+            Some(DigestChange {
+                kind: c.kind.label(),
+                time: format!(
+                    "{} {}",
+                    start_day.format("%d.%m.%Y"),
+                    time_range(start_day, c.start_time, c.end_time)
+                ),
+                room: room.name.clone(),
+                title: c.title.clone(),
+            })
+        })
+        .collect();
+
+    Digest {
+        group: group.name.clone(),
+        day: format_day(day),
+        buildings,
+        changes,
+    }
+}
+
+/// Something to tell the alert recipients about the sync
+#[derive(Debug, PartialEq)]
+enum SyncAlert {
+    Failing { since: DateTime<Utc>, error: String },
+    Recovered { since: DateTime<Utc> },
+}
+
+/// Follows the sync status to alert once when it starts failing and once when it recovers
+#[derive(Debug, Default)]
+struct SyncWatch {
+    failing_since: Option<DateTime<Utc>>,
+    alerted: bool,
+}
+impl SyncWatch {
+    fn observe(
+        &mut self,
+        status: &SyncStatus,
+        now: DateTime<Utc>,
+        threshold: TimeDelta,
+    ) -> Option<SyncAlert> {
+        if status.last_results.is_empty() {
+            return None;
+        }
+        // the sync is failing as long as the last run of any source failed
+        let failing = status
+            .last_results
+            .values()
+            .filter_map(|run| Some((run, run.error.as_ref()?)))
+            .collect::<Vec<_>>();
+        match failing.iter().map(|(run, _)| run.started_at).min() {
+            Some(started_at) => {
+                let since = *self.failing_since.get_or_insert(started_at);
+                if self.alerted || now - since < threshold {
+                    return None;
+                }
+                self.alerted = true;
+                Some(SyncAlert::Failing {
+                    since,
+                    error: failing
+                        .iter()
+                        .map(|(run, error)| format!("{}: {error}", run.source))
+                        .join("\n"),
+                })
+            }
+            None => {
+                let since = self.failing_since.take();
+                if !core::mem::take(&mut self.alerted) {
+                    return None;
+                }
+                since.map(|since| SyncAlert::Recovered { since })
+            }
+        }
+    }
+}
+
+/// The first time after `now` at which `time` is reached in local time
+fn next_digest_time(time: NaiveTime, now: DateTime<Utc>) -> DateTime<Utc> {
+    let today = now.with_timezone(&Berlin).date_naive();
+    (0..=2)
+        .filter_map(|offset| {
+            let local = (today + TimeDelta::days(offset)).and_time(time);
+            // a time skipped by DST happens an hour later that day
+            local
+                .and_local_timezone(Berlin)
+                .earliest()
+                .or_else(|| {
+                    (local + TimeDelta::hours(1))
+                        .and_local_timezone(Berlin)
+                        .earliest()
+                })
+                .map(|t| t.to_utc())
+        })
+        .find(|t| *t > now)
+        .expect("the digest time is reached within two days")
+}
+
+/// Sends emails over the configured SMTP server
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+impl Mailer {
+    pub fn new(email: &EmailConfig) -> Result<Self, EmailError> {
+        let mut builder = match email.smtp_security {
+            SmtpSecurity::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&email.smtp_host)
+                    .map_err(EmailError::Smtp)?
+            }
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&email.smtp_host)
+                .map_err(EmailError::Smtp)?,
+            SmtpSecurity::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&email.smtp_host)
+            }
+        };
+        if let Some(port) = email.smtp_port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&email.username, &email.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(Self {
+            transport: builder.build(),
+            from: email.from.parse().map_err(EmailError::Address)?,
+        })
+    }
+
+    /// Send a mail with a plain text body and optionally an html alternative
+    async fn send(
+        &self,
+        to: &[String],
+        subject: &str,
+        text: String,
+        html: Option<String>,
+    ) -> Result<(), EmailError> {
+        if to.is_empty() {
+            return Ok(());
+        }
+        let mut builder = Message::builder().from(self.from.clone()).subject(subject);
+        for address in to {
+            builder = builder.to(address.parse().map_err(EmailError::Address)?);
+        }
+        let message = match html {
+            Some(html) => builder.multipart(MultiPart::alternative_plain_html(text, html)),
+            None => builder.header(ContentType::TEXT_PLAIN).body(text),
+        }
+        .map_err(EmailError::Build)?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(EmailError::Smtp)?;
+        Ok(())
+    }
+
+    async fn send_digest(&self, group: &EmailGroup, digest: &Digest) -> Result<(), EmailError> {
+        let text = DigestTextTemplate { digest }
+            .render()
+            .map_err(EmailError::Render)?;
+        let html = DigestHtmlTemplate { digest }
+            .render()
+            .map_err(EmailError::Render)?;
+        self.send(&group.recipients, &digest.subject(), text, Some(html))
+            .await
+    }
+
+    async fn send_sync_alert(&self, to: &[String], alert: &SyncAlert) -> Result<(), EmailError> {
+        let (subject, since, error) = match alert {
+            SyncAlert::Failing { since, error } => (
+                "Synchronisation mit ChurchTools schlägt fehl",
+                since,
+                Some(error.as_str()),
+            ),
+            SyncAlert::Recovered { since } => (
+                "Synchronisation mit ChurchTools funktioniert wieder",
+                since,
+                None,
+            ),
+        };
+        let text = SyncAlertTemplate {
+            error,
+            since: since
+                .with_timezone(&Berlin)
+                .format("%d.%m.%Y %H:%M")
+                .to_string(),
+        }
+        .render()
+        .map_err(EmailError::Render)?;
+        self.send(to, subject, text, None).await
+    }
+}
+
+/// Send the digest of the day containing `now` to every group
+pub async fn send_digests(
+    config: &Config,
+    email: &EmailConfig,
+    mailer: &Mailer,
+    now: DateTime<Utc>,
+) -> Result<(), EmailError> {
+    let day = now.with_timezone(&Berlin).date_naive();
+    let (start, end) = day_interval_utc(day);
+    let bookings = config
+        .db
+        .get_visible_bookings_in_timeframe(start, end)
+        .await?;
+    let filter = BookingEventFilter {
+        since: Some(now - TimeDelta::days(1)),
+        ..Default::default()
+    };
+    // oldest first
+    let mut changes = config
+        .db
+        .get_booking_events(&filter, MAX_DIGEST_CHANGES)
+        .await?;
+    changes.reverse();
+    for group in &email.groups {
+        let digest = build_digest(&config.rooms, group, day, &bookings, &changes);
+        mailer.send_digest(group, &digest).await?;
+        debug!("Sent the digest to group {}.", group.name);
+    }
+    Ok(())
+}
+
+/// Send the daily digests and sync alerts, until shutdown.
+///
+/// Sync alerts are about the syncs of this instance, so every instance sends its own.
+pub async fn keep_sending_emails(
+    config: Arc<Config>,
+    sync: Arc<SyncState>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+) {
+    let Some(email) = &config.email else {
+        return;
+    };
+    let mailer = match Mailer::new(email) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Not sending any emails: {e}");
+            return;
+        }
+    };
+    info!("Starting email task");
+    let mut next_digest = next_digest_time(email.digest_time, Utc::now());
+    let mut sync_watch = SyncWatch::default();
+    let mut sync_status = sync.subscribe();
+    loop {
+        let now = Utc::now();
+        if now >= next_digest {
+            // of several instances sharing the db, only the first one sends the digest
+            match config.db.claim_job_run("digest", next_digest, now).await {
+                Ok(true) => {
+                    if let Err(e) = send_digests(&config, email, &mailer, now).await {
+                        warn!("Unable to send the daily digest: {e}");
+                    }
+                }
+                Ok(false) => debug!("Another instance sent the daily digest."),
+                Err(e) => warn!("Unable to send the daily digest: {e}"),
+            }
+            next_digest = next_digest_time(email.digest_time, now);
+        }
+        if let Some(minutes) = email.sync_alert_minutes {
+            let status = sync.status();
+            if let Some(alert) = sync_watch.observe(&status, now, TimeDelta::minutes(minutes)) {
+                if let Err(e) = mailer
+                    .send_sync_alert(&email.alert_recipients, &alert)
+                    .await
+                {
+                    warn!("Unable to send the sync alert: {e}");
+                }
+            }
+        }
+        let sleep = (next_digest - now)
+            .to_std()
+            .map_or(POLL_INTERVAL, |d| d.min(POLL_INTERVAL));
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down email task now.");
+                return;
+            }
+            _ = sync_status.changed() => {}
+            () = tokio::time::sleep(sleep) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use crate::{
+        sync::SyncRun,
+        test_helpers::{booking, room, time},
+    };
+
+    fn group(rooms: Vec<i64>, buildings: Vec<&str>) -> EmailGroup {
+        EmailGroup {
+            name: "Küster".to_owned(),
+            recipients: vec!["kuester@example.com".to_owned()],
+            rooms,
+            buildings: buildings.into_iter().map(ToOwned::to_owned).collect(),
+        }
+    }
+
+    fn rooms() -> Vec<RoomConfig> {
+        let room = |id, name: &str, building: Option<&str>| RoomConfig {
+            name: name.to_owned(),
+            building: building.map(ToOwned::to_owned),
+            ..room(id)
+        };
+        vec![
+            room(10, "Keller", None),
+            room(12, "Saal", Some("Gemeindehaus")),
+            room(13, "Kapelle", Some("Kirche")),
+        ]
+    }
+
+    fn day() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 10, 16).unwrap()
+    }
+
+    #[test]
+    fn digest_by_building() {
+        let mut local = booking(
+            "Putzen",
+            10,
+            "2024-10-16T08:00:00+00:00",
+            "2024-10-16T09:00:00+00:00",
+        );
+        local.origin = BookingOrigin::Local;
+        let bookings = vec![
+            local,
+            booking(
+                "Chorprobe",
+                12,
+                "2024-10-16T16:00:00+00:00",
+                "2024-10-16T18:00:00+00:00",
+            ),
+            booking(
+                "Nachtgebet",
+                13,
+                "2024-10-16T21:00:00+00:00",
+                "2024-10-17T01:00:00+00:00",
+            ),
+        ];
+        let event = |kind| BookingEvent {
+            id: 1,
+            sync_run: None,
+            occurred_at: time("2024-10-15T12:00:00+00:00"),
+            kind,
+            booking_id: 2,
+            source: "churchtools".to_owned(),
+            resource_id: 13,
+            title: "Taufe".to_owned(),
+            start_time: time("2024-10-20T09:00:00+00:00"),
+            end_time: time("2024-10-20T10:00:00+00:00"),
+            diff: vec![],
+        };
+        // pruning is not interesting for the digest
+        let changes = vec![
+            event(BookingEventKind::Deleted),
+            event(BookingEventKind::Pruned),
+        ];
+
+        let digest = build_digest(&rooms(), &group(vec![], vec![]), day(), &bookings, &changes);
+        assert_eq!(digest.day, "Mittwoch, 16.10.2024");
+        assert_eq!(
+            digest
+                .buildings
+                .iter()
+                .map(|b| b.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Gemeindehaus", "Kirche", NO_BUILDING]
+        );
+        assert_eq!(
+            digest.buildings[1].bookings,
+            vec![DigestBooking {
+                time: "23:00-17.10. 03:00".to_owned(),
+                room: "Kapelle".to_owned(),
+                title: "Nachtgebet".to_owned(),
+                local: false,
+            }]
+        );
+        assert!(digest.buildings[2].bookings[0].local);
+        assert_eq!(
+            digest.changes,
+            vec![DigestChange {
+                kind: "Gelöscht",
+                time: "20.10.2024 11:00-12:00".to_owned(),
+                room: "Kapelle".to_owned(),
+                title: "Taufe".to_owned(),
+            }]
+        );
+
+        // only the rooms of the group
+        let digest = build_digest(
+            &rooms(),
+            &group(vec![10], vec!["Gemeindehaus"]),
+            day(),
+            &bookings,
+            &changes,
+        );
+        assert_eq!(digest.buildings.len(), 2);
+        assert!(digest.changes.is_empty());
+    }
+
+    #[test]
+    fn digest_time_in_local_time() {
+        let six = NaiveTime::from_hms_opt(6, 0, 0).unwrap();
+        assert_eq!(
+            next_digest_time(six, time("2024-10-16T03:00:00+00:00")),
+            time("2024-10-16T04:00:00+00:00")
+        );
+        assert_eq!(
+            next_digest_time(six, time("2024-10-16T04:00:00+00:00")),
+            time("2024-10-17T04:00:00+00:00")
+        );
+        // winter time from the 27th
+        assert_eq!(
+            next_digest_time(six, time("2024-10-26T12:00:00+00:00")),
+            time("2024-10-27T05:00:00+00:00")
+        );
+        // 02:30 does not exist when switching to summer time
+        assert_eq!(
+            next_digest_time(
+                NaiveTime::from_hms_opt(2, 30, 0).unwrap(),
+                time("2024-03-30T12:00:00+00:00")
+            ),
+            time("2024-03-31T01:30:00+00:00")
+        );
+    }
+
+    fn status(started_at: &str, error: Option<&str>) -> SyncStatus {
+        SyncStatus {
+            last_results: [(
+                "churchtools".to_owned(),
+                SyncRun {
+                    id: "run".to_owned(),
+                    source: "churchtools".to_owned(),
+                    started_at: time(started_at),
+                    finished_at: time(started_at),
+                    error: error.map(ToOwned::to_owned),
+                },
+            )]
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sync_alerts_once() {
+        let threshold = TimeDelta::minutes(30);
+        let mut watch = SyncWatch::default();
+        let now = time("2024-10-16T12:00:00+00:00");
+        assert_eq!(watch.observe(&SyncStatus::default(), now, threshold), None);
+        let failing = status("2024-10-16T12:00:00+00:00", Some("timeout"));
+        assert_eq!(watch.observe(&failing, now, threshold), None);
+        let later = status("2024-10-16T12:20:00+00:00", Some("timeout"));
+        assert_eq!(
+            watch.observe(&later, now + TimeDelta::minutes(29), threshold),
+            None
+        );
+        assert_eq!(
+            watch.observe(&later, now + threshold, threshold),
+            Some(SyncAlert::Failing {
+                since: now,
+                error: "churchtools: timeout".to_owned()
+            })
+        );
+        assert_eq!(
+            watch.observe(&later, now + TimeDelta::hours(2), threshold),
+            None
+        );
+        let good = status("2024-10-16T14:00:00+00:00", None);
+        assert_eq!(
+            watch.observe(&good, now + TimeDelta::hours(2), threshold),
+            Some(SyncAlert::Recovered { since: now })
+        );
+        assert_eq!(
+            watch.observe(&good, now + TimeDelta::hours(3), threshold),
+            None
+        );
+
+        // short failures are not reported at all
+        let mut watch = SyncWatch::default();
+        assert_eq!(watch.observe(&failing, now, threshold), None);
+        assert_eq!(watch.observe(&good, now, threshold), None);
+    }
+
+    /// A local SMTP server accepting every mail, returning its port and the received messages
+    async fn smtp_sink() -> (u16, Arc<Mutex<Vec<String>>>) {
+        let received = Arc::new(Mutex::new(vec![]));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn({
+            let received = received.clone();
+            async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let (read, mut write) = stream.into_split();
+                    let mut lines = BufReader::new(read).lines();
+                    write.write_all(b"220 sink ESMTP\r\n").await.unwrap();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let command = line.to_ascii_uppercase();
+                        if command.starts_with("DATA") {
+                            write.write_all(b"354 go ahead\r\n").await.unwrap();
+                            let mut data = String::new();
+                            while let Ok(Some(line)) = lines.next_line().await {
+                                if line == "." {
+                                    break;
+                                }
+                                data.push_str(&line);
+                                data.push('\n');
+                            }
+                            received.lock().unwrap().push(data);
+                            write.write_all(b"250 queued\r\n").await.unwrap();
+                        } else if command.starts_with("QUIT") {
+                            write.write_all(b"221 bye\r\n").await.unwrap();
+                            break;
+                        } else {
+                            write.write_all(b"250 ok\r\n").await.unwrap();
+                        }
+                    }
+                }
+            }
+        });
+        (port, received)
+    }
+
+    fn email_config(port: u16) -> EmailConfig {
+        EmailConfig {
+            smtp_host: "127.0.0.1".to_owned(),
+            smtp_port: Some(port),
+            smtp_security: SmtpSecurity::None,
+            username: None,
+            password: None,
+            from: "Raumübersicht <raeume@example.com>".to_owned(),
+            digest_time: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+            groups: vec![group(vec![], vec![])],
+            sync_alert_minutes: Some(30),
+            alert_recipients: vec!["admin@example.com".to_owned()],
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_digests_and_alerts() {
+        let (port, received) = smtp_sink().await;
+        let email = email_config(port);
+        let mailer = Mailer::new(&email).unwrap();
+
+        let bookings = vec![booking(
+            "Chorprobe",
+            12,
+            "2024-10-16T16:00:00+00:00",
+            "2024-10-16T18:00:00+00:00",
+        )];
+        let digest = build_digest(&rooms(), &email.groups[0], day(), &bookings, &[]);
+        mailer.send_digest(&email.groups[0], &digest).await.unwrap();
+        mailer
+            .send_sync_alert(
+                &email.alert_recipients,
+                &SyncAlert::Failing {
+                    since: time("2024-10-16T12:00:00+00:00"),
+                    error: "timeout".to_owned(),
+                },
+            )
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 2);
+        assert!(received[0].contains("To: kuester@example.com"));
+        assert!(received[0].contains("multipart/alternative"));
+        assert!(received[0].contains("text/html"));
+        assert!(received[0].contains("18:00-20:00"));
+        assert!(received[0].contains("Chorprobe"));
+        assert!(received[1].contains("To: admin@example.com"));
+        assert!(received[1].contains("timeout"));
+        assert!(received[1].contains("16.10.2024 14:00"));
+    }
+}
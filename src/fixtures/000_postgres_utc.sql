@@ -0,0 +1,2 @@
+-- the shared fixtures write naive UTC times, as sqlite stores them
+SET TIME ZONE 'UTC';
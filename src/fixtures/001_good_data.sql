@@ -1,3 +1,3 @@
-INSERT INTO bookings (booking_id, title, resource_id, start_time, end_time) VALUES
-(123, 'title', 10, '2021-03-26T15:30:00+00:00', '2021-03-26T17:00:00+00:00'),
-(125, 'title', 11, '2021-03-28T15:30:00+00:00', '2021-03-28T17:00:00+00:00');
+INSERT INTO bookings (source, booking_id, title, resource_id, start_time, end_time) VALUES
+('churchtools', 123, 'title', 10, '2021-03-26T15:30:00Z', '2021-03-26T17:00:00Z'),
+('churchtools', 125, 'title', 11, '2021-03-28T15:30:00Z', '2021-03-28T17:00:00Z');
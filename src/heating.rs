@@ -0,0 +1,459 @@
+//! Comfort schedules: when to heat a room so it is warm for its bookings.
+//!
+//! Every booking (with setup and teardown) is preceded by the pre-heat lead time of its room.
+//! Bookings close to each other share one comfort period, so the heating does not switch off for
+//! short gaps.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use chrono_tz::Europe::Berlin;
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{Config, MAX_BUFFER},
+    db::DBError,
+    local_bookings::{LocalBooking, LocalBookingKind},
+    sync::SyncState,
+    webhooks::{self, WebhookEvent},
+    Booking, InShutdown,
+};
+
+/// Switches are looked for this far ahead
+const SWITCH_LOOKAHEAD: TimeDelta = TimeDelta::days(2);
+/// Check the db for changed bookings this often
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How the comfort schedule of a room is computed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeatingRules {
+    pub preheat: TimeDelta,
+    pub merge_gap: TimeDelta,
+    pub min_off: TimeDelta,
+}
+
+/// A time in which a room should be warm
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ComfortPeriod {
+    pub resource_id: i64,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Titles of the bookings this period is for
+    pub bookings: Vec<String>,
+}
+
+/// The comfort periods of room `resource_id`, given bookings with their buffers applied
+pub fn comfort_periods(
+    resource_id: i64,
+    rules: HeatingRules,
+    bookings: &[Booking],
+) -> Vec<ComfortPeriod> {
+    let mut bookings = bookings
+        .iter()
+        .filter(|b| b.resource_id == resource_id)
+        .collect::<Vec<_>>();
+    bookings.sort_by_key(|b| b.start_time);
+    let mut periods: Vec<ComfortPeriod> = vec![];
+    for booking in bookings {
+        let start = booking.start_time - rules.preheat;
+        if let Some(last) = periods.last_mut() {
+            if booking.start_time - last.end <= rules.merge_gap || start - last.end < rules.min_off
+            {
+                last.end = last.end.max(booking.end_time);
+                last.bookings.push(booking.title.clone());
+                continue;
+            }
+        }
+        periods.push(ComfortPeriod {
+            resource_id,
+            start,
+            end: booking.end_time,
+            bookings: vec![booking.title.clone()],
+        });
+    }
+    periods
+}
+
+/// The next time after `now` at which the heating of any room switches
+pub fn next_switch(periods: &[ComfortPeriod], now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    periods
+        .iter()
+        .flat_map(|p| [p.start, p.end])
+        .filter(|t| *t > now)
+        .min()
+}
+
+/// The comfort period room `resource_id` is in at `now`, if any
+pub fn current_period(
+    periods: &[ComfortPeriod],
+    resource_id: i64,
+    now: DateTime<Utc>,
+) -> Option<&ComfortPeriod> {
+    periods
+        .iter()
+        .find(|p| p.resource_id == resource_id && p.start <= now && now < p.end)
+}
+
+/// Comfort periods overlapping [start, end] of all rooms, or only of `room`, ordered by start
+pub async fn comfort_schedule(
+    config: &Config,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    room: Option<i64>,
+) -> Result<Vec<ComfortPeriod>, DBError> {
+    let rooms = config
+        .rooms
+        .iter()
+        .map(|r| r.id())
+        .filter(|id| room.is_none_or(|room| room == *id))
+        .collect::<Vec<_>>();
+    let max_preheat = rooms
+        .iter()
+        .map(|id| config.heating_rules_for(*id).preheat)
+        .max()
+        .unwrap_or_default();
+    let (from, to) = (
+        (start - MAX_BUFFER).naive_utc(),
+        (end + MAX_BUFFER + max_preheat).naive_utc(),
+    );
+    // blocked rooms are not used, so they need no heating
+    let local_bookings = config
+        .db
+        .get_local_bookings_in_timeframe(from, to)
+        .await?
+        .into_iter()
+        .filter(|b| b.kind == LocalBookingKind::Booking)
+        .map(LocalBooking::into_booking);
+    let bookings = config
+        .db
+        .get_bookings_in_timeframe(from, to)
+        .await?
+        .into_iter()
+        .chain(local_bookings)
+        .map(|b| config.with_buffers(&b))
+        .collect::<Vec<_>>();
+    let mut periods = rooms
+        .into_iter()
+        .flat_map(|id| comfort_periods(id, config.heating_rules_for(id), &bookings))
+        .filter(|p| p.start <= end && start <= p.end)
+        .collect::<Vec<_>>();
+    periods.sort_by_key(|p| p.start);
+    Ok(periods)
+}
+
+fn csv_field(value: &str) -> String {
+    // titles come from CT, so keep spreadsheets from running them as formulas
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{value}")
+    } else {
+        value.to_owned()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// The schedule as CSV, with local times
+pub fn to_csv(config: &Config, periods: &[ComfortPeriod]) -> String {
+    let mut csv = "room_id,room,start,end,bookings\r\n".to_owned();
+    for period in periods {
+        let room = config
+            .rooms
+            .iter()
+            .find(|r| r.id() == period.resource_id)
+            .map(|r| r.name.as_str())
+            .unwrap_or_default();
+        let fmt = |t: DateTime<Utc>| {
+            t.with_timezone(&Berlin)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        };
+        csv.push_str(&format!(
+            "{},{},{},{},{}\r\n",
+            period.resource_id,
+            csv_field(room),
+            fmt(period.start),
+            fmt(period.end),
+            csv_field(&period.bookings.join(" / ")),
+        ));
+    }
+    csv
+}
+
+/// Send webhooks for all rooms whose heating switched since the last call.
+///
+/// `heating` holds the end of the comfort period each room is in, or `None` if it is off.
+/// Of several instances sharing the db, only the first one sends a switch; the state at startup is
+/// sent by every instance. Returns when the heating switches next.
+async fn trigger_switches(
+    config: &Config,
+    heating: &mut HashMap<i64, Option<DateTime<Utc>>>,
+    now: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, DBError> {
+    let periods = comfort_schedule(config, now, now + SWITCH_LOOKAHEAD, None).await?;
+    let mut events = vec![];
+    for room in &config.rooms {
+        let current = current_period(&periods, room.id(), now);
+        let previous = heating.insert(room.id(), current.map(|p| p.end));
+        if previous.map(|p| p.is_some()) == Some(current.is_some()) {
+            continue;
+        }
+        if let Some(previous) = previous {
+            // the period may have been cut short by a removed booking
+            let switched_at = current.map_or_else(
+                || previous.map_or(now, |end| end.min(now)),
+                |period| period.start,
+            );
+            let job = format!("heating:{}", room.id());
+            if !config.db.claim_job_run(&job, switched_at, now).await? {
+                continue;
+            }
+        }
+        debug!(
+            "Heating of room {} switches {}.",
+            room.id(),
+            if current.is_some() { "on" } else { "off" }
+        );
+        events.push(match current {
+            Some(period) => WebhookEvent::HeatingOn { period },
+            None => WebhookEvent::HeatingOff {
+                resource_id: room.id(),
+            },
+        });
+    }
+    webhooks::enqueue(&*config.db, &config.webhooks, &events).await?;
+    Ok(next_switch(&periods, now))
+}
+
+/// Send webhooks whenever the heating of a room switches, until shutdown.
+///
+/// The state of every room is sent once at startup as well.
+pub async fn keep_triggering_heating(
+    config: Arc<Config>,
+    sync: Arc<SyncState>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+) {
+    if !config.heating.webhooks {
+        return;
+    }
+    info!("Starting heating webhook task");
+    let mut heating = HashMap::new();
+    let mut sync_status = sync.subscribe();
+    loop {
+        let now = Utc::now();
+        let next = match trigger_switches(&config, &mut heating, now).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Unable to trigger heating switches: {e}");
+                None
+            }
+        };
+        // wake up just after the switch, so the period has started or ended by then
+        let sleep = next
+            .and_then(|t| (t - now + TimeDelta::seconds(1)).to_std().ok())
+            .map_or(POLL_INTERVAL, |d| d.min(POLL_INTERVAL));
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down heating webhook task now.");
+                return;
+            }
+            _ = sync_status.changed() => {}
+            () = tokio::time::sleep(sleep) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sqlx::SqlitePool;
+
+    use crate::{
+        local_bookings::NewLocalBooking,
+        test_helpers::{booking, config, time},
+    };
+
+    const RULES: HeatingRules = HeatingRules {
+        preheat: TimeDelta::minutes(60),
+        merge_gap: TimeDelta::minutes(30),
+        min_off: TimeDelta::minutes(45),
+    };
+
+    #[test]
+    fn preheat_and_merge() {
+        let bookings = vec![
+            // merged, they are only 30 minutes apart
+            booking(
+                "Kinderchor",
+                12,
+                "2024-10-16T15:00:00+00:00",
+                "2024-10-16T16:00:00+00:00",
+            ),
+            booking(
+                "Chorprobe",
+                12,
+                "2024-10-16T16:30:00+00:00",
+                "2024-10-16T18:00:00+00:00",
+            ),
+            // merged, the heating would only be off for 30 minutes
+            booking(
+                "Hauskreis",
+                12,
+                "2024-10-16T19:30:00+00:00",
+                "2024-10-16T21:00:00+00:00",
+            ),
+            // separate, the heating is off for an hour
+            booking(
+                "Gebet",
+                12,
+                "2024-10-17T08:00:00+00:00",
+                "2024-10-17T09:00:00+00:00",
+            ),
+        ];
+        let periods = comfort_periods(12, RULES, &bookings);
+        assert_eq!(
+            periods,
+            vec![
+                ComfortPeriod {
+                    resource_id: 12,
+                    start: time("2024-10-16T14:00:00+00:00"),
+                    end: time("2024-10-16T21:00:00+00:00"),
+                    bookings: vec![
+                        "Kinderchor".to_owned(),
+                        "Chorprobe".to_owned(),
+                        "Hauskreis".to_owned()
+                    ],
+                },
+                ComfortPeriod {
+                    resource_id: 12,
+                    start: time("2024-10-17T07:00:00+00:00"),
+                    end: time("2024-10-17T09:00:00+00:00"),
+                    bookings: vec!["Gebet".to_owned()],
+                },
+            ]
+        );
+        assert!(comfort_periods(13, RULES, &bookings).is_empty());
+
+        let now = time("2024-10-16T13:00:00+00:00");
+        assert_eq!(current_period(&periods, 12, now), None);
+        assert_eq!(
+            next_switch(&periods, now),
+            Some(time("2024-10-16T14:00:00+00:00"))
+        );
+        assert_eq!(
+            current_period(&periods, 12, time("2024-10-16T14:00:00+00:00")),
+            Some(&periods[0])
+        );
+    }
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        assert_eq!(csv_field("Saal"), "Saal");
+        assert_eq!(csv_field("Saal, oben"), "\"Saal, oben\"");
+        assert_eq!(csv_field("\"Saal\""), "\"\"\"Saal\"\"\"");
+        // titles must not run as formulas in spreadsheets
+        assert_eq!(csv_field("=HYPERLINK(1)"), "'=HYPERLINK(1)");
+        assert_eq!(csv_field("-1,5"), "\"'-1,5\"");
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    async fn local_bookings_are_heated_for(pool: SqlitePool) {
+        let config = config(
+            r#"
+            [heating]
+            preheat_minutes = 60
+            "#,
+            pool,
+        )
+        .await;
+        for (title, resource_id, kind) in [
+            ("Trauung", 10, LocalBookingKind::Booking),
+            ("Renovierung", 11, LocalBookingKind::Block),
+        ] {
+            config
+                .db
+                .insert_local_booking(&NewLocalBooking {
+                    title: title.to_owned(),
+                    resource_id,
+                    start_time: time("2024-10-16T14:00:00+00:00"),
+                    end_time: time("2024-10-16T16:00:00+00:00"),
+                    kind,
+                    note: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        let periods = comfort_schedule(
+            &config,
+            time("2024-10-16T00:00:00+00:00"),
+            time("2024-10-17T00:00:00+00:00"),
+            None,
+        )
+        .await
+        .unwrap();
+        // blocked rooms are not heated
+        assert_eq!(
+            periods,
+            vec![ComfortPeriod {
+                resource_id: 10,
+                start: time("2024-10-16T13:00:00+00:00"),
+                end: time("2024-10-16T16:00:00+00:00"),
+                bookings: vec!["Trauung".to_owned()],
+            }]
+        );
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    async fn switches_are_sent_by_one_instance(pool: SqlitePool) {
+        let extra = r#"
+            [heating]
+            preheat_minutes = 60
+
+            [[webhooks.targets]]
+            name = "heating"
+            url = "http://127.0.0.1:1/"
+            events = ["heating_on", "heating_off"]
+            "#;
+        let instances = [config(extra, pool.clone()).await, config(extra, pool).await];
+        instances[0]
+            .db
+            .insert_local_booking(&NewLocalBooking {
+                title: "Trauung".to_owned(),
+                resource_id: 10,
+                start_time: time("2024-10-16T14:00:00+00:00"),
+                end_time: time("2024-10-16T16:00:00+00:00"),
+                kind: LocalBookingKind::Booking,
+                note: None,
+            })
+            .await
+            .unwrap();
+
+        let mut heating = [HashMap::new(), HashMap::new()];
+        for now in [
+            "2024-10-16T12:00:00+00:00",
+            "2024-10-16T13:00:00+00:00",
+            "2024-10-16T16:00:00+00:00",
+        ] {
+            for (config, heating) in instances.iter().zip(&mut heating) {
+                trigger_switches(config, heating, time(now)).await.unwrap();
+            }
+        }
+        let far = time("2100-01-01T00:00:00+00:00");
+        let events = instances[0]
+            .db
+            .claim_due_webhook_deliveries(far, far, i64::MAX)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|d| d.event)
+            .collect::<Vec<_>>();
+        // every instance sends the state of all three rooms at startup, but only one the switches
+        assert_eq!(events.iter().filter(|e| *e == "heating_off").count(), 7);
+        assert_eq!(events.iter().filter(|e| *e == "heating_on").count(), 1);
+    }
+}
@@ -0,0 +1,217 @@
+//! The booking history: an append-only log of everything the sync did to bookings.
+//!
+//! Every insert, update, delete and prune is recorded with the sync run that caused it, so
+//! disappearing bookings can be traced back to CT.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::Booking;
+
+/// What happened to a booking
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BookingEventKind {
+    Inserted,
+    Updated,
+    /// No longer in CT
+    Deleted,
+    /// Removed from the db because it ended before today
+    Pruned,
+}
+impl BookingEventKind {
+    pub fn as_db_str(self) -> &'static str {
+        match self {
+            Self::Inserted => "inserted",
+            Self::Updated => "updated",
+            Self::Deleted => "deleted",
+            Self::Pruned => "pruned",
+        }
+    }
+
+    pub fn from_db_str(value: &str) -> Self {
+        match value {
+            "inserted" => Self::Inserted,
+            "deleted" => Self::Deleted,
+            "pruned" => Self::Pruned,
+            _ => Self::Updated,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Inserted => "Neu",
+            Self::Updated => "Geändert",
+            Self::Deleted => "Gelöscht",
+            Self::Pruned => "Aufgeräumt",
+        }
+    }
+}
+
+/// A single field an update changed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// The fields changed between `previous` and `booking`, as shown to people
+pub fn diff(previous: &Booking, booking: &Booking) -> Vec<FieldChange> {
+    let minutes = |m: Option<i64>| m.map(|m| m.to_string()).unwrap_or_default();
+    [
+        ("title", previous.title.clone(), booking.title.clone()),
+        (
+            "resource_id",
+            previous.resource_id.to_string(),
+            booking.resource_id.to_string(),
+        ),
+        (
+            "start_time",
+            previous.start_time.to_rfc3339(),
+            booking.start_time.to_rfc3339(),
+        ),
+        (
+            "end_time",
+            previous.end_time.to_rfc3339(),
+            booking.end_time.to_rfc3339(),
+        ),
+        (
+            "setup_minutes",
+            minutes(previous.setup_minutes),
+            minutes(booking.setup_minutes),
+        ),
+        (
+            "teardown_minutes",
+            minutes(previous.teardown_minutes),
+            minutes(booking.teardown_minutes),
+        ),
+    ]
+    .into_iter()
+    .filter(|(_, old, new)| old != new)
+    .map(|(field, old, new)| FieldChange {
+        field: field.to_owned(),
+        old,
+        new,
+    })
+    .collect()
+}
+
+/// An event about to be recorded
+#[derive(Debug, PartialEq)]
+pub struct NewBookingEvent<'a> {
+    pub kind: BookingEventKind,
+    pub booking: &'a Booking,
+    pub diff: Vec<FieldChange>,
+}
+impl<'a> NewBookingEvent<'a> {
+    pub fn inserted(booking: &'a Booking) -> Self {
+        Self {
+            kind: BookingEventKind::Inserted,
+            booking,
+            diff: vec![],
+        }
+    }
+
+    pub fn updated(previous: &Booking, booking: &'a Booking) -> Self {
+        Self {
+            kind: BookingEventKind::Updated,
+            booking,
+            diff: diff(previous, booking),
+        }
+    }
+
+    pub fn deleted(booking: &'a Booking) -> Self {
+        Self {
+            kind: BookingEventKind::Deleted,
+            booking,
+            diff: vec![],
+        }
+    }
+}
+
+/// An event from the history, with the booking as it was afterwards (or before it was removed)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookingEvent {
+    pub id: i64,
+    /// ID of the sync run that caused this event
+    pub sync_run: Option<String>,
+    pub occurred_at: DateTime<Utc>,
+    pub kind: BookingEventKind,
+    pub booking_id: i64,
+    /// The `[[ct]]` source of the booking
+    pub source: String,
+    pub resource_id: i64,
+    pub title: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub diff: Vec<FieldChange>,
+}
+
+/// Which events to get from the history
+#[derive(Debug, Default, Deserialize)]
+pub struct BookingEventFilter {
+    pub booking_id: Option<i64>,
+    /// only events of the room with this `id`
+    pub room: Option<i64>,
+    pub sync_run: Option<String>,
+    /// only events at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// only events before this time
+    pub until: Option<DateTime<Utc>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::BookingOrigin;
+
+    #[test]
+    fn diff_lists_changed_fields() {
+        let previous = Booking {
+            title: "Chorprobe".to_owned(),
+            booking_id: 1,
+            source: "churchtools".to_owned(),
+            resource_id: 12,
+            start_time: DateTime::parse_from_rfc3339("2024-10-16T18:00:00+00:00")
+                .unwrap()
+                .into(),
+            end_time: DateTime::parse_from_rfc3339("2024-10-16T20:00:00+00:00")
+                .unwrap()
+                .into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::ChurchTools,
+        };
+        assert!(diff(&previous, &previous).is_empty());
+        let booking = Booking {
+            resource_id: 13,
+            end_time: DateTime::parse_from_rfc3339("2024-10-16T21:00:00+00:00")
+                .unwrap()
+                .into(),
+            setup_minutes: Some(15),
+            ..previous.clone()
+        };
+        assert_eq!(
+            diff(&previous, &booking),
+            vec![
+                FieldChange {
+                    field: "resource_id".to_owned(),
+                    old: "12".to_owned(),
+                    new: "13".to_owned(),
+                },
+                FieldChange {
+                    field: "end_time".to_owned(),
+                    old: "2024-10-16T20:00:00+00:00".to_owned(),
+                    new: "2024-10-16T21:00:00+00:00".to_owned(),
+                },
+                FieldChange {
+                    field: "setup_minutes".to_owned(),
+                    old: String::new(),
+                    new: "15".to_owned(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Bookings created in room-overview itself instead of CT.
+//!
+//! These are stored in their own table, so the CT sync never deletes them.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, Booking, BookingOrigin};
+
+/// The source of all local bookings. Sync sources cannot have this name.
+pub const LOCAL_SOURCE: &str = "local";
+
+/// What a local booking means for its room
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LocalBookingKind {
+    /// An ad-hoc event, shown like any other booking
+    #[default]
+    Booking,
+    /// The room cannot be used at all, e.g. during renovation
+    Block,
+}
+impl LocalBookingKind {
+    pub fn as_db_str(self) -> &'static str {
+        match self {
+            Self::Booking => "booking",
+            Self::Block => "block",
+        }
+    }
+
+    pub fn from_db_str(value: &str) -> Self {
+        match value {
+            "block" => Self::Block,
+            _ => Self::Booking,
+        }
+    }
+}
+
+/// A local booking as stored in the db
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct LocalBooking {
+    pub id: i64,
+    pub title: String,
+    pub resource_id: i64,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub kind: LocalBookingKind,
+    /// Internal note, not shown on the screens
+    pub note: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+impl LocalBooking {
+    /// Turn this into a booking that can be shown next to the bookings from CT
+    pub fn into_booking(self) -> Booking {
+        let title = match self.kind {
+            LocalBookingKind::Booking => self.title,
+            LocalBookingKind::Block => format!("Gesperrt: {}", self.title),
+        };
+        Booking {
+            resource_id: self.resource_id,
+            booking_id: self.id,
+            source: LOCAL_SOURCE.to_owned(),
+            title,
+            start_time: self.start_time,
+            end_time: self.end_time,
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::Local,
+        }
+    }
+}
+
+/// A local booking as sent to the API to create it
+#[derive(Debug, Deserialize)]
+pub struct NewLocalBooking {
+    pub title: String,
+    pub resource_id: i64,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    #[serde(default)]
+    pub kind: LocalBookingKind,
+    pub note: Option<String>,
+}
+impl NewLocalBooking {
+    /// Check that this booking can be stored, returning a human readable reason if not
+    pub fn validate(&self, config: &Config) -> Result<(), String> {
+        if self.title.trim().is_empty() {
+            return Err("title must not be empty".to_owned());
+        }
+        if self.end_time <= self.start_time {
+            return Err("end_time must be after start_time".to_owned());
+        }
+        if !config.rooms.iter().any(|r| r.id() == self.resource_id) {
+            return Err(format!(
+                "there is no room with resource_id {}",
+                self.resource_id
+            ));
+        }
+        Ok(())
+    }
+}
@@ -2,28 +2,78 @@ use core::str::FromStr;
 use std::sync::Arc;
 
 use chrono::Utc;
+use serde::{Deserialize, Serialize};
 
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{filter, fmt::format::FmtSpan};
 use tracing_subscriber::{prelude::*, EnvFilter};
 
+mod announcements;
+mod auth;
+mod availability;
+mod backup;
+mod cli;
 mod config;
+mod conflicts;
+mod ct_auth;
 mod db;
+mod email;
+mod heating;
+mod history;
+mod local_bookings;
+mod mqtt;
+mod pdf;
+mod pull_from_calendar;
 mod pull_from_ct;
+mod reports;
+mod sync;
+#[cfg(test)]
+mod test_helpers;
 mod web;
+mod webhooks;
 
 pub(crate) const BOOKING_DATABASE_NAME: &str = ".bookings.db";
 
+/// Where a booking comes from
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BookingOrigin {
+    /// Pulled from CT or a calendar and kept in sync with it
+    #[serde(rename = "churchtools")]
+    ChurchTools,
+    /// Created in room-overview itself, never touched by the CT sync
+    Local,
+}
+impl BookingOrigin {
+    /// How the origin is stored in the booking archive
+    fn as_db_str(self) -> &'static str {
+        match self {
+            Self::ChurchTools => "churchtools",
+            Self::Local => "local",
+        }
+    }
+
+    fn from_db_str(value: &str) -> Self {
+        match value {
+            "local" => Self::Local,
+            _ => Self::ChurchTools,
+        }
+    }
+}
+
 /// A single booking for a room
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 struct Booking {
-    /// the ID of the resource for this booking.
-    /// NOTE: this is NOT the ID of the booking, but of the resource in CT.
-    /// This ID is used for matching ressources against rooms defined in the config.
+    /// the ID of the room for this booking.
+    /// NOTE: this is NOT the ID of the booking, but the `id` of a room defined in the config.
+    /// The sync translates the resource IDs of each CT instance to it.
     resource_id: i64,
     /// The ID of this booking. This is used to update bookings when they are updated in CT.
     #[allow(clippy::struct_field_names)]
     booking_id: i64,
+    /// Name of the `[[ct]]` or `[[calendars]]` source this booking was pulled from, as booking IDs
+    /// are only unique within one source. [`local_bookings::LOCAL_SOURCE`] for local bookings.
+    source: String,
     /// Title of the booking in CT
     title: String,
     /// The booking starts at...
@@ -31,6 +81,11 @@ struct Booking {
     start_time: chrono::DateTime<Utc>,
     /// The booking ends at...
     end_time: chrono::DateTime<Utc>,
+    /// Minutes the room is needed before `start_time`, if recorded in CT
+    setup_minutes: Option<i64>,
+    /// Minutes the room is needed after `end_time`, if recorded in CT
+    teardown_minutes: Option<i64>,
+    origin: BookingOrigin,
 }
 
 enum InShutdown {
@@ -108,7 +163,13 @@ async fn main() -> Result<(), Box<dyn core::error::Error>> {
         .install_default()
         .expect("Failed to install rustls crypto provider");
 
-    let config = Arc::new(config::Config::create().await?);
+    let command = cli::parse_args(std::env::args().skip(1))?;
+    // does not need the config
+    if command == cli::Command::HashPassword {
+        cli::hash_password()?;
+        return Ok(());
+    }
+    let mut config = config::Config::create().await?;
     // Setup tracing
 
     let my_crate_filter = EnvFilter::new("room_overview");
@@ -122,26 +183,158 @@ async fn main() -> Result<(), Box<dyn core::error::Error>> {
     );
     tracing::subscriber::set_global_default(subscriber).expect("static tracing config");
     debug!("Tracing enabled");
+    if config.admin.api_token.is_some() {
+        warn!("admin.api_token is deprecated and allows everything. Create API tokens with scopes using `room-overview create-token` instead.");
+    }
+
+    pull_from_ct::merge_room_attributes_from_ct(&mut config).await;
+    let config = Arc::new(config);
 
     // migrate the database
-    sqlx::migrate!().run(&config.db).await?;
+    config.db.migrate().await?;
+
+    match command {
+        cli::Command::Serve => {}
+        cli::Command::HashPassword => unreachable!("handled before reading the config"),
+        cli::Command::RenderPdf(args) => {
+            cli::render_pdf(&config, args).await?;
+            return Ok(());
+        }
+        cli::Command::CreateToken(args) => {
+            cli::create_token(&config, args).await?;
+            return Ok(());
+        }
+        cli::Command::ListTokens => {
+            cli::list_tokens(&config).await?;
+            return Ok(());
+        }
+        cli::Command::RevokeToken(name) => {
+            cli::revoke_token(&config, name).await?;
+            return Ok(());
+        }
+        cli::Command::ReceiveWebhooks(addr) => {
+            cli::receive_webhooks(&config, addr).await?;
+            return Ok(());
+        }
+        cli::Command::SendDigest => {
+            cli::send_digest(&config).await?;
+            return Ok(());
+        }
+        cli::Command::Export(args) => {
+            cli::export(&config, args).await?;
+            return Ok(());
+        }
+        cli::Command::Import(path) => {
+            cli::import(&config, path).await?;
+            return Ok(());
+        }
+        cli::Command::Backup => {
+            cli::backup(&config).await?;
+            return Ok(());
+        }
+        cli::Command::CheckSources => {
+            cli::check_sources(&config).await?;
+            return Ok(());
+        }
+    }
+
+    // bookings of renamed or removed sources would otherwise stay until they are pruned
+    let run_id = uuid::Uuid::new_v4().to_string();
+    sync::remove_unconfigured_sources(&config, &run_id).await?;
 
     // cancellation channel
     let (tx, rx) = tokio::sync::watch::channel(InShutdown::No);
 
-    // start the data-gatherer
-    let gatherer_handle = tokio::spawn(pull_from_ct::keep_db_up_to_date(config.clone(), rx));
+    // start one data-gatherer per CT source and calendar
+    let sources = sync::sources(&config);
+    let sync = Arc::new(sync::SyncState::new(
+        sources
+            .iter()
+            .map(|source| source.name().to_owned())
+            .collect(),
+        core::time::Duration::from_secs(config.min_sync_request_interval()),
+    ));
+    let gatherer_handle = tokio::spawn(futures::future::join_all(sources.into_iter().map(
+        |source| {
+            tokio::spawn(sync::keep_db_up_to_date(
+                config.clone(),
+                source,
+                sync.clone(),
+                rx.clone(),
+            ))
+        },
+    )));
+
+    // start pruning the db
+    let prune_handle = tokio::spawn(sync::keep_db_pruned(config.clone(), tx.subscribe()));
+
+    // start publishing room states
+    let mqtt_handle = tokio::spawn(mqtt::keep_publishing_room_states(
+        config.clone(),
+        sync.clone(),
+        tx.subscribe(),
+    ));
+
+    // start switching the heating
+    let heating_handle = tokio::spawn(heating::keep_triggering_heating(
+        config.clone(),
+        sync.clone(),
+        tx.subscribe(),
+    ));
+
+    // start delivering webhooks
+    let webhooks_handle = tokio::spawn(webhooks::keep_delivering_webhooks(
+        config.clone(),
+        tx.subscribe(),
+    ));
+
+    // start sending emails
+    let email_handle = tokio::spawn(email::keep_sending_emails(
+        config.clone(),
+        sync.clone(),
+        tx.subscribe(),
+    ));
+
+    // start writing backups
+    let backup_handle = tokio::spawn(backup::keep_backing_up(config.clone(), tx.subscribe()));
 
     // start the Signal handler
     let signal_handle = tokio::spawn(signal_handler(tx.subscribe(), tx.clone()));
 
     // start the web server
-    let web_server = web::run_web_server(config.clone(), tx.subscribe(), tx.clone());
+    let web_server = web::run_web_server(config.clone(), sync, tx.subscribe(), tx.clone());
 
     // Join both tasks
-    let (gather_res, signal_res, web_res) =
-        tokio::join!(gatherer_handle, signal_handle, web_server,);
-    gather_res?;
+    let (
+        gather_res,
+        prune_res,
+        mqtt_res,
+        heating_res,
+        webhooks_res,
+        email_res,
+        backup_res,
+        signal_res,
+        web_res,
+    ) = tokio::join!(
+        gatherer_handle,
+        prune_handle,
+        mqtt_handle,
+        heating_handle,
+        webhooks_handle,
+        email_handle,
+        backup_handle,
+        signal_handle,
+        web_server,
+    );
+    for res in gather_res? {
+        res?;
+    }
+    prune_res?;
+    mqtt_res?;
+    heating_res?;
+    webhooks_res?;
+    email_res?;
+    backup_res?;
     signal_res??;
     web_res?;
 
@@ -0,0 +1,507 @@
+//! Publish the current state of every room to an MQTT broker.
+//!
+//! States are retained, so building automation gets the current state as soon as it subscribes.
+//! They are computed from the db and republished whenever a booking starts or ends, after every
+//! sync and every `mqtt.poll_interval`. With `heating.mqtt`, the same goes for the heating.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use rumqttc::{
+    AsyncClient, ClientError, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS,
+};
+use serde::Serialize;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{Config, MqttConfig, RoomConfig, MAX_BUFFER},
+    db::DBError,
+    heating::{comfort_schedule, current_period, next_switch, ComfortPeriod},
+    sync::SyncState,
+    Booking, InShutdown,
+};
+
+/// Wait this long before reconnecting to the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum MqttError {
+    DB(DBError),
+    Publish(ClientError),
+    Serialize(serde_json::Error),
+}
+impl core::fmt::Display for MqttError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::DB(e) => write!(f, "{e}"),
+            Self::Publish(e) => write!(f, "Unable to publish to MQTT. Inner Error: {e}."),
+            Self::Serialize(e) => {
+                write!(f, "Unable to serialize MQTT payload. Inner Error: {e}.")
+            }
+        }
+    }
+}
+impl core::error::Error for MqttError {}
+impl From<DBError> for MqttError {
+    fn from(value: DBError) -> Self {
+        Self::DB(value)
+    }
+}
+impl From<ClientError> for MqttError {
+    fn from(value: ClientError) -> Self {
+        Self::Publish(value)
+    }
+}
+impl From<serde_json::Error> for MqttError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Serialize(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Occupancy {
+    Occupied,
+    Free,
+}
+
+/// What is published for a room. Times include setup and teardown.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RoomState {
+    pub state: Occupancy,
+    /// Title of the booking the room is occupied by
+    pub title: Option<String>,
+    pub current_end: Option<DateTime<Utc>>,
+    pub next_title: Option<String>,
+    pub next_start: Option<DateTime<Utc>>,
+    pub next_end: Option<DateTime<Utc>>,
+}
+
+/// The state of room `resource_id` at `now`, given bookings with their buffers applied
+pub fn room_state(resource_id: i64, bookings: &[Booking], now: DateTime<Utc>) -> RoomState {
+    let in_room = || bookings.iter().filter(|b| b.resource_id == resource_id);
+    // with overlapping bookings, the room stays occupied until the last one ends
+    let current = in_room()
+        .filter(|b| b.start_time <= now && now < b.end_time)
+        .max_by_key(|b| b.end_time);
+    let next = in_room()
+        .filter(|b| b.start_time > now)
+        .min_by_key(|b| b.start_time);
+    RoomState {
+        state: if current.is_some() {
+            Occupancy::Occupied
+        } else {
+            Occupancy::Free
+        },
+        title: current.map(|b| b.title.clone()),
+        current_end: current.map(|b| b.end_time),
+        next_title: next.map(|b| b.title.clone()),
+        next_start: next.map(|b| b.start_time),
+        next_end: next.map(|b| b.end_time),
+    }
+}
+
+/// The next time after `now` at which any booking starts or ends
+pub fn next_transition(bookings: &[Booking], now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    bookings
+        .iter()
+        .flat_map(|b| [b.start_time, b.end_time])
+        .filter(|t| *t > now)
+        .min()
+}
+
+/// Publishes room states, remembering what was published last
+struct Publisher {
+    client: AsyncClient,
+    topic_prefix: String,
+    published: HashMap<i64, RoomState>,
+    /// Whether the heating of each room was last published as on
+    heating: HashMap<i64, bool>,
+}
+impl Publisher {
+    fn state_topic(&self, resource_id: i64) -> String {
+        format!("{}/rooms/{resource_id}/state", self.topic_prefix)
+    }
+
+    fn status_topic(&self) -> String {
+        format!("{}/status", self.topic_prefix)
+    }
+
+    /// Topic prefix usable as part of IDs and topic levels in Home Assistant
+    fn node_id(&self) -> String {
+        self.topic_prefix
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect()
+    }
+
+    /// Announce that we are online, after every (re)connect
+    async fn publish_online(
+        &mut self,
+        mqtt: &MqttConfig,
+        rooms: &[RoomConfig],
+    ) -> Result<(), MqttError> {
+        // the broker may have lost retained messages, so publish everything again
+        self.published.clear();
+        self.heating.clear();
+        self.client
+            .publish(self.status_topic(), QoS::AtLeastOnce, true, "online")
+            .await?;
+        if !mqtt.home_assistant_discovery {
+            return Ok(());
+        }
+        let node_id = self.node_id();
+        for room in rooms {
+            let topic = format!(
+                "{}/binary_sensor/{node_id}/room_{}/config",
+                mqtt.discovery_prefix,
+                room.id()
+            );
+            let state_topic = self.state_topic(room.id());
+            let payload = serde_json::json!({
+                "name": room.name,
+                "unique_id": format!("{node_id}_room_{}", room.id()),
+                "device_class": "occupancy",
+                "state_topic": state_topic,
+                "value_template": "{{ value_json.state }}",
+                "payload_on": "occupied",
+                "payload_off": "free",
+                "json_attributes_topic": state_topic,
+                "availability_topic": self.status_topic(),
+                "device": {
+                    "identifiers": [node_id],
+                    "name": "room-overview",
+                },
+            });
+            self.client
+                .publish(topic, QoS::AtLeastOnce, true, serde_json::to_vec(&payload)?)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Publish the states of all rooms that changed since they were last published
+    async fn publish_changes(
+        &mut self,
+        rooms: &[RoomConfig],
+        bookings: &[Booking],
+        now: DateTime<Utc>,
+    ) -> Result<(), MqttError> {
+        for room in rooms {
+            let state = room_state(room.id(), bookings, now);
+            if self.published.get(&room.id()) == Some(&state) {
+                continue;
+            }
+            debug!("Room {} is now {:?}.", room.id(), state.state);
+            self.client
+                .publish(
+                    self.state_topic(room.id()),
+                    QoS::AtLeastOnce,
+                    true,
+                    serde_json::to_vec(&state)?,
+                )
+                .await?;
+            self.published.insert(room.id(), state);
+        }
+        Ok(())
+    }
+
+    /// Publish `on` or `off` for all rooms whose heating switched
+    async fn publish_heating(
+        &mut self,
+        rooms: &[RoomConfig],
+        periods: &[ComfortPeriod],
+        now: DateTime<Utc>,
+    ) -> Result<(), MqttError> {
+        for room in rooms {
+            let on = current_period(periods, room.id(), now).is_some();
+            if self.heating.get(&room.id()) == Some(&on) {
+                continue;
+            }
+            self.client
+                .publish(
+                    format!("{}/rooms/{}/heating", self.topic_prefix, room.id()),
+                    QoS::AtLeastOnce,
+                    true,
+                    if on { "on" } else { "off" },
+                )
+                .await?;
+            self.heating.insert(room.id(), on);
+        }
+        Ok(())
+    }
+
+    /// Get the bookings from the db and publish what changed.
+    ///
+    /// Returns when the next booking starts or ends, or the heating switches.
+    async fn update(
+        &mut self,
+        config: &Config,
+        now: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>, MqttError> {
+        let bookings = config
+            .db
+            .get_visible_bookings_in_timeframe(
+                (now - MAX_BUFFER).naive_utc(),
+                (now + config.pull_window() + MAX_BUFFER).naive_utc(),
+            )
+            .await?
+            .iter()
+            .map(|b| config.with_buffers(b))
+            .collect::<Vec<_>>();
+        self.publish_changes(&config.rooms, &bookings, now).await?;
+        let next = next_transition(&bookings, now);
+        if !config.heating.mqtt {
+            return Ok(next);
+        }
+        let periods = comfort_schedule(config, now, now + config.pull_window(), None).await?;
+        self.publish_heating(&config.rooms, &periods, now).await?;
+        Ok(next.into_iter().chain(next_switch(&periods, now)).min())
+    }
+}
+
+fn mqtt_options(mqtt: &MqttConfig) -> MqttOptions {
+    let mut options = MqttOptions::new(&mqtt.client_id, &mqtt.host, mqtt.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        format!("{}/status", mqtt.topic_prefix),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let (Some(username), Some(password)) = (&mqtt.username, &mqtt.password) {
+        options.set_credentials(username, password);
+    }
+    options
+}
+
+/// Keep the connection to the broker alive, notifying `connected` after every connect
+async fn drive_eventloop(mut eventloop: EventLoop, connected: Arc<Notify>) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to the MQTT broker.");
+                connected.notify_one();
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Lost connection to the MQTT broker: {e}");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Publish room states until shutdown
+pub async fn keep_publishing_room_states(
+    config: Arc<Config>,
+    sync: Arc<SyncState>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+) {
+    let Some(mqtt) = &config.mqtt else {
+        return;
+    };
+    info!("Starting MQTT publisher for {}:{}", mqtt.host, mqtt.port);
+    let (client, eventloop) = AsyncClient::new(mqtt_options(mqtt), 64);
+    let connected = Arc::new(Notify::new());
+    let eventloop_handle = tokio::spawn(drive_eventloop(eventloop, connected.clone()));
+    let mut publisher = Publisher {
+        client,
+        topic_prefix: mqtt.topic_prefix.clone(),
+        published: HashMap::new(),
+        heating: HashMap::new(),
+    };
+    let mut sync_status = sync.subscribe();
+    let poll_interval = Duration::from_secs(mqtt.poll_interval);
+    loop {
+        let now = Utc::now();
+        let next = match publisher.update(&config, now).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Unable to publish room states: {e}");
+                None
+            }
+        };
+        // wake up just after the transition, so the booking has started or ended by then
+        let sleep = next
+            .and_then(|t| (t - now + TimeDelta::seconds(1)).to_std().ok())
+            .map_or(poll_interval, |d| d.min(poll_interval));
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down MQTT publisher now.");
+                let _ = publisher
+                    .client
+                    .publish(publisher.status_topic(), QoS::AtLeastOnce, true, "offline")
+                    .await;
+                let _ = publisher.client.disconnect().await;
+                if tokio::time::timeout(Duration::from_secs(2), eventloop_handle).await.is_err() {
+                    debug!("MQTT connection did not close in time.");
+                }
+                return;
+            }
+            _ = sync_status.changed() => {}
+            () = connected.notified() => {
+                if let Err(e) = publisher.publish_online(mqtt, &config.rooms).await {
+                    warn!("Unable to publish MQTT discovery: {e}");
+                }
+            }
+            () = tokio::time::sleep(sleep) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::BytesMut;
+    use rumqttc::{ConnAck, ConnectReturnCode, PingResp, PubAck, Publish};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::test_helpers::{booking, room, time};
+
+    #[test]
+    fn state_and_transitions() {
+        let bookings = vec![
+            booking(
+                "Chorprobe",
+                12,
+                "2024-10-16T18:00:00+00:00",
+                "2024-10-16T20:00:00+00:00",
+            ),
+            booking(
+                "Jugend",
+                12,
+                "2024-10-17T18:00:00+00:00",
+                "2024-10-17T21:00:00+00:00",
+            ),
+            booking(
+                "Seniorenkreis",
+                13,
+                "2024-10-16T14:00:00+00:00",
+                "2024-10-16T16:00:00+00:00",
+            ),
+        ];
+        let now = time("2024-10-16T19:00:00+00:00");
+        let state = room_state(12, &bookings, now);
+        assert_eq!(state.state, Occupancy::Occupied);
+        assert_eq!(state.title.as_deref(), Some("Chorprobe"));
+        assert_eq!(state.current_end, Some(time("2024-10-16T20:00:00+00:00")));
+        assert_eq!(state.next_title.as_deref(), Some("Jugend"));
+        assert_eq!(state.next_start, Some(time("2024-10-17T18:00:00+00:00")));
+
+        let state = room_state(13, &bookings, now);
+        assert_eq!(state.state, Occupancy::Free);
+        assert_eq!(state.next_start, None);
+
+        assert_eq!(
+            next_transition(&bookings, now),
+            Some(time("2024-10-16T20:00:00+00:00"))
+        );
+        // bookings end exactly when the next transition happens
+        assert_eq!(
+            room_state(12, &bookings, time("2024-10-16T20:00:00+00:00")).state,
+            Occupancy::Free
+        );
+    }
+
+    /// A minimal broker accepting a single client and recording what it publishes
+    async fn fake_broker() -> (u16, tokio::sync::mpsc::UnboundedReceiver<Publish>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut read = BytesMut::new();
+            loop {
+                let packet = match rumqttc::read(&mut read, 1 << 20) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        if stream.read_buf(&mut read).await.unwrap() == 0 {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                let mut write = BytesMut::new();
+                match packet {
+                    Packet::Connect(_) => {
+                        ConnAck::new(ConnectReturnCode::Success, false)
+                            .write(&mut write)
+                            .unwrap();
+                    }
+                    Packet::Publish(publish) => {
+                        if publish.qos == QoS::AtLeastOnce {
+                            PubAck::new(publish.pkid).write(&mut write).unwrap();
+                        }
+                        tx.send(publish).unwrap();
+                    }
+                    Packet::PingReq => {
+                        PingResp.write(&mut write).unwrap();
+                    }
+                    Packet::Disconnect => return,
+                    _ => {}
+                }
+                stream.write_all(&write).await.unwrap();
+            }
+        });
+        (port, rx)
+    }
+
+    #[tokio::test]
+    async fn publishes_retained_states_on_transitions() {
+        let (port, mut received) = fake_broker().await;
+        let (client, eventloop) = AsyncClient::new(
+            MqttOptions::new("room-overview-test", "127.0.0.1", port),
+            10,
+        );
+        let connected = Arc::new(Notify::new());
+        tokio::spawn(drive_eventloop(eventloop, connected.clone()));
+        connected.notified().await;
+
+        let mut publisher = Publisher {
+            client,
+            topic_prefix: "room-overview".to_owned(),
+            published: HashMap::new(),
+            heating: HashMap::new(),
+        };
+        let rooms = vec![room(12)];
+        let bookings = vec![booking(
+            "Chorprobe",
+            12,
+            "2024-10-16T18:00:00+00:00",
+            "2024-10-16T20:00:00+00:00",
+        )];
+
+        publisher
+            .publish_changes(&rooms, &bookings, time("2024-10-16T17:00:00+00:00"))
+            .await
+            .unwrap();
+        let free = received.recv().await.unwrap();
+        assert_eq!(free.topic, "room-overview/rooms/12/state");
+        assert!(free.retain);
+        let payload: serde_json::Value = serde_json::from_slice(&free.payload).unwrap();
+        assert_eq!(payload["state"], "free");
+        assert_eq!(payload["next_title"], "Chorprobe");
+
+        // nothing changed, nothing is published
+        publisher
+            .publish_changes(&rooms, &bookings, time("2024-10-16T17:30:00+00:00"))
+            .await
+            .unwrap();
+        publisher
+            .publish_changes(&rooms, &bookings, time("2024-10-16T18:00:00+00:00"))
+            .await
+            .unwrap();
+        let occupied = received.recv().await.unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&occupied.payload).unwrap();
+        assert_eq!(payload["state"], "occupied");
+        assert_eq!(payload["title"], "Chorprobe");
+        assert!(received.try_recv().is_err());
+    }
+}
@@ -0,0 +1,398 @@
+//! Render printable weekly schedules as PDF.
+//!
+//! The schedules are meant to be printed and taped to doors, so the layout is a plain A4 list of
+//! bookings grouped by day.
+
+use chrono::{DateTime, Datelike, NaiveDate, TimeDelta, Utc};
+use chrono_tz::Europe::Berlin;
+use printpdf::{BuiltinFont, IndirectFontRef, Mm, PdfDocument, PdfLayerReference};
+
+use crate::{
+    config::{Config, RoomConfig},
+    db::DBError,
+    Booking, BookingOrigin,
+};
+
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 20.0;
+const LINE_HEIGHT: f32 = 6.0;
+/// Titles longer than this are cut off so they do not run off the page
+const MAX_TITLE_CHARS: usize = 60;
+
+pub(crate) const WEEKDAYS: [&str; 7] = [
+    "Montag",
+    "Dienstag",
+    "Mittwoch",
+    "Donnerstag",
+    "Freitag",
+    "Samstag",
+    "Sonntag",
+];
+
+#[derive(Debug)]
+pub enum PdfError {
+    DB(DBError),
+    Render(printpdf::Error),
+}
+impl core::fmt::Display for PdfError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::DB(e) => {
+                write!(
+                    f,
+                    "Unable to get the bookings for the PDF. Inner Error: {e}."
+                )
+            }
+            Self::Render(e) => {
+                write!(f, "Unable to render the PDF. Inner Error: {e}.")
+            }
+        }
+    }
+}
+impl core::error::Error for PdfError {}
+impl From<DBError> for PdfError {
+    fn from(value: DBError) -> Self {
+        Self::DB(value)
+    }
+}
+impl From<printpdf::Error> for PdfError {
+    fn from(value: printpdf::Error) -> Self {
+        Self::Render(value)
+    }
+}
+
+/// What a single schedule is about
+pub enum ScheduleSubject<'a> {
+    /// A single room. Its name and location hint make up the heading.
+    Room(&'a RoomConfig),
+    /// All rooms in a building. Every line shows the room the booking belongs to.
+    Building(&'a str, &'a [RoomConfig]),
+}
+impl ScheduleSubject<'_> {
+    fn heading(&self) -> String {
+        match self {
+            Self::Room(room) => room.name.clone(),
+            Self::Building(name, _) => (*name).to_owned(),
+        }
+    }
+
+    fn subheading(&self) -> Option<String> {
+        match self {
+            Self::Room(room) => Some(room.location_hint.clone()),
+            Self::Building(_, _) => None,
+        }
+    }
+
+    /// The room a booking belongs to, if it belongs to this subject at all
+    fn room_for(&self, booking: &Booking) -> Option<&RoomConfig> {
+        match self {
+            Self::Room(room) => Some(*room).filter(|r| r.id() == booking.resource_id),
+            Self::Building(_, rooms) => rooms.iter().find(|r| r.id() == booking.resource_id),
+        }
+    }
+}
+
+/// Get the monday of the week containing `day`
+pub fn week_start(day: NaiveDate) -> NaiveDate {
+    day - TimeDelta::days(i64::from(day.weekday().num_days_from_monday()))
+}
+
+/// The start of `day` in local time, as naive UTC
+fn local_midnight_utc(day: NaiveDate) -> chrono::NaiveDateTime {
+    let midnight = day.and_hms_opt(0, 0, 0).expect("midnight always exists");
+    midnight
+        .and_local_timezone(Berlin)
+        .earliest()
+        .map_or(midnight, |t| t.naive_utc())
+}
+
+/// Get the UTC interval [start, end] covering `day` in local time.
+pub fn day_interval_utc(day: NaiveDate) -> (chrono::NaiveDateTime, chrono::NaiveDateTime) {
+    (
+        local_midnight_utc(day),
+        local_midnight_utc(day + TimeDelta::days(1)) - TimeDelta::seconds(1),
+    )
+}
+
+/// Get the UTC interval [start, end] covering the week starting at `monday` in local time.
+pub fn week_interval_utc(monday: NaiveDate) -> (chrono::NaiveDateTime, chrono::NaiveDateTime) {
+    (
+        local_midnight_utc(monday),
+        local_midnight_utc(monday + TimeDelta::days(7)) - TimeDelta::seconds(1),
+    )
+}
+
+/// A layer that knows where to write the next line and opens new pages when it runs full
+struct Cursor<'a> {
+    doc: &'a printpdf::PdfDocumentReference,
+    layer: PdfLayerReference,
+    y: f32,
+}
+impl Cursor<'_> {
+    fn line(&mut self, text: &str, size: f32, x: f32, font: &IndirectFontRef) {
+        self.row(&[(x, text)], size, font);
+    }
+
+    /// Write several cells on the same line, each starting at its own x offset
+    fn row(&mut self, cells: &[(f32, &str)], size: f32, font: &IndirectFontRef) {
+        if self.y < MARGIN {
+            let (page, layer) = self
+                .doc
+                .add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Schedule");
+            self.layer = self.doc.get_page(page).get_layer(layer);
+            self.y = PAGE_HEIGHT - MARGIN;
+        }
+        for (x, text) in cells {
+            self.layer.use_text(*text, size, Mm(*x), Mm(self.y), font);
+        }
+        self.y -= LINE_HEIGHT * size / 11.0;
+    }
+
+    fn skip(&mut self, mm: f32) {
+        self.y -= mm;
+    }
+}
+
+fn truncate(title: &str) -> String {
+    if title.chars().count() > MAX_TITLE_CHARS {
+        let mut short = title.chars().take(MAX_TITLE_CHARS - 3).collect::<String>();
+        short.push_str("...");
+        short
+    } else {
+        title.to_owned()
+    }
+}
+
+/// The local times of `booking` on `day`, cut to that day, or None if it is not on that day.
+///
+/// Bookings spanning several days are shown on each of them.
+fn times_on_day(booking: &Booking, day: NaiveDate) -> Option<String> {
+    let start = booking.start_time.with_timezone(&Berlin);
+    let end = booking.end_time.with_timezone(&Berlin);
+    let ends_at_midnight = end.date_naive() == day && end.time() == chrono::NaiveTime::MIN;
+    if start.date_naive() > day || end.date_naive() < day || (ends_at_midnight && start < end) {
+        return None;
+    }
+    let from = if start.date_naive() == day {
+        start.format("%H:%M").to_string()
+    } else {
+        "00:00".to_owned()
+    };
+    let to = if end.date_naive() == day {
+        end.format("%H:%M").to_string()
+    } else {
+        "24:00".to_owned()
+    };
+    Some(format!("{from} - {to}"))
+}
+
+/// Render the weekly schedule starting at `monday` for `subject`.
+///
+/// `bookings` may contain bookings for other rooms or outside the week, they are filtered out.
+pub fn render_week_schedule(
+    subject: &ScheduleSubject,
+    bookings: &[Booking],
+    monday: NaiveDate,
+    generated_at: DateTime<Utc>,
+) -> Result<Vec<u8>, PdfError> {
+    let heading = subject.heading();
+    let (doc, page, layer) = PdfDocument::new(
+        format!("Raumbelegung {heading}"),
+        Mm(PAGE_WIDTH),
+        Mm(PAGE_HEIGHT),
+        "Schedule",
+    );
+    let regular = doc.add_builtin_font(BuiltinFont::Helvetica)?;
+    let bold = doc.add_builtin_font(BuiltinFont::HelveticaBold)?;
+    let mut cursor = Cursor {
+        doc: &doc,
+        layer: doc.get_page(page).get_layer(layer),
+        y: PAGE_HEIGHT - MARGIN,
+    };
+
+    cursor.line(&heading, 22.0, MARGIN, &bold);
+    if let Some(subheading) = subject.subheading() {
+        cursor.line(&subheading, 13.0, MARGIN, &regular);
+    }
+    let sunday = monday + TimeDelta::days(6);
+    cursor.line(
+        &format!(
+            "Woche vom {} bis {}",
+            monday.format("%d.%m.%Y"),
+            sunday.format("%d.%m.%Y")
+        ),
+        13.0,
+        MARGIN,
+        &regular,
+    );
+    cursor.skip(LINE_HEIGHT);
+
+    for (offset, weekday) in WEEKDAYS.iter().enumerate() {
+        let day = monday + TimeDelta::days(offset as i64);
+        cursor.line(
+            &format!("{weekday}, {}", day.format("%d.%m.")),
+            13.0,
+            MARGIN,
+            &bold,
+        );
+        let mut any = false;
+        for booking in bookings {
+            let Some(room) = subject.room_for(booking) else {
+                continue;
+            };
+            let Some(times) = times_on_day(booking, day) else {
+                continue;
+            };
+            let title = match booking.origin {
+                BookingOrigin::ChurchTools => booking.title.clone(),
+                BookingOrigin::Local => format!("{} [lokal]", booking.title),
+            };
+            let text = match subject {
+                ScheduleSubject::Room(_) => truncate(&title),
+                ScheduleSubject::Building(_, _) => truncate(&format!("{title} ({})", room.name)),
+            };
+            cursor.row(
+                &[(MARGIN + 5.0, &times), (MARGIN + 35.0, &text)],
+                11.0,
+                &regular,
+            );
+            any = true;
+        }
+        if !any {
+            cursor.line("keine Buchungen", 11.0, MARGIN + 5.0, &regular);
+        }
+        cursor.skip(LINE_HEIGHT / 2.0);
+    }
+
+    cursor.skip(LINE_HEIGHT);
+    cursor.line(
+        &format!(
+            "Erstellt am {}",
+            generated_at.with_timezone(&Berlin).format("%d.%m.%Y %H:%M")
+        ),
+        9.0,
+        MARGIN,
+        &regular,
+    );
+
+    Ok(doc.save_to_bytes()?)
+}
+
+/// Get the bookings for the week starting at `monday` from the db and render them for `subject`.
+pub async fn render_week_schedule_from_db(
+    config: &Config,
+    subject: &ScheduleSubject<'_>,
+    monday: NaiveDate,
+) -> Result<Vec<u8>, PdfError> {
+    let (start, end) = week_interval_utc(monday);
+    let bookings = config
+        .db
+        .get_visible_bookings_in_timeframe(start, end)
+        .await?;
+    render_week_schedule(subject, &bookings, monday, Utc::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn week_starts_on_monday() {
+        let wednesday = NaiveDate::from_ymd_opt(2024, 10, 16).unwrap();
+        assert_eq!(
+            week_start(wednesday),
+            NaiveDate::from_ymd_opt(2024, 10, 14).unwrap()
+        );
+        let monday = NaiveDate::from_ymd_opt(2024, 10, 14).unwrap();
+        assert_eq!(week_start(monday), monday);
+    }
+
+    #[test]
+    fn week_interval_is_in_local_time() {
+        // last week of daylight saving time in 2024
+        let monday = NaiveDate::from_ymd_opt(2024, 10, 21).unwrap();
+        let (start, end) = week_interval_utc(monday);
+        assert_eq!(start.to_string(), "2024-10-20 22:00:00");
+        assert_eq!(end.to_string(), "2024-10-27 22:59:59");
+    }
+
+    #[test]
+    fn multi_day_bookings_on_every_day() {
+        let booking = Booking {
+            title: "Freizeit".to_owned(),
+            booking_id: 1,
+            source: "churchtools".to_owned(),
+            resource_id: 10,
+            // 18:00 on the 16th to 12:00 on the 18th, local time
+            start_time: DateTime::parse_from_rfc3339("2024-10-16T16:00:00+00:00")
+                .unwrap()
+                .into(),
+            end_time: DateTime::parse_from_rfc3339("2024-10-18T10:00:00+00:00")
+                .unwrap()
+                .into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: crate::BookingOrigin::ChurchTools,
+        };
+        let day = |d| NaiveDate::from_ymd_opt(2024, 10, d).unwrap();
+        assert_eq!(times_on_day(&booking, day(15)), None);
+        assert_eq!(times_on_day(&booking, day(16)).unwrap(), "18:00 - 24:00");
+        assert_eq!(times_on_day(&booking, day(17)).unwrap(), "00:00 - 24:00");
+        assert_eq!(times_on_day(&booking, day(18)).unwrap(), "00:00 - 12:00");
+        assert_eq!(times_on_day(&booking, day(19)), None);
+
+        // ending at local midnight does not show on the next day
+        let until_midnight = Booking {
+            end_time: DateTime::parse_from_rfc3339("2024-10-16T22:00:00+00:00")
+                .unwrap()
+                .into(),
+            ..booking
+        };
+        assert_eq!(
+            times_on_day(&until_midnight, day(16)).unwrap(),
+            "18:00 - 24:00"
+        );
+        assert_eq!(times_on_day(&until_midnight, day(17)), None);
+    }
+
+    #[test]
+    fn render_room_schedule() {
+        let room = RoomConfig {
+            churchtools_id: 10,
+            id: None,
+            source: "churchtools".to_owned(),
+            name: "Saal".to_owned(),
+            location_hint: "Erdgeschoss".to_owned(),
+            building: None,
+            attributes: crate::config::RoomAttributes::default(),
+            attributes_from_ct: false,
+            setup_minutes: None,
+            teardown_minutes: None,
+            preheat_minutes: None,
+        };
+        let bookings = vec![Booking {
+            title: "Gemeindefrühstück".to_owned(),
+            booking_id: 1,
+            source: "churchtools".to_owned(),
+            resource_id: 10,
+            start_time: DateTime::parse_from_rfc3339("2024-10-16T08:00:00+00:00")
+                .unwrap()
+                .into(),
+            end_time: DateTime::parse_from_rfc3339("2024-10-16T10:00:00+00:00")
+                .unwrap()
+                .into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: crate::BookingOrigin::ChurchTools,
+        }];
+        let pdf = render_week_schedule(
+            &ScheduleSubject::Room(&room),
+            &bookings,
+            NaiveDate::from_ymd_opt(2024, 10, 14).unwrap(),
+            Utc::now(),
+        )
+        .unwrap();
+        assert!(pdf.starts_with(b"%PDF"));
+    }
+}
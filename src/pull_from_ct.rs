@@ -2,12 +2,16 @@
 
 use std::{collections::HashMap, sync::Arc};
 
-use chrono::Utc;
 use itertools::Itertools;
 use serde::Deserialize;
-use tracing::{debug, info, trace, warn};
+use tracing::{debug, warn};
 
-use crate::{config::Config, db::DBError, Booking, InShutdown};
+use crate::{
+    config::{ChurchToolsConfig, Config, RoomConfig},
+    ct_auth::{send, LoginError},
+    sync::{BookingSource, SourceError},
+    Booking, BookingOrigin,
+};
 // do not show bookings with this string in their description
 pub(crate) const DO_NOT_SHOW_MAGIC_STRING: &str = "NICHT_ANZEIGEN";
 
@@ -29,6 +33,12 @@ struct BookingsDataBase {
     resource: ResourceData,
     appointment: Option<AppointmentData>,
     note: Option<String>,
+    /// setup time in minutes
+    #[serde(rename = "minutesBefore", alias = "minpre")]
+    minutes_before: Option<i64>,
+    /// teardown time in minutes
+    #[serde(rename = "minutesAfter", alias = "minpost")]
+    minutes_after: Option<i64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -53,10 +63,31 @@ struct BookingsDataCalculated {
     end_date: String,
 }
 
+/// The full struct returned from CTs /api/resource/masterdata
+#[derive(Debug, Deserialize)]
+struct CTResourceMasterdataResponse {
+    data: ResourceMasterdata,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResourceMasterdata {
+    resources: Vec<ResourceDescription>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResourceDescription {
+    /// this is the resources ID
+    id: i64,
+    description: Option<String>,
+}
+
 #[derive(Debug)]
 pub enum CTApiError {
     GetBookings(reqwest::Error),
     GetAppointments(reqwest::Error),
+    GetResources(reqwest::Error),
+    CheckLogin(reqwest::Error),
+    Login(LoginError),
     Deserialize,
     Utf8Decode,
     ParseTime(chrono::ParseError),
@@ -72,6 +103,13 @@ impl core::fmt::Display for CTApiError {
             Self::GetAppointments(e) => {
                 write!(f, "Cannot get appointments. reqwest Error: {e}")
             }
+            Self::GetResources(e) => {
+                write!(f, "Cannot get resources. reqwest Error: {e}")
+            }
+            Self::CheckLogin(e) => {
+                write!(f, "Cannot check the login. reqwest Error: {e}")
+            }
+            Self::Login(e) => write!(f, "{e}"),
             Self::Deserialize => {
                 write!(f, "Cannot deserialize the response.")
             }
@@ -98,32 +136,6 @@ impl core::fmt::Display for CTApiError {
 }
 impl core::error::Error for CTApiError {}
 
-/// Something went wrong while gathering Information from CT into the DB
-#[derive(Debug)]
-pub enum GatherError {
-    DB(crate::db::DBError),
-    CT(CTApiError),
-}
-impl core::fmt::Display for GatherError {
-    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
-        match self {
-            Self::DB(x) => write!(f, "DBError: {x}"),
-            Self::CT(x) => write!(f, "CTApiError: {x}"),
-        }
-    }
-}
-impl core::error::Error for GatherError {}
-impl From<DBError> for GatherError {
-    fn from(value: DBError) -> Self {
-        Self::DB(value)
-    }
-}
-impl From<CTApiError> for GatherError {
-    fn from(value: CTApiError) -> Self {
-        Self::CT(value)
-    }
-}
-
 /// The full struct returned from CTs /api/calendar/{id}/appointments.
 #[derive(Debug, Deserialize)]
 struct CTAppointmentResponse {
@@ -155,27 +167,23 @@ struct Timeframe {
 /// of the resource.
 ///
 /// # INPUTS
-///     `config`
+///     `ct`: the CT instance
 ///     `appointment_id`: ID of the appointment (calender entry)
 ///     `calendar_id`: ID of the calendar
 ///     `day`: YYYY-mm-dd representation of the day on which to take the date for a repeating
 ///     appointment
 async fn get_appointment(
-    config: &Config,
+    ct: &ChurchToolsConfig,
     appointment_id: i64,
     calendar_id: i64,
     day: &str,
 ) -> Result<Timeframe, CTApiError> {
-    let response = match reqwest::Client::new()
-        .get(format!(
-            "https://{}/api/calendars/{}/appointments/{}",
-            config.ct.host, calendar_id, appointment_id
-        ))
-        .header("accept", "application/json")
-        .header("Authorization", format!("Login {}", config.ct.login_token))
-        .send()
-        .await
-    {
+    let request = reqwest::Client::new()
+        .get(ct.api_url(&format!(
+            "/api/calendars/{calendar_id}/appointments/{appointment_id}"
+        )))
+        .header("accept", "application/json");
+    let response = match send(ct, request, CTApiError::GetAppointments).await {
         Ok(x) => {
             let text_res = x.text().await;
             match text_res {
@@ -197,7 +205,7 @@ async fn get_appointment(
         }
         Err(e) => {
             warn!("There was a problem getting a response from CT");
-            return Err(CTApiError::GetAppointments(e));
+            return Err(e);
         }
     };
     if let Some(mut calculated_dates) = response.data.calculated_dates {
@@ -212,19 +220,118 @@ async fn get_appointment(
     }
 }
 
-/// Bet all the relevant bookings in the given timeframe.
+/// Get the descriptions of all resources from CT
+async fn get_resource_descriptions(
+    ct: &ChurchToolsConfig,
+) -> Result<Vec<ResourceDescription>, CTApiError> {
+    let request = reqwest::Client::new()
+        .get(ct.api_url("/api/resource/masterdata"))
+        .header("accept", "application/json");
+    let response = match send(ct, request, CTApiError::GetResources).await {
+        Ok(x) => {
+            let text_res = x.text().await;
+            match text_res {
+                Ok(text) => {
+                    let deser_res: Result<CTResourceMasterdataResponse, _> =
+                        serde_json::from_str(&text);
+                    if let Ok(y) = deser_res {
+                        y
+                    } else {
+                        warn!("There was an error parsing the return value from CT.");
+                        warn!("The complete text received was: {text}");
+                        return Err(CTApiError::Deserialize);
+                    }
+                }
+                Err(e) => {
+                    warn!("There was an error reading the response from CT as utf-8: {e}");
+                    return Err(CTApiError::Utf8Decode);
+                }
+            }
+        }
+        Err(e) => {
+            warn!("There was a problem getting a response from CT");
+            return Err(e);
+        }
+    };
+    Ok(response.data.resources)
+}
+
+/// Check that CT can be reached and accepts our credentials
+async fn check_login(ct: &ChurchToolsConfig) -> Result<(), CTApiError> {
+    let request = reqwest::Client::new()
+        .get(ct.api_url("/api/whoami"))
+        .header("accept", "application/json");
+    send(ct, request, CTApiError::CheckLogin)
+        .await?
+        .error_for_status()
+        .map_err(CTApiError::CheckLogin)?;
+    Ok(())
+}
+
+/// Fill the attributes of rooms with `attributes_from_ct` from their resource descriptions in CT.
+///
+/// Attributes set in the config take precedence. If a CT instance cannot be reached, the
+/// configured attributes of its rooms are kept as they are.
+pub async fn merge_room_attributes_from_ct(config: &mut Config) {
+    for ct in &config.ct {
+        if !config
+            .rooms
+            .iter()
+            .any(|r| r.attributes_from_ct && r.source == ct.name)
+        {
+            continue;
+        }
+        let resources = match get_resource_descriptions(ct).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(
+                    "Unable to get room attributes from CT source {}, using the configured ones only: {e}",
+                    ct.name
+                );
+                continue;
+            }
+        };
+        for room in config
+            .rooms
+            .iter_mut()
+            .filter(|r| r.attributes_from_ct && r.source == ct.name)
+        {
+            let Some(description) = resources
+                .iter()
+                .find(|r| r.id == room.churchtools_id)
+                .and_then(|r| r.description.as_deref())
+            else {
+                continue;
+            };
+            room.attributes
+                .merge(crate::config::RoomAttributes::from_description(description));
+            debug!(
+                "Attributes for room {} after merging CT: {:?}",
+                room.name, room.attributes
+            );
+        }
+    }
+}
+
+/// Bet all the relevant bookings of one CT instance in the given timeframe.
 ///
+/// Their `resource_id` is translated to the `id` of the room in the config.
 /// This is the main CT API function, the rest are helpers to this one
 async fn get_relevant_bookings(
     config: &Config,
+    ct: &ChurchToolsConfig,
     start_date: chrono::NaiveDate,
     end_date: chrono::NaiveDate,
 ) -> Result<Vec<Booking>, CTApiError> {
-    let mut query_strings = config
+    let room_ids = config
         .rooms
         .iter()
-        .map(|room_config| room_config.churchtools_id)
-        .unique()
+        .filter(|room_config| room_config.source == ct.name)
+        .map(|room_config| (room_config.churchtools_id, room_config.id()))
+        .collect::<HashMap<_, _>>();
+    let mut query_strings = room_ids
+        .keys()
+        .sorted()
         // we now have the resource ids we care about
         // convert them to the query parameters we need
         .map(|id| ("resource_ids[]", format!("{id}")))
@@ -232,14 +339,11 @@ async fn get_relevant_bookings(
     query_strings.push(("from", start_date.to_string()));
     query_strings.push(("to", end_date.to_string()));
     query_strings.push(("status_ids[]", "2".to_owned()));
-    let response = match reqwest::Client::new()
-        .get(format!("https://{}/api/bookings", config.ct.host))
+    let request = reqwest::Client::new()
+        .get(ct.api_url("/api/bookings"))
         .query(&query_strings)
-        .header("accept", "application/json")
-        .header("Authorization", format!("Login {}", config.ct.login_token))
-        .send()
-        .await
-    {
+        .header("accept", "application/json");
+    let response = match send(ct, request, CTApiError::GetBookings).await {
         Ok(x) => {
             let text_res = x.text().await;
             match text_res {
@@ -261,7 +365,7 @@ async fn get_relevant_bookings(
         }
         Err(e) => {
             warn!("There was a problem getting a response from CT");
-            return Err(CTApiError::GetBookings(e));
+            return Err(e);
         }
     };
     futures::future::join_all(
@@ -275,7 +379,13 @@ async fn get_relevant_bookings(
                     .as_ref()
                     .is_some_and(|note| note.contains(DO_NOT_SHOW_MAGIC_STRING))
             })
-            .map(|x: BookingsData| async move {
+            // CT only sends the resources we asked for
+            .filter_map(|x: BookingsData| {
+                room_ids
+                    .get(&x.base.resource.id)
+                    .map(|&resource_id| (resource_id, x))
+            })
+            .map(|(resource_id, x)| async move {
                 // potentially change the start/end date to those of a calendar appointment if this
                 // resource bookings was created from a calendar appointment
                 let (start_date, end_date) = if let Some(AppointmentData {
@@ -290,7 +400,7 @@ async fn get_relevant_bookings(
                         .next()
                         .expect("Split always has a first element");
                     let calendar_appointment =
-                        get_appointment(config, appointment_id, calendar_id, start_day).await?;
+                        get_appointment(ct, appointment_id, calendar_id, start_day).await?;
                     (
                         calendar_appointment.start_date,
                         calendar_appointment.end_date,
@@ -301,7 +411,8 @@ async fn get_relevant_bookings(
                 Ok::<Booking, CTApiError>(Booking {
                     title: x.base.title,
                     booking_id: x.base.id,
-                    resource_id: x.base.resource.id,
+                    source: ct.name.clone(),
+                    resource_id,
                     start_time: chrono::DateTime::parse_from_rfc3339(&start_date)
                         .map_err(CTApiError::ParseTime)?
                         // we get the date from CT with an unknown offset, and need to cast to UTC
@@ -310,6 +421,10 @@ async fn get_relevant_bookings(
                     end_time: chrono::DateTime::parse_from_rfc3339(&end_date)
                         .map_err(CTApiError::ParseTime)?
                         .into(),
+                    // CT sends 0 when no buffer was entered
+                    setup_minutes: x.base.minutes_before.filter(|&m| m > 0),
+                    teardown_minutes: x.base.minutes_after.filter(|&m| m > 0),
+                    origin: BookingOrigin::ChurchTools,
                 })
             }),
     )
@@ -318,88 +433,54 @@ async fn get_relevant_bookings(
     .collect::<Result<Vec<_>, _>>()
 }
 
-async fn get_bookings_into_db(config: Arc<Config>) -> Result<(), GatherError> {
-    let start = Utc::now().naive_utc().into();
-    let end = start + chrono::TimeDelta::days(1);
-    // get bookings from CT
-    let bookings_from_ct = get_relevant_bookings(&config, start, end).await?;
-    // get bookings from db
-    let bookings_from_db = crate::db::get_bookings_in_timeframe(
-        &config.db,
-        start.and_time(chrono::NaiveTime::from_hms_opt(0, 0, 0).expect("statically good time")),
-        end.and_time(chrono::NaiveTime::from_hms_opt(23, 59, 59).expect("statically good time")),
-    )
-    .await?;
+/// A `[[ct]]` instance as a source of bookings
+pub struct ChurchToolsSource {
+    config: Arc<Config>,
+    /// Position in `config.ct`
+    index: usize,
+}
+impl ChurchToolsSource {
+    pub fn new(config: Arc<Config>, index: usize) -> Self {
+        Self { config, index }
+    }
 
-    // compare the two sources
-    // add new bookings
-    trace!("in db: {bookings_from_db:?}");
-    trace!("in ct: {bookings_from_ct:?}");
-    let new_bookings = bookings_from_ct.iter().filter(|b| {
-        !bookings_from_db
-            .iter()
-            .any(|x| x.booking_id == b.booking_id)
-    });
-    trace!(
-        "Adding these bookings: {:?}",
-        new_bookings.clone().collect::<Vec<_>>()
-    );
-    crate::db::insert_bookings(&config.db, new_bookings).await?;
+    fn ct(&self) -> &ChurchToolsConfig {
+        &self.config.ct[self.index]
+    }
+}
+#[async_trait::async_trait]
+impl BookingSource for ChurchToolsSource {
+    fn name(&self) -> &str {
+        &self.ct().name
+    }
 
-    // remove bookings no longer present in ct
-    let deprecated_bookings = bookings_from_db
-        .iter()
-        .map(|b| b.booking_id)
-        .filter(|&id| !bookings_from_ct.iter().any(|x| x.booking_id == id));
-    crate::db::delete_bookings(&config.db, deprecated_bookings).await?;
+    fn pull_frequency(&self) -> u64 {
+        self.ct().ct_pull_frequency
+    }
 
-    // Update bookings that have changed times in CT
-    let changed_bookings = bookings_from_ct.iter().filter(|b| {
-        bookings_from_db
+    fn pull_days(&self) -> i64 {
+        self.ct().ct_pull_days
+    }
+
+    fn room_ids(&self) -> Vec<i64> {
+        self.config
+            .rooms
             .iter()
-            .any(|x| x.booking_id == b.booking_id && x != *b)
-    });
-    crate::db::update_bookings(&config.db, changed_bookings).await?;
-    Ok(())
-}
+            .filter(|r| r.source == self.ct().name)
+            .map(RoomConfig::id)
+            .unique()
+            .collect()
+    }
 
-pub async fn keep_db_up_to_date(
-    config: Arc<Config>,
-    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
-) {
-    info!("Starting CT -> DB Sync task");
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(
-        config.ct.ct_pull_frequency,
-    ));
-    interval.tick().await;
-    loop {
-        debug!("Gatherer starting new run.");
-        // get new data
-        let ct_to_db_res = get_bookings_into_db(config.clone()).await;
-        match ct_to_db_res {
-            Ok(()) => debug!("Successfully updated db."),
-            Err(e) => {
-                warn!("Failed to update db from CT. Error encountered: {e}");
-            }
-        }
-        // prune old entries in db
-        let db_prune_res = crate::db::prune_old_bookings(&config.db).await;
-        match db_prune_res {
-            Ok(x) => match x {
-                0 => debug!("Successfully pruned db. Removed {x} old bookings."),
-                y => info!("Successfully pruned db. Removed {y} old bookings."),
-            },
-            Err(e) => {
-                warn!("Failed to prune db. Error encountered: {e}");
-            }
-        }
-        // stop on cancellation or continue after the next tick
-        tokio::select! {
-            _ = watcher.changed() => {
-                debug!("Shutting down data gatherer now.");
-                return;
-            }
-            _ = interval.tick() => {}
-        }
+    async fn fetch_bookings(
+        &self,
+        start: chrono::NaiveDate,
+        end: chrono::NaiveDate,
+    ) -> Result<Vec<Booking>, SourceError> {
+        Ok(get_relevant_bookings(&self.config, self.ct(), start, end).await?)
+    }
+
+    async fn check_health(&self) -> Result<(), SourceError> {
+        Ok(check_login(self.ct()).await?)
     }
 }
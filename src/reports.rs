@@ -0,0 +1,487 @@
+//! Utilization reports: how much of the time a room could be used it was actually booked.
+//!
+//! The report counts the hours `from_hour..to_hour` (local time) of the selected days in a period.
+//! Bookings come from the archive as well as from the current bookings, blocks are not counted.
+//! Overlapping bookings of the same room are only counted once.
+
+use chrono::{DateTime, Datelike, NaiveDate, TimeDelta, Utc, Weekday};
+use chrono_tz::Europe::Berlin;
+use serde::Deserialize;
+
+use crate::{config::Config, db::DBError, pdf::WEEKDAYS, Booking};
+
+/// Reports may cover at most this many days
+const MAX_REPORT_DAYS: i64 = 3 * 366;
+
+/// Which days of the week count as available
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DaySelection {
+    All,
+    /// Monday to Friday
+    #[default]
+    Weekdays,
+    Weekends,
+}
+impl DaySelection {
+    pub fn contains(self, day: Weekday) -> bool {
+        let weekend = matches!(day, Weekday::Sat | Weekday::Sun);
+        match self {
+            Self::All => true,
+            Self::Weekdays => !weekend,
+            Self::Weekends => weekend,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::All => "all",
+            Self::Weekdays => "weekdays",
+            Self::Weekends => "weekends",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::All => "an allen Tagen",
+            Self::Weekdays => "an Werktagen",
+            Self::Weekends => "am Wochenende",
+        }
+    }
+}
+
+/// The time a report is about
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportPeriod {
+    /// First day, inclusive
+    pub start: NaiveDate,
+    /// Last day, inclusive
+    pub end: NaiveDate,
+    pub days: DaySelection,
+    /// Local hour at which rooms become available each day
+    pub from_hour: u32,
+    /// Local hour at which rooms stop being available each day, at most 24
+    pub to_hour: u32,
+}
+impl ReportPeriod {
+    /// The full quarter before the one containing `today`, 8 to 22 on weekdays
+    pub fn last_quarter(today: NaiveDate) -> Self {
+        let this_quarter = NaiveDate::from_ymd_opt(today.year(), (today.month0() / 3) * 3 + 1, 1)
+            .expect("first day of a quarter always exists");
+        let start = this_quarter
+            .checked_sub_months(chrono::Months::new(3))
+            .expect("quarters before year 0 are not used");
+        Self {
+            start,
+            end: this_quarter - TimeDelta::days(1),
+            days: DaySelection::Weekdays,
+            from_hour: 8,
+            to_hour: 22,
+        }
+    }
+
+    /// Check that the period makes sense. Errors are human readable.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.end < self.start {
+            return Err("Das Ende liegt vor dem Anfang.".to_owned());
+        }
+        if (self.end - self.start).num_days() >= MAX_REPORT_DAYS {
+            return Err("Der Zeitraum darf höchstens drei Jahre lang sein.".to_owned());
+        }
+        if self.to_hour > 24 || self.from_hour >= self.to_hour {
+            return Err(
+                "Die Stunden müssen zwischen 0 und 24 liegen, der Beginn vor dem Ende.".to_owned(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Every hour counted as available, as (weekday, local hour, UTC start, UTC end)
+    fn hours(&self) -> Vec<(Weekday, u32, DateTime<Utc>, DateTime<Utc>)> {
+        let local_utc = |day: NaiveDate, hour: u32| {
+            let day = day + TimeDelta::days(i64::from(hour / 24));
+            day.and_hms_opt(hour % 24, 0, 0)?
+                .and_local_timezone(Berlin)
+                .earliest()
+                .map(|t| t.to_utc())
+        };
+        self.start
+            .iter_days()
+            .take_while(|d| *d <= self.end)
+            .filter(|d| self.days.contains(d.weekday()))
+            .flat_map(|day| {
+                (self.from_hour..self.to_hour).filter_map(move |hour| {
+                    // hours skipped by DST are not available
+                    Some((
+                        day.weekday(),
+                        hour,
+                        local_utc(day, hour)?,
+                        local_utc(day, hour + 1)?,
+                    ))
+                })
+            })
+            .collect()
+    }
+
+    /// e.g. "8-22 Uhr an Werktagen"
+    pub fn describe_hours(&self) -> String {
+        format!(
+            "{}-{} Uhr {}",
+            self.from_hour,
+            self.to_hour,
+            self.days.label()
+        )
+    }
+}
+
+/// Booked and available minutes of some time slot
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub booked_minutes: i64,
+    pub available_minutes: i64,
+}
+impl Usage {
+    /// Share of the available time that was booked, 0 if nothing was available
+    pub fn ratio(self) -> f64 {
+        if self.available_minutes == 0 {
+            0.0
+        } else {
+            self.booked_minutes as f64 / self.available_minutes as f64
+        }
+    }
+
+    pub fn percent(self) -> String {
+        format!("{:.0}%", self.ratio() * 100.0)
+    }
+
+    fn add(&mut self, other: Self) {
+        self.booked_minutes += other.booked_minutes;
+        self.available_minutes += other.available_minutes;
+    }
+}
+
+/// The utilization of a single room
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomUtilization {
+    pub resource_id: i64,
+    pub room: String,
+    pub total: Usage,
+    /// By weekday (Monday first) and local hour
+    pub by_hour: [[Usage; 24]; 7],
+}
+
+/// The bookings of one room as disjoint intervals, sorted by start
+fn merged_intervals(resource_id: i64, bookings: &[Booking]) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut intervals = bookings
+        .iter()
+        .filter(|b| b.resource_id == resource_id && b.start_time < b.end_time)
+        .map(|b| (b.start_time, b.end_time))
+        .collect::<Vec<_>>();
+    intervals.sort();
+    let mut merged: Vec<(DateTime<Utc>, DateTime<Utc>)> = vec![];
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// The utilization of room `resource_id` in `period`
+pub fn room_utilization(
+    resource_id: i64,
+    room: &str,
+    period: &ReportPeriod,
+    bookings: &[Booking],
+) -> RoomUtilization {
+    let intervals = merged_intervals(resource_id, bookings);
+    let mut res = RoomUtilization {
+        resource_id,
+        room: room.to_owned(),
+        total: Usage::default(),
+        by_hour: [[Usage::default(); 24]; 7],
+    };
+    for (weekday, hour, start, end) in period.hours() {
+        let booked = intervals
+            .iter()
+            .map(|(s, e)| (end.min(*e) - start.max(*s)).num_minutes().max(0))
+            .sum();
+        let usage = Usage {
+            booked_minutes: booked,
+            available_minutes: (end - start).num_minutes(),
+        };
+        res.total.add(usage);
+        res.by_hour[weekday.num_days_from_monday() as usize][hour as usize].add(usage);
+    }
+    res
+}
+
+/// The utilization of all rooms, or only of `room`, in the order of the config
+pub async fn utilization(
+    config: &Config,
+    period: &ReportPeriod,
+    room: Option<i64>,
+) -> Result<Vec<RoomUtilization>, DBError> {
+    let hours = period.hours();
+    let (Some(first), Some(last)) = (hours.first(), hours.last()) else {
+        return Ok(vec![]);
+    };
+    let bookings = config
+        .db
+        .get_used_bookings_in_timeframe(first.2.naive_utc(), last.3.naive_utc())
+        .await?;
+    Ok(config
+        .rooms
+        .iter()
+        .filter(|r| room.is_none_or(|id| id == r.id()))
+        .map(|r| room_utilization(r.id(), &r.name, period, &bookings))
+        .collect())
+}
+
+/// The usage of all `rooms` together, by weekday and hour
+pub fn combined_by_hour(rooms: &[RoomUtilization]) -> [[Usage; 24]; 7] {
+    let mut res = [[Usage::default(); 24]; 7];
+    for room in rooms {
+        for (day, hours) in room.by_hour.iter().enumerate() {
+            for (hour, usage) in hours.iter().enumerate() {
+                res[day][hour].add(*usage);
+            }
+        }
+    }
+    res
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// One line per room, or per room, weekday and hour with `by_hour`
+pub fn to_csv(rooms: &[RoomUtilization], by_hour: bool) -> String {
+    let mut csv = if by_hour {
+        "room_id,room,weekday,hour,booked_minutes,available_minutes,utilization\r\n".to_owned()
+    } else {
+        "room_id,room,booked_minutes,available_minutes,utilization\r\n".to_owned()
+    };
+    for room in rooms {
+        if !by_hour {
+            csv.push_str(&format!(
+                "{},{},{},{},{:.3}\r\n",
+                room.resource_id,
+                csv_field(&room.room),
+                room.total.booked_minutes,
+                room.total.available_minutes,
+                room.total.ratio()
+            ));
+            continue;
+        }
+        for (day, hours) in room.by_hour.iter().enumerate() {
+            for (hour, usage) in hours.iter().enumerate() {
+                if usage.available_minutes == 0 {
+                    continue;
+                }
+                csv.push_str(&format!(
+                    "{},{},{},{hour},{},{},{:.3}\r\n",
+                    room.resource_id,
+                    csv_field(&room.room),
+                    WEEKDAYS[day],
+                    usage.booked_minutes,
+                    usage.available_minutes,
+                    usage.ratio()
+                ));
+            }
+        }
+    }
+    csv
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Horizontal bars with the utilization of every room
+pub fn bar_chart_svg(rooms: &[RoomUtilization]) -> String {
+    const LABEL_WIDTH: usize = 180;
+    const BAR_WIDTH: f64 = 400.0;
+    const ROW_HEIGHT: usize = 28;
+    let height = rooms.len() * ROW_HEIGHT + 8;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{height}\" \
+         font-family=\"monospace\" font-size=\"14\">",
+        LABEL_WIDTH + BAR_WIDTH as usize + 60
+    );
+    for (i, room) in rooms.iter().enumerate() {
+        let y = i * ROW_HEIGHT + 4;
+        let width = room.total.ratio() * BAR_WIDTH;
+        svg.push_str(&format!(
+            "<text x=\"0\" y=\"{}\" fill=\"#e5e5e5\">{}</text>\
+             <rect x=\"{LABEL_WIDTH}\" y=\"{y}\" width=\"{BAR_WIDTH}\" height=\"20\" fill=\"#404040\"/>\
+             <rect x=\"{LABEL_WIDTH}\" y=\"{y}\" width=\"{width:.1}\" height=\"20\" fill=\"#7dd3fc\"/>\
+             <text x=\"{}\" y=\"{}\" fill=\"#e5e5e5\">{}</text>",
+            y + 15,
+            xml_escape(&room.room),
+            LABEL_WIDTH + BAR_WIDTH as usize + 8,
+            y + 15,
+            room.total.percent()
+        ));
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// A grid of weekdays and hours, darker the more it was booked
+pub fn heatmap_svg(by_hour: &[[Usage; 24]; 7], period: &ReportPeriod) -> String {
+    const LABEL_WIDTH: u32 = 40;
+    const CELL: u32 = 32;
+    let days = (0..7)
+        .filter(|d| {
+            period
+                .days
+                .contains(Weekday::try_from(*d as u8).expect("0..7 are weekdays"))
+        })
+        .collect::<Vec<usize>>();
+    let hours = period.from_hour..period.to_hour;
+    let width = LABEL_WIDTH + hours.len() as u32 * CELL;
+    let height = CELL + days.len() as u32 * CELL;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+         font-family=\"monospace\" font-size=\"12\">"
+    );
+    for (column, hour) in hours.clone().enumerate() {
+        svg.push_str(&format!(
+            "<text x=\"{}\" y=\"20\" fill=\"#e5e5e5\">{hour}</text>",
+            LABEL_WIDTH + column as u32 * CELL + 8
+        ));
+    }
+    for (row, day) in days.iter().enumerate() {
+        let y = CELL + row as u32 * CELL;
+        let weekday = WEEKDAYS[*day];
+        svg.push_str(&format!(
+            "<text x=\"0\" y=\"{}\" fill=\"#e5e5e5\">{}</text>",
+            y + 20,
+            &weekday[..2]
+        ));
+        for (column, hour) in hours.clone().enumerate() {
+            let usage = by_hour[*day][hour as usize];
+            svg.push_str(&format!(
+                "<rect x=\"{}\" y=\"{y}\" width=\"{}\" height=\"{}\" fill=\"#7dd3fc\" \
+                 fill-opacity=\"{:.2}\" stroke=\"#404040\"><title>{weekday} {hour}-{} Uhr: {}</title></rect>",
+                LABEL_WIDTH + column as u32 * CELL,
+                CELL,
+                CELL,
+                usage.ratio().max(0.03),
+                hour + 1,
+                usage.percent()
+            ));
+        }
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::BookingOrigin;
+
+    fn booking(resource_id: i64, start: &str, end: &str) -> Booking {
+        Booking {
+            title: "Chorprobe".to_owned(),
+            booking_id: 1,
+            source: "churchtools".to_owned(),
+            resource_id,
+            start_time: DateTime::parse_from_rfc3339(start).unwrap().into(),
+            end_time: DateTime::parse_from_rfc3339(end).unwrap().into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::ChurchTools,
+        }
+    }
+
+    #[test]
+    fn last_quarter() {
+        let period = ReportPeriod::last_quarter(NaiveDate::from_ymd_opt(2024, 10, 16).unwrap());
+        assert_eq!(period.start, NaiveDate::from_ymd_opt(2024, 7, 1).unwrap());
+        assert_eq!(period.end, NaiveDate::from_ymd_opt(2024, 9, 30).unwrap());
+        let period = ReportPeriod::last_quarter(NaiveDate::from_ymd_opt(2024, 2, 29).unwrap());
+        assert_eq!(period.start, NaiveDate::from_ymd_opt(2023, 10, 1).unwrap());
+        assert_eq!(period.end, NaiveDate::from_ymd_opt(2023, 12, 31).unwrap());
+        assert!(period.validate().is_ok());
+        assert!(ReportPeriod {
+            to_hour: 8,
+            ..period
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn utilization_of_a_week() {
+        // Monday to Sunday, in summer time
+        let period = ReportPeriod {
+            start: NaiveDate::from_ymd_opt(2024, 10, 14).unwrap(),
+            end: NaiveDate::from_ymd_opt(2024, 10, 20).unwrap(),
+            days: DaySelection::Weekdays,
+            from_hour: 8,
+            to_hour: 22,
+        };
+        let bookings = vec![
+            // Monday 18:00-20:00 local, overlapping bookings count once
+            booking(12, "2024-10-14T16:00:00+00:00", "2024-10-14T18:00:00+00:00"),
+            booking(12, "2024-10-14T17:00:00+00:00", "2024-10-14T18:00:00+00:00"),
+            // Tuesday 21:00-23:00 local, only until 22:00 counts
+            booking(12, "2024-10-15T19:00:00+00:00", "2024-10-15T21:00:00+00:00"),
+            // Saturday is not counted
+            booking(12, "2024-10-19T08:00:00+00:00", "2024-10-19T12:00:00+00:00"),
+            booking(13, "2024-10-14T08:00:00+00:00", "2024-10-14T12:00:00+00:00"),
+        ];
+        let room = room_utilization(12, "Saal", &period, &bookings);
+        assert_eq!(
+            room.total,
+            Usage {
+                booked_minutes: 180,
+                available_minutes: 5 * 14 * 60,
+            }
+        );
+        assert_eq!(room.total.percent(), "4%");
+        assert_eq!(
+            room.by_hour[0][18],
+            Usage {
+                booked_minutes: 60,
+                available_minutes: 60,
+            }
+        );
+        assert_eq!(room.by_hour[1][21].booked_minutes, 60);
+        assert_eq!(room.by_hour[5][12], Usage::default());
+
+        let csv = to_csv(core::slice::from_ref(&room), false);
+        assert_eq!(
+            csv,
+            "room_id,room,booked_minutes,available_minutes,utilization\r\n12,Saal,180,4200,0.043\r\n"
+        );
+        assert!(to_csv(&[room], true).contains("12,Saal,Montag,18,60,60,1.000\r\n"));
+    }
+
+    #[test]
+    fn dst_days_have_other_lengths() {
+        // the clocks go back on 2024-10-27 at 03:00
+        let period = ReportPeriod {
+            start: NaiveDate::from_ymd_opt(2024, 10, 27).unwrap(),
+            end: NaiveDate::from_ymd_opt(2024, 10, 27).unwrap(),
+            days: DaySelection::All,
+            from_hour: 0,
+            to_hour: 24,
+        };
+        let room = room_utilization(12, "Saal", &period, &[]);
+        assert_eq!(room.total.available_minutes, 25 * 60);
+    }
+}
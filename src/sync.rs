@@ -0,0 +1,911 @@
+//! Keep the db in sync with the sources bookings are pulled from
+//!
+//! Each source implements [`BookingSource`]. The sync tasks, the comparison with the db and the
+//! shared [`SyncState`] do not know what kind of source they are syncing.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use tracing::{debug, info, trace, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::Config,
+    db::{BookingChanges, DBError},
+    history::NewBookingEvent,
+    local_bookings::LOCAL_SOURCE,
+    pull_from_calendar::{CalendarError, CalendarSource},
+    pull_from_ct::{CTApiError, ChurchToolsSource},
+    webhooks::WebhookEvent,
+    Booking, InShutdown,
+};
+
+/// Something bookings are pulled from and kept in sync with
+#[async_trait::async_trait]
+pub trait BookingSource: Send + Sync {
+    /// The name bookings of this source are stored under
+    fn name(&self) -> &str;
+
+    /// Seconds between two syncs
+    fn pull_frequency(&self) -> u64;
+
+    /// How many days ahead of today are synced
+    fn pull_days(&self) -> i64;
+
+    /// The `id`s of all rooms booked in this source
+    fn room_ids(&self) -> Vec<i64>;
+
+    /// All bookings touching the days `start` to `end`.
+    ///
+    /// Their `resource_id` is the `id` of the room in the config and their `source` is `name`.
+    async fn fetch_bookings(
+        &self,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> Result<Vec<Booking>, SourceError>;
+
+    /// Check that the source can be reached with the configured credentials
+    async fn check_health(&self) -> Result<(), SourceError>;
+}
+
+/// All sources in the config, CT first
+pub fn sources(config: &Arc<Config>) -> Vec<Box<dyn BookingSource>> {
+    (0..config.ct.len())
+        .map(|i| Box::new(ChurchToolsSource::new(config.clone(), i)) as Box<dyn BookingSource>)
+        .chain(
+            (0..config.calendars.len()).map(|i| {
+                Box::new(CalendarSource::new(config.clone(), i)) as Box<dyn BookingSource>
+            }),
+        )
+        .collect()
+}
+
+/// A source did not give us its bookings
+#[derive(Debug)]
+pub enum SourceError {
+    CT(CTApiError),
+    Calendar(CalendarError),
+}
+impl core::fmt::Display for SourceError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::CT(x) => write!(f, "CTApiError: {x}"),
+            Self::Calendar(x) => write!(f, "CalendarError: {x}"),
+        }
+    }
+}
+impl core::error::Error for SourceError {}
+impl From<CTApiError> for SourceError {
+    fn from(value: CTApiError) -> Self {
+        Self::CT(value)
+    }
+}
+impl From<CalendarError> for SourceError {
+    fn from(value: CalendarError) -> Self {
+        Self::Calendar(value)
+    }
+}
+
+/// Something went wrong while gathering Information from a source into the DB
+#[derive(Debug)]
+pub enum GatherError {
+    DB(DBError),
+    Source(SourceError),
+}
+impl core::fmt::Display for GatherError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::DB(x) => write!(f, "DBError: {x}"),
+            Self::Source(x) => write!(f, "{x}"),
+        }
+    }
+}
+impl core::error::Error for GatherError {}
+impl From<DBError> for GatherError {
+    fn from(value: DBError) -> Self {
+        Self::DB(value)
+    }
+}
+impl From<SourceError> for GatherError {
+    fn from(value: SourceError) -> Self {
+        Self::Source(value)
+    }
+}
+
+/// Pull the bookings of the next `pull_days` days from `source` into the db
+async fn sync_source(
+    config: &Config,
+    source: &dyn BookingSource,
+    sync_run: &str,
+) -> Result<(), GatherError> {
+    let start = Utc::now().naive_utc().into();
+    let end = start + chrono::TimeDelta::days(source.pull_days());
+    let bookings = source.fetch_bookings(start, end).await?;
+    sync_bookings(
+        config,
+        source.name(),
+        &source.room_ids(),
+        start,
+        end,
+        bookings,
+        sync_run,
+    )
+    .await?;
+    Ok(())
+}
+
+/// How the bookings of one source in the db have to change to match the source
+#[derive(Debug, PartialEq)]
+struct BookingDiff<'a> {
+    /// In the source, but not in the db
+    new: Vec<&'a Booking>,
+    /// Changed in the source, as (in the db, in the source)
+    changed: Vec<(&'a Booking, &'a Booking)>,
+    /// In the db, but no longer in the source
+    deprecated: Vec<&'a Booking>,
+}
+impl<'a> BookingDiff<'a> {
+    /// Compare bookings by their `booking_id`
+    fn new(from_db: &'a [Booking], from_source: &'a [Booking]) -> Self {
+        Self {
+            new: from_source
+                .iter()
+                .filter(|b| !from_db.iter().any(|x| x.booking_id == b.booking_id))
+                .collect(),
+            changed: from_source
+                .iter()
+                .filter_map(|b| {
+                    from_db
+                        .iter()
+                        .find(|x| x.booking_id == b.booking_id && x != &b)
+                        .map(|previous| (previous, b))
+                })
+                .collect(),
+            deprecated: from_db
+                .iter()
+                .filter(|b| !from_source.iter().any(|x| x.booking_id == b.booking_id))
+                .collect(),
+        }
+    }
+}
+
+/// Bring the bookings of `source` between `start` and `end` in the db in line with
+/// `bookings_from_source`, and look for conflicts in the rooms `resource_ids` it books
+async fn sync_bookings(
+    config: &Config,
+    source: &str,
+    resource_ids: &[i64],
+    start: chrono::NaiveDate,
+    end: chrono::NaiveDate,
+    bookings_from_source: Vec<Booking>,
+    sync_run: &str,
+) -> Result<(), DBError> {
+    let timeframe = (
+        start.and_time(chrono::NaiveTime::from_hms_opt(0, 0, 0).expect("statically good time")),
+        end.and_time(chrono::NaiveTime::from_hms_opt(23, 59, 59).expect("statically good time")),
+    );
+    // get bookings from db
+    let bookings_from_db = config
+        .db
+        .get_bookings_in_timeframe(timeframe.0, timeframe.1)
+        .await?;
+    // bookings the next prune would remove are left to it, so they are not reinserted every run
+    let reference = config.database.prune_reference(Utc::now()).and_utc();
+    let (bookings_from_source, bookings_from_db): (Vec<_>, Vec<_>) = (
+        bookings_from_source
+            .into_iter()
+            .filter(|b| b.end_time >= reference)
+            .collect(),
+        bookings_from_db
+            .into_iter()
+            // bookings of the other sources are left to their sync
+            .filter(|b| b.end_time >= reference && b.source == source)
+            .collect(),
+    );
+
+    // compare the two sources
+    trace!("in db: {bookings_from_db:?}");
+    trace!("in {source}: {bookings_from_source:?}");
+    let BookingDiff {
+        new: new_bookings,
+        changed: changed_bookings,
+        deprecated: deprecated_bookings,
+    } = BookingDiff::new(&bookings_from_db, &bookings_from_source);
+
+    let events = new_bookings
+        .iter()
+        .map(|booking| WebhookEvent::BookingInserted { booking })
+        .chain(
+            changed_bookings
+                .iter()
+                .map(|(previous, booking)| WebhookEvent::BookingUpdated { previous, booking }),
+        )
+        .chain(
+            deprecated_bookings
+                .iter()
+                .map(|booking| WebhookEvent::BookingDeleted { booking }),
+        )
+        .collect::<Vec<_>>();
+    let history = new_bookings
+        .iter()
+        .map(|booking| NewBookingEvent::inserted(booking))
+        .chain(
+            changed_bookings
+                .iter()
+                .map(|(previous, booking)| NewBookingEvent::updated(previous, booking)),
+        )
+        .chain(
+            deprecated_bookings
+                .iter()
+                .map(|booking| NewBookingEvent::deleted(booking)),
+        )
+        .collect::<Vec<_>>();
+    // the bookings, their webhooks and history are written together, so no change goes unannounced
+    // or unrecorded
+    config
+        .db
+        .apply_booking_changes(&BookingChanges {
+            source,
+            inserted: new_bookings.clone(),
+            updated: changed_bookings.iter().map(|(_, b)| *b).collect(),
+            deleted: deprecated_bookings.iter().map(|b| b.booking_id).collect(),
+            deliveries: crate::webhooks::deliveries(&config.webhooks, &events, Utc::now()),
+            sync_run: Some(sync_run),
+            history,
+        })
+        .await?;
+    for b in &new_bookings {
+        info!("Inserted new booking: {b:?}");
+    }
+    for (_, b) in &changed_bookings {
+        info!("Updated Booking {}. Is now: {:?}", b.booking_id, b);
+    }
+
+    // find bookings blocking the same resource at the same time, including those of other sources
+    // booking the same rooms
+    let bookings_in_rooms = config
+        .db
+        .get_bookings_in_timeframe(timeframe.0, timeframe.1)
+        .await?
+        .into_iter()
+        .filter(|b| b.end_time >= reference && resource_ids.contains(&b.resource_id))
+        .collect::<Vec<_>>();
+    let conflicts = crate::conflicts::detect_conflicts(&bookings_in_rooms);
+    let new_conflicts = config
+        .db
+        .sync_conflicts(timeframe.0, timeframe.1, resource_ids, &conflicts)
+        .await?;
+    crate::conflicts::notify_new_conflicts(config, &new_conflicts).await?;
+    Ok(())
+}
+
+/// Number of sync errors kept for the admin area
+const MAX_RECENT_SYNC_ERRORS: usize = 20;
+
+/// The outcome of a single sync run of one source
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SyncRun {
+    /// Recorded with the changes of this run in the booking history
+    pub id: String,
+    /// Name of the `[[ct]]` or `[[calendars]]` source that was synced
+    pub source: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// None if the run was successful
+    pub error: Option<String>,
+}
+
+/// What the sync tasks have been doing, as shown in the admin area
+#[derive(Debug, Default, Clone)]
+pub struct SyncStatus {
+    /// Start of the last run of any source
+    pub last_run: Option<DateTime<Utc>>,
+    /// Start of the last successful run of any source
+    pub last_success: Option<DateTime<Utc>>,
+    /// Most recent first, prefixed with the source
+    pub recent_errors: VecDeque<(DateTime<Utc>, String)>,
+    /// The sources syncing right now
+    pub running: BTreeSet<String>,
+    /// Number of runs of all sources since startup
+    pub completed_runs: u64,
+    /// The last run of each source
+    pub last_results: BTreeMap<String, SyncRun>,
+}
+
+/// A sync was requested too soon after the last requested sync
+#[derive(Debug, PartialEq)]
+pub struct RateLimited {
+    pub retry_after: Duration,
+}
+
+/// A requested sync, finished once each of `sources` has finished a run after `requested_at`
+#[derive(Debug, PartialEq)]
+pub struct RequestedRun {
+    pub sources: Vec<String>,
+    pub requested_at: DateTime<Utc>,
+    /// Whether the request joined the syncs that were already running
+    pub coalesced: bool,
+}
+
+/// State shared between the sync tasks of all sources and the web server
+#[derive(Debug)]
+pub struct SyncState {
+    /// Names of all sources
+    sources: Vec<String>,
+    status: tokio::sync::watch::Sender<SyncStatus>,
+    /// Bumped to wake all sync tasks for an immediate run
+    trigger: tokio::sync::watch::Sender<u64>,
+    /// Requested syncs are refused for this long after the last one
+    min_request_interval: Duration,
+    last_request: Mutex<Option<Instant>>,
+}
+impl SyncState {
+    pub fn new(sources: Vec<String>, min_request_interval: Duration) -> Self {
+        Self {
+            sources,
+            status: tokio::sync::watch::Sender::new(SyncStatus::default()),
+            trigger: tokio::sync::watch::Sender::new(0),
+            min_request_interval,
+            last_request: Mutex::new(None),
+        }
+    }
+
+    pub fn status(&self) -> SyncStatus {
+        self.status.borrow().clone()
+    }
+
+    /// Changes whenever a sync starts or finishes
+    pub fn subscribe(&self) -> tokio::sync::watch::Receiver<SyncStatus> {
+        self.status.subscribe()
+    }
+
+    /// Ask the sync tasks of all sources to run now instead of waiting for their next tick.
+    ///
+    /// A request while syncs are running joins those syncs instead of starting others.
+    /// Other requests are rate limited, so CT is not hammered.
+    pub fn request_run(&self) -> Result<RequestedRun, RateLimited> {
+        let status = self.status.borrow();
+        let requested_at = Utc::now();
+        if !status.running.is_empty() {
+            return Ok(RequestedRun {
+                sources: status.running.iter().cloned().collect(),
+                requested_at,
+                coalesced: true,
+            });
+        }
+        let now = Instant::now();
+        let mut last_request = self
+            .last_request
+            .lock()
+            .expect("sync request lock is never poisoned");
+        if let Some(last) = *last_request {
+            let since = now.duration_since(last);
+            if since < self.min_request_interval {
+                return Err(RateLimited {
+                    retry_after: self.min_request_interval - since,
+                });
+            }
+        }
+        *last_request = Some(now);
+        self.trigger.send_modify(|x| *x += 1);
+        Ok(RequestedRun {
+            sources: self.sources.clone(),
+            requested_at,
+            coalesced: false,
+        })
+    }
+
+    /// Wait until the requested run has finished and return the runs of its sources
+    pub async fn wait_for_run(&self, requested: &RequestedRun) -> Option<Vec<SyncRun>> {
+        let mut status = self.status.subscribe();
+        let status = status
+            .wait_for(|s| {
+                requested.sources.iter().all(|source| {
+                    s.last_results
+                        .get(source)
+                        .is_some_and(|r| r.finished_at >= requested.requested_at)
+                })
+            })
+            .await
+            .ok()?;
+        Some(
+            requested
+                .sources
+                .iter()
+                .filter_map(|source| status.last_results.get(source).cloned())
+                .collect(),
+        )
+    }
+
+    fn start_run(&self, source: &str) {
+        self.status.send_modify(|s| {
+            s.running.insert(source.to_owned());
+        });
+    }
+
+    fn record_run(
+        &self,
+        source: &str,
+        id: String,
+        started_at: DateTime<Utc>,
+        result: Result<(), String>,
+    ) {
+        let finished_at = Utc::now();
+        self.status.send_modify(|status| {
+            status.running.remove(source);
+            status.completed_runs += 1;
+            status.last_run = Some(started_at);
+            status.last_results.insert(
+                source.to_owned(),
+                SyncRun {
+                    id,
+                    source: source.to_owned(),
+                    started_at,
+                    finished_at,
+                    error: result.as_ref().err().cloned(),
+                },
+            );
+            match result {
+                Ok(()) => status.last_success = Some(started_at),
+                Err(e) => {
+                    status
+                        .recent_errors
+                        .push_front((started_at, format!("{source}: {e}")));
+                    status.recent_errors.truncate(MAX_RECENT_SYNC_ERRORS);
+                }
+            }
+        });
+    }
+}
+
+/// Remove everything from the db that is no longer needed.
+///
+/// Everything that ended before the start of the day `[db] retain_days` ago is removed.
+/// `sync_run` is recorded with the pruned bookings in the history.
+/// Returns the number of bookings removed.
+pub async fn prune_db(config: &Config, sync_run: Option<&str>) -> Result<u64, DBError> {
+    let now = Utc::now();
+    let reference = config.database.prune_reference(now);
+    let removed = config.db.prune_old_bookings(sync_run, reference).await?;
+    config.db.prune_old_conflicts(reference).await?;
+    config.db.prune_old_local_bookings(reference).await?;
+    config.db.prune_old_announcements(reference).await?;
+    config.db.prune_expired_admin_sessions().await?;
+    config
+        .db
+        .prune_old_booking_events(
+            (now - chrono::TimeDelta::days(config.history.retain_days)).naive_utc(),
+        )
+        .await?;
+    Ok(removed)
+}
+
+/// Delete the bookings of sources that are no longer configured, e.g. after a rename.
+///
+/// `sync_run` is recorded with the deleted bookings in the history.
+/// Returns the number of bookings removed.
+pub async fn remove_unconfigured_sources(config: &Config, sync_run: &str) -> Result<u64, DBError> {
+    let configured = config
+        .ct
+        .iter()
+        .map(|ct| ct.name.as_str())
+        .chain(config.calendars.iter().map(|c| c.name.as_str()))
+        .chain([LOCAL_SOURCE])
+        .collect::<BTreeSet<_>>();
+    let everything = (
+        DateTime::UNIX_EPOCH.naive_utc(),
+        NaiveDate::from_ymd_opt(9999, 12, 31)
+            .and_then(|d| d.and_hms_opt(23, 59, 59))
+            .expect("statically good time"),
+    );
+    let mut orphans = BTreeMap::<_, Vec<_>>::new();
+    for booking in config
+        .db
+        .get_bookings_in_timeframe(everything.0, everything.1)
+        .await?
+    {
+        if !configured.contains(booking.source.as_str()) {
+            orphans
+                .entry(booking.source.clone())
+                .or_default()
+                .push(booking);
+        }
+    }
+    let mut removed = 0;
+    for (source, bookings) in &orphans {
+        let events = bookings
+            .iter()
+            .map(|booking| WebhookEvent::BookingDeleted { booking })
+            .collect::<Vec<_>>();
+        config
+            .db
+            .apply_booking_changes(&BookingChanges {
+                source,
+                deleted: bookings.iter().map(|b| b.booking_id).collect(),
+                deliveries: crate::webhooks::deliveries(&config.webhooks, &events, Utc::now()),
+                sync_run: Some(sync_run),
+                history: bookings.iter().map(NewBookingEvent::deleted).collect(),
+                ..Default::default()
+            })
+            .await?;
+        info!(
+            "Deleted {} bookings of the source {source}, which is no longer configured.",
+            bookings.len()
+        );
+        removed += bookings.len() as u64;
+    }
+    Ok(removed)
+}
+
+/// Prune the db every `[db] prune_interval` seconds, starting right away.
+///
+/// Of several instances sharing the db, only one prunes per interval.
+pub async fn keep_db_pruned(
+    config: Arc<Config>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+) {
+    info!("Starting DB pruning task");
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(
+        config.database.prune_interval,
+    ));
+    let period = chrono::TimeDelta::from_std(interval.period()).unwrap_or(chrono::TimeDelta::MAX);
+    loop {
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down db pruning now.");
+                return;
+            }
+            _ = interval.tick() => {}
+        }
+        match config
+            .db
+            .claim_periodic_job_run("prune", period, Utc::now())
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!("Not pruning the db, another instance did so recently.");
+                continue;
+            }
+            Err(e) => {
+                warn!("Failed to prune db. Error encountered: {e}");
+                continue;
+            }
+        }
+        let run_id = Uuid::new_v4().to_string();
+        match prune_db(&config, Some(&run_id)).await {
+            Ok(0) => debug!("Successfully pruned db. Removed 0 old bookings."),
+            Ok(x) => info!("Successfully pruned db in run {run_id}. Removed {x} old bookings."),
+            Err(e) => warn!("Failed to prune db. Error encountered: {e}"),
+        }
+    }
+}
+
+/// Sync `source` once, unless another instance sharing the db synced it recently.
+///
+/// A periodic run is skipped if another instance synced the source within `interval`, a requested
+/// run only if another instance started one since. Returns whether this instance synced.
+async fn sync_claimed(
+    config: &Config,
+    source: &dyn BookingSource,
+    sync: &SyncState,
+    interval: chrono::TimeDelta,
+    requested: bool,
+) -> bool {
+    let name = source.name();
+    let job = format!("sync:{name}");
+    let run_start = Utc::now();
+    let claim = if requested {
+        config.db.claim_job_run(&job, run_start, run_start).await
+    } else {
+        config
+            .db
+            .claim_periodic_job_run(&job, interval, run_start)
+            .await
+    };
+    if let Ok(false) = claim {
+        debug!("Not syncing source {name}, another instance did so recently.");
+        return false;
+    }
+    debug!("Gatherer for source {name} starting new run.");
+    let run_id = Uuid::new_v4().to_string();
+    sync.start_run(name);
+    // get new data
+    let source_to_db_res = match claim {
+        Ok(_) => sync_source(config, source, &run_id).await,
+        Err(e) => Err(e.into()),
+    };
+    match &source_to_db_res {
+        Ok(()) => debug!("Successfully updated db from source {name}."),
+        Err(e) => {
+            warn!("Failed to update db from source {name}. Error encountered: {e}");
+        }
+    }
+    sync.record_run(
+        name,
+        run_id,
+        run_start,
+        source_to_db_res.map_err(|e| e.to_string()),
+    );
+    true
+}
+
+/// Sync `source` every `pull_frequency` seconds, starting right away.
+///
+/// Of several instances sharing the db, only one syncs `source` per interval.
+pub async fn keep_db_up_to_date(
+    config: Arc<Config>,
+    source: Box<dyn BookingSource>,
+    sync: Arc<SyncState>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+) {
+    let name = source.name();
+    info!("Starting Sync task for source {name}");
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_secs(source.pull_frequency()));
+    interval.tick().await;
+    let period = chrono::TimeDelta::from_std(interval.period()).unwrap_or(chrono::TimeDelta::MAX);
+    let mut trigger = sync.trigger.subscribe();
+    let mut requested = false;
+    loop {
+        sync_claimed(&config, &*source, &sync, period, requested).await;
+        // stop on cancellation or continue after the next tick
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down data gatherer for source {name} now.");
+                return;
+            }
+            _ = interval.tick() => requested = false,
+            Ok(()) = trigger.changed() => {
+                info!("Running sync of source {name} now, as requested.");
+                interval.reset();
+                requested = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::SubsecRound;
+    use sqlx::SqlitePool;
+
+    use crate::{
+        history::{BookingEventFilter, BookingEventKind},
+        test_helpers::config,
+        BookingOrigin,
+    };
+
+    /// Serves fixed bookings without talking to anything
+    struct FakeSource {
+        bookings: Vec<Booking>,
+        reachable: bool,
+    }
+    #[async_trait::async_trait]
+    impl BookingSource for FakeSource {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn pull_frequency(&self) -> u64 {
+            60
+        }
+
+        fn pull_days(&self) -> i64 {
+            1
+        }
+
+        fn room_ids(&self) -> Vec<i64> {
+            vec![12]
+        }
+
+        async fn fetch_bookings(
+            &self,
+            start: NaiveDate,
+            end: NaiveDate,
+        ) -> Result<Vec<Booking>, SourceError> {
+            self.check_health().await?;
+            Ok(self
+                .bookings
+                .iter()
+                .filter(|b| b.start_time.date_naive() <= end && b.end_time.date_naive() >= start)
+                .cloned()
+                .collect())
+        }
+
+        async fn check_health(&self) -> Result<(), SourceError> {
+            if self.reachable {
+                Ok(())
+            } else {
+                Err(CalendarError::Parse("unreachable".to_owned()).into())
+            }
+        }
+    }
+
+    fn booking(booking_id: i64, day: u32, title: &str) -> Booking {
+        let start = NaiveDate::from_ymd_opt(2024, 10, day)
+            .unwrap()
+            .and_hms_opt(18, 0, 0)
+            .unwrap()
+            .and_utc();
+        Booking {
+            resource_id: 12,
+            booking_id,
+            source: "fake".to_owned(),
+            title: title.to_owned(),
+            start_time: start,
+            end_time: start + chrono::TimeDelta::hours(2),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::ChurchTools,
+        }
+    }
+
+    #[tokio::test]
+    async fn diff_against_fake_source() {
+        let source = FakeSource {
+            bookings: vec![
+                booking(1, 16, "Chorprobe"),
+                booking(2, 16, "Jugendkreis"),
+                booking(3, 17, "Hauskreis"),
+                // outside of the window
+                booking(4, 20, "Gottesdienst"),
+            ],
+            reachable: true,
+        };
+        let start = NaiveDate::from_ymd_opt(2024, 10, 16).unwrap();
+        let end = NaiveDate::from_ymd_opt(2024, 10, 17).unwrap();
+        let from_source = source.fetch_bookings(start, end).await.unwrap();
+        assert_eq!(from_source.len(), 3);
+        let from_db = vec![
+            booking(1, 16, "Chorprobe"),
+            booking(2, 16, "Jugendgruppe"),
+            booking(5, 17, "Abgesagt"),
+        ];
+        let diff = BookingDiff::new(&from_db, &from_source);
+        assert_eq!(diff.new, vec![&from_source[2]]);
+        assert_eq!(diff.changed, vec![(&from_db[1], &from_source[1])]);
+        assert_eq!(diff.deprecated, vec![&from_db[2]]);
+
+        let unreachable = FakeSource {
+            bookings: vec![],
+            reachable: false,
+        };
+        assert!(unreachable.check_health().await.is_err());
+        assert!(unreachable.fetch_bookings(start, end).await.is_err());
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    async fn sources_are_synced_by_one_instance(pool: SqlitePool) {
+        // the db stores whole seconds
+        let start = Utc::now().trunc_subsecs(0) + chrono::TimeDelta::hours(1);
+        let source = FakeSource {
+            bookings: vec![Booking {
+                start_time: start,
+                end_time: start + chrono::TimeDelta::hours(2),
+                ..booking(1, 16, "Chorprobe")
+            }],
+            reachable: true,
+        };
+        let instances = [config("", pool.clone()).await, config("", pool).await];
+        let syncs = [
+            SyncState::new(vec!["fake".to_owned()], Duration::from_secs(60)),
+            SyncState::new(vec!["fake".to_owned()], Duration::from_secs(60)),
+        ];
+        let interval = chrono::TimeDelta::minutes(5);
+
+        assert!(sync_claimed(&instances[0], &source, &syncs[0], interval, false).await);
+        assert!(!sync_claimed(&instances[1], &source, &syncs[1], interval, false).await);
+        assert_eq!(syncs[1].status().completed_runs, 0);
+        // a requested sync runs anyway, but finds nothing to change
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(sync_claimed(&instances[1], &source, &syncs[1], interval, true).await);
+        assert_eq!(syncs[1].status().last_results["fake"].error, None);
+        assert_eq!(
+            instances[0]
+                .db
+                .get_booking_events(&BookingEventFilter::default(), 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    async fn bookings_of_unconfigured_sources_are_removed(pool: SqlitePool) {
+        let config = config("", pool).await;
+        let kept = Booking {
+            source: "churchtools".to_owned(),
+            ..booking(1, 16, "Chorprobe")
+        };
+        // `fake` was renamed to `churchtools`
+        let renamed = booking(1, 16, "Chorprobe");
+        for booking in [&kept, &renamed] {
+            config
+                .db
+                .apply_booking_changes(&BookingChanges {
+                    source: &booking.source,
+                    inserted: vec![booking],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            remove_unconfigured_sources(&config, "run").await.unwrap(),
+            1
+        );
+        let start = NaiveDate::from_ymd_opt(2024, 10, 16).unwrap();
+        let bookings = config
+            .db
+            .get_bookings_in_timeframe(
+                start.and_hms_opt(0, 0, 0).unwrap(),
+                start.and_hms_opt(23, 59, 59).unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(bookings, vec![kept]);
+        let events = config
+            .db
+            .get_booking_events(&BookingEventFilter::default(), 10)
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, BookingEventKind::Deleted);
+        assert_eq!(events[0].source, "fake");
+        assert_eq!(events[0].sync_run.as_deref(), Some("run"));
+        assert_eq!(
+            remove_unconfigured_sources(&config, "run").await.unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn sync_requests_coalesce_and_are_rate_limited() {
+        let sources = vec!["a".to_owned(), "b".to_owned()];
+        let sync = Arc::new(SyncState::new(sources.clone(), Duration::from_secs(60)));
+        let mut trigger = sync.trigger.subscribe();
+        let first = sync.request_run().unwrap();
+        assert_eq!(first.sources, sources);
+        assert!(!first.coalesced);
+        // too soon after the first request
+        assert!(sync.request_run().is_err());
+
+        // a request while a sync is running joins it
+        trigger.changed().await.unwrap();
+        sync.start_run("a");
+        sync.start_run("b");
+        let joined = sync.request_run().unwrap();
+        assert!(joined.coalesced);
+        let waiter = tokio::spawn({
+            let sync = sync.clone();
+            async move { sync.wait_for_run(&first).await }
+        });
+        sync.record_run("a", "run a".to_owned(), Utc::now(), Ok(()));
+        assert!(!waiter.is_finished());
+        sync.record_run(
+            "b",
+            "run b".to_owned(),
+            Utc::now(),
+            Err("CT is down".to_owned()),
+        );
+        let runs = waiter.await.unwrap().unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].error, None);
+        assert_eq!(runs[1].error.as_deref(), Some("CT is down"));
+        assert_eq!(
+            sync.status().recent_errors.front().unwrap().1,
+            "b: CT is down"
+        );
+        assert!(sync.status().running.is_empty());
+    }
+}
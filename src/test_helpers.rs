@@ -0,0 +1,90 @@
+//! Helpers shared by the tests of several modules
+
+use chrono::{DateTime, Utc};
+use sqlx::SqlitePool;
+
+use crate::{
+    config::{Config, RoomAttributes, RoomConfig},
+    db::SqliteStorage,
+    Booking, BookingOrigin,
+};
+
+/// Parse an RFC 3339 time
+pub fn time(x: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(x).unwrap().into()
+}
+
+/// A booking with ID 1 from the CT source `churchtools`, without setup or teardown
+pub fn booking(title: &str, resource_id: i64, start: &str, end: &str) -> Booking {
+    Booking {
+        title: title.to_owned(),
+        booking_id: 1,
+        source: "churchtools".to_owned(),
+        resource_id,
+        start_time: time(start),
+        end_time: time(end),
+        setup_minutes: None,
+        teardown_minutes: None,
+        origin: BookingOrigin::ChurchTools,
+    }
+}
+
+/// A room of the CT source `churchtools`, in no building and without attributes
+pub fn room(churchtools_id: i64) -> RoomConfig {
+    RoomConfig {
+        churchtools_id,
+        id: None,
+        source: "churchtools".to_owned(),
+        name: "Saal".to_owned(),
+        location_hint: "Erdgeschoss".to_owned(),
+        building: None,
+        attributes: RoomAttributes::default(),
+        attributes_from_ct: false,
+        setup_minutes: None,
+        teardown_minutes: None,
+        preheat_minutes: None,
+    }
+}
+
+/// A config with the CT source `churchtools` and the rooms 10 to 12, storing everything in `pool`.
+///
+/// `extra` is appended to the config file, so it may only contain tables.
+pub async fn config(extra: &str, pool: SqlitePool) -> Config {
+    let mut config = Config::from_toml(&format!(
+        r#"
+        log_level = "warn"
+
+        [db]
+        url = "sqlite::memory:"
+
+        [web]
+        addr = "127.0.0.1"
+
+        [ct]
+        host = "gemeinde.church.tools"
+        login_token = "token"
+        ct_pull_frequency = 300
+
+        [[rooms]]
+        churchtools_id = 10
+        name = "Saal"
+        location_hint = "Erdgeschoss"
+
+        [[rooms]]
+        churchtools_id = 11
+        name = "Jugendraum"
+        location_hint = "Keller"
+
+        [[rooms]]
+        churchtools_id = 12
+        name = "Kapelle"
+        location_hint = "Obergeschoss"
+
+        {extra}
+        "#
+    ))
+    .await
+    .unwrap();
+    config.db = Box::new(SqliteStorage::new(pool));
+    config
+}
@@ -0,0 +1,117 @@
+//! Per-route access policies, configured in `[access]`.
+
+use std::{net::SocketAddr, sync::Arc, time::Instant};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Query, Request},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use super::api::check_api_token;
+use crate::{
+    auth::{LoginThrottle, VerifiedLogins},
+    config::{AccessPolicy, Config},
+};
+
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// The user name and password from a basic auth header
+fn basic_credentials(request: &Request) -> Option<(String, String)> {
+    let encoded = request
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+    let (user_name, password) = decoded.split_once(':')?;
+    Some((user_name.to_owned(), password.to_owned()))
+}
+
+/// Enforce the policy configured for the matched route
+pub(super) async fn enforce_access(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(throttle): Extension<Arc<LoginThrottle>>,
+    Extension(verified): Extension<Arc<VerifiedLogins>>,
+    matched_path: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    // the peer address; proxy headers are deliberately not trusted
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    match config.access.policy_for(matched_path.as_str()) {
+        AccessPolicy::Public => (),
+        AccessPolicy::Allowlist { allow } => {
+            if !peer.is_some_and(|ip| allow.iter().any(|net| net.contains(ip))) {
+                debug!(
+                    "Denying access to {} for {peer:?}: not in allowlist.",
+                    matched_path.as_str()
+                );
+                return (StatusCode::FORBIDDEN, "Access denied.").into_response();
+            }
+        }
+        AccessPolicy::BasicAuth => {
+            let throttled = peer.and_then(|ip| throttle.retry_after(ip, Instant::now()));
+            if let Some(retry_after) = throttled {
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, retry_after.as_secs().to_string())],
+                    "Too many failed logins.",
+                )
+                    .into_response();
+            }
+            let credentials = basic_credentials(&request);
+            let authorized = match &credentials {
+                Some((user_name, password)) => {
+                    verified
+                        .verify(&config.admin, user_name, password, Instant::now())
+                        .await
+                }
+                None => false,
+            };
+            if !authorized {
+                // browsers ask without credentials first, which is no failed attempt
+                if let (Some(ip), Some((user_name, _))) = (peer, &credentials) {
+                    warn!("Failed basic auth login for user {user_name} from {ip}.");
+                    throttle.record_failure(ip, Instant::now());
+                }
+                return (
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, "Basic realm=\"room-overview\"")],
+                    "Login required.",
+                )
+                    .into_response();
+            }
+        }
+        AccessPolicy::Token { scope } => {
+            let bearer = request
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .map(ToOwned::to_owned);
+            // calendar clients cannot send headers, so they put the token into the url
+            let token = bearer.or_else(|| {
+                Query::<TokenQuery>::try_from_uri(request.uri())
+                    .ok()
+                    .and_then(|Query(q)| q.token)
+            });
+            if let Some(rejection) = check_api_token(&config, token.as_deref(), *scope).await {
+                return rejection;
+            }
+        }
+    };
+    next.run(request).await
+}
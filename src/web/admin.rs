@@ -0,0 +1,924 @@
+//! The admin area under /admin, behind a login with session cookies.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+
+use askama_axum::Template;
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{ConnectInfo, DefaultBodyLimit, FromRequestParts, Path},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Extension, Form, Router,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use super::{parse_local_interval, InternalServerErrorTemplate};
+use crate::{
+    auth::{verify_login, AdminSession, LoginThrottle},
+    backup::{self, BackupError},
+    config::{Config, RoomConfig},
+    db::{DBError, DbStatistics},
+    history::{BookingEventFilter, FieldChange},
+    local_bookings::{LocalBooking, LocalBookingKind, NewLocalBooking},
+    reports::{
+        bar_chart_svg, combined_by_hour, heatmap_svg, to_csv, utilization, DaySelection,
+        ReportPeriod,
+    },
+    sync::{prune_db, RateLimited, SyncState},
+};
+
+const SESSION_COOKIE: &str = "room_overview_session";
+/// The import is uploaded by a script, which sends the CSRF token in this header
+const CSRF_HEADER: &str = "x-csrf-token";
+/// Larger uploads to /admin/import are rejected
+const MAX_IMPORT_BYTES: usize = 256 * 1024 * 1024;
+
+/// All routes of the admin area. The extensions are added by the caller.
+pub(super) fn router() -> Router {
+    Router::new()
+        .route("/admin", get(dashboard))
+        .route("/admin/login", get(login_page).post(login))
+        .route("/admin/logout", post(logout))
+        .route("/admin/sync", post(trigger_sync))
+        .route("/admin/prune", post(prune))
+        .route("/admin/conflicts", get(conflicts_page))
+        .route("/admin/history", get(history_page))
+        .route("/admin/reports", get(reports_page))
+        .route("/admin/reports.csv", get(reports_csv))
+        .route("/admin/export.json", get(export_json))
+        .route("/admin/export.sqlite", get(export_sqlite))
+        .route(
+            "/admin/import",
+            post(import).layer(DefaultBodyLimit::max(MAX_IMPORT_BYTES)),
+        )
+        .route(
+            "/admin/local_bookings",
+            get(local_bookings_page).post(create_local_booking),
+        )
+        .route(
+            "/admin/local_bookings/:id/delete",
+            post(delete_local_booking_form),
+        )
+}
+
+fn internal_server_error(what: &str, e: impl core::fmt::Display) -> Response {
+    let error_uuid = Uuid::new_v4();
+    warn!("Sending internal server error because there was a problem {what}.");
+    warn!("Error: {e} Error-UUID: {error_uuid}");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        InternalServerErrorTemplate { error_uuid },
+    )
+        .into_response()
+}
+
+fn local_time(time: chrono::DateTime<Utc>) -> String {
+    time.with_timezone(&chrono_tz::Europe::Berlin)
+        .format("%d.%m.%Y %H:%M")
+        .to_string()
+}
+
+/// The value of the session cookie, if the request has one
+fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|c| c.trim().strip_prefix(SESSION_COOKIE)?.strip_prefix('='))
+}
+
+/// Handlers taking an [`AdminSession`] are only reachable when logged in.
+/// Everyone else is sent to the login page.
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AdminSession {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Extension(config) = Extension::<Arc<Config>>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let Some(id) = session_cookie(&parts.headers) else {
+            return Err(Redirect::to("/admin/login").into_response());
+        };
+        match config.db.get_admin_session(id).await {
+            Ok(Some(session)) => Ok(session),
+            Ok(None) => Err(Redirect::to("/admin/login").into_response()),
+            Err(e) => Err(internal_server_error("getting a session", e)),
+        }
+    }
+}
+
+/// Forms that change something have to send the CSRF token of the session
+#[derive(Debug, Deserialize)]
+struct CsrfForm {
+    csrf_token: String,
+}
+
+fn csrf_rejection(session: &AdminSession, token: &str) -> Option<Response> {
+    if session.csrf_token_matches(token) {
+        None
+    } else {
+        warn!(
+            "Rejecting a form from admin user {} with a wrong CSRF token.",
+            session.user_name
+        );
+        Some((StatusCode::FORBIDDEN, "Invalid CSRF token.").into_response())
+    }
+}
+
+#[derive(Debug, Template)]
+#[template(path = "admin_login.html")]
+struct LoginTemplate {
+    error: Option<String>,
+}
+
+async fn login_page() -> Response {
+    LoginTemplate { error: None }.into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginForm {
+    user_name: String,
+    password: String,
+}
+
+/// Attributes of the session cookie
+fn cookie_attributes(config: &Config, max_age: i64) -> String {
+    let secure = if config.admin.secure_cookie {
+        " Secure;"
+    } else {
+        ""
+    };
+    format!("Path=/admin; HttpOnly;{secure} SameSite=Strict; Max-Age={max_age}")
+}
+
+async fn login(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(throttle): Extension<Arc<LoginThrottle>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    Form(form): Form<LoginForm>,
+) -> Response {
+    let ip = peer.map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |ConnectInfo(addr)| {
+        addr.ip()
+    });
+    if let Some(retry_after) = throttle.retry_after(ip, Instant::now()) {
+        warn!("Refusing an admin login from {ip} after too many failed attempts.");
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.as_secs().to_string())],
+            LoginTemplate {
+                error: Some(
+                    "Zu viele fehlgeschlagene Anmeldungen. Bitte später erneut versuchen."
+                        .to_owned(),
+                ),
+            },
+        )
+            .into_response();
+    }
+    if !verify_login(&config.admin, &form.user_name, &form.password).await {
+        warn!("Failed admin login for user {} from {ip}.", form.user_name);
+        throttle.record_failure(ip, Instant::now());
+        return (
+            StatusCode::UNAUTHORIZED,
+            LoginTemplate {
+                error: Some("Benutzername oder Passwort ist falsch.".to_owned()),
+            },
+        )
+            .into_response();
+    }
+    throttle.record_success(ip);
+    let session = AdminSession::new(&form.user_name, &config.admin);
+    if let Err(e) = config.db.insert_admin_session(&session).await {
+        return internal_server_error("creating a session", e);
+    }
+    info!("Admin user {} logged in.", session.user_name);
+    let cookie = format!(
+        "{SESSION_COOKIE}={}; {}",
+        session.id,
+        cookie_attributes(&config, config.admin.session_minutes * 60)
+    );
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/admin")).into_response()
+}
+
+async fn logout(
+    Extension(config): Extension<Arc<Config>>,
+    session: AdminSession,
+    Form(form): Form<CsrfForm>,
+) -> Response {
+    if let Some(resp) = csrf_rejection(&session, &form.csrf_token) {
+        return resp;
+    }
+    if let Err(e) = config.db.delete_admin_session(&session.id).await {
+        return internal_server_error("deleting a session", e);
+    }
+    let cookie = format!("{SESSION_COOKIE}=; {}", cookie_attributes(&config, 0));
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/admin/login")).into_response()
+}
+
+#[derive(Debug, Template)]
+#[template(path = "admin.html")]
+struct DashboardTemplate {
+    session: AdminSession,
+    last_run: String,
+    last_success: String,
+    recent_errors: Vec<(String, String)>,
+    rooms: Vec<RoomConfig>,
+    statistics: DbStatistics,
+    message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DashboardQuery {
+    message: Option<String>,
+}
+
+async fn dashboard(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(sync): Extension<Arc<SyncState>>,
+    session: AdminSession,
+    axum::extract::Query(query): axum::extract::Query<DashboardQuery>,
+) -> Response {
+    let statistics = match config.db.get_statistics().await {
+        Ok(x) => x,
+        Err(e) => return internal_server_error("getting db statistics", e),
+    };
+    let status = sync.status();
+    let never = || "noch nie".to_owned();
+    DashboardTemplate {
+        session,
+        last_run: status.last_run.map_or_else(never, local_time),
+        last_success: status.last_success.map_or_else(never, local_time),
+        recent_errors: status
+            .recent_errors
+            .into_iter()
+            .map(|(at, e)| (local_time(at), e))
+            .collect(),
+        rooms: config.rooms.clone(),
+        statistics,
+        message: query.message,
+    }
+    .into_response()
+}
+
+async fn trigger_sync(
+    Extension(sync): Extension<Arc<SyncState>>,
+    session: AdminSession,
+    Form(form): Form<CsrfForm>,
+) -> Response {
+    if let Some(resp) = csrf_rejection(&session, &form.csrf_token) {
+        return resp;
+    }
+    info!("Admin user {} triggered a sync.", session.user_name);
+    match sync.request_run() {
+        Ok(_) => Redirect::to("/admin?message=Synchronisation+gestartet.").into_response(),
+        Err(RateLimited { retry_after }) => Redirect::to(&format!(
+            "/admin?message=Die+letzte+Synchronisation+wurde+gerade+erst+angefordert.+Bitte+in+{}+Sekunden+erneut+versuchen.",
+            retry_after.as_secs_f64().ceil()
+        ))
+        .into_response(),
+    }
+}
+
+async fn prune(
+    Extension(config): Extension<Arc<Config>>,
+    session: AdminSession,
+    Form(form): Form<CsrfForm>,
+) -> Response {
+    if let Some(resp) = csrf_rejection(&session, &form.csrf_token) {
+        return resp;
+    }
+    info!("Admin user {} triggered pruning.", session.user_name);
+    match prune_db(&config, None).await {
+        Ok(x) => Redirect::to(&format!("/admin?message={x}+alte+Buchungen+gel%C3%B6scht."))
+            .into_response(),
+        Err(e) => internal_server_error("pruning the db", e),
+    }
+}
+
+/// A backup as a file download
+fn backup_download(content_type: &str, extension: &str, content: Vec<u8>) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"room-overview-{}.{extension}\"",
+                    Utc::now().format("%Y%m%dT%H%M%SZ")
+                ),
+            ),
+        ],
+        content,
+    )
+        .into_response()
+}
+
+async fn export_json(Extension(config): Extension<Arc<Config>>, session: AdminSession) -> Response {
+    info!("Admin user {} exported the db.", session.user_name);
+    let data = match config.db.export().await {
+        Ok(x) => x,
+        Err(e) => return internal_server_error("exporting the db", e),
+    };
+    match serde_json::to_vec(&data) {
+        Ok(json) => backup_download("application/json", "json", json),
+        Err(e) => internal_server_error("serializing the export", e),
+    }
+}
+
+async fn export_sqlite(
+    Extension(config): Extension<Arc<Config>>,
+    session: AdminSession,
+) -> Response {
+    info!(
+        "Admin user {} downloaded a snapshot of the db.",
+        session.user_name
+    );
+    let path = std::env::temp_dir().join(format!("room-overview-export-{}.sqlite", Uuid::new_v4()));
+    match config.db.snapshot(&path).await {
+        Ok(()) => {}
+        Err(DBError::SnapshotUnsupported) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "Eine SQLite-Datei gibt es nur, wenn die Datenbank SQLite ist.",
+            )
+                .into_response()
+        }
+        Err(e) => return internal_server_error("writing a snapshot", e),
+    }
+    let content = std::fs::read(&path);
+    if let Err(e) = std::fs::remove_file(&path) {
+        warn!("Unable to remove {}: {e}", path.display());
+    }
+    match content {
+        Ok(x) => backup_download("application/vnd.sqlite3", "sqlite", x),
+        Err(e) => internal_server_error("reading a snapshot", e),
+    }
+}
+
+/// Replace the db with the uploaded export or snapshot. Answers with a message for the dashboard.
+async fn import(
+    Extension(config): Extension<Arc<Config>>,
+    session: AdminSession,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let token = headers
+        .get(CSRF_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if let Some(resp) = csrf_rejection(&session, token) {
+        return resp;
+    }
+    info!("Admin user {} is importing a backup.", session.user_name);
+    let rejected = |e: BackupError| {
+        warn!("Rejected the import: {e}");
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Die Datei kann nicht importiert werden: {e}"),
+        )
+            .into_response()
+    };
+    let data = match backup::parse(&body).await {
+        Ok(x) => x,
+        Err(e) => return rejected(e),
+    };
+    match backup::import(&*config.db, &data).await {
+        Ok(()) => format!(
+            "{} Buchungen, {} lokale Buchungen, {} archivierte Buchungen und {} Verlaufseinträge importiert.",
+            data.bookings.len(),
+            data.local_bookings.len(),
+            data.archived_bookings.len(),
+            data.booking_events.len()
+        )
+        .into_response(),
+        Err(BackupError::DB(e)) => internal_server_error("importing a backup", e),
+        Err(e) => rejected(e),
+    }
+}
+
+#[derive(Debug)]
+struct ConflictRow {
+    room: String,
+    overlap: String,
+    first_source: String,
+    first_booking_id: i64,
+    first_title: String,
+    second_source: String,
+    second_booking_id: i64,
+    second_title: String,
+    detected_at: String,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "conflicts.html")]
+struct ConflictsTemplate {
+    session: AdminSession,
+    conflicts: Vec<ConflictRow>,
+}
+
+async fn conflicts_page(
+    Extension(config): Extension<Arc<Config>>,
+    session: AdminSession,
+) -> Response {
+    let reports = match config.db.get_conflict_reports().await {
+        Ok(x) => x,
+        Err(e) => return internal_server_error("getting conflicts", e),
+    };
+    let local = |t: chrono::DateTime<Utc>| t.with_timezone(&chrono_tz::Europe::Berlin);
+    let conflicts = reports
+        .into_iter()
+        .map(|r| ConflictRow {
+            room: config
+                .rooms
+                .iter()
+                .find(|room| room.id() == r.conflict.resource_id)
+                .map_or_else(
+                    || format!("Ressource {}", r.conflict.resource_id),
+                    |room| room.name.clone(),
+                ),
+            overlap: format!(
+                "{} - {}",
+                local(r.conflict.overlap_start).format("%d.%m. %H:%M"),
+                local(r.conflict.overlap_end).format("%H:%M")
+            ),
+            first_source: r.conflict.first_source,
+            first_booking_id: r.conflict.first_booking_id,
+            first_title: r.first_title,
+            second_source: r.conflict.second_source,
+            second_booking_id: r.conflict.second_booking_id,
+            second_title: r.second_title,
+            detected_at: local(r.detected_at).format("%d.%m. %H:%M").to_string(),
+        })
+        .collect();
+    ConflictsTemplate { session, conflicts }.into_response()
+}
+
+/// At most this many events are shown on the history page
+const HISTORY_PAGE_EVENTS: i64 = 200;
+
+#[derive(Debug)]
+struct HistoryRow {
+    occurred_at: String,
+    kind: &'static str,
+    booking_id: i64,
+    title: String,
+    room: String,
+    time: String,
+    changes: Vec<String>,
+    sync_run: String,
+}
+
+/// The filter form of the history page. Empty fields do not filter.
+#[derive(Debug, Default, Deserialize)]
+struct HistoryQuery {
+    #[serde(default)]
+    booking_id: String,
+    #[serde(default)]
+    room: String,
+    #[serde(default)]
+    sync_run: String,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "admin_history.html")]
+struct HistoryTemplate {
+    session: AdminSession,
+    events: Vec<HistoryRow>,
+    rooms: Vec<RoomConfig>,
+    query: HistoryQuery,
+}
+
+fn room_name(config: &Config, resource_id: i64) -> String {
+    config
+        .rooms
+        .iter()
+        .find(|r| r.id() == resource_id)
+        .map_or_else(|| format!("Ressource {resource_id}"), |r| r.name.clone())
+}
+
+/// A changed field as shown in the history, e.g. `Ende: 20:00 → 21:00`
+fn describe_change(config: &Config, change: &FieldChange) -> String {
+    let value = |v: &str| match change.field.as_str() {
+        "resource_id" => v
+            .parse()
+            .map_or_else(|_| v.to_owned(), |id| room_name(config, id)),
+        "start_time" | "end_time" => chrono::DateTime::parse_from_rfc3339(v)
+            .map_or_else(|_| v.to_owned(), |t| local_time(t.to_utc())),
+        _ if v.is_empty() => "-".to_owned(),
+        _ => v.to_owned(),
+    };
+    let field = match change.field.as_str() {
+        "title" => "Titel",
+        "resource_id" => "Raum",
+        "start_time" => "Beginn",
+        "end_time" => "Ende",
+        "setup_minutes" => "Vorbereitung (min)",
+        "teardown_minutes" => "Nachbereitung (min)",
+        x => x,
+    };
+    format!("{field}: {} → {}", value(&change.old), value(&change.new))
+}
+
+async fn history_page(
+    Extension(config): Extension<Arc<Config>>,
+    session: AdminSession,
+    axum::extract::Query(query): axum::extract::Query<HistoryQuery>,
+) -> Response {
+    let filter = BookingEventFilter {
+        booking_id: query.booking_id.trim().parse().ok(),
+        room: query.room.parse().ok(),
+        sync_run: Some(query.sync_run.trim().to_owned()).filter(|r| !r.is_empty()),
+        ..Default::default()
+    };
+    let events = match config
+        .db
+        .get_booking_events(&filter, HISTORY_PAGE_EVENTS)
+        .await
+    {
+        Ok(x) => x,
+        Err(e) => return internal_server_error("getting booking events", e),
+    };
+    let events = events
+        .into_iter()
+        .map(|e| HistoryRow {
+            occurred_at: local_time(e.occurred_at),
+            kind: e.kind.label(),
+            booking_id: e.booking_id,
+            room: room_name(&config, e.resource_id),
+            time: format!(
+                "{} - {}",
+                local_time(e.start_time),
+                e.end_time
+                    .with_timezone(&chrono_tz::Europe::Berlin)
+                    .format("%H:%M")
+            ),
+            changes: e.diff.iter().map(|c| describe_change(&config, c)).collect(),
+            sync_run: e.sync_run.unwrap_or_default(),
+            title: e.title,
+        })
+        .collect();
+    HistoryTemplate {
+        session,
+        events,
+        rooms: config.rooms.clone(),
+        query,
+    }
+    .into_response()
+}
+
+/// The form of the reports page. Empty fields mean the last quarter, 8 to 22 on weekdays.
+#[derive(Debug, Default, Deserialize)]
+struct ReportQuery {
+    #[serde(default)]
+    start: String,
+    #[serde(default)]
+    end: String,
+    days: Option<DaySelection>,
+    from_hour: Option<u32>,
+    to_hour: Option<u32>,
+    /// only this room; empty for all rooms
+    #[serde(default)]
+    room: String,
+    /// `hour` for one CSV line per room, weekday and hour
+    #[serde(default)]
+    breakdown: String,
+}
+impl ReportQuery {
+    /// Parse the form into a period, filling in defaults. Errors are human readable.
+    fn period(&self) -> Result<ReportPeriod, String> {
+        let default = ReportPeriod::last_quarter(
+            Utc::now()
+                .with_timezone(&chrono_tz::Europe::Berlin)
+                .date_naive(),
+        );
+        let day = |value: &str, default| {
+            if value.is_empty() {
+                Ok(default)
+            } else {
+                chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                    .map_err(|_| format!("{value} ist kein gültiges Datum."))
+            }
+        };
+        let period = ReportPeriod {
+            start: day(&self.start, default.start)?,
+            end: day(&self.end, default.end)?,
+            days: self.days.unwrap_or(default.days),
+            from_hour: self.from_hour.unwrap_or(default.from_hour),
+            to_hour: self.to_hour.unwrap_or(default.to_hour),
+        };
+        period.validate()?;
+        Ok(period)
+    }
+}
+
+#[derive(Debug)]
+struct ReportRow {
+    room: String,
+    percent: String,
+    booked_hours: String,
+    available_hours: String,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "admin_reports.html")]
+struct ReportsTemplate {
+    session: AdminSession,
+    rooms: Vec<RoomConfig>,
+    start: String,
+    end: String,
+    days: &'static str,
+    /// value, label and whether it is selected
+    day_options: Vec<(&'static str, &'static str, bool)>,
+    from_hour: u32,
+    to_hour: u32,
+    room: String,
+    hours: String,
+    report: Vec<ReportRow>,
+    bar_chart: String,
+    heatmap: String,
+    error: Option<String>,
+}
+
+async fn reports_page(
+    Extension(config): Extension<Arc<Config>>,
+    session: AdminSession,
+    axum::extract::Query(query): axum::extract::Query<ReportQuery>,
+) -> Response {
+    let (period, error) = match query.period() {
+        Ok(x) => (x, None),
+        Err(e) => (ReportPeriod::last_quarter(Utc::now().date_naive()), Some(e)),
+    };
+    let rooms = match utilization(&config, &period, query.room.parse().ok()).await {
+        Ok(x) => x,
+        Err(e) => return internal_server_error("computing the utilization", e),
+    };
+    let hours = |minutes: i64| format!("{:.1}", minutes as f64 / 60.0);
+    ReportsTemplate {
+        session,
+        rooms: config.rooms.clone(),
+        start: period.start.format("%Y-%m-%d").to_string(),
+        end: period.end.format("%Y-%m-%d").to_string(),
+        days: period.days.as_str(),
+        day_options: [
+            (DaySelection::Weekdays, "Werktage"),
+            (DaySelection::Weekends, "Wochenende"),
+            (DaySelection::All, "Alle Tage"),
+        ]
+        .into_iter()
+        .map(|(d, label)| (d.as_str(), label, d == period.days))
+        .collect(),
+        from_hour: period.from_hour,
+        to_hour: period.to_hour,
+        room: query.room,
+        hours: period.describe_hours(),
+        report: rooms
+            .iter()
+            .map(|r| ReportRow {
+                room: r.room.clone(),
+                percent: r.total.percent(),
+                booked_hours: hours(r.total.booked_minutes),
+                available_hours: hours(r.total.available_minutes),
+            })
+            .collect(),
+        bar_chart: bar_chart_svg(&rooms),
+        heatmap: heatmap_svg(&combined_by_hour(&rooms), &period),
+        error,
+    }
+    .into_response()
+}
+
+async fn reports_csv(
+    Extension(config): Extension<Arc<Config>>,
+    _session: AdminSession,
+    axum::extract::Query(query): axum::extract::Query<ReportQuery>,
+) -> Response {
+    let period = match query.period() {
+        Ok(x) => x,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    match utilization(&config, &period, query.room.parse().ok()).await {
+        Ok(rooms) => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_owned()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!(
+                        "attachment; filename=\"utilization_{}_{}.csv\"",
+                        period.start, period.end
+                    ),
+                ),
+            ],
+            to_csv(&rooms, query.breakdown == "hour"),
+        )
+            .into_response(),
+        Err(e) => internal_server_error("computing the utilization", e),
+    }
+}
+
+#[derive(Debug)]
+struct LocalBookingRow {
+    id: i64,
+    title: String,
+    room: String,
+    time: String,
+    is_block: bool,
+    note: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct LocalBookingForm {
+    csrf_token: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    resource_id: String,
+    #[serde(default)]
+    day: String,
+    #[serde(default)]
+    from: String,
+    #[serde(default)]
+    to: String,
+    #[serde(default)]
+    kind: String,
+    #[serde(default)]
+    note: String,
+}
+impl LocalBookingForm {
+    /// Parse the form into a booking. Errors are human readable.
+    fn parse(&self, config: &Config) -> Result<NewLocalBooking, String> {
+        let (start_time, end_time) = parse_local_interval(&self.day, &self.from, &self.to)?;
+        let booking = NewLocalBooking {
+            title: self.title.trim().to_owned(),
+            resource_id: self
+                .resource_id
+                .parse()
+                .map_err(|_| "Der Raum ist ungültig.".to_owned())?,
+            start_time,
+            end_time,
+            kind: if self.kind == "block" {
+                LocalBookingKind::Block
+            } else {
+                LocalBookingKind::Booking
+            },
+            note: Some(self.note.trim().to_owned()).filter(|n| !n.is_empty()),
+        };
+        booking.validate(config)?;
+        Ok(booking)
+    }
+}
+
+#[derive(Debug, Template)]
+#[template(path = "admin_local_bookings.html")]
+struct LocalBookingsTemplate {
+    session: AdminSession,
+    bookings: Vec<LocalBookingRow>,
+    rooms: Vec<RoomConfig>,
+    form: LocalBookingForm,
+    error: Option<String>,
+}
+
+/// Render the local bookings page with all upcoming local bookings
+async fn render_local_bookings(
+    config: &Config,
+    session: AdminSession,
+    form: LocalBookingForm,
+    error: Option<String>,
+) -> Response {
+    let now = Utc::now();
+    let bookings = match config
+        .db
+        .get_local_bookings_in_timeframe(
+            now.naive_utc(),
+            (now + chrono::TimeDelta::days(366)).naive_utc(),
+        )
+        .await
+    {
+        Ok(x) => x,
+        Err(e) => return internal_server_error("getting local bookings", e),
+    };
+    let room_name = |b: &LocalBooking| {
+        config
+            .rooms
+            .iter()
+            .find(|r| r.id() == b.resource_id)
+            .map_or_else(
+                || format!("Ressource {}", b.resource_id),
+                |r| r.name.clone(),
+            )
+    };
+    let bookings = bookings
+        .into_iter()
+        .map(|b| LocalBookingRow {
+            id: b.id,
+            room: room_name(&b),
+            time: format!(
+                "{} - {}",
+                local_time(b.start_time),
+                b.end_time
+                    .with_timezone(&chrono_tz::Europe::Berlin)
+                    .format("%H:%M")
+            ),
+            is_block: b.kind == LocalBookingKind::Block,
+            note: b.note.unwrap_or_default(),
+            title: b.title,
+        })
+        .collect();
+    let status = if error.is_some() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::OK
+    };
+    (
+        status,
+        LocalBookingsTemplate {
+            session,
+            bookings,
+            rooms: config.rooms.clone(),
+            form,
+            error,
+        },
+    )
+        .into_response()
+}
+
+async fn local_bookings_page(
+    Extension(config): Extension<Arc<Config>>,
+    session: AdminSession,
+) -> Response {
+    render_local_bookings(&config, session, LocalBookingForm::default(), None).await
+}
+
+async fn create_local_booking(
+    Extension(config): Extension<Arc<Config>>,
+    session: AdminSession,
+    Form(form): Form<LocalBookingForm>,
+) -> Response {
+    if let Some(resp) = csrf_rejection(&session, &form.csrf_token) {
+        return resp;
+    }
+    let booking = match form.parse(&config) {
+        Ok(x) => x,
+        Err(e) => return render_local_bookings(&config, session, form, Some(e)).await,
+    };
+    match config.db.insert_local_booking(&booking).await {
+        Ok(id) => {
+            info!(
+                "Admin user {} created local booking {id}.",
+                session.user_name
+            );
+            Redirect::to("/admin/local_bookings").into_response()
+        }
+        Err(e) => internal_server_error("inserting a local booking", e),
+    }
+}
+
+async fn delete_local_booking_form(
+    Extension(config): Extension<Arc<Config>>,
+    session: AdminSession,
+    Path(id): Path<i64>,
+    Form(form): Form<CsrfForm>,
+) -> Response {
+    if let Some(resp) = csrf_rejection(&session, &form.csrf_token) {
+        return resp;
+    }
+    match config.db.delete_local_booking(id).await {
+        Ok(_) => {
+            info!(
+                "Admin user {} deleted local booking {id}.",
+                session.user_name
+            );
+            Redirect::to("/admin/local_bookings").into_response()
+        }
+        Err(e) => internal_server_error("deleting a local booking", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_session_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            "other=1; room_overview_session=abc; x=y".parse().unwrap(),
+        );
+        assert_eq!(session_cookie(&headers), Some("abc"));
+        headers.insert(
+            header::COOKIE,
+            "room_overview_session_old=abc".parse().unwrap(),
+        );
+        assert_eq!(session_cookie(&headers), None);
+    }
+}
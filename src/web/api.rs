@@ -0,0 +1,516 @@
+//! The JSON API under /api/v1.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{rejection::JsonRejection, MatchedPath, Path, Query, Request},
+    http::{header, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use super::{split_list, AnnouncementsChanged};
+use crate::{
+    announcements::NewAnnouncement,
+    auth::{constant_time_eq, hash_api_token, Scope},
+    availability::{find_free_rooms, RoomFilter},
+    config::Config,
+    heating::comfort_schedule,
+    history::BookingEventFilter,
+    local_bookings::NewLocalBooking,
+    sync::{RateLimited, SyncRun, SyncState},
+};
+
+/// All routes of the API, behind bearer tokens. The extensions are added by the caller.
+pub(super) fn router() -> Router {
+    Router::new()
+        .route("/api/v1/rooms", get(rooms_api))
+        .route("/api/v1/free_rooms", get(free_rooms_api))
+        .route("/api/v1/conflicts", get(conflicts_api))
+        .route(
+            "/api/v1/local_bookings",
+            get(local_bookings_api).post(create_local_booking_api),
+        )
+        .route(
+            "/api/v1/local_bookings/:id",
+            delete(delete_local_booking_api),
+        )
+        .route(
+            "/api/v1/announcements",
+            get(announcements_api).post(create_announcement_api),
+        )
+        .route("/api/v1/announcements/:id", delete(delete_announcement_api))
+        .route("/api/v1/sync", post(sync_api))
+        .route("/api/v1/bookings", get(bookings_api))
+        .route("/api/v1/comfort_schedule", get(comfort_schedule_api))
+        .route("/api/v1/booking_events", get(booking_events_api))
+        .route_layer(middleware::from_fn(authenticate))
+}
+
+/// At most this many booking events are returned at once
+const MAX_BOOKING_EVENTS: i64 = 1000;
+/// The last use of an API token is recorded at most this often
+const TOKEN_TOUCH_INTERVAL: chrono::TimeDelta = chrono::TimeDelta::minutes(1);
+
+/// How long a sync request waits for the sync to finish
+const SYNC_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(120);
+
+/// The scope a request to the API needs, by method and matched route
+fn required_scope(method: &Method, route: &str) -> Scope {
+    match (method, route) {
+        (_, "/api/v1/sync") => Scope::AdminSync,
+        (&Method::GET, "/api/v1/rooms") => Scope::ReadRooms,
+        (&Method::GET, _) => Scope::ReadBookings,
+        _ => Scope::WriteOverrides,
+    }
+}
+
+fn bearer_rejection(status: StatusCode, error: &str, message: &'static str) -> Response {
+    (
+        status,
+        [(
+            header::WWW_AUTHENTICATE,
+            format!("Bearer error=\"{error}\""),
+        )],
+        message,
+    )
+        .into_response()
+}
+
+/// Only let requests with a valid bearer token with the required scope through
+async fn authenticate(
+    Extension(config): Extension<Arc<Config>>,
+    matched_path: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let scope = required_scope(request.method(), matched_path.as_str());
+    if let Some(rejection) = check_api_token(&config, token, scope).await {
+        return rejection;
+    }
+    next.run(request).await
+}
+
+/// Check that `token` is a valid API token with `scope` and record its use.
+///
+/// Returns the response to send instead if it is not.
+pub(super) async fn check_api_token(
+    config: &Config,
+    token: Option<&str>,
+    scope: Scope,
+) -> Option<Response> {
+    let Some(token) = token else {
+        return Some(
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                "Missing bearer token.",
+            )
+                .into_response(),
+        );
+    };
+    // deprecated, but still accepted with all scopes
+    if config
+        .admin
+        .api_token
+        .as_ref()
+        .is_some_and(|legacy| constant_time_eq(token.as_bytes(), legacy.as_bytes()))
+    {
+        debug!("Accepting the deprecated admin.api_token for scope {scope}.");
+        return None;
+    }
+    let token = match config
+        .db
+        .get_api_token_by_hash(&hash_api_token(token))
+        .await
+    {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            return Some(bearer_rejection(
+                StatusCode::UNAUTHORIZED,
+                "invalid_token",
+                "Unknown bearer token.",
+            ))
+        }
+        Err(e) => return Some(internal_server_error("getting an API token", e)),
+    };
+    let now = Utc::now();
+    if token.is_expired(now) {
+        return Some(bearer_rejection(
+            StatusCode::UNAUTHORIZED,
+            "invalid_token",
+            "The bearer token has expired.",
+        ));
+    }
+    if !token.allows(scope) {
+        debug!("API token {} lacks scope {scope}.", token.name);
+        return Some(bearer_rejection(
+            StatusCode::FORBIDDEN,
+            "insufficient_scope",
+            "The bearer token does not allow this.",
+        ));
+    }
+    // a busy client would otherwise write to the db on every request
+    let recently_used = token
+        .last_used_at
+        .is_some_and(|t| now - t < TOKEN_TOUCH_INTERVAL);
+    if !recently_used {
+        if let Err(e) = config.db.touch_api_token(token.id, now).await {
+            warn!("Unable to record the use of API token {}: {e}", token.name);
+        }
+    }
+    None
+}
+
+fn internal_server_error(what: &str, e: impl core::fmt::Display) -> Response {
+    let error_uuid = Uuid::new_v4();
+    warn!("Sending internal server error because there was a problem {what}.");
+    warn!("DBError: {e} Error-UUID: {error_uuid}");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Internal server error. Error-UUID: {error_uuid}"),
+    )
+        .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct RoomsQuery {
+    building: Option<String>,
+    min_seats: Option<u32>,
+    /// comma separated
+    equipment: Option<String>,
+}
+
+/// All configured rooms with their attributes, optionally filtered
+async fn rooms_api(
+    Extension(config): Extension<Arc<Config>>,
+    Query(query): Query<RoomsQuery>,
+) -> Response {
+    let filter = RoomFilter {
+        building: query.building,
+        min_seats: query.min_seats,
+        equipment: split_list(query.equipment.as_deref()),
+    };
+    Json(
+        config
+            .rooms
+            .iter()
+            .filter(|r| filter.matches(r))
+            .collect::<Vec<_>>(),
+    )
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct FreeRoomsQuery {
+    start: chrono::DateTime<Utc>,
+    end: chrono::DateTime<Utc>,
+    building: Option<String>,
+    min_seats: Option<u32>,
+    /// comma separated
+    equipment: Option<String>,
+}
+
+async fn free_rooms_api(
+    Extension(config): Extension<Arc<Config>>,
+    Query(query): Query<FreeRoomsQuery>,
+) -> Response {
+    if query.end <= query.start {
+        return (StatusCode::BAD_REQUEST, "end must be after start").into_response();
+    }
+    let filter = RoomFilter {
+        building: query.building,
+        min_seats: query.min_seats,
+        equipment: split_list(query.equipment.as_deref()),
+    };
+    match find_free_rooms(&config, &filter, query.start, query.end).await {
+        Ok(x) => Json(x).into_response(),
+        Err(e) => {
+            let error_uuid = Uuid::new_v4();
+            warn!("Sending internal server error because there was a problem getting bookings.");
+            warn!("DBError: {e} Error-UUID: {error_uuid}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Internal server error. Error-UUID: {error_uuid}"),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// A single conflict prepared for display
+async fn conflicts_api(Extension(config): Extension<Arc<Config>>) -> Response {
+    match config.db.get_conflict_reports().await {
+        Ok(x) => Json(x).into_response(),
+        Err(e) => {
+            let error_uuid = Uuid::new_v4();
+            warn!("Sending internal server error because there was a problem getting conflicts.");
+            warn!("DBError: {e} Error-UUID: {error_uuid}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Internal server error. Error-UUID: {error_uuid}"),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// A time range for the listings
+#[derive(Debug, Deserialize)]
+struct TimeframeQuery {
+    start: Option<chrono::DateTime<Utc>>,
+    end: Option<chrono::DateTime<Utc>>,
+}
+
+/// Local bookings in [start, end], by default those in the next year
+async fn local_bookings_api(
+    Extension(config): Extension<Arc<Config>>,
+    Query(query): Query<TimeframeQuery>,
+) -> Response {
+    let start = query.start.unwrap_or_else(Utc::now);
+    let end = query.end.unwrap_or(start + chrono::TimeDelta::days(366));
+    match config
+        .db
+        .get_local_bookings_in_timeframe(start.naive_utc(), end.naive_utc())
+        .await
+    {
+        Ok(mut x) => {
+            // the room may have been removed from the config since
+            x.retain(|b| config.has_room(b.resource_id));
+            Json(x).into_response()
+        }
+        Err(e) => internal_server_error("getting local bookings", e),
+    }
+}
+
+async fn create_local_booking_api(
+    Extension(config): Extension<Arc<Config>>,
+    booking: Result<Json<NewLocalBooking>, JsonRejection>,
+) -> Response {
+    let Json(booking) = match booking {
+        Ok(x) => x,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(reason) = booking.validate(&config) {
+        return (StatusCode::BAD_REQUEST, reason).into_response();
+    }
+    match config.db.insert_local_booking(&booking).await {
+        Ok(id) => (StatusCode::CREATED, Json(serde_json::json!({ "id": id }))).into_response(),
+        Err(e) => internal_server_error("inserting a local booking", e),
+    }
+}
+
+async fn delete_local_booking_api(
+    Extension(config): Extension<Arc<Config>>,
+    Path(id): Path<i64>,
+) -> Response {
+    match config.db.delete_local_booking(id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => internal_server_error("deleting a local booking", e),
+    }
+}
+
+/// Announcements in [start, end], by default those in the next year
+async fn announcements_api(
+    Extension(config): Extension<Arc<Config>>,
+    Query(query): Query<TimeframeQuery>,
+) -> Response {
+    let start = query.start.unwrap_or_else(Utc::now);
+    let end = query.end.unwrap_or(start + chrono::TimeDelta::days(366));
+    match config
+        .db
+        .get_announcements_in_timeframe(start.naive_utc(), end.naive_utc())
+        .await
+    {
+        Ok(x) => Json(x).into_response(),
+        Err(e) => internal_server_error("getting announcements", e),
+    }
+}
+
+async fn create_announcement_api(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(announcements_changed): Extension<AnnouncementsChanged>,
+    announcement: Result<Json<NewAnnouncement>, JsonRejection>,
+) -> Response {
+    let Json(announcement) = match announcement {
+        Ok(x) => x,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(reason) = announcement.validate(&config) {
+        return (StatusCode::BAD_REQUEST, reason).into_response();
+    }
+    match config.db.insert_announcement(&announcement).await {
+        Ok(id) => {
+            announcements_changed.send_replace(());
+            (StatusCode::CREATED, Json(serde_json::json!({ "id": id }))).into_response()
+        }
+        Err(e) => internal_server_error("inserting an announcement", e),
+    }
+}
+
+async fn delete_announcement_api(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(announcements_changed): Extension<AnnouncementsChanged>,
+    Path(id): Path<i64>,
+) -> Response {
+    match config.db.delete_announcement(id).await {
+        Ok(true) => {
+            announcements_changed.send_replace(());
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => internal_server_error("deleting an announcement", e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BookingsQuery {
+    start: Option<chrono::DateTime<Utc>>,
+    end: Option<chrono::DateTime<Utc>>,
+    /// only bookings of the room with this `id`
+    room: Option<i64>,
+}
+
+/// Bookings from CT and local bookings in [start, end], by default those of the next day
+async fn bookings_api(
+    Extension(config): Extension<Arc<Config>>,
+    Query(query): Query<BookingsQuery>,
+) -> Response {
+    if query.room.is_some_and(|room| !config.has_room(room)) {
+        return (StatusCode::NOT_FOUND, "There is no such room.").into_response();
+    }
+    let start = query.start.unwrap_or_else(Utc::now);
+    let end = query.end.unwrap_or(start + chrono::TimeDelta::days(1));
+    match config
+        .db
+        .get_visible_bookings_in_timeframe(start.naive_utc(), end.naive_utc())
+        .await
+    {
+        Ok(mut x) => {
+            // local bookings may still be in rooms that have been removed from the config
+            x.retain(|b| {
+                query.room.map_or_else(
+                    || config.has_room(b.resource_id),
+                    |room| b.resource_id == room,
+                )
+            });
+            Json(x).into_response()
+        }
+        Err(e) => internal_server_error("getting bookings", e),
+    }
+}
+
+/// Comfort periods in [start, end], by default those of the next week
+async fn comfort_schedule_api(
+    Extension(config): Extension<Arc<Config>>,
+    Query(query): Query<BookingsQuery>,
+) -> Response {
+    let start = query.start.unwrap_or_else(Utc::now);
+    let end = query.end.unwrap_or(start + chrono::TimeDelta::days(7));
+    match comfort_schedule(&config, start, end, query.room).await {
+        Ok(x) => Json(x).into_response(),
+        Err(e) => internal_server_error("getting the comfort schedule", e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BookingEventsQuery {
+    #[serde(flatten)]
+    filter: BookingEventFilter,
+    limit: Option<i64>,
+}
+
+/// The booking history, newest first, by default the last 100 events
+async fn booking_events_api(
+    Extension(config): Extension<Arc<Config>>,
+    Query(query): Query<BookingEventsQuery>,
+) -> Response {
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_BOOKING_EVENTS);
+    match config.db.get_booking_events(&query.filter, limit).await {
+        Ok(x) => Json(x).into_response(),
+        Err(e) => internal_server_error("getting booking events", e),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SyncResponse {
+    /// Whether the request joined the syncs that were already running
+    coalesced: bool,
+    /// One run per synced source
+    runs: Vec<SyncRun>,
+}
+
+/// Sync all CT sources now and return the results
+async fn sync_api(Extension(sync): Extension<Arc<SyncState>>) -> Response {
+    let requested = match sync.request_run() {
+        Ok(x) => x,
+        Err(RateLimited { retry_after }) => {
+            let seconds = retry_after.as_secs_f64().ceil().to_string();
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, seconds)],
+                "A sync was requested too recently.",
+            )
+                .into_response();
+        }
+    };
+    match tokio::time::timeout(SYNC_TIMEOUT, sync.wait_for_run(&requested)).await {
+        Ok(Some(runs)) => {
+            let status = if runs.iter().all(|r| r.error.is_none()) {
+                StatusCode::OK
+            } else {
+                StatusCode::BAD_GATEWAY
+            };
+            (
+                status,
+                Json(SyncResponse {
+                    coalesced: requested.coalesced,
+                    runs,
+                }),
+            )
+                .into_response()
+        }
+        Ok(None) | Err(_) => (
+            StatusCode::GATEWAY_TIMEOUT,
+            "The sync did not finish in time.",
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_by_route() {
+        assert_eq!(
+            required_scope(&Method::GET, "/api/v1/rooms"),
+            Scope::ReadRooms
+        );
+        assert_eq!(
+            required_scope(&Method::GET, "/api/v1/local_bookings"),
+            Scope::ReadBookings
+        );
+        assert_eq!(
+            required_scope(&Method::DELETE, "/api/v1/local_bookings/:id"),
+            Scope::WriteOverrides
+        );
+        assert_eq!(
+            required_scope(&Method::POST, "/api/v1/sync"),
+            Scope::AdminSync
+        );
+    }
+}
@@ -4,23 +4,83 @@ use askama_axum::Template;
 use chrono::{Local, TimeDelta, Utc};
 use uuid::Uuid;
 
-use core::{str::FromStr, time::Duration};
+use core::{net::SocketAddr, str::FromStr, time::Duration};
 use std::sync::Arc;
 
 use axum::{
+    extract::{Path, Query},
     http::{header, HeaderMap, StatusCode},
-    response::{Html, IntoResponse},
+    middleware,
+    response::{
+        sse::{self, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
     routing::get,
     Extension, Router,
 };
+use futures::Stream;
+use serde::Deserialize;
 use tracing::{debug, event, warn, Level};
 
 use crate::{
-    config::{Config, RoomConfig},
-    db::get_bookings_in_timeframe,
-    Booking, InShutdown,
+    announcements::{Announcement, Screen},
+    auth::{LoginThrottle, VerifiedLogins},
+    availability::{find_free_rooms, Availability, RoomFilter},
+    config::{Config, RoomConfig, MAX_BUFFER},
+    db::DBError,
+    heating::{comfort_schedule, to_csv},
+    pdf::{render_week_schedule_from_db, week_start, ScheduleSubject},
+    sync::SyncState,
+    Booking, BookingOrigin, InShutdown,
 };
 
+mod access;
+mod admin;
+mod api;
+
+/// Changed whenever announcements are created or deleted, so screens can reload immediately
+type AnnouncementsChanged = Arc<tokio::sync::watch::Sender<()>>;
+
+/// All routes behind the access policies of `[access]`, as written in the router
+pub(crate) const ROUTES: [&str; 33] = [
+    "/",
+    "/all_rooms.ics",
+    "/rooms/:id",
+    "/rooms/:id/schedule.pdf",
+    "/buildings/:building/schedule.pdf",
+    "/announcements/stream",
+    "/free_rooms",
+    "/comfort_schedule.csv",
+    "/admin",
+    "/admin/login",
+    "/admin/logout",
+    "/admin/sync",
+    "/admin/prune",
+    "/admin/conflicts",
+    "/admin/history",
+    "/admin/reports",
+    "/admin/reports.csv",
+    "/admin/export.json",
+    "/admin/export.sqlite",
+    "/admin/import",
+    "/admin/local_bookings",
+    "/admin/local_bookings/:id/delete",
+    "/api/v1/rooms",
+    "/api/v1/free_rooms",
+    "/api/v1/conflicts",
+    "/api/v1/local_bookings",
+    "/api/v1/local_bookings/:id",
+    "/api/v1/announcements",
+    "/api/v1/announcements/:id",
+    "/api/v1/sync",
+    "/api/v1/bookings",
+    "/api/v1/comfort_schedule",
+    "/api/v1/booking_events",
+];
+
+/// How often open announcement streams check whether an announcement started or ended
+const ANNOUNCEMENT_POLL_INTERVAL: Duration = Duration::from_secs(15);
+
 #[derive(Template)]
 #[template(path = "500.html")]
 struct InternalServerErrorTemplate {
@@ -39,16 +99,40 @@ async fn shutdown_signal(
     }
 }
 
+/// The routes listed in [`ROUTES`], without the extensions they need
+fn routes() -> Router {
+    Router::new()
+        .route("/", get(root))
+        .route("/all_rooms.ics", get(all_rooms_ics))
+        .route("/rooms/:id", get(room_door_sign))
+        .route("/rooms/:id/schedule.pdf", get(room_schedule_pdf))
+        .route(
+            "/buildings/:building/schedule.pdf",
+            get(building_schedule_pdf),
+        )
+        .route("/announcements/stream", get(announcement_stream))
+        .route("/free_rooms", get(free_rooms_page))
+        .route("/comfort_schedule.csv", get(comfort_schedule_csv))
+        .merge(admin::router())
+        .merge(api::router())
+}
+
 /// Run the web server
 pub async fn run_web_server(
     config: Arc<Config>,
+    sync: Arc<SyncState>,
     watcher: tokio::sync::watch::Receiver<InShutdown>,
     shutdown_tx: tokio::sync::watch::Sender<InShutdown>,
 ) -> Result<(), Box<dyn core::error::Error>> {
-    let app = Router::new()
-        .route("/", get(root))
-        .route("/all_rooms.ics", get(all_rooms_ics))
+    let announcements_changed: AnnouncementsChanged = Arc::new(tokio::sync::watch::Sender::new(()));
+    let app = routes()
+        .route_layer(middleware::from_fn(access::enforce_access))
         .layer(Extension(config.clone()))
+        .layer(Extension(announcements_changed))
+        .layer(Extension(sync))
+        .layer(Extension(watcher.clone()))
+        .layer(Extension(Arc::new(LoginThrottle::default())))
+        .layer(Extension(Arc::new(VerifiedLogins::default())))
         .route("/style.css", get(css_style))
         .route("/jquery-3.2.1.min.js", get(jquery))
         .fallback(fallback);
@@ -68,7 +152,10 @@ pub async fn run_web_server(
     // serve the main app on HTTP
     let http_future = axum_server::bind(addr)
         .handle(shutdown_handle.clone())
-        .serve(app.clone().into_make_service());
+        .serve(
+            app.clone()
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        );
 
     if let Some(rustls_conf) = &config.web.rustls_config {
         let addr_tls = core::net::SocketAddr::from_str(&format!(
@@ -79,7 +166,7 @@ pub async fn run_web_server(
         // serve the main app on HTTPS
         let https_future = axum_server::bind_rustls(addr_tls, rustls_conf.clone())
             .handle(shutdown_handle.clone())
-            .serve(app.into_make_service());
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>());
         event!(Level::INFO, "Webserver (HTTP) listening on {}", addr);
         event!(Level::INFO, "Webserver (HTTPS) listening on {}", addr_tls);
         tokio::select! {
@@ -145,32 +232,84 @@ async fn fallback() -> impl IntoResponse {
     )
 }
 
+/// Where an event is relative to the current time
+#[derive(Debug, PartialEq)]
+enum Phase {
+    Upcoming,
+    /// The room is being prepared for the event
+    Setup,
+    InUse,
+    /// The room is being cleaned up after the event
+    Teardown,
+    Over,
+}
+
 #[derive(Debug)]
 struct Event {
     name: String,
     start_time: chrono::DateTime<Local>,
     end_time: chrono::DateTime<Local>,
+    /// start of the setup before the event, equal to `start_time` without setup
+    setup_start: chrono::DateTime<Local>,
+    /// end of the teardown after the event, equal to `end_time` without teardown
+    teardown_end: chrono::DateTime<Local>,
     room: RoomConfig,
+    /// created in room-overview instead of CT
+    is_local: bool,
 }
 impl Event {
-    /// Create this event from a churchtools booking
+    /// Create this event from a booking
     fn create_from_booking(value: Booking, config: &Config) -> Option<Self> {
-        let room = config
-            .rooms
-            .iter()
-            .find(|r| r.churchtools_id == value.resource_id)?;
+        let room = config.rooms.iter().find(|r| r.id() == value.resource_id)?;
+        let (setup, teardown) = config.buffers_for(&value);
         Some(Self {
             name: value.title,
             start_time: value.start_time.into(),
             end_time: value.end_time.into(),
+            setup_start: (value.start_time - setup).into(),
+            teardown_end: (value.end_time + teardown).into(),
             room: room.clone(),
+            is_local: value.origin == BookingOrigin::Local,
         })
     }
 
+    fn phase(&self) -> Phase {
+        let current_time = Utc::now();
+        if current_time < self.setup_start {
+            Phase::Upcoming
+        } else if current_time < self.start_time {
+            Phase::Setup
+        } else if current_time <= self.end_time {
+            Phase::InUse
+        } else if current_time <= self.teardown_end {
+            Phase::Teardown
+        } else {
+            Phase::Over
+        }
+    }
+
     /// Is this event currently running?
     fn is_active(&self) -> bool {
-        let current_time = Utc::now();
-        self.start_time <= current_time && current_time <= self.end_time
+        self.phase() == Phase::InUse
+    }
+
+    /// Is the room currently being set up or torn down for this event?
+    fn is_in_buffer(&self) -> bool {
+        matches!(self.phase(), Phase::Setup | Phase::Teardown)
+    }
+
+    /// Does the event block its room at some point in [start, end], including setup and teardown?
+    fn blocks_room_during(&self, start: chrono::DateTime<Utc>, end: chrono::DateTime<Utc>) -> bool {
+        self.setup_start <= end && start <= self.teardown_end
+    }
+
+    /// human readable phase, empty if the event is not in setup or teardown
+    fn phase_label(&self) -> &'static str {
+        match self.phase() {
+            Phase::Setup => "Aufbau",
+            Phase::Teardown => "Abbau",
+            Phase::Upcoming | Phase::InUse | Phase::Over => "",
+        }
     }
 
     /// human readable start time for this event
@@ -210,25 +349,116 @@ impl Event {
             self.name,
             self.room.ics_location()
         )));
-        ics_event.push(ics::properties::Description::new(self.room.ics_location()));
+        let description = if self.is_local {
+            format!("Lokale Buchung. {}", self.room.ics_description())
+        } else {
+            self.room.ics_description()
+        };
+        ics_event.push(ics::properties::Description::new(description));
         ics_event.push(ics::properties::Location::new(self.room.ics_location()));
         ics_event
     }
 }
 
+/// The announcements currently shown on `screen`, split into emergencies and everything else
+async fn current_announcements(
+    config: &Config,
+    screen: Screen,
+) -> Result<(Vec<Announcement>, Vec<Announcement>), DBError> {
+    let now = Utc::now().naive_utc();
+    Ok(config
+        .db
+        .get_announcements_in_timeframe(now, now)
+        .await?
+        .into_iter()
+        .filter(|a| a.targets(screen))
+        .partition(Announcement::is_emergency))
+}
+
+#[derive(Debug, Deserialize)]
+struct AnnouncementStreamQuery {
+    /// The door sign to stream for. Without it, stream for the landing page.
+    room: Option<i64>,
+}
+
+/// Server-sent events telling a screen to reload because its announcements changed
+async fn announcement_stream(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(announcements_changed): Extension<AnnouncementsChanged>,
+    Extension(shutdown): Extension<tokio::sync::watch::Receiver<InShutdown>>,
+    Query(query): Query<AnnouncementStreamQuery>,
+) -> Sse<impl Stream<Item = Result<sse::Event, core::convert::Infallible>>> {
+    let screen = query.room.map_or(Screen::Landing, Screen::Room);
+    let ids = move |config: Arc<Config>| async move {
+        match current_announcements(&config, screen).await {
+            Ok((emergencies, others)) => Some(
+                emergencies
+                    .into_iter()
+                    .chain(others)
+                    .map(|a| a.id)
+                    .collect::<Vec<_>>(),
+            ),
+            Err(e) => {
+                warn!("Unable to get announcements for a stream: {e}");
+                None
+            }
+        }
+    };
+    let shown = ids(config.clone()).await;
+    let state = (config, announcements_changed.subscribe(), shutdown, shown);
+    let stream = futures::stream::unfold(
+        state,
+        move |(config, mut changed, mut shutdown, mut shown)| async move {
+            loop {
+                tokio::select! {
+                    res = changed.changed() => {
+                        // the web server is gone
+                        if res.is_err() {
+                            return None;
+                        }
+                    }
+                    // end the stream, otherwise the graceful shutdown waits for it
+                    _ = shutdown.changed() => {
+                        return None;
+                    }
+                    () = tokio::time::sleep(ANNOUNCEMENT_POLL_INTERVAL) => {}
+                }
+                let now_shown = ids(config.clone()).await;
+                if now_shown.is_some() && now_shown != shown {
+                    shown = now_shown;
+                    let event = sse::Event::default().event("announcements").data("changed");
+                    return Some((Ok(event), (config, changed, shutdown, shown)));
+                }
+            }
+        },
+    );
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
 #[derive(Debug, Template)]
 #[template(path = "landing.html")]
 struct LandingTemplate {
     events: Vec<Event>,
+    emergencies: Vec<Announcement>,
+    announcements: Vec<Announcement>,
+    announcement_stream_url: String,
 }
 
 async fn root(Extension(config): Extension<Arc<Config>>) -> impl IntoResponse {
     let mut headers = HeaderMap::new();
     headers.insert(header::SERVER, "axum".parse().expect("static string"));
-    // get the current booking states
-    let start = Utc::now().naive_utc();
+    // get the current booking states, including those whose setup or teardown reaches into the
+    // next two hours
+    let start = Utc::now();
     let end = start + TimeDelta::minutes(120);
-    let bookings = match get_bookings_in_timeframe(&config.db, start, end).await {
+    let bookings = match config
+        .db
+        .get_visible_bookings_in_timeframe(
+            (start - MAX_BUFFER).naive_utc(),
+            (end + MAX_BUFFER).naive_utc(),
+        )
+        .await
+    {
         Ok(x) => x,
         Err(e) => {
             let error_uuid = Uuid::new_v4();
@@ -241,8 +471,10 @@ async fn root(Extension(config): Extension<Arc<Config>>) -> impl IntoResponse {
                 .into_response();
         }
     };
-    let Some(events) = bookings
+    let Some(mut events) = bookings
         .into_iter()
+        // local bookings may still be in rooms that have been removed from the config
+        .filter(|b| b.origin != BookingOrigin::Local || config.has_room(b.resource_id))
         .map(|b| Event::create_from_booking(b, &config))
         .collect::<Option<Vec<_>>>()
     else {
@@ -255,9 +487,31 @@ async fn root(Extension(config): Extension<Arc<Config>>) -> impl IntoResponse {
         )
             .into_response();
     };
+    events.retain(|e| e.blocks_room_during(start, end));
+    let (emergencies, announcements) = match current_announcements(&config, Screen::Landing).await {
+        Ok(x) => x,
+        Err(e) => {
+            let error_uuid = Uuid::new_v4();
+            warn!(
+                "Sending internal server error because there was a problem getting announcements."
+            );
+            warn!("DBError: {e} Error-UUID: {error_uuid}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                InternalServerErrorTemplate { error_uuid },
+            )
+                .into_response();
+        }
+    };
 
     // push the templated table
-    LandingTemplate { events }.into_response()
+    LandingTemplate {
+        events,
+        emergencies,
+        announcements,
+        announcement_stream_url: "/announcements/stream".to_owned(),
+    }
+    .into_response()
 }
 
 async fn all_rooms_ics(Extension(config): Extension<Arc<Config>>) -> impl IntoResponse {
@@ -266,7 +520,11 @@ async fn all_rooms_ics(Extension(config): Extension<Arc<Config>>) -> impl IntoRe
     // get the current booking states
     let start = Utc::now().naive_utc();
     let end = start + TimeDelta::minutes(120);
-    let bookings = match get_bookings_in_timeframe(&config.db, start, end).await {
+    let bookings = match config
+        .db
+        .get_visible_bookings_in_timeframe(start, end)
+        .await
+    {
         Ok(x) => x,
         Err(e) => {
             let error_uuid = Uuid::new_v4();
@@ -281,6 +539,8 @@ async fn all_rooms_ics(Extension(config): Extension<Arc<Config>>) -> impl IntoRe
     };
     let Some(events) = bookings
         .into_iter()
+        // local bookings may still be in rooms that have been removed from the config
+        .filter(|b| b.origin != BookingOrigin::Local || config.has_room(b.resource_id))
         .map(|b| Event::create_from_booking(b, &config))
         .collect::<Option<Vec<_>>>()
     else {
@@ -308,3 +568,346 @@ async fn all_rooms_ics(Extension(config): Extension<Arc<Config>>) -> impl IntoRe
     );
     (StatusCode::OK, resp_headers, calendar.to_string()).into_response()
 }
+
+#[derive(Debug, Template)]
+#[template(path = "room.html")]
+struct RoomTemplate {
+    room: RoomConfig,
+    events: Vec<Event>,
+    emergencies: Vec<Announcement>,
+    announcements: Vec<Announcement>,
+    announcement_stream_url: String,
+}
+
+/// The door sign for a single room, showing its attributes and the remaining bookings of today
+async fn room_door_sign(
+    Extension(config): Extension<Arc<Config>>,
+    Path(id): Path<i64>,
+) -> Response {
+    let Some(room) = config.rooms.iter().find(|r| r.id() == id) else {
+        return fallback().await.into_response();
+    };
+    let start = Utc::now();
+    let today = start.with_timezone(&chrono_tz::Europe::Berlin).date_naive();
+    let (_, end) = crate::pdf::day_interval_utc(today);
+    let bookings = match config
+        .db
+        .get_visible_bookings_in_timeframe((start - MAX_BUFFER).naive_utc(), end)
+        .await
+    {
+        Ok(x) => x,
+        Err(e) => {
+            let error_uuid = Uuid::new_v4();
+            warn!("Sending internal server error because there was a problem getting bookings.");
+            warn!("DBError: {e} Error-UUID: {error_uuid}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                InternalServerErrorTemplate { error_uuid },
+            )
+                .into_response();
+        }
+    };
+    let events = bookings
+        .into_iter()
+        .filter(|b| b.resource_id == room.id())
+        .filter_map(|b| Event::create_from_booking(b, &config))
+        .filter(|e| e.phase() != Phase::Over)
+        .collect();
+    let (emergencies, announcements) =
+        match current_announcements(&config, Screen::Room(room.id())).await {
+            Ok(x) => x,
+            Err(e) => {
+                let error_uuid = Uuid::new_v4();
+                warn!(
+                "Sending internal server error because there was a problem getting announcements."
+            );
+                warn!("DBError: {e} Error-UUID: {error_uuid}");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    InternalServerErrorTemplate { error_uuid },
+                )
+                    .into_response();
+            }
+        };
+    RoomTemplate {
+        room: room.clone(),
+        events,
+        emergencies,
+        announcements,
+        announcement_stream_url: format!("/announcements/stream?room={}", room.id()),
+    }
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct WeekQuery {
+    /// Any day in the requested week. Defaults to today.
+    week: Option<chrono::NaiveDate>,
+}
+impl WeekQuery {
+    fn monday(&self) -> chrono::NaiveDate {
+        week_start(self.week.unwrap_or_else(|| {
+            Utc::now()
+                .with_timezone(&chrono_tz::Europe::Berlin)
+                .date_naive()
+        }))
+    }
+}
+
+async fn schedule_pdf_response(
+    config: &Config,
+    subject: &ScheduleSubject<'_>,
+    monday: chrono::NaiveDate,
+) -> Response {
+    match render_week_schedule_from_db(config, subject, monday).await {
+        Ok(pdf) => {
+            let mut resp_headers = HeaderMap::new();
+            resp_headers.insert(
+                header::CONTENT_TYPE,
+                "application/pdf".parse().expect("static string"),
+            );
+            (StatusCode::OK, resp_headers, pdf).into_response()
+        }
+        Err(e) => {
+            let error_uuid = Uuid::new_v4();
+            warn!("Sending internal server error because there was a problem rendering a PDF.");
+            warn!("PdfError: {e} Error-UUID: {error_uuid}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                InternalServerErrorTemplate { error_uuid },
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn room_schedule_pdf(
+    Extension(config): Extension<Arc<Config>>,
+    Path(id): Path<i64>,
+    Query(query): Query<WeekQuery>,
+) -> Response {
+    let Some(room) = config.rooms.iter().find(|r| r.id() == id) else {
+        return fallback().await.into_response();
+    };
+    schedule_pdf_response(&config, &ScheduleSubject::Room(room), query.monday()).await
+}
+
+async fn building_schedule_pdf(
+    Extension(config): Extension<Arc<Config>>,
+    Path(building): Path<String>,
+    Query(query): Query<WeekQuery>,
+) -> Response {
+    let rooms = config.rooms_in_building(&building);
+    if rooms.is_empty() {
+        return fallback().await.into_response();
+    }
+    schedule_pdf_response(
+        &config,
+        &ScheduleSubject::Building(&building, &rooms),
+        query.monday(),
+    )
+    .await
+}
+
+#[derive(Debug, Deserialize)]
+struct ComfortScheduleQuery {
+    start: Option<chrono::DateTime<Utc>>,
+    end: Option<chrono::DateTime<Utc>>,
+    /// only the room with this `id`
+    room: Option<i64>,
+}
+
+/// The comfort schedule as CSV, by default for the next week
+async fn comfort_schedule_csv(
+    Extension(config): Extension<Arc<Config>>,
+    Query(query): Query<ComfortScheduleQuery>,
+) -> Response {
+    let start = query.start.unwrap_or_else(Utc::now);
+    let end = query.end.unwrap_or(start + TimeDelta::days(7));
+    match comfort_schedule(&config, start, end, query.room).await {
+        Ok(periods) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/csv;charset=utf-8")],
+            to_csv(&config, &periods),
+        )
+            .into_response(),
+        Err(e) => {
+            let error_uuid = Uuid::new_v4();
+            warn!("Sending internal server error because there was a problem getting the comfort schedule.");
+            warn!("DBError: {e} Error-UUID: {error_uuid}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                InternalServerErrorTemplate { error_uuid },
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Split a comma separated list, ignoring empty entries
+fn split_list(list: Option<&str>) -> Vec<String> {
+    list.unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// The search form for free rooms. All values are kept as entered so they can be shown again.
+#[derive(Debug, Default, Deserialize)]
+struct FreeRoomsForm {
+    #[serde(default)]
+    day: String,
+    #[serde(default)]
+    from: String,
+    #[serde(default)]
+    to: String,
+    #[serde(default)]
+    building: String,
+    #[serde(default)]
+    min_seats: String,
+    #[serde(default)]
+    equipment: String,
+}
+impl FreeRoomsForm {
+    fn is_empty(&self) -> bool {
+        self.day.is_empty() && self.from.is_empty() && self.to.is_empty()
+    }
+
+    /// Parse the form into the UTC interval and filter to search for
+    fn parse(&self) -> Result<(chrono::DateTime<Utc>, chrono::DateTime<Utc>, RoomFilter), String> {
+        let (start, end) = parse_local_interval(&self.day, &self.from, &self.to)?;
+        let min_seats = if self.min_seats.is_empty() {
+            None
+        } else {
+            Some(
+                self.min_seats
+                    .parse()
+                    .map_err(|_| "Die Anzahl der Plätze ist ungültig.".to_owned())?,
+            )
+        };
+        let filter = RoomFilter {
+            building: Some(self.building.clone()).filter(|b| !b.is_empty()),
+            min_seats,
+            equipment: split_list(Some(&self.equipment)),
+        };
+        Ok((start, end, filter))
+    }
+}
+
+/// Parse a day and two times from a form in local time into a UTC interval.
+///
+/// Errors are human readable.
+fn parse_local_interval(
+    day: &str,
+    from: &str,
+    to: &str,
+) -> Result<(chrono::DateTime<Utc>, chrono::DateTime<Utc>), String> {
+    let day = chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d")
+        .map_err(|_| "Der Tag ist ungültig.".to_owned())?;
+    let to_utc = |time: &str| {
+        chrono::NaiveTime::parse_from_str(time, "%H:%M")
+            .ok()
+            .and_then(|t| {
+                day.and_time(t)
+                    .and_local_timezone(chrono_tz::Europe::Berlin)
+                    .earliest()
+            })
+            .map(|t| t.with_timezone(&Utc))
+            .ok_or_else(|| format!("Die Uhrzeit {time} ist ungültig."))
+    };
+    let start = to_utc(from)?;
+    let end = to_utc(to)?;
+    if end <= start {
+        return Err("Das Ende muss nach dem Beginn liegen.".to_owned());
+    }
+    Ok((start, end))
+}
+
+#[derive(Debug, Template)]
+#[template(path = "free_rooms.html")]
+struct FreeRoomsTemplate {
+    form: FreeRoomsForm,
+    buildings: Vec<String>,
+    error: Option<String>,
+    availability: Option<Availability>,
+}
+impl FreeRoomsTemplate {
+    fn hr_time(&self, time: &chrono::DateTime<Utc>) -> String {
+        time.with_timezone(&chrono_tz::Europe::Berlin)
+            .format("%d.%m. %H:%M")
+            .to_string()
+    }
+}
+
+async fn free_rooms_page(
+    Extension(config): Extension<Arc<Config>>,
+    Query(form): Query<FreeRoomsForm>,
+) -> Response {
+    let mut template = FreeRoomsTemplate {
+        form,
+        buildings: config.buildings(),
+        error: None,
+        availability: None,
+    };
+    if template.form.is_empty() {
+        return template.into_response();
+    }
+    let (start, end, filter) = match template.form.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            template.error = Some(e);
+            return (StatusCode::BAD_REQUEST, template).into_response();
+        }
+    };
+    match find_free_rooms(&config, &filter, start, end).await {
+        Ok(x) => {
+            template.availability = Some(x);
+            template.into_response()
+        }
+        Err(e) => {
+            let error_uuid = Uuid::new_v4();
+            warn!("Sending internal server error because there was a problem getting bookings.");
+            warn!("DBError: {e} Error-UUID: {error_uuid}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                InternalServerErrorTemplate { error_uuid },
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn all_routes_are_listed() {
+        // every route is answered by its handler, which fails without the extensions, instead of
+        // the fallback
+        let app = routes().fallback(|| async { StatusCode::IM_A_TEAPOT });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let client = reqwest::Client::new();
+        for route in ROUTES {
+            let path = route.replace(":building", "x").replace(":id", "1");
+            let status = client
+                .get(format!("http://{addr}{path}"))
+                .send()
+                .await
+                .unwrap()
+                .status();
+            assert_ne!(status, StatusCode::IM_A_TEAPOT, "{route} is not routed");
+        }
+        let status = client
+            .get(format!("http://{addr}/unrouted"))
+            .send()
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(status, StatusCode::IM_A_TEAPOT);
+    }
+}
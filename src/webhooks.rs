@@ -0,0 +1,678 @@
+//! Signed webhooks about changes made by the gatherer.
+//!
+//! Events are queued in the db and delivered by a separate task, so deliveries survive restarts
+//! and failed deliveries are retried with exponential backoff.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use hmac::{Hmac, Mac};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{Config, WebhookConfig},
+    conflicts::Conflict,
+    db::{DBError, Storage},
+    heating::ComfortPeriod,
+    Booking, InShutdown,
+};
+
+/// `sha256=` followed by the hex encoded HMAC-SHA256 of the body
+pub const SIGNATURE_HEADER: &str = "x-room-overview-signature";
+pub const EVENT_HEADER: &str = "x-room-overview-event";
+pub const DELIVERY_HEADER: &str = "x-room-overview-delivery";
+/// Name of the target, so a test receiver knows which secret to check against
+pub const TARGET_HEADER: &str = "x-room-overview-target";
+
+/// How often the queue is checked for due deliveries
+const POLL_INTERVAL: core::time::Duration = core::time::Duration::from_secs(10);
+const REQUEST_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(10);
+/// Deliveries claimed at once. Even if all are for one target and time out, they are attempted
+/// within [`CLAIM_DURATION`].
+const CLAIM_LIMIT: i64 = 50;
+/// Other instances do not attempt a claimed delivery for this long
+const CLAIM_DURATION: TimeDelta = TimeDelta::minutes(10);
+const FIRST_RETRY_DELAY: TimeDelta = TimeDelta::seconds(30);
+const MAX_RETRY_DELAY: TimeDelta = TimeDelta::hours(6);
+
+/// The kinds of events a target can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    BookingInserted,
+    BookingUpdated,
+    BookingDeleted,
+    ConflictDetected,
+    HeatingOn,
+    HeatingOff,
+}
+impl WebhookEventKind {
+    pub const ALL: [Self; 6] = [
+        Self::BookingInserted,
+        Self::BookingUpdated,
+        Self::BookingDeleted,
+        Self::ConflictDetected,
+        Self::HeatingOn,
+        Self::HeatingOff,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::BookingInserted => "booking_inserted",
+            Self::BookingUpdated => "booking_updated",
+            Self::BookingDeleted => "booking_deleted",
+            Self::ConflictDetected => "conflict_detected",
+            Self::HeatingOn => "heating_on",
+            Self::HeatingOff => "heating_off",
+        }
+    }
+}
+
+/// A change made by the gatherer
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent<'a> {
+    BookingInserted {
+        booking: &'a Booking,
+    },
+    BookingUpdated {
+        previous: &'a Booking,
+        booking: &'a Booking,
+    },
+    BookingDeleted {
+        booking: &'a Booking,
+    },
+    ConflictDetected {
+        conflict: &'a Conflict,
+    },
+    /// The comfort period of a room starts
+    HeatingOn {
+        period: &'a ComfortPeriod,
+    },
+    HeatingOff {
+        resource_id: i64,
+    },
+}
+impl WebhookEvent<'_> {
+    pub fn kind(&self) -> WebhookEventKind {
+        match self {
+            Self::BookingInserted { .. } => WebhookEventKind::BookingInserted,
+            Self::BookingUpdated { .. } => WebhookEventKind::BookingUpdated,
+            Self::BookingDeleted { .. } => WebhookEventKind::BookingDeleted,
+            Self::ConflictDetected { .. } => WebhookEventKind::ConflictDetected,
+            Self::HeatingOn { .. } => WebhookEventKind::HeatingOn,
+            Self::HeatingOff { .. } => WebhookEventKind::HeatingOff,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a WebhookEvent<'a>,
+    occurred_at: DateTime<Utc>,
+}
+
+/// A queued delivery, as stored in the db
+#[derive(Debug, PartialEq)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub target: String,
+    pub event: String,
+    pub payload: String,
+    /// Failed attempts so far
+    pub attempts: i64,
+    pub next_attempt_at: DateTime<Utc>,
+}
+
+/// The value of the signature header for `body`
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body.as_bytes());
+    let digest = mac.finalize().into_bytes();
+    let hex = digest
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    format!("sha256={hex}")
+}
+
+/// Check a signature header in constant time
+pub fn signature_matches(secret: &str, body: &str, signature: &str) -> bool {
+    crate::auth::constant_time_eq(sign(secret, body).as_bytes(), signature.as_bytes())
+}
+
+/// How long to wait after the `attempts`th failed attempt
+fn retry_delay(attempts: i64) -> TimeDelta {
+    let exponent = u32::try_from(attempts.saturating_sub(1))
+        .unwrap_or(0)
+        .min(16);
+    (FIRST_RETRY_DELAY * 2_i32.pow(exponent)).min(MAX_RETRY_DELAY)
+}
+
+/// A delivery about to be queued
+#[derive(Debug, PartialEq)]
+pub struct NewWebhookDelivery {
+    pub target: String,
+    pub event: &'static str,
+    pub payload: String,
+}
+
+/// The deliveries of `events` to all targets that subscribed to them
+pub fn deliveries(
+    webhooks: &WebhookConfig,
+    events: &[WebhookEvent<'_>],
+    now: DateTime<Utc>,
+) -> Vec<NewWebhookDelivery> {
+    let mut deliveries = vec![];
+    for event in events {
+        let targets = webhooks
+            .targets
+            .iter()
+            .filter(|t| !t.legacy_conflicts && t.events.contains(&event.kind()))
+            .collect::<Vec<_>>();
+        if targets.is_empty() {
+            continue;
+        }
+        let payload = match serde_json::to_string(&Payload {
+            event,
+            occurred_at: now,
+        }) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Unable to serialize webhook payload for {event:?}: {e}");
+                continue;
+            }
+        };
+        for target in targets {
+            deliveries.push(NewWebhookDelivery {
+                target: target.name.clone(),
+                event: event.kind().as_str(),
+                payload: payload.clone(),
+            });
+        }
+    }
+    let conflicts = events
+        .iter()
+        .filter_map(|e| match e {
+            WebhookEvent::ConflictDetected { conflict } => Some(*conflict),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if !conflicts.is_empty() {
+        let payload = serde_json::json!({ "new_conflicts": conflicts }).to_string();
+        for target in webhooks.targets.iter().filter(|t| t.legacy_conflicts) {
+            deliveries.push(NewWebhookDelivery {
+                target: target.name.clone(),
+                event: WebhookEventKind::ConflictDetected.as_str(),
+                payload: payload.clone(),
+            });
+        }
+    }
+    deliveries
+}
+
+/// Queue deliveries of `events` for all targets that subscribed to them.
+///
+/// Changes to bookings queue their deliveries with
+/// [`Storage::apply_booking_changes`] instead, in the same transaction.
+pub async fn enqueue(
+    db: &dyn Storage,
+    webhooks: &WebhookConfig,
+    events: &[WebhookEvent<'_>],
+) -> Result<(), DBError> {
+    for delivery in deliveries(webhooks, events, Utc::now()) {
+        db.insert_webhook_delivery(&delivery.target, delivery.event, &delivery.payload)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Try to deliver once
+async fn deliver(
+    webhooks: &WebhookConfig,
+    client: &reqwest::Client,
+    delivery: &WebhookDelivery,
+) -> Result<(), String> {
+    let Some(target) = webhooks.targets.iter().find(|t| t.name == delivery.target) else {
+        return Err(format!("There is no webhook target {}.", delivery.target));
+    };
+    let url = webhooks.test_receiver.as_ref().unwrap_or(&target.url);
+    let mut request = client
+        .post(url)
+        .timeout(REQUEST_TIMEOUT)
+        .header("content-type", "application/json")
+        .header(EVENT_HEADER, &delivery.event)
+        .header(DELIVERY_HEADER, delivery.id)
+        .header(TARGET_HEADER, &target.name);
+    if let Some(secret) = &target.secret {
+        request = request.header(SIGNATURE_HEADER, sign(secret, &delivery.payload));
+    }
+    request
+        .body(delivery.payload.clone())
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Claim and attempt the deliveries that are due at `now`.
+///
+/// Targets are delivered to concurrently, so a slow target only holds up its own deliveries,
+/// which are still attempted in order. Deliveries claimed by another instance sharing the db are
+/// left to it.
+pub async fn deliver_due(
+    db: &dyn Storage,
+    webhooks: &WebhookConfig,
+    client: &reqwest::Client,
+    now: DateTime<Utc>,
+) -> Result<(), DBError> {
+    let by_target = db
+        .claim_due_webhook_deliveries(now, now + CLAIM_DURATION, CLAIM_LIMIT)
+        .await?
+        .into_iter()
+        .into_group_map_by(|d| d.target.clone());
+    futures::future::join_all(by_target.values().map(|deliveries| async move {
+        for delivery in deliveries {
+            attempt(db, webhooks, client, delivery, now).await?;
+        }
+        Ok(())
+    }))
+    .await
+    .into_iter()
+    .collect()
+}
+
+/// Attempt one delivery and update the queue accordingly
+async fn attempt(
+    db: &dyn Storage,
+    webhooks: &WebhookConfig,
+    client: &reqwest::Client,
+    delivery: &WebhookDelivery,
+    now: DateTime<Utc>,
+) -> Result<(), DBError> {
+    match deliver(webhooks, client, delivery).await {
+        Ok(()) => {
+            debug!(
+                "Delivered webhook {} ({}) to {}.",
+                delivery.id, delivery.event, delivery.target
+            );
+            db.delete_webhook_delivery(delivery.id).await
+        }
+        Err(e) if delivery.attempts + 1 >= webhooks.max_attempts => {
+            warn!(
+                "Giving up on webhook {} ({}) to {} after {} attempts. Last error: {e}",
+                delivery.id,
+                delivery.event,
+                delivery.target,
+                delivery.attempts + 1
+            );
+            db.delete_webhook_delivery(delivery.id).await
+        }
+        Err(e) => {
+            let next_attempt_at = now + retry_delay(delivery.attempts + 1);
+            info!(
+                "Unable to deliver webhook {} to {}, retrying at {next_attempt_at}: {e}",
+                delivery.id, delivery.target
+            );
+            db.record_failed_webhook_delivery(delivery.id, &e, next_attempt_at)
+                .await
+        }
+    }
+}
+
+/// Work through the delivery queue until shutdown
+pub async fn keep_delivering_webhooks(
+    config: Arc<Config>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+) {
+    if config.webhooks.targets.is_empty() {
+        return;
+    }
+    if let Some(receiver) = &config.webhooks.test_receiver {
+        warn!("Webhook test mode: sending all deliveries to {receiver}.");
+    }
+    info!("Starting webhook delivery task");
+    let client = match reqwest::Client::builder()
+        .connect_timeout(REQUEST_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+    {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Unable to build the webhook client: {e}");
+            return;
+        }
+    };
+    loop {
+        if let Err(e) = deliver_due(&*config.db, &config.webhooks, &client, Utc::now()).await {
+            warn!("Unable to deliver webhooks: {e}");
+        }
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down webhook delivery now.");
+                return;
+            }
+            () = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
+
+/// A receiver for test mode, printing everything it gets and checking signatures
+pub async fn run_test_receiver(config: &Config, addr: SocketAddr) -> std::io::Result<()> {
+    let secrets = config
+        .webhooks
+        .targets
+        .iter()
+        .map(|t| (t.name.clone(), t.secret.clone()))
+        .collect::<Vec<_>>();
+    let app = Router::new().fallback(post(move |headers: HeaderMap, body: String| {
+        let secrets = secrets.clone();
+        async move {
+            let header = |name| {
+                headers
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_owned()
+            };
+            let target = header(TARGET_HEADER);
+            let signature = match secrets.iter().find(|(name, _)| *name == target) {
+                None => "unknown target",
+                Some((_, None)) => "unsigned",
+                Some((_, Some(secret))) => {
+                    if signature_matches(secret, &body, &header(SIGNATURE_HEADER)) {
+                        "valid signature"
+                    } else {
+                        "INVALID SIGNATURE"
+                    }
+                }
+            };
+            println!(
+                "{} {} for {target} ({signature}): {body}",
+                header(DELIVERY_HEADER),
+                header(EVENT_HEADER)
+            );
+            StatusCode::NO_CONTENT
+        }
+    }));
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    println!("Receiving webhooks on http://{addr}/");
+    axum::serve(listener, app).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use sqlx::SqlitePool;
+
+    use crate::{config::WebhookTarget, db::SqliteStorage, BookingOrigin};
+
+    #[test]
+    fn signature() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let signature = sign("secret", "{}");
+        assert!(signature_matches("secret", "{}", &signature));
+        assert!(!signature_matches("other", "{}", &signature));
+    }
+
+    #[test]
+    fn retry_delay_backs_off() {
+        assert_eq!(retry_delay(1), TimeDelta::seconds(30));
+        assert_eq!(retry_delay(2), TimeDelta::seconds(60));
+        assert_eq!(retry_delay(4), TimeDelta::seconds(240));
+        assert_eq!(retry_delay(40), MAX_RETRY_DELAY);
+    }
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+    /// A local receiver answering with `status`, returning its url and what it received
+    async fn receiver(status: StatusCode) -> (String, Received) {
+        let received = Received::default();
+        let app = Router::new().fallback(post({
+            let received = received.clone();
+            move |headers: HeaderMap, body: String| async move {
+                received.lock().unwrap().push((headers, body));
+                status
+            }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, received)
+    }
+
+    /// The deliveries due at `now`, without claiming them
+    async fn due(db: &dyn Storage, now: DateTime<Utc>) -> Vec<WebhookDelivery> {
+        db.claim_due_webhook_deliveries(now, now, i64::MAX)
+            .await
+            .unwrap()
+    }
+
+    fn webhooks(url: &str, events: Vec<WebhookEventKind>) -> WebhookConfig {
+        WebhookConfig {
+            targets: vec![WebhookTarget {
+                name: "heating".to_owned(),
+                url: url.to_owned(),
+                secret: Some("secret".to_owned()),
+                events,
+                legacy_conflicts: false,
+            }],
+            max_attempts: 2,
+            test_receiver: None,
+        }
+    }
+
+    fn booking() -> Booking {
+        Booking {
+            title: "Chorprobe".to_owned(),
+            booking_id: 1,
+            source: "churchtools".to_owned(),
+            resource_id: 10,
+            start_time: DateTime::parse_from_rfc3339("2024-10-16T18:00:00+00:00")
+                .unwrap()
+                .into(),
+            end_time: DateTime::parse_from_rfc3339("2024-10-16T20:00:00+00:00")
+                .unwrap()
+                .into(),
+            setup_minutes: None,
+            teardown_minutes: None,
+            origin: BookingOrigin::ChurchTools,
+        }
+    }
+
+    #[test]
+    fn legacy_conflict_target_gets_the_old_body() {
+        let mut webhooks = webhooks("http://localhost/hook", WebhookEventKind::ALL.to_vec());
+        webhooks.targets.push(WebhookTarget {
+            name: "conflicts".to_owned(),
+            url: "http://localhost/conflicts".to_owned(),
+            secret: None,
+            events: vec![WebhookEventKind::ConflictDetected],
+            legacy_conflicts: true,
+        });
+        let booking = booking();
+        let conflicts = [1, 2].map(|second_booking_id| Conflict {
+            resource_id: 10,
+            first_source: "churchtools".to_owned(),
+            first_booking_id: 1,
+            second_source: "churchtools".to_owned(),
+            second_booking_id,
+            overlap_start: booking.start_time,
+            overlap_end: booking.end_time,
+        });
+        let deliveries = deliveries(
+            &webhooks,
+            &[
+                WebhookEvent::BookingInserted { booking: &booking },
+                WebhookEvent::ConflictDetected {
+                    conflict: &conflicts[0],
+                },
+                WebhookEvent::ConflictDetected {
+                    conflict: &conflicts[1],
+                },
+            ],
+            Utc::now(),
+        );
+
+        assert_eq!(
+            deliveries.iter().filter(|d| d.target == "heating").count(),
+            3
+        );
+        let legacy = deliveries
+            .iter()
+            .filter(|d| d.target == "conflicts")
+            .collect::<Vec<_>>();
+        assert_eq!(legacy.len(), 1);
+        assert_eq!(legacy[0].event, "conflict_detected");
+        let payload: serde_json::Value = serde_json::from_str(&legacy[0].payload).unwrap();
+        assert_eq!(
+            payload,
+            serde_json::json!({ "new_conflicts": conflicts.iter().collect::<Vec<_>>() })
+        );
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    async fn delivers_signed_payloads(pool: SqlitePool) {
+        let db = SqliteStorage::new(pool);
+        let (url, received) = receiver(StatusCode::OK).await;
+        let webhooks = webhooks(&url, vec![WebhookEventKind::BookingInserted]);
+        let booking = booking();
+        enqueue(
+            &db,
+            &webhooks,
+            &[
+                WebhookEvent::BookingInserted { booking: &booking },
+                WebhookEvent::BookingDeleted { booking: &booking },
+            ],
+        )
+        .await
+        .unwrap();
+        let client = reqwest::Client::new();
+        deliver_due(&db, &webhooks, &client, Utc::now())
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap().clone();
+        // the target did not subscribe to deletions
+        assert_eq!(received.len(), 1);
+        let (headers, body) = &received[0];
+        assert_eq!(headers[EVENT_HEADER], "booking_inserted");
+        assert!(signature_matches(
+            "secret",
+            body,
+            headers[SIGNATURE_HEADER].to_str().unwrap()
+        ));
+        let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(payload["event"], "booking_inserted");
+        assert_eq!(payload["booking"]["title"], "Chorprobe");
+        assert!(due(&db, Utc::now() + TimeDelta::days(365)).await.is_empty());
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    async fn targets_are_delivered_to_concurrently(pool: SqlitePool) {
+        let db = SqliteStorage::new(pool);
+        // the slow target only answers once the other one got its delivery
+        let other_received = Arc::new(tokio::sync::Notify::new());
+        let app = Router::new()
+            .route(
+                "/slow",
+                post({
+                    let other_received = other_received.clone();
+                    move || async move {
+                        tokio::time::timeout(
+                            core::time::Duration::from_secs(5),
+                            other_received.notified(),
+                        )
+                        .await
+                        .map_or(StatusCode::SERVICE_UNAVAILABLE, |()| StatusCode::OK)
+                    }
+                }),
+            )
+            .route(
+                "/other",
+                post({
+                    let other_received = other_received.clone();
+                    move || async move {
+                        other_received.notify_one();
+                        StatusCode::OK
+                    }
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let target = |name: &str| WebhookTarget {
+            name: name.to_owned(),
+            url: format!("http://{addr}/{name}"),
+            secret: None,
+            events: WebhookEventKind::ALL.to_vec(),
+            legacy_conflicts: false,
+        };
+        let webhooks = WebhookConfig {
+            targets: vec![target("slow"), target("other")],
+            max_attempts: 2,
+            test_receiver: None,
+        };
+        let booking = booking();
+        enqueue(
+            &db,
+            &webhooks,
+            &[WebhookEvent::BookingInserted { booking: &booking }],
+        )
+        .await
+        .unwrap();
+        let client = reqwest::Client::new();
+        deliver_due(&db, &webhooks, &client, Utc::now())
+            .await
+            .unwrap();
+        // a failed delivery would still be queued for a retry
+        assert!(due(&db, Utc::now() + TimeDelta::days(365)).await.is_empty());
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    async fn failed_deliveries_are_retried_then_given_up(pool: SqlitePool) {
+        let db = SqliteStorage::new(pool);
+        let (url, received) = receiver(StatusCode::SERVICE_UNAVAILABLE).await;
+        let webhooks = webhooks(&url, WebhookEventKind::ALL.to_vec());
+        let booking = booking();
+        enqueue(
+            &db,
+            &webhooks,
+            &[WebhookEvent::BookingDeleted { booking: &booking }],
+        )
+        .await
+        .unwrap();
+        let client = reqwest::Client::new();
+        let now = Utc::now();
+        deliver_due(&db, &webhooks, &client, now).await.unwrap();
+
+        // not due again right away
+        assert!(due(&db, now).await.is_empty());
+        let later = now + TimeDelta::seconds(31);
+        let queued = due(&db, later).await;
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].attempts, 1);
+
+        deliver_due(&db, &webhooks, &client, later).await.unwrap();
+        assert_eq!(received.lock().unwrap().len(), 2);
+        assert!(due(&db, Utc::now() + TimeDelta::days(365)).await.is_empty());
+    }
+}